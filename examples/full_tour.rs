@@ -0,0 +1,173 @@
+//! A tour of `datafusion-objectstore-gcs` against a local Cloud Storage emulator.
+//!
+//! Start [fake-gcs-server](https://github.com/fsouza/fake-gcs-server) and run the tour:
+//!
+//! ```text
+//! docker run -d -p 4443:4443 fsouza/fake-gcs-server -scheme http
+//! STORAGE_EMULATOR_HOST=localhost:4443 cargo run --example full_tour
+//! ```
+//!
+//! The tour seeds a Hive-partitioned CSV table, queries it through DataFusion, writes
+//! the result back to the bucket, reads it again and prints what the store did.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use datafusion::arrow::csv::Writer;
+use datafusion::arrow::util::pretty::pretty_format_batches;
+use datafusion::datafusion_data_access::object_store::ObjectStore;
+use datafusion::datasource::file_format::csv::CsvFormat;
+use datafusion::datasource::listing::ListingOptions;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::prelude::*;
+use futures::StreamExt;
+
+use datafusion_objectstore_gcs::object_store::auth::Credentials;
+use datafusion_objectstore_gcs::object_store::gcs::GCSFileSystem;
+use datafusion_objectstore_gcs::object_store::metrics::MetricsRecorder;
+
+const BUCKET: &str = "tour";
+
+const DAYS: [(&str, &str); 3] = [
+    ("2022-05-01", "vendor,fare\na,12.5\nb,7.25\na,30.0\n"),
+    ("2022-05-02", "vendor,fare\nb,9.0\nb,11.5\n"),
+    ("2022-05-03", "vendor,fare\na,4.75\nc,22.0\nc,18.5\na,6.0\n"),
+];
+
+fn io_error(err: std::io::Error) -> DataFusionError {
+    DataFusionError::External(Box::new(err))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let endpoint = match std::env::var("STORAGE_EMULATOR_HOST") {
+        Ok(host) if host.contains("://") => host,
+        Ok(host) => format!("http://{}", host),
+        Err(_) => "http://localhost:4443".to_string(),
+    };
+
+    let metrics = Arc::new(MetricsRecorder::new());
+    // waits for the emulator to accept requests, so the tour can run right after it starts
+    let store = GCSFileSystem::builder()
+        .with_endpoint(&endpoint)
+        .with_credentials(Credentials::Anonymous)
+        .with_context_label("purpose", "full-tour")
+        .with_listing_cache_ttl(Duration::from_secs(60))
+        .with_block_cache(64 * 1024 * 1024)
+        .with_metrics(metrics.clone())
+        .connect()
+        .await
+        .map_err(io_error)?;
+    let store = Arc::new(store);
+    println!("store configuration:\n{}\n", store.describe());
+
+    // the crate manages objects, not buckets, so the bucket is created directly
+    reqwest::Client::new()
+        .post(format!("{}/storage/v1/b", endpoint))
+        .query(&[("project", "tour")])
+        .json(&serde_json::json!({ "name": BUCKET }))
+        .send()
+        .await
+        .map_err(|err| DataFusionError::External(Box::new(err)))?;
+
+    for (day, rows) in DAYS {
+        let uri = format!("gs://{}/trips/day={}/part-0.csv", BUCKET, day);
+        store.put(&uri, rows).await.map_err(io_error)?;
+    }
+
+    let mut listed = store
+        .list_file(&format!("gs://{}/trips/", BUCKET))
+        .await
+        .map_err(io_error)?;
+    println!("seeded objects:");
+    while let Some(file) = listed.next().await {
+        let file = file.map_err(io_error)?;
+        println!(
+            "  {} ({} bytes)",
+            file.sized_file.path, file.sized_file.size
+        );
+    }
+
+    let ctx = SessionContext::new();
+    ctx.runtime_env()
+        .register_object_store("gs", store.clone() as Arc<dyn ObjectStore>);
+
+    let options = ListingOptions {
+        format: Arc::new(CsvFormat::default()),
+        collect_stat: false,
+        file_extension: ".csv".to_string(),
+        target_partitions: 1,
+        table_partition_cols: vec!["day".to_string()],
+    };
+    ctx.register_listing_table("trips", &format!("gs://{}/trips", BUCKET), options, None)
+        .await?;
+
+    let started = Instant::now();
+    let summary = ctx
+        .sql(
+            "SELECT day, count(*) AS trips, sum(fare) AS fares \
+             FROM trips GROUP BY day ORDER BY day",
+        )
+        .await?
+        .collect()
+        .await?;
+    println!("\n{}", pretty_format_batches(&summary)?);
+    println!("query took {:?}", started.elapsed());
+
+    // write the result back next to the source data
+    let mut csv = vec![];
+    {
+        let mut writer = Writer::new(&mut csv);
+        for batch in &summary {
+            writer.write(batch)?;
+        }
+    }
+    let mut output = store
+        .writer(&format!("gs://{}/results/summary.csv", BUCKET))
+        .map_err(io_error)?;
+    std::io::Write::write_all(&mut output, &csv).map_err(io_error)?;
+    output.finish().await.map_err(io_error)?;
+
+    ctx.register_csv(
+        "summary",
+        &format!("gs://{}/results/", BUCKET),
+        CsvReadOptions::new(),
+    )
+    .await?;
+    let busiest = ctx
+        .sql("SELECT day, trips FROM summary ORDER BY trips DESC LIMIT 1")
+        .await?
+        .collect()
+        .await?;
+    println!("\nbusiest day:\n{}", pretty_format_batches(&busiest)?);
+
+    // querying trips again plans from the listing cache and reads from the block cache
+    let started = Instant::now();
+    ctx.sql("SELECT count(*) FROM trips")
+        .await?
+        .collect()
+        .await?;
+    println!("\ncached query took {:?}", started.elapsed());
+
+    let snapshot = store.cache_snapshot();
+    println!(
+        "caches hold {} objects and {} blocks",
+        snapshot.objects.len(),
+        snapshot.blocks.len()
+    );
+
+    println!("\nrequests:");
+    for (kind, totals) in metrics.totals() {
+        println!(
+            "  {:?}: {} requests ({} retried, {} failed) in {:?}, {} bytes down, {} bytes up",
+            kind,
+            totals.requests,
+            totals.retries,
+            totals.failures,
+            totals.latency,
+            totals.bytes_downloaded,
+            totals.bytes_uploaded
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Tables discovered from the layout of a bucket
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use datafusion::catalog::schema::SchemaProvider;
+use datafusion::datafusion_data_access::object_store::ObjectStore;
+use datafusion::datafusion_data_access::ListEntry;
+use datafusion::datasource::file_format::csv::CsvFormat;
+use datafusion::datasource::file_format::json::JsonFormat;
+use datafusion::datasource::file_format::parquet::ParquetFormat;
+use datafusion::datasource::file_format::FileFormat;
+use datafusion::datasource::listing::{ListingOptions, ListingTable, ListingTableConfig};
+use datafusion::datasource::TableProvider;
+use datafusion::error::Result;
+use futures::StreamExt;
+
+use crate::object_store::gcs::GCSFileSystem;
+use crate::object_store::path::GCSPath;
+
+/// Files looked at to pick the format of a table
+const FORMAT_SAMPLE: usize = 100;
+
+/// `SchemaProvider` exposing every directory under a `gs://` prefix as a table.
+///
+/// A table is named after its directory and holds the files below it with the most
+/// common supported extension (`.parquet`, `.csv`, `.json` or `.ndjson`); directories
+/// without such files are skipped. Hive-style `column=value` directories become
+/// partition columns. Register the provider as a schema of a catalog, e.g.
+/// as `events` of a `MemoryCatalogProvider` registered as `gcs`, to query
+/// `gs://bucket/events/clicks/` as `gcs.events.clicks`.
+///
+/// Tables are discovered and their schemas inferred when the provider is created, and
+/// again on [`refresh`](Self::refresh).
+pub struct GCSSchemaProvider {
+    store: Arc<GCSFileSystem>,
+    /// `bucket/prefix/` the table directories are in
+    root: String,
+    tables: RwLock<HashMap<String, Arc<dyn TableProvider>>>,
+}
+
+impl std::fmt::Debug for GCSSchemaProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GCSSchemaProvider")
+            .field("root", &self.root)
+            .field("tables", &self.table_names())
+            .finish()
+    }
+}
+
+impl GCSSchemaProvider {
+    /// Discover the tables in the directories directly under `uri`
+    pub async fn discover(store: Arc<GCSFileSystem>, uri: &str) -> Result<Self> {
+        let path = GCSPath::parse(uri)?;
+        let root = match path.key.as_str() {
+            "" => format!("{}/", path.bucket),
+            key => format!("{}/{}/", path.bucket, key.trim_end_matches('/')),
+        };
+        let provider = Self {
+            store,
+            root,
+            tables: RwLock::new(HashMap::new()),
+        };
+        provider.refresh().await?;
+        Ok(provider)
+    }
+
+    /// Discover the tables again, picking up added and removed directories and changed
+    /// schemas
+    pub async fn refresh(&self) -> Result<()> {
+        let mut entries = self
+            .store
+            .list_dir(&self.root, Some("/".to_string()))
+            .await?;
+        let mut tables = HashMap::new();
+        while let Some(entry) = entries.next().await {
+            let directory = match entry? {
+                ListEntry::Prefix(directory) => directory,
+                ListEntry::FileMeta(_) => continue,
+            };
+            let name = directory
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if let Some(table) = self.load_table(&directory).await? {
+                tables.insert(name, table);
+            }
+        }
+        *self.tables.write().unwrap() = tables;
+        Ok(())
+    }
+
+    /// The table of the files in `directory` (`bucket/prefix/`), if any have a supported
+    /// format
+    async fn load_table(&self, directory: &str) -> Result<Option<Arc<dyn TableProvider>>> {
+        let mut counts = HashMap::new();
+        let mut files = self.store.list_file(directory).await?.take(FORMAT_SAMPLE);
+        while let Some(file) = files.next().await {
+            if let Some((extension, format)) = file_format(&file?.sized_file.path) {
+                counts.entry(extension).or_insert((0, format)).0 += 1;
+            }
+        }
+        let most_common = counts.into_iter().max_by_key(|(_, (count, _))| *count);
+        let (extension, (_, format)) = match most_common {
+            Some(most_common) => most_common,
+            None => return Ok(None),
+        };
+
+        let options = ListingOptions {
+            format,
+            collect_stat: true,
+            file_extension: extension,
+            target_partitions: num_cpus::get(),
+            table_partition_cols: self.store.partition_columns(directory).await?,
+        };
+        let store = self.store.clone() as Arc<dyn ObjectStore>;
+        let schema = options.infer_schema(store.clone(), directory).await?;
+        let config = ListingTableConfig::new(store, directory)
+            .with_listing_options(options)
+            .with_schema(schema);
+        Ok(Some(Arc::new(ListingTable::try_new(config)?)))
+    }
+}
+
+/// Extension and format of a file name, for the formats tables are discovered for
+pub(crate) fn file_format(name: &str) -> Option<(String, Arc<dyn FileFormat>)> {
+    let extension = name.rsplit('/').next()?.rsplit_once('.')?.1;
+    let format: Arc<dyn FileFormat> = match extension {
+        "parquet" => Arc::new(ParquetFormat::default()),
+        "csv" => Arc::new(CsvFormat::default()),
+        "json" | "ndjson" => Arc::new(JsonFormat::default()),
+        _ => return None,
+    };
+    Some((format!(".{}", extension), format))
+}
+
+impl SchemaProvider for GCSSchemaProvider {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn table_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.tables.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn table(&self, name: &str) -> Option<Arc<dyn TableProvider>> {
+        self.tables.read().unwrap().get(name).cloned()
+    }
+
+    fn table_exist(&self, name: &str) -> bool {
+        self.tables.read().unwrap().contains_key(name)
+    }
+}
@@ -0,0 +1,157 @@
+//! Registering GCS on a DataFusion `SessionContext`
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::datafusion_data_access::object_store::ObjectStore;
+use datafusion::datasource::listing::{ListingOptions, ListingTable, ListingTableConfig};
+use datafusion::error::Result;
+use datafusion::prelude::{CsvReadOptions, NdJsonReadOptions, ParquetReadOptions, SessionContext};
+
+use crate::insert::{self, InsertSummary};
+use crate::object_store::gcs::{self, GCSFileSystem, GCSFileSystemBuilder};
+use crate::object_store::path::SCHEMES;
+use crate::object_store::provider::GCSObjectStoreProvider;
+
+/// GCS helpers on `SessionContext`
+#[async_trait]
+pub trait GCSContextExt {
+    /// Build a `GCSFileSystem` from `builder` and register it for the `gs://` and `gcs://`
+    /// schemes, replacing any store registered for them. Returns the store, e.g. for
+    /// writing results or taking cache snapshots.
+    fn register_gcs(&self, builder: GCSFileSystemBuilder) -> Arc<GCSFileSystem>;
+
+    /// Register `provider` for the `gs://` and `gcs://` schemes, so every bucket is read
+    /// through the store configured for it
+    fn register_gcs_provider(
+        &self,
+        provider: GCSObjectStoreProvider,
+    ) -> Arc<GCSObjectStoreProvider>;
+
+    /// Register the Parquet files at `uri` as table `name`, like `register_parquet` but
+    /// with partition columns discovered unless `options` name them
+    async fn register_parquet_gcs(
+        &self,
+        name: &str,
+        uri: &str,
+        options: ParquetReadOptions<'_>,
+    ) -> Result<()>;
+
+    /// Register the CSV files at `uri` as table `name`, like `register_csv` but with
+    /// partition columns discovered unless `options` name them
+    async fn register_csv_gcs(
+        &self,
+        name: &str,
+        uri: &str,
+        options: CsvReadOptions<'_>,
+    ) -> Result<()>;
+
+    /// Register the newline-delimited JSON files at `uri` as table `name`, like
+    /// `register_json` but with partition columns discovered unless `options` name them
+    async fn register_json_gcs(
+        &self,
+        name: &str,
+        uri: &str,
+        options: NdJsonReadOptions<'_>,
+    ) -> Result<()>;
+
+    /// Run `sql`, an `INSERT INTO table SELECT ...` statement, appending the query's rows
+    /// to a listing table of `store` as new part files, see [`insert::insert_into`]
+    async fn insert_into_gcs(&self, store: &GCSFileSystem, sql: &str) -> Result<InsertSummary>;
+}
+
+#[async_trait]
+impl GCSContextExt for SessionContext {
+    fn register_gcs(&self, builder: GCSFileSystemBuilder) -> Arc<GCSFileSystem> {
+        let store = Arc::new(builder.build());
+        let runtime = self.runtime_env();
+        for scheme in SCHEMES {
+            runtime.register_object_store(scheme, store.clone() as Arc<dyn ObjectStore>);
+        }
+        store
+    }
+
+    fn register_gcs_provider(
+        &self,
+        provider: GCSObjectStoreProvider,
+    ) -> Arc<GCSObjectStoreProvider> {
+        let provider = Arc::new(provider);
+        let runtime = self.runtime_env();
+        for scheme in SCHEMES {
+            runtime.register_object_store(scheme, provider.clone() as Arc<dyn ObjectStore>);
+        }
+        provider
+    }
+
+    async fn register_parquet_gcs(
+        &self,
+        name: &str,
+        uri: &str,
+        options: ParquetReadOptions<'_>,
+    ) -> Result<()> {
+        let options = options.to_listing_options(target_partitions(self));
+        register_listing(self, name, uri, options, None).await
+    }
+
+    async fn register_csv_gcs(
+        &self,
+        name: &str,
+        uri: &str,
+        options: CsvReadOptions<'_>,
+    ) -> Result<()> {
+        let schema = options.schema.map(|schema| Arc::new(schema.clone()));
+        let options = options.to_listing_options(target_partitions(self));
+        register_listing(self, name, uri, options, schema).await
+    }
+
+    async fn register_json_gcs(
+        &self,
+        name: &str,
+        uri: &str,
+        options: NdJsonReadOptions<'_>,
+    ) -> Result<()> {
+        let schema = options.schema.clone();
+        let options = options.to_listing_options(target_partitions(self));
+        register_listing(self, name, uri, options, schema).await
+    }
+
+    async fn insert_into_gcs(&self, store: &GCSFileSystem, sql: &str) -> Result<InsertSummary> {
+        insert::insert_into(self, store, sql).await
+    }
+}
+
+/// Build a `GCSFileSystem` from `builder` and register it on `ctx`, see
+/// [`GCSContextExt::register_gcs`]
+pub fn register_gcs(ctx: &SessionContext, builder: GCSFileSystemBuilder) -> Arc<GCSFileSystem> {
+    ctx.register_gcs(builder)
+}
+
+fn target_partitions(ctx: &SessionContext) -> usize {
+    ctx.state.read().config.target_partitions
+}
+
+/// Register the files at `uri` as a listing table, using the store registered for its
+/// scheme, discovering partition columns if `options` has none and inferring the schema
+/// unless one is given
+async fn register_listing(
+    ctx: &SessionContext,
+    name: &str,
+    uri: &str,
+    mut options: ListingOptions,
+    schema: Option<SchemaRef>,
+) -> Result<()> {
+    let (store, path) = ctx.runtime_env().object_store(uri)?;
+    if options.table_partition_cols.is_empty() {
+        options.table_partition_cols = gcs::list_partition_columns(store.as_ref(), path).await?;
+    }
+    let schema = match schema {
+        Some(schema) => schema,
+        None => options.infer_schema(store.clone(), path).await?,
+    };
+    let config = ListingTableConfig::new(store, path)
+        .with_listing_options(options)
+        .with_schema(schema);
+    ctx.register_table(name, Arc::new(ListingTable::try_new(config)?))?;
+    Ok(())
+}
@@ -2,6 +2,9 @@
 
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+
+use crate::object_store::path;
 
 /// Enum with all errors in this crate.
 /// PartialEq is to enable testing for specific error types
@@ -9,17 +12,480 @@ use std::fmt::{Display, Formatter};
 pub enum GCSError {
     /// Returned when functionaly is not yet available.
     NotImplemented(String),
-    /// Wrapper for GCS errors
+    /// Wrapper for GCS errors without more structure, e.g. transport failures
     GCS(String),
+    /// The bucket or object does not exist
+    NotFound {
+        /// Bucket of the request
+        bucket: Option<String>,
+        /// Object of the request, if it named one
+        key: Option<String>,
+        /// Message returned by GCS
+        message: String,
+    },
+    /// The credentials are not allowed to perform the request (401 or 403)
+    PermissionDenied {
+        /// Bucket of the request
+        bucket: Option<String>,
+        /// Object of the request, if it named one
+        key: Option<String>,
+        /// HTTP status of the response
+        status: u16,
+        /// Message returned by GCS
+        message: String,
+    },
+    /// GCS asked to slow down (429)
+    RateLimited {
+        /// Bucket of the request
+        bucket: Option<String>,
+        /// Object of the request, if it named one
+        key: Option<String>,
+        /// HTTP status of the response
+        status: u16,
+        /// Message returned by GCS
+        message: String,
+    },
+    /// A precondition such as `ifGenerationMatch` did not hold (304 or 412)
+    PreconditionFailed {
+        /// Bucket of the request
+        bucket: Option<String>,
+        /// Object of the request, if it named one
+        key: Option<String>,
+        /// HTTP status of the response
+        status: u16,
+        /// Message returned by GCS
+        message: String,
+    },
+    /// Any other unsuccessful response
+    Http {
+        /// Bucket of the request
+        bucket: Option<String>,
+        /// Object of the request, if it named one
+        key: Option<String>,
+        /// HTTP status of the response
+        status: u16,
+        /// Message returned by GCS
+        message: String,
+    },
+    /// Obtaining an access token or signing with the credentials failed
+    Auth(String),
+    /// The object was overwritten or deleted after a reader pinned its generation
+    GenerationChanged {
+        /// `gs://` URI of the object
+        uri: String,
+        /// Generation the reader was pinned to
+        generation: u64,
+    },
+    /// Downloaded data does not match the hash GCS stored for the object
+    ChecksumMismatch {
+        /// `gs://` URI of the object
+        uri: String,
+        /// `crc32c` or `md5`
+        algorithm: String,
+        /// Base64 hash published by GCS
+        expected: String,
+        /// Base64 hash of the received data
+        actual: String,
+    },
+    /// A table listing found an object in the `COLDLINE` or `ARCHIVE` storage class while
+    /// configured to fail on them
+    ArchivedObject {
+        /// `gs://` URI of the object
+        uri: String,
+    },
+    /// The Cloud KMS key protecting an object is disabled, destroyed or not usable
+    /// by the Cloud Storage service agent
+    KmsKeyUnavailable {
+        /// Resource name of the key, if GCS or the object metadata named it
+        key: Option<String>,
+        /// Message returned by GCS
+        message: String,
+    },
+    /// The request is for a bucket or object outside the sandbox of the store
+    OutsideSandbox {
+        /// `gs://` URI of the object or listed prefix
+        uri: String,
+    },
+    /// An environment variable the configuration is read from is malformed or names
+    /// something that does not exist
+    Environment {
+        /// Name of the variable
+        variable: String,
+        /// What is wrong with its value
+        message: String,
+    },
+    /// Reading a byte range of an object failed
+    Read {
+        /// `gs://` URI of the object
+        uri: String,
+        /// Offset of the first byte requested
+        start: u64,
+        /// Number of bytes requested, `0` for the rest of the object
+        length: usize,
+        /// Generation of the object being read, if known
+        generation: Option<u64>,
+        /// What went wrong
+        source: Box<GCSError>,
+    },
 }
 
 impl Display for GCSError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             GCSError::NotImplemented(desc) => write!(f, "Not yet implemented: {}", desc),
-            GCSError::GCS(desc) => write!(f, "AWS error: {}", desc),
+            GCSError::GCS(desc) => write!(f, "GCS error: {}", desc),
+            GCSError::NotFound {
+                bucket,
+                key,
+                message,
+            } => write!(f, "{} not found: {}", location(bucket, key), message),
+            GCSError::PermissionDenied {
+                bucket,
+                key,
+                status,
+                message,
+            } => write!(
+                f,
+                "Permission denied ({}) on {}: {}",
+                status,
+                location(bucket, key),
+                message
+            ),
+            GCSError::RateLimited {
+                bucket,
+                key,
+                status,
+                message,
+            } => write!(
+                f,
+                "Rate limited ({}) on {}: {}",
+                status,
+                location(bucket, key),
+                message
+            ),
+            GCSError::PreconditionFailed {
+                bucket,
+                key,
+                status,
+                message,
+            } => write!(
+                f,
+                "Precondition failed ({}) on {}: {}",
+                status,
+                location(bucket, key),
+                message
+            ),
+            GCSError::Http {
+                bucket,
+                key,
+                status,
+                message,
+            } => write!(
+                f,
+                "GCS returned {} for {}: {}",
+                status,
+                location(bucket, key),
+                message
+            ),
+            GCSError::Auth(desc) => write!(f, "Authentication failed: {}", desc),
+            GCSError::GenerationChanged { uri, generation } => write!(
+                f,
+                "{} changed after generation {} was opened, reopen it to read the current version",
+                uri, generation
+            ),
+            GCSError::ChecksumMismatch {
+                uri,
+                algorithm,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} checksum mismatch for {}: expected {}, got {}",
+                algorithm, uri, expected, actual
+            ),
+            GCSError::ArchivedObject { uri } => write!(
+                f,
+                "{} is in COLDLINE or ARCHIVE storage, reading it incurs retrieval fees",
+                uri
+            ),
+            GCSError::KmsKeyUnavailable { key, message } => write!(
+                f,
+                "Cloud KMS key {} is unavailable: {}",
+                key.as_deref().unwrap_or("(unknown)"),
+                message
+            ),
+            GCSError::OutsideSandbox { uri } => write!(
+                f,
+                "{} is outside the buckets and prefixes this store may access",
+                uri
+            ),
+            GCSError::Environment { variable, message } => {
+                write!(f, "Invalid ${}: {}", variable, message)
+            }
+            GCSError::Read {
+                uri,
+                start,
+                length,
+                generation,
+                source,
+            } => {
+                write!(f, "Reading {} bytes {}-", uri, start)?;
+                if *length > 0 {
+                    write!(f, "{}", start + *length as u64 - 1)?;
+                }
+                if let Some(generation) = generation {
+                    write!(f, " (generation {})", generation)?;
+                }
+                write!(f, " failed: {}", source)
+            }
+        }
+    }
+}
+
+impl Error for GCSError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GCSError::Read { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl GCSError {
+    /// Classify an unsuccessful response to a request for `url`
+    pub(crate) fn from_response(url: &str, status: u16, body: &str) -> Self {
+        let message = error_message(body);
+        if matches!(status, 400 | 403) && is_kms_failure(body, &message) {
+            return GCSError::KmsKeyUnavailable {
+                key: kms_key_name(&message),
+                message,
+            };
+        }
+        let (bucket, key) = path::resource(url);
+        Self::from_status(bucket, key, status, message)
+    }
+
+    /// Classify an unsuccessful HTTP `status` of a request for `bucket` and `key`
+    pub(crate) fn from_status(
+        bucket: Option<String>,
+        key: Option<String>,
+        status: u16,
+        message: String,
+    ) -> Self {
+        match status {
+            404 => GCSError::NotFound {
+                bucket,
+                key,
+                message,
+            },
+            401 | 403 => GCSError::PermissionDenied {
+                bucket,
+                key,
+                status,
+                message,
+            },
+            429 => GCSError::RateLimited {
+                bucket,
+                key,
+                status,
+                message,
+            },
+            304 | 412 => GCSError::PreconditionFailed {
+                bucket,
+                key,
+                status,
+                message,
+            },
+            _ => GCSError::Http {
+                bucket,
+                key,
+                status,
+                message,
+            },
+        }
+    }
+
+    /// The `std::io::ErrorKind` this error is reported as
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            GCSError::NotImplemented(_) | GCSError::ArchivedObject { .. } => ErrorKind::Unsupported,
+            GCSError::NotFound { .. } => ErrorKind::NotFound,
+            GCSError::PermissionDenied { .. }
+            | GCSError::Auth(_)
+            | GCSError::OutsideSandbox { .. }
+            | GCSError::KmsKeyUnavailable { .. } => ErrorKind::PermissionDenied,
+            GCSError::Read { source, .. } => source.kind(),
+            GCSError::ChecksumMismatch { .. } => ErrorKind::InvalidData,
+            GCSError::Environment { .. } => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
         }
     }
 }
 
-impl Error for GCSError {}
+impl From<GCSError> for std::io::Error {
+    fn from(err: GCSError) -> Self {
+        std::io::Error::new(err.kind(), err)
+    }
+}
+
+fn location(bucket: &Option<String>, key: &Option<String>) -> String {
+    match (bucket, key) {
+        (Some(bucket), Some(key)) => format!("gs://{}/{}", bucket, key),
+        (Some(bucket), None) => format!("gs://{}", bucket),
+        _ => "request".to_string(),
+    }
+}
+
+/// The message of a JSON API error response, or the whole body if it is not one
+fn error_message(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| value["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.to_string())
+}
+
+/// Whether a JSON API error response blames a Cloud KMS key, by the `reason` of one of
+/// its errors, e.g. `cloudKmsDisabledKey`, or its message
+fn is_kms_failure(body: &str, message: &str) -> bool {
+    let value = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    let reasons = value["error"]["errors"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|error| error["reason"].as_str());
+    reasons
+        .map(str::to_ascii_lowercase)
+        .any(|reason| reason.contains("kms"))
+        || message.contains("Cloud KMS")
+}
+
+/// Find a `projects/../cryptoKeys/..` resource name in an error message
+fn kms_key_name(message: &str) -> Option<String> {
+    let start = message.find("projects/")?;
+    let name: String = message[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || "/-_.".contains(*c))
+        .collect();
+    name.contains("/cryptoKeys/")
+        .then(|| name.trim_end_matches('.').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://storage.googleapis.com/storage/v1/b/bucket/o/data%2Fa.csv";
+    const KEY: &str = "projects/p/locations/us/keyRings/ring/cryptoKeys/key";
+
+    fn error_body(status: u16, reason: &str, message: &str) -> String {
+        serde_json::json!({
+            "error": {
+                "code": status,
+                "message": message,
+                "errors": [{ "reason": reason, "message": message }],
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn kms_failures_by_reason() {
+        let body = error_body(
+            400,
+            "cloudKmsDisabledKey",
+            &format!("The key {} is disabled.", KEY),
+        );
+        match GCSError::from_response(URL, 400, &body) {
+            GCSError::KmsKeyUnavailable { key, message } => {
+                assert_eq!(key.as_deref(), Some(KEY));
+                assert_eq!(message, format!("The key {} is disabled.", KEY));
+            }
+            err => panic!("unexpected {:?}", err),
+        }
+    }
+
+    #[test]
+    fn kms_failures_by_message() {
+        let message = format!("Permission denied on Cloud KMS key. Key: {}", KEY);
+        let body = error_body(403, "forbidden", &message);
+        assert!(matches!(
+            GCSError::from_response(URL, 403, &body),
+            GCSError::KmsKeyUnavailable { key: Some(key), .. } if key == KEY
+        ));
+    }
+
+    #[test]
+    fn status_wins_over_key_names() {
+        let message = format!("No such object, it was encrypted with {}", KEY);
+        let body = error_body(404, "notFound", &message);
+        match GCSError::from_response(URL, 404, &body) {
+            GCSError::NotFound {
+                bucket,
+                key,
+                message: got,
+            } => {
+                assert_eq!(bucket.as_deref(), Some("bucket"));
+                assert_eq!(key.as_deref(), Some("data/a.csv"));
+                assert_eq!(got, message);
+            }
+            err => panic!("unexpected {:?}", err),
+        }
+
+        let body = error_body(503, "backendError", "Cloud KMS is unavailable");
+        assert!(matches!(
+            GCSError::from_response(URL, 503, &body),
+            GCSError::Http { status: 503, .. }
+        ));
+        let body = error_body(429, "rateLimitExceeded", "Cloud KMS quota exceeded");
+        assert!(matches!(
+            GCSError::from_response(URL, 429, &body),
+            GCSError::RateLimited { status: 429, .. }
+        ));
+        let body = error_body(403, "forbidden", &format!("Caller lacks access to {}", KEY));
+        assert!(matches!(
+            GCSError::from_response(URL, 403, &body),
+            GCSError::PermissionDenied { status: 403, .. }
+        ));
+    }
+
+    #[test]
+    fn status_taxonomy() {
+        let cases = [
+            (401, ErrorKind::PermissionDenied),
+            (403, ErrorKind::PermissionDenied),
+            (404, ErrorKind::NotFound),
+            (412, ErrorKind::Other),
+            (500, ErrorKind::Other),
+        ];
+        for (status, kind) in cases {
+            assert_eq!(GCSError::from_response(URL, status, "oops").kind(), kind);
+        }
+        assert!(matches!(
+            GCSError::from_response(URL, 304, ""),
+            GCSError::PreconditionFailed { status: 304, .. }
+        ));
+        assert!(matches!(
+            GCSError::from_response(URL, 412, ""),
+            GCSError::PreconditionFailed { status: 412, .. }
+        ));
+        // bodies that are not JSON API errors are the message as is
+        match GCSError::from_response(URL, 500, "Service Unavailable") {
+            GCSError::Http { message, .. } => assert_eq!(message, "Service Unavailable"),
+            err => panic!("unexpected {:?}", err),
+        }
+    }
+
+    #[test]
+    fn kms_key_names() {
+        assert_eq!(kms_key_name(&format!("Key {}.", KEY)).as_deref(), Some(KEY));
+        assert_eq!(
+            kms_key_name(&format!("{}/cryptoKeyVersions/3 is destroyed", KEY)).as_deref(),
+            Some(format!("{}/cryptoKeyVersions/3", KEY).as_str())
+        );
+        assert_eq!(kms_key_name("projects/p/buckets/b"), None);
+        assert_eq!(kms_key_name("no key here"), None);
+    }
+}
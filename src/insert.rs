@@ -0,0 +1,851 @@
+//! `INSERT INTO` for listing tables stored in GCS
+
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use datafusion::arrow::csv::WriterBuilder;
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::catalog::TableReference;
+use datafusion::datasource::file_format::csv::CsvFormat;
+use datafusion::datasource::file_format::parquet::ParquetFormat;
+use datafusion::datasource::file_format::FileFormat;
+use datafusion::datasource::listing::ListingTable;
+use datafusion::datasource::TableProvider;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_plan::{source_as_provider, DFSchema, LogicalPlan};
+use datafusion::parquet::arrow::ArrowWriter;
+use datafusion::parquet::file::writer::TryClone;
+use datafusion::physical_plan::SendableRecordBatchStream;
+use datafusion::prelude::{DataFrame, SessionContext};
+use datafusion::sql::parser::{DFParser, Statement};
+use futures::TryStreamExt;
+use sqlparser::ast::{ObjectName, Statement as SQLStatement};
+
+use crate::object_store::gcs::GCSFileSystem;
+use crate::object_store::path::GCSPath;
+use crate::object_store::writer::{Precondition, ResumableUpload};
+
+/// Files and rows added to a table by an insert
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InsertSummary {
+    /// Rows written
+    pub rows: u64,
+    /// `gs://` URIs of the new part files
+    pub files: Vec<String>,
+}
+
+/// Run `sql`, an `INSERT INTO table SELECT ...` statement, against a listing table of
+/// `store` registered on `ctx`, such as one registered with `register_parquet_gcs`.
+///
+/// DataFusion cannot plan inserts itself, so the statement is parsed here: the query is
+/// planned and run by `ctx`, and its results are written to `store` with
+/// [`write_table`]. Column lists, `INSERT OVERWRITE` and `PARTITION` clauses are not
+/// supported.
+pub async fn insert_into(
+    ctx: &SessionContext,
+    store: &GCSFileSystem,
+    sql: &str,
+) -> Result<InsertSummary> {
+    let (name, query) = parse_insert(sql)?;
+    let provider = table_provider(ctx, &name)?;
+    let table = provider
+        .as_any()
+        .downcast_ref::<ListingTable>()
+        .ok_or_else(|| {
+            DataFusionError::NotImplemented(format!(
+                "INSERT INTO {}, which is not a listing table",
+                name
+            ))
+        })?;
+    let df = ctx.sql(&query).await?;
+    write_table(store, table, &df).await
+}
+
+/// Append the results of `df` to `table` as new part files in the table's format (Parquet
+/// or CSV) and ending in its file extension. Each output partition with rows writes
+/// `part-{job}-{partition}-{n}` files, rolling over to the next `n` at the limits set with
+/// `GCSFileSystemBuilder::with_target_part_size` and `with_max_part_rows`.
+///
+/// Parts are first written below `_temporary/` in the table directory, then copied next
+/// to the existing files once every part is written, so a failed query adds nothing.
+/// Each part is streamed through a resumable upload as the query produces its rows, so
+/// partitions are never held in memory as a whole.
+/// Concurrent queries see the staged parts unless the store skips hidden files (see
+/// `GCSFileSystemBuilder::with_skip_hidden_files`). If committing fails, the parts
+/// copied before the failure stay in the table.
+pub async fn write_table(
+    store: &GCSFileSystem,
+    table: &ListingTable,
+    df: &DataFrame,
+) -> Result<InsertSummary> {
+    let options = table.options();
+    if !options.table_partition_cols.is_empty() {
+        return Err(DataFusionError::NotImplemented(format!(
+            "INSERT INTO the partitioned table at {}",
+            table.table_path()
+        )));
+    }
+    let format = PartFormat::of(options.format.as_ref())?;
+    let directory = table_directory(table.table_path(), &options.file_extension)?;
+    let schema = table.schema();
+    check_schema(&schema, df.schema())?;
+
+    let job = format!("{:016x}", rand::random::<u64>());
+    let staging = format!("{}_temporary/{}/", directory, job);
+    // file `n` of a partition, named the same when staged and committed
+    let name =
+        |partition: usize, n: usize| format!("{:05}-{:05}{}", partition, n, options.file_extension);
+    let parts = df.execute_stream_partitioned().await?;
+    let staged =
+        futures::future::try_join_all(parts.into_iter().enumerate().map(|(partition, part)| {
+            let (staging, name) = (&staging, &name);
+            let uri = move |n| format!("{}part-{}", staging, name(partition, n));
+            stage_part(store, &format, schema.clone(), part, uri)
+        }))
+        .await;
+    let staged = match staged {
+        Ok(staged) => staged,
+        Err(err) => {
+            // best effort, a failed cleanup only leaves files below `_temporary/`
+            let _ = store.delete_prefix(&staging).await;
+            return Err(err);
+        }
+    };
+
+    let mut summary = InsertSummary::default();
+    for (partition, files) in staged.into_iter().enumerate() {
+        for (n, rows) in files.into_iter().enumerate() {
+            let uri = format!("{}part-{}", staging, name(partition, n));
+            let committed = format!("{}part-{}-{}", directory, job, name(partition, n));
+            store
+                .copy_if(&uri, &committed, Precondition::DoesNotExist)
+                .await?;
+            summary.rows += rows;
+            summary.files.push(format!("gs://{}", committed));
+        }
+    }
+    store.delete_prefix(&staging).await?;
+    store.invalidate_listings(&directory)?;
+    Ok(summary)
+}
+
+/// Format of the part files written to a table
+#[derive(Debug, Clone, Copy)]
+enum PartFormat {
+    Parquet,
+    Csv { has_header: bool, delimiter: u8 },
+}
+
+impl PartFormat {
+    fn of(format: &dyn FileFormat) -> Result<Self> {
+        let format = format.as_any();
+        if format.is::<ParquetFormat>() {
+            return Ok(Self::Parquet);
+        }
+        if let Some(csv) = format.downcast_ref::<CsvFormat>() {
+            return Ok(Self::Csv {
+                has_header: csv.has_header(),
+                delimiter: csv.delimiter(),
+            });
+        }
+        Err(DataFusionError::NotImplemented(
+            "INSERT INTO tables of formats other than Parquet and CSV".to_string(),
+        ))
+    }
+
+    /// An encoder of a part file of batches of `schema`
+    fn encoder(&self, schema: SchemaRef) -> Result<PartEncoder> {
+        Ok(match *self {
+            Self::Parquet => {
+                let buffer = SharedBuffer::default();
+                let writer = ArrowWriter::try_new(buffer.clone(), schema, None)?;
+                PartEncoder::Parquet {
+                    writer,
+                    buffer,
+                    buffered: 0,
+                }
+            }
+            Self::Csv {
+                has_header,
+                delimiter,
+            } => PartEncoder::Csv {
+                header: has_header,
+                delimiter,
+                written: 0,
+            },
+        })
+    }
+}
+
+/// Encoder of one part file, handing out the encoded bytes batch by batch so they can be
+/// uploaded while the query runs
+enum PartEncoder {
+    /// Row groups are only encoded once complete, so bytes come with every row group
+    Parquet {
+        writer: ArrowWriter<SharedBuffer>,
+        buffer: SharedBuffer,
+        /// In-memory size of the rows written since bytes were last encoded
+        buffered: u64,
+    },
+    Csv {
+        /// Whether the header is still to be written
+        header: bool,
+        delimiter: u8,
+        written: u64,
+    },
+}
+
+impl PartEncoder {
+    /// Encode `batch`, which takes `memory` bytes in memory, and return the bytes encoded
+    /// since the last call
+    fn write(&mut self, batch: &RecordBatch, memory: u64) -> Result<Vec<u8>> {
+        match self {
+            Self::Parquet {
+                writer,
+                buffer,
+                buffered,
+            } => {
+                let position = buffer.position();
+                writer.write(batch)?;
+                // a row group was encoded, the rows left over for the next are not counted
+                *buffered = match buffer.position() == position {
+                    true => *buffered + memory,
+                    false => 0,
+                };
+                Ok(buffer.take())
+            }
+            Self::Csv {
+                header,
+                delimiter,
+                written,
+            } => {
+                let mut data = vec![];
+                // dropping the writer flushes it into `data`
+                WriterBuilder::new()
+                    .has_headers(std::mem::take(header))
+                    .with_delimiter(*delimiter)
+                    .build(&mut data)
+                    .write(batch)?;
+                *written += data.len() as u64;
+                Ok(data)
+            }
+        }
+    }
+
+    /// Size of the part file so far, counting rows not yet encoded by their size in memory
+    fn size(&self) -> u64 {
+        match self {
+            Self::Parquet {
+                buffer, buffered, ..
+            } => buffer.position() + buffered,
+            Self::Csv { written, .. } => *written,
+        }
+    }
+
+    /// The remaining bytes of the part file
+    fn finish(self) -> Result<Vec<u8>> {
+        match self {
+            Self::Parquet {
+                mut writer, buffer, ..
+            } => {
+                writer.close()?;
+                Ok(buffer.take())
+            }
+            Self::Csv { .. } => Ok(vec![]),
+        }
+    }
+}
+
+/// `Write` sink whose bytes can be taken while a writer owns it. Clones share the data
+/// and position, as the Parquet writer expects of `TryClone`.
+#[derive(Debug, Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Buffered>>);
+
+#[derive(Debug, Default)]
+struct Buffered {
+    data: Vec<u8>,
+    /// Bytes written so far, including those already taken
+    position: u64,
+}
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap().data)
+    }
+
+    fn position(&self) -> u64 {
+        self.0.lock().unwrap().position
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut buffered = self.0.lock().unwrap();
+        buffered.data.extend_from_slice(buf);
+        buffered.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SharedBuffer {
+    /// Only reports the position, which is all the Parquet writer seeks for
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.0.lock().unwrap().position;
+        match pos {
+            SeekFrom::Current(0) => Ok(position),
+            SeekFrom::Start(start) if start == position => Ok(position),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "part files are written sequentially",
+            )),
+        }
+    }
+}
+
+impl TryClone for SharedBuffer {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+/// Write the rows of `part` to the files `uri(0)`, `uri(1)` and so on, each within the
+/// store's part limits, and return the number of rows of each file
+async fn stage_part(
+    store: &GCSFileSystem,
+    format: &PartFormat,
+    schema: SchemaRef,
+    part: SendableRecordBatchStream,
+    uri: impl Fn(usize) -> String,
+) -> Result<Vec<u64>> {
+    let mut upload = None;
+    match upload_part(store, format, schema, part, uri, &mut upload).await {
+        Ok(files) => Ok(files),
+        Err(err) => {
+            // best effort, an abandoned session also expires without writing the object
+            if let Some(upload) = upload {
+                let _ = upload.abort().await;
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Stream the rows of `part` to the files `uri(n)` through `upload`, started with the
+/// first rows of each file, and return the number of rows of each file
+async fn upload_part(
+    store: &GCSFileSystem,
+    format: &PartFormat,
+    schema: SchemaRef,
+    mut part: SendableRecordBatchStream,
+    uri: impl Fn(usize) -> String,
+    upload: &mut Option<ResumableUpload>,
+) -> Result<Vec<u64>> {
+    let limits = store.part_limits();
+    let mut files = vec![];
+    // encoder and rows of the file being written
+    let mut file: Option<(PartEncoder, u64)> = None;
+    while let Some(batch) = part.try_next().await? {
+        // the columns get the table's names, which the query need not use
+        let mut batch = RecordBatch::try_new(schema.clone(), batch.columns().to_vec())?;
+        let memory = batch
+            .columns()
+            .iter()
+            .map(|column| column.get_array_memory_size() as u64)
+            .sum::<u64>();
+        let row_size = memory / batch.num_rows().max(1) as u64;
+        while batch.num_rows() > 0 {
+            if file.is_none() {
+                file = Some((format.encoder(schema.clone())?, 0));
+            }
+            let (encoder, rows) = file.as_mut().unwrap();
+            let take = match limits.max_rows {
+                Some(max) => (max - *rows).min(batch.num_rows() as u64) as usize,
+                None => batch.num_rows(),
+            };
+            let data = encoder.write(&batch.slice(0, take), row_size * take as u64)?;
+            batch = batch.slice(take, batch.num_rows() - take);
+            *rows += take as u64;
+            if upload.is_none() {
+                *upload = Some(store.resumable_upload(&uri(files.len())).await?);
+            }
+            if let Some(upload) = upload.as_mut() {
+                upload.write(&data).await?;
+            }
+            if encoder.size() >= limits.target_size || Some(*rows) == limits.max_rows {
+                let (encoder, rows) = file.take().unwrap();
+                finish_file(encoder, upload).await?;
+                files.push(rows);
+            }
+        }
+    }
+    if let Some((encoder, rows)) = file {
+        finish_file(encoder, upload).await?;
+        files.push(rows);
+    }
+    Ok(files)
+}
+
+/// Upload the rest of the file `encoder` encoded through `upload`, and finish it
+async fn finish_file(encoder: PartEncoder, upload: &mut Option<ResumableUpload>) -> Result<()> {
+    if let Some(upload) = upload.as_mut() {
+        upload.write(&encoder.finish()?).await?;
+    }
+    if let Some(upload) = upload.take() {
+        upload.finish().await?;
+    }
+    Ok(())
+}
+
+/// The table name and query of an `INSERT INTO table SELECT ...` statement
+fn parse_insert(sql: &str) -> Result<(ObjectName, String)> {
+    let mut statements = DFParser::parse_sql(sql)?;
+    let statement = match (statements.pop_front(), statements.is_empty()) {
+        (Some(statement), true) => statement,
+        _ => {
+            return Err(DataFusionError::Plan(
+                "Expected a single INSERT INTO statement".to_string(),
+            ))
+        }
+    };
+    let insert = match statement {
+        Statement::Statement(statement) => *statement,
+        _ => {
+            return Err(DataFusionError::Plan(format!(
+                "Expected an INSERT INTO statement, got {}",
+                sql
+            )))
+        }
+    };
+    match insert {
+        SQLStatement::Insert {
+            table_name,
+            columns,
+            overwrite,
+            source,
+            partitioned,
+            ..
+        } => {
+            if !columns.is_empty() || overwrite || partitioned.is_some() {
+                return Err(DataFusionError::NotImplemented(
+                    "INSERT INTO with column lists, OVERWRITE or PARTITION".to_string(),
+                ));
+            }
+            Ok((table_name, source.to_string()))
+        }
+        _ => Err(DataFusionError::Plan(format!(
+            "Expected an INSERT INTO statement, got {}",
+            sql
+        ))),
+    }
+}
+
+/// The table `name` refers to in `ctx`, in the default catalog and schema unless named
+fn table_provider(ctx: &SessionContext, name: &ObjectName) -> Result<Arc<dyn TableProvider>> {
+    let parts: Vec<_> = name.0.iter().map(|ident| ident.value.as_str()).collect();
+    let reference = match parts.as_slice() {
+        [table] => TableReference::Bare { table },
+        [schema, table] => TableReference::Partial { schema, table },
+        [catalog, schema, table] => TableReference::Full {
+            catalog,
+            schema,
+            table,
+        },
+        _ => {
+            return Err(DataFusionError::Plan(format!(
+                "Invalid table name {}",
+                name
+            )))
+        }
+    };
+    // the context resolves the default catalog and schema, and scans name the provider
+    match ctx.table(reference)?.to_logical_plan()? {
+        LogicalPlan::TableScan(scan) => source_as_provider(&scan.source),
+        _ => Err(DataFusionError::Plan(format!("Table {} not found", name))),
+    }
+}
+
+/// `bucket/prefix/` of a table registered for `table_path`, which must be a directory
+/// rather than a single file or a glob pattern
+fn table_directory(table_path: &str, extension: &str) -> Result<String> {
+    let path = GCSPath::parse(table_path)?;
+    let (_, pattern) = path.split_glob()?;
+    if pattern.is_some() || (!extension.is_empty() && path.key.ends_with(extension)) {
+        return Err(DataFusionError::NotImplemented(format!(
+            "INSERT INTO {}, which is not a table directory",
+            table_path
+        )));
+    }
+    Ok(match path.key.trim_end_matches('/') {
+        "" => format!("{}/", path.bucket),
+        key => format!("{}/{}/", path.bucket, key),
+    })
+}
+
+/// Fail unless the query's columns have the types of the table's, in order
+fn check_schema(table: &Schema, query: &DFSchema) -> Result<()> {
+    if table.fields().len() != query.fields().len() {
+        return Err(DataFusionError::Plan(format!(
+            "INSERT INTO a table of {} columns from a query of {}",
+            table.fields().len(),
+            query.fields().len()
+        )));
+    }
+    for (column, value) in table.fields().iter().zip(query.fields()) {
+        if column.data_type() != value.data_type() {
+            return Err(DataFusionError::Plan(format!(
+                "Column {} is {:?}, but the query gives {} as {:?}",
+                column.name(),
+                column.data_type(),
+                value.name(),
+                value.data_type()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::auth::Credentials;
+    use crate::object_store::test_server::{FakeGcs, Request};
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field};
+    use datafusion::datafusion_data_access::object_store::ObjectStore;
+    use datafusion::datasource::listing::{ListingOptions, ListingTableConfig};
+    use datafusion::datasource::MemTable;
+    use datafusion::parquet::file::reader::{FileReader, SerializedFileReader};
+    use datafusion::prelude::SessionConfig;
+
+    fn schema(names: [&str; 2]) -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new(names[0], DataType::Int64, false),
+            Field::new(names[1], DataType::Utf8, false),
+        ]))
+    }
+
+    fn batch(schema: SchemaRef, numbers: &[i64], names: &[&str]) -> RecordBatch {
+        let columns: Vec<Arc<dyn datafusion::arrow::array::Array>> = vec![
+            Arc::new(Int64Array::from(numbers.to_vec())),
+            Arc::new(StringArray::from(names.to_vec())),
+        ];
+        RecordBatch::try_new(schema, columns).unwrap()
+    }
+
+    fn store(endpoint: &str) -> GCSFileSystem {
+        GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(endpoint)
+            .build()
+    }
+
+    #[test]
+    fn parses_inserts() {
+        let (name, query) = parse_insert("INSERT INTO db.t SELECT a FROM s").unwrap();
+        assert_eq!(name.to_string(), "db.t");
+        assert_eq!(query, "SELECT a FROM s");
+
+        for sql in [
+            "SELECT 1",
+            "CREATE EXTERNAL TABLE t STORED AS CSV LOCATION 'gs://bucket/t/'",
+            "INSERT INTO t SELECT 1; INSERT INTO t SELECT 2",
+        ] {
+            let err = parse_insert(sql).unwrap_err();
+            assert!(matches!(err, DataFusionError::Plan(_)), "{}: {}", sql, err);
+        }
+        for sql in [
+            "INSERT INTO t (a) SELECT 1",
+            "INSERT OVERWRITE TABLE t SELECT 1",
+            "INSERT INTO t PARTITION (p = 1) SELECT 1",
+        ] {
+            let err = parse_insert(sql).unwrap_err();
+            assert!(
+                matches!(err, DataFusionError::NotImplemented(_)),
+                "{}: {}",
+                sql,
+                err
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn only_inserts_into_listing_tables() {
+        let ctx = SessionContext::new();
+        let memory = MemTable::try_new(schema(["a", "b"]), vec![vec![]]).unwrap();
+        ctx.register_table("memory", Arc::new(memory)).unwrap();
+        // fails before any request is sent
+        let store = store("http://127.0.0.1:9");
+
+        let err = insert_into(&ctx, &store, "INSERT INTO memory SELECT 1, 'one'")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataFusionError::NotImplemented(_)), "{}", err);
+        let err = insert_into(&ctx, &store, "INSERT INTO missing SELECT 1, 'one'")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataFusionError::Plan(_)), "{}", err);
+    }
+
+    #[test]
+    fn table_directories() {
+        assert_eq!(
+            table_directory("gs://bucket/t", ".csv").unwrap(),
+            "bucket/t/"
+        );
+        assert_eq!(
+            table_directory("gs://bucket/t/", ".csv").unwrap(),
+            "bucket/t/"
+        );
+        assert_eq!(table_directory("bucket/a/b/", "").unwrap(), "bucket/a/b/");
+        assert_eq!(table_directory("gs://bucket", ".csv").unwrap(), "bucket/");
+        assert_eq!(table_directory("gs://bucket/", ".csv").unwrap(), "bucket/");
+
+        for path in ["gs://bucket/t/*.csv", "gs://bucket/t/part.csv"] {
+            let err = table_directory(path, ".csv").unwrap_err();
+            assert!(matches!(err, DataFusionError::NotImplemented(_)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn schemas_must_match_by_position() {
+        let table = schema(["a", "b"]);
+        // names may differ
+        let query = DFSchema::try_from(schema(["x", "y"]).as_ref().clone()).unwrap();
+        check_schema(&table, &query).unwrap();
+
+        let fewer = Schema::new(vec![Field::new("x", DataType::Int64, false)]);
+        let err = check_schema(&table, &DFSchema::try_from(fewer).unwrap()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("table of 2 columns from a query of 1"));
+
+        let swapped = Schema::new(vec![
+            Field::new("y", DataType::Utf8, false),
+            Field::new("x", DataType::Int64, false),
+        ]);
+        let err = check_schema(&table, &DFSchema::try_from(swapped).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Column a is Int64"), "{}", err);
+    }
+
+    #[test]
+    fn encodes_csv_parts() {
+        let schema = schema(["a", "b"]);
+        let format = PartFormat::Csv {
+            has_header: true,
+            delimiter: b'|',
+        };
+        let mut encoder = format.encoder(schema.clone()).unwrap();
+        let first = encoder.write(&batch(schema.clone(), &[1], &["one"]), 0);
+        assert_eq!(first.unwrap(), b"a|b\n1|one\n");
+        let second = encoder.write(&batch(schema.clone(), &[2, 3], &["two", "three"]), 0);
+        assert_eq!(second.unwrap(), b"2|two\n3|three\n");
+        assert_eq!(encoder.size(), 24);
+        assert!(encoder.finish().unwrap().is_empty());
+
+        let format = PartFormat::Csv {
+            has_header: false,
+            delimiter: b',',
+        };
+        let mut encoder = format.encoder(schema.clone()).unwrap();
+        let data = encoder.write(&batch(schema, &[1], &["one"]), 0).unwrap();
+        assert_eq!(data, b"1,one\n");
+    }
+
+    #[test]
+    fn encodes_parquet_parts() {
+        // the dictionary encoder of parquet 13 reads strings unaligned, which debug builds
+        // reject, so the part only has numbers
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::Int64, false),
+        ]));
+        let numbers = |values: &[i64]| {
+            let column = Arc::new(Int64Array::from(values.to_vec()));
+            RecordBatch::try_new(schema.clone(), vec![column.clone(), column]).unwrap()
+        };
+        let mut encoder = PartFormat::Parquet.encoder(schema.clone()).unwrap();
+        let mut data = encoder.write(&numbers(&[1]), 100).unwrap();
+        data.extend(encoder.write(&numbers(&[2, 3]), 200).unwrap());
+        // the rows are buffered for the row group, and count with their size in memory
+        assert_eq!(encoder.size(), 4 + 300);
+        assert!(data.starts_with(b"PAR1"));
+        // the footer comes with the rest of the file
+        let rest = encoder.finish().unwrap();
+        assert!(rest.len() > 4 && rest.ends_with(b"PAR1"));
+        data.extend(rest);
+
+        let file = std::env::temp_dir().join(format!("insert-{}.parquet", rand::random::<u64>()));
+        std::fs::write(&file, &data).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&file).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 3);
+        assert_eq!(metadata.schema_descr().column(1).name(), "b");
+        std::fs::remove_file(file).unwrap();
+    }
+
+    /// The CSV table at `gs://bucket/t/` of `store`, with columns `a` and `b`
+    fn csv_table(store: &Arc<GCSFileSystem>) -> ListingTable {
+        let options = ListingOptions {
+            file_extension: ".csv".to_string(),
+            ..ListingOptions::new(Arc::new(CsvFormat::default()))
+        };
+        let config =
+            ListingTableConfig::new(store.clone() as Arc<dyn ObjectStore>, "gs://bucket/t/")
+                .with_listing_options(options)
+                .with_schema(schema(["a", "b"]));
+        ListingTable::try_new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stages_and_commits_parts() {
+        let (fake, server) = FakeGcs::start();
+        fake.insert("bucket/t/existing.csv", "a,b\n0,zero\n");
+        let store = Arc::new(store(&server.endpoint));
+
+        // one partition per batch, the empty one writes no part
+        let ctx = SessionContext::with_config(SessionConfig::new().with_target_partitions(1));
+        let query = schema(["x", "y"]);
+        let partitions = vec![
+            vec![batch(query.clone(), &[1, 2], &["one", "two"])],
+            vec![],
+            vec![batch(query.clone(), &[3], &["three"])],
+        ];
+        let source = MemTable::try_new(query, partitions).unwrap();
+        let df = ctx.read_table(Arc::new(source)).unwrap();
+        let table = csv_table(&store);
+
+        let summary = write_table(&store, &table, &df).await.unwrap();
+        assert_eq!(summary.rows, 3);
+        let job = summary.files[0]
+            .strip_prefix("gs://bucket/t/part-")
+            .and_then(|name| name.strip_suffix("-00000-00000.csv"))
+            .unwrap();
+        let committed =
+            |partition: usize| format!("bucket/t/part-{}-{:05}-00000.csv", job, partition);
+        assert_eq!(
+            summary.files,
+            [
+                format!("gs://{}", committed(0)),
+                format!("gs://{}", committed(2))
+            ]
+        );
+        assert_eq!(
+            fake.paths(),
+            [
+                "bucket/t/existing.csv".to_string(),
+                committed(0),
+                committed(2)
+            ]
+        );
+        assert_eq!(fake.get(&committed(0)).unwrap(), "a,b\n1,one\n2,two\n");
+        assert_eq!(fake.get(&committed(2)).unwrap(), "a,b\n3,three\n");
+        assert_eq!(fake.open_sessions(), 0);
+
+        // every part is uploaded before the first is committed, and the staged parts are
+        // deleted after the last
+        let requests = server.requests();
+        let step = |request: &Request| {
+            let (_, key) = request.object();
+            let key = key.unwrap_or_default();
+            match request.method.as_str() {
+                "POST" if request.url.contains("/rewriteTo/") => {
+                    assert!(
+                        key.starts_with(&format!("t/_temporary/{}/", job)),
+                        "{}",
+                        key
+                    );
+                    assert_eq!(request.query("ifGenerationMatch"), Some("0"));
+                    "copy"
+                }
+                "GET" => {
+                    let prefix = format!("t%2F_temporary%2F{}%2F", job);
+                    assert_eq!(request.query("prefix"), Some(prefix.as_str()));
+                    "list"
+                }
+                "DELETE" => {
+                    assert!(
+                        key.starts_with(&format!("t/_temporary/{}/", job)),
+                        "{}",
+                        key
+                    );
+                    "delete"
+                }
+                _ => "stage",
+            }
+        };
+        let mut steps: Vec<_> = requests.iter().map(step).collect();
+        steps.dedup();
+        assert_eq!(steps, ["stage", "copy", "list", "delete"]);
+
+        // later scans list the new parts
+        ctx.register_table("t", Arc::new(table)).unwrap();
+        let rows = ctx.sql("SELECT a FROM t ORDER BY a").await.unwrap();
+        let rows = rows.collect().await.unwrap();
+        let numbers = rows[0].column(0).as_any().downcast_ref::<Int64Array>();
+        assert_eq!(numbers.unwrap().values(), [0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn rolls_parts_at_the_limits() {
+        let (fake, server) = FakeGcs::start();
+        let builder = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(&server.endpoint);
+        let ctx = SessionContext::with_config(SessionConfig::new().with_target_partitions(1));
+        let query = schema(["x", "y"]);
+        let batches = vec![vec![
+            batch(query.clone(), &[1, 2], &["one", "two"]),
+            batch(query.clone(), &[3], &["three"]),
+            batch(query.clone(), &[4, 5, 6], &["four", "five", "six"]),
+        ]];
+        let source = Arc::new(MemTable::try_new(query, batches).unwrap());
+
+        // rows are split across batches, each file starting with the header
+        let store = Arc::new(builder.clone().with_max_part_rows(2).build());
+        assert!(store
+            .describe()
+            .contains("part files: 268435456 bytes, 2 rows"));
+        let df = ctx.read_table(source.clone()).unwrap();
+        let summary = write_table(&store, &csv_table(&store), &df).await.unwrap();
+        assert_eq!(summary.rows, 6);
+        let files: Vec<_> = summary
+            .files
+            .iter()
+            .map(|file| fake.get(file.strip_prefix("gs://").unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            files,
+            [
+                "a,b\n1,one\n2,two\n",
+                "a,b\n3,three\n4,four\n",
+                "a,b\n5,five\n6,six\n"
+            ]
+        );
+        for (n, file) in summary.files.iter().enumerate() {
+            assert!(file.ends_with(&format!("-00000-{:05}.csv", n)), "{}", file);
+        }
+
+        // files are finished after the batch that reaches the target size
+        let store = Arc::new(builder.with_target_part_size(20).build());
+        let df = ctx.read_table(source).unwrap();
+        let summary = write_table(&store, &csv_table(&store), &df).await.unwrap();
+        let files: Vec<_> = summary
+            .files
+            .iter()
+            .map(|file| fake.get(file.strip_prefix("gs://").unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            files,
+            [
+                "a,b\n1,one\n2,two\n3,three\n",
+                "a,b\n4,four\n5,five\n6,six\n"
+            ]
+        );
+        assert_eq!(fake.open_sessions(), 0);
+    }
+}
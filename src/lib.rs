@@ -19,7 +19,8 @@
 
 //! [DataFusion-ObjectStore-GCS](https://github.com/datafusion-contrib/datafusion-objectstore-s3)
 //! provides a `TableProvider` interface for using `Datafusion` to query data in GCS.  This includes GCS
-//! and services such as MinIO that implement the GCS API.
+//! and services such as MinIO that implement the GCS API.  Results can be written back with
+//! `GCSFileSystem::put` and `GCSFileSystem::writer`.
 //!
 //! ## Examples
 //!
@@ -48,9 +49,20 @@
 //! # }
 //! ```
 //!
-//! Using DataFusion's `ListingOtions` and `ListingTable` we register a table into a DataFusion `ExecutionContext` so that it can be queried.
+//! On GKE with Workload Identity, tokens can be fetched from the metadata server instead of a key file.
 //!
 //! ```rust
+//! use datafusion_objectstore_gcs::object_store::auth::Credentials;
+//! use datafusion_objectstore_gcs::object_store::gcs::GCSFileSystem;
+//!
+//! let gcs_file_system = GCSFileSystem::builder()
+//!     .with_credentials(Credentials::MetadataServer(None))
+//!     .build();
+//! ```
+//!
+//! Using DataFusion's `ListingOtions` and `ListingTable` we register a table into a DataFusion `ExecutionContext` so that it can be queried.
+//!
+//! ```rust,no_run
 //! use std::sync::Arc;
 //!
 //! use datafusion::datasource::listing::*;
@@ -83,9 +95,13 @@
 //! # }
 //! ```
 //!
+//! Both `gs://bucket/key` and `gcs://bucket/key` URIs are accepted.
+//! Table URIs may contain glob patterns such as `gcs://bucket/logs/2023/*/events-*.parquet`.
+//! Only the literal prefix before the first wildcard is listed; `*` and `?` do not match `/`.
+//!
 //! We can also register the `GCSFileSystem` directly as an `ObjectStore` on an `ExecutionContext`. This provides an idiomatic way of creating `TableProviders` that can be queried.
 //!
-//! ```rust
+//! ```rust,no_run
 //! use std::sync::Arc;
 //!
 //! use datafusion::datasource::listing::*;
@@ -104,7 +120,7 @@
 //!
 //!     let ctx = SessionContext::new();
 //!
-//!     let uri = "gsc://data/alltypes_plain.snappy.parquet";
+//!     let uri = "gs://data/alltypes_plain.snappy.parquet";
 //!
 //!     let config = ListingTableConfig::new(gcs_file_system, uri)
 //!         .infer()
@@ -119,6 +135,50 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! `GCSContextExt::register_gcs` builds a store and registers it for both schemes in one call.
+//!
+//! ```rust
+//! use datafusion::prelude::SessionContext;
+//!
+//! use datafusion_objectstore_gcs::context::GCSContextExt;
+//! use datafusion_objectstore_gcs::object_store::gcs::GCSFileSystem;
+//!
+//! let ctx = SessionContext::new();
+//! let gcs_file_system = ctx.register_gcs(GCSFileSystem::builder());
+//! ```
+//!
+//! `GCSContextExt::insert_into_gcs` runs `INSERT INTO tbl SELECT ...` on Parquet and CSV tables,
+//! adding the query's rows to the table directory as new part files.
+//!
+//! ```rust,no_run
+//! use datafusion::error::Result;
+//! use datafusion::prelude::{ParquetReadOptions, SessionContext};
+//!
+//! use datafusion_objectstore_gcs::context::GCSContextExt;
+//! use datafusion_objectstore_gcs::object_store::gcs::GCSFileSystem;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<()> {
+//! let ctx = SessionContext::new();
+//! let gcs_file_system = ctx.register_gcs(GCSFileSystem::builder());
+//! ctx.register_parquet_gcs("events", "gs://data/events/", ParquetReadOptions::default())
+//!     .await?;
+//! ctx.register_parquet_gcs("staged", "gs://data/staged/", ParquetReadOptions::default())
+//!     .await?;
+//! let summary = ctx
+//!     .insert_into_gcs(&gcs_file_system, "INSERT INTO events SELECT * FROM staged")
+//!     .await?;
+//! println!("added {} rows in {:?}", summary.rows, summary.files);
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+compile_error!("enable the `native-tls` or `rustls-tls` feature for HTTPS");
 
+pub mod catalog;
+pub mod context;
 pub mod error;
+pub mod insert;
 pub mod object_store;
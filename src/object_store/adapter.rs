@@ -0,0 +1,573 @@
+//! `object_store::ObjectStore` implementation for DataFusion versions that replaced
+//! `datafusion-data-access` with the `object_store` crate
+
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use ::object_store::path::Path;
+use ::object_store::{GetResult, ListResult, MultipartId, ObjectMeta};
+use async_trait::async_trait;
+use bytes::Bytes;
+use datafusion::datafusion_data_access::object_store::ObjectStore;
+use datafusion::datafusion_data_access::FileMeta;
+use datafusion::datafusion_data_access::ListEntry;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+
+use crate::error::GCSError;
+use crate::object_store::gcs::{GCSFileSystem, NameFilter, STREAM_BUFFER_CHUNKS};
+use crate::object_store::path::{self, GCSPath};
+use crate::object_store::writer::{self, Precondition, ResumableUpload};
+
+/// Name of the store in `object_store::Error::Generic`
+const STORE: &str = "GCS";
+
+/// One bucket of a `GCSFileSystem` as an `object_store::ObjectStore`, keyed by object
+/// name like the crate's own `GoogleCloudStorage`. Register it with the `RuntimeEnv` for
+/// `gs://bucket`.
+///
+/// Requests go through the same client, caches, retries and metrics as the wrapped
+/// store. `get` streams the object as it is received, and multipart uploads go through a
+/// resumable upload session, so neither holds more than a few chunks in memory. The
+/// `MultipartId` of an upload is the URL of its session.
+#[derive(Debug, Clone)]
+pub struct GCSObjectStore {
+    store: Arc<GCSFileSystem>,
+    bucket: String,
+}
+
+impl GCSObjectStore {
+    /// Serve the objects of `bucket` through `store`
+    pub fn new(store: Arc<GCSFileSystem>, bucket: impl Into<String>) -> Self {
+        Self {
+            store,
+            bucket: bucket.into(),
+        }
+    }
+
+    /// The wrapped store
+    pub fn inner(&self) -> &Arc<GCSFileSystem> {
+        &self.store
+    }
+
+    fn gcs_path(&self, location: &Path) -> GCSPath {
+        GCSPath {
+            bucket: self.bucket.clone(),
+            key: location.as_ref().to_string(),
+        }
+    }
+
+    fn uri(&self, location: &Path) -> String {
+        format!("{}/{}", self.bucket, location.as_ref())
+    }
+
+    /// `bucket/prefix/` of the objects under `prefix`
+    fn directory(&self, prefix: Option<&Path>) -> String {
+        match prefix.map(AsRef::as_ref) {
+            None | Some("") => format!("{}/", self.bucket),
+            Some(prefix) => format!("{}/{}/", self.bucket, prefix),
+        }
+    }
+
+    /// The location of a `bucket/key` listed by the wrapped store
+    fn location(&self, listed: &str) -> Path {
+        let key = listed
+            .strip_prefix(self.bucket.as_str())
+            .and_then(|key| key.strip_prefix('/'))
+            .unwrap_or(listed);
+        // object names are the locations' raw strings, which `Path::from` would escape
+        let key = key.trim_end_matches('/');
+        Path::parse(key).unwrap_or_else(|_| Path::from(key))
+    }
+
+    fn object_meta(&self, file: FileMeta) -> ObjectMeta {
+        ObjectMeta {
+            location: self.location(&file.sized_file.path),
+            last_modified: file.last_modified.unwrap_or_default(),
+            size: file.sized_file.size as usize,
+        }
+    }
+}
+
+impl Display for GCSObjectStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GCSObjectStore({})", self.bucket)
+    }
+}
+
+#[async_trait]
+impl ::object_store::ObjectStore for GCSObjectStore {
+    async fn put(&self, location: &Path, bytes: Bytes) -> ::object_store::Result<()> {
+        let path = self.gcs_path(location);
+        writer::upload(self.store.api(), &path, bytes.to_vec(), None)
+            .await
+            .map_err(|err| to_object_store_error(err, location))?;
+        Ok(())
+    }
+
+    async fn put_multipart(
+        &self,
+        location: &Path,
+    ) -> ::object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        let upload = self
+            .store
+            .resumable_upload(&self.uri(location))
+            .await
+            .map_err(|err| to_object_store_error(err, location))?;
+        let id = upload.session_url().to_string();
+        let upload = MultipartUpload {
+            path: self.gcs_path(location),
+            state: UploadState::Ready(Box::new(upload)),
+        };
+        Ok((id, Box::new(upload)))
+    }
+
+    async fn abort_multipart(
+        &self,
+        location: &Path,
+        multipart_id: &MultipartId,
+    ) -> ::object_store::Result<()> {
+        // the request carries the store's credentials, so only to sessions of its endpoint
+        let api = self.store.api();
+        if !multipart_id.starts_with(&format!("{}/", api.endpoint)) {
+            return Err(generic_error(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                GCSError::GCS(format!(
+                    "{} is not an upload session of {}",
+                    multipart_id, location
+                )),
+            )));
+        }
+        writer::cancel(api, multipart_id)
+            .await
+            .map_err(|err| to_object_store_error(err, location))
+    }
+
+    async fn get(&self, location: &Path) -> ::object_store::Result<GetResult> {
+        let (api, path) = (self.store.api().clone(), self.gcs_path(location));
+        let (tx, mut rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+        tokio::spawn(async move {
+            // stops once the stream is dropped
+            let received = api
+                .receive(&path, 0, 0, None, |chunk| {
+                    let tx = tx.clone();
+                    async move { tx.send(Ok(chunk)).await.is_ok() }
+                })
+                .await;
+            if let Err(err) = received {
+                let _ = tx.send(Err(err)).await;
+            }
+        });
+        // failures before the body, such as a missing object, fail the call itself
+        let first = match rx.recv().await {
+            Some(Err(err)) => return Err(to_object_store_error(err, location)),
+            first => first,
+        };
+        let rest = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        });
+        Ok(GetResult::Stream(
+            futures::stream::iter(first)
+                .chain(rest)
+                .map(|chunk| chunk.map_err(generic_error))
+                .boxed(),
+        ))
+    }
+
+    async fn get_range(
+        &self,
+        location: &Path,
+        range: Range<usize>,
+    ) -> ::object_store::Result<Bytes> {
+        if range.is_empty() {
+            return Ok(Bytes::new());
+        }
+        let (bytes, _) = self
+            .store
+            .api()
+            .download(
+                &self.gcs_path(location),
+                range.start as u64,
+                range.len(),
+                None,
+            )
+            .await
+            .map_err(|err| to_object_store_error(err, location))?;
+        Ok(bytes)
+    }
+
+    async fn head(&self, location: &Path) -> ::object_store::Result<ObjectMeta> {
+        let file = self
+            .store
+            .head(&self.uri(location))
+            .await
+            .map_err(|err| to_object_store_error(err, location))?;
+        Ok(self.object_meta(file))
+    }
+
+    async fn delete(&self, location: &Path) -> ::object_store::Result<()> {
+        self.store
+            .delete(&self.uri(location))
+            .await
+            .map_err(|err| to_object_store_error(err, location))
+    }
+
+    async fn list(
+        &self,
+        prefix: Option<&Path>,
+    ) -> ::object_store::Result<BoxStream<'_, ::object_store::Result<ObjectMeta>>> {
+        // locations are literal, listed URIs could be glob patterns
+        let directory = self.directory(prefix);
+        let (bucket, key) = directory.split_once('/').unwrap_or((&directory, ""));
+        let files = self
+            .store
+            .list_objects(
+                &format!("{}/{}", bucket, path::escape_glob(key)),
+                false,
+                NameFilter::default(),
+            )
+            .await
+            .map_err(generic_error)?;
+        Ok(files
+            .map(move |file| {
+                file.map(|file| self.object_meta(file))
+                    .map_err(generic_error)
+            })
+            .boxed())
+    }
+
+    async fn list_with_delimiter(
+        &self,
+        prefix: Option<&Path>,
+    ) -> ::object_store::Result<ListResult> {
+        let mut entries = self
+            .store
+            .list_dir(&self.directory(prefix), Some("/".to_string()))
+            .await
+            .map_err(generic_error)?;
+        let mut result = ListResult {
+            common_prefixes: vec![],
+            objects: vec![],
+        };
+        while let Some(entry) = entries.next().await {
+            match entry.map_err(generic_error)? {
+                ListEntry::Prefix(directory) => {
+                    result.common_prefixes.push(self.location(&directory))
+                }
+                ListEntry::FileMeta(file) => result.objects.push(self.object_meta(file)),
+            }
+        }
+        Ok(result)
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> ::object_store::Result<()> {
+        self.store
+            .copy(&self.uri(from), &self.uri(to))
+            .await
+            .map_err(|err| to_object_store_error(err, from))
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> ::object_store::Result<()> {
+        let copied = self
+            .store
+            .copy_if(&self.uri(from), &self.uri(to), Precondition::DoesNotExist)
+            .await;
+        match copied {
+            Err(err) if is_precondition_failure(&err) => {
+                Err(::object_store::Error::AlreadyExists {
+                    path: to.to_string(),
+                    source: Box::new(err),
+                })
+            }
+            copied => copied.map_err(|err| to_object_store_error(err, from)),
+        }
+    }
+}
+
+/// Writer of `put_multipart`, which sends the data through a resumable upload session as
+/// it is written and completes the object when shut down
+struct MultipartUpload {
+    path: GCSPath,
+    state: UploadState,
+}
+
+enum UploadState {
+    Ready(Box<ResumableUpload>),
+    /// Writing data, yielding the upload back unless it failed and was aborted
+    Writing(BoxFuture<'static, (Option<Box<ResumableUpload>>, std::io::Result<()>)>),
+    Finishing(BoxFuture<'static, std::io::Result<()>>),
+    Finished,
+    Failed,
+}
+
+impl MultipartUpload {
+    /// Wait for the pending write, if any
+    fn poll_written(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if let UploadState::Writing(write) = &mut self.state {
+            let (upload, result) = futures::ready!(write.poll_unpin(cx));
+            self.state = match upload {
+                Some(upload) => UploadState::Ready(upload),
+                None => UploadState::Failed,
+            };
+            return Poll::Ready(result);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn error(&self, desc: &str) -> std::io::Error {
+        std::io::Error::other(GCSError::GCS(format!("{} {}", desc, self.path)))
+    }
+}
+
+impl AsyncWrite for MultipartUpload {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_written(cx))?;
+        match std::mem::replace(&mut this.state, UploadState::Failed) {
+            UploadState::Ready(mut upload) => {
+                let data = buf.to_vec();
+                this.state = UploadState::Writing(
+                    async move {
+                        match upload.write(&data).await {
+                            Ok(()) => (Some(upload), Ok(())),
+                            Err(err) => {
+                                // best effort, an abandoned session expires on its own
+                                let _ = (*upload).abort().await;
+                                (None, Err(err))
+                            }
+                        }
+                    }
+                    .boxed(),
+                );
+                Poll::Ready(Ok(buf.len()))
+            }
+            UploadState::Failed => Poll::Ready(Err(this.error("Write after a failed write to"))),
+            state => {
+                this.state = state;
+                Poll::Ready(Err(this.error("Write after shutdown to")))
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_written(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_written(cx))?;
+        loop {
+            match std::mem::replace(&mut this.state, UploadState::Failed) {
+                UploadState::Ready(upload) => {
+                    this.state = UploadState::Finishing((*upload).finish().boxed());
+                }
+                UploadState::Finishing(mut finish) => {
+                    return match finish.poll_unpin(cx) {
+                        Poll::Pending => {
+                            this.state = UploadState::Finishing(finish);
+                            Poll::Pending
+                        }
+                        Poll::Ready(result) => {
+                            if result.is_ok() {
+                                this.state = UploadState::Finished;
+                            }
+                            Poll::Ready(result)
+                        }
+                    };
+                }
+                UploadState::Finished => {
+                    this.state = UploadState::Finished;
+                    return Poll::Ready(Ok(()));
+                }
+                UploadState::Writing(_) => unreachable!("waited for the pending write"),
+                UploadState::Failed => {
+                    return Poll::Ready(Err(this.error("Shutdown after a failed write to")))
+                }
+            }
+        }
+    }
+}
+
+fn is_precondition_failure(err: &std::io::Error) -> bool {
+    matches!(
+        err.get_ref().and_then(|err| err.downcast_ref::<GCSError>()),
+        Some(GCSError::PreconditionFailed { .. })
+    )
+}
+
+fn to_object_store_error(err: std::io::Error, location: &Path) -> ::object_store::Error {
+    match err.kind() {
+        ErrorKind::NotFound => ::object_store::Error::NotFound {
+            path: location.to_string(),
+            source: Box::new(err),
+        },
+        _ => generic_error(err),
+    }
+}
+
+fn generic_error(err: std::io::Error) -> ::object_store::Error {
+    ::object_store::Error::Generic {
+        store: STORE,
+        source: Box::new(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::auth::Credentials;
+    use crate::object_store::test_server::{FakeGcs, TestServer};
+    use ::object_store::ObjectStore as _;
+    use tokio::io::AsyncWriteExt;
+
+    fn start() -> (Arc<FakeGcs>, TestServer, GCSObjectStore) {
+        let (fake, server) = FakeGcs::start();
+        let store = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(&server.endpoint)
+            .with_upload_chunk_size(256 * 1024)
+            .build();
+        let store = GCSObjectStore::new(Arc::new(store), "bucket");
+        (fake, server, store)
+    }
+
+    #[tokio::test]
+    async fn multipart_uploads() {
+        let (fake, _server, store) = start();
+        let location = Path::from("data/a.csv");
+
+        let (_, mut upload) = store.put_multipart(&location).await.unwrap();
+        upload.write_all(b"a\n").await.unwrap();
+        upload.write_all(b"1\n").await.unwrap();
+        assert_eq!(fake.get("bucket/data/a.csv"), None);
+        upload.shutdown().await.unwrap();
+        assert_eq!(fake.get("bucket/data/a.csv").unwrap(), "a\n1\n");
+        // shutting down again does not upload twice
+        upload.shutdown().await.unwrap();
+        let err = upload.write_all(b"2\n").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "GCS error: Write after shutdown to bucket/data/a.csv"
+        );
+        assert_eq!(fake.open_sessions(), 0);
+    }
+
+    #[tokio::test]
+    async fn multipart_uploads_after_a_failed_write() {
+        let (fake, _server, store) = start();
+        let location = Path::from("data/a.csv");
+
+        let (id, mut upload) = store.put_multipart(&location).await.unwrap();
+        store.abort_multipart(&location, &id).await.unwrap();
+        assert_eq!(fake.open_sessions(), 0);
+        // the write of a full chunk is accepted, and fails sending it
+        let chunk = vec![0; 256 * 1024];
+        assert_eq!(upload.write(&chunk).await.unwrap(), chunk.len());
+        assert_eq!(
+            upload.flush().await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        let err = upload.write_all(b"a\n").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "GCS error: Write after a failed write to bucket/data/a.csv"
+        );
+        let err = upload.shutdown().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "GCS error: Shutdown after a failed write to bucket/data/a.csv"
+        );
+        assert_eq!(fake.get("bucket/data/a.csv"), None);
+
+        // ids of other endpoints are never sent anything
+        let other = "https://example.com/upload/session/1".to_string();
+        let err = store.abort_multipart(&location, &other).await.unwrap_err();
+        assert!(
+            err.to_string().contains("is not an upload session"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn copies_only_to_free_locations() {
+        let (fake, _server, store) = start();
+        fake.insert("bucket/a.csv", "a\n1\n");
+        fake.insert("bucket/b.csv", "b\n2\n");
+        let (a, b, c) = (
+            Path::from("a.csv"),
+            Path::from("b.csv"),
+            Path::from("c.csv"),
+        );
+
+        let err = store.copy_if_not_exists(&a, &b).await.unwrap_err();
+        assert!(
+            matches!(&err, ::object_store::Error::AlreadyExists { path, .. } if path == "b.csv"),
+            "{}",
+            err
+        );
+        assert_eq!(fake.get("bucket/b.csv").unwrap(), "b\n2\n");
+        store.copy_if_not_exists(&a, &c).await.unwrap();
+        assert_eq!(fake.get("bucket/c.csv").unwrap(), "a\n1\n");
+
+        let missing = Path::from("missing.csv");
+        let err = store.copy_if_not_exists(&missing, &c).await.unwrap_err();
+        assert!(
+            matches!(err, ::object_store::Error::NotFound { .. }),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn locations_of_listed_objects() {
+        let store = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .build();
+        let store = GCSObjectStore::new(Arc::new(store), "bucket");
+        assert_eq!(store.location("bucket/data/a.csv").as_ref(), "data/a.csv");
+        assert_eq!(store.location("bucket/data/").as_ref(), "data");
+        assert_eq!(store.location("bucket/a b.csv").as_ref(), "a b.csv");
+        // only the bucket is stripped, not a prefix that starts like it
+        assert_eq!(store.location("buckets/a.csv").as_ref(), "buckets/a.csv");
+    }
+
+    #[tokio::test]
+    async fn lists_with_delimiter() {
+        let (fake, _server, store) = start();
+        for path in ["a.csv", "dir/b.csv", "dir/sub/c.csv", "other/d.csv"] {
+            fake.insert(&format!("bucket/{}", path), "x\n");
+        }
+        let listed = |result: ListResult| {
+            let prefixes = result.common_prefixes.iter().map(|p| p.to_string());
+            let objects = result.objects.iter().map(|o| o.location.to_string());
+            (prefixes.collect::<Vec<_>>(), objects.collect::<Vec<_>>())
+        };
+
+        let root = store.list_with_delimiter(None).await.unwrap();
+        assert_eq!(
+            listed(root),
+            (vec!["dir".into(), "other".into()], vec!["a.csv".into()])
+        );
+        let dir = store
+            .list_with_delimiter(Some(&Path::from("dir")))
+            .await
+            .unwrap();
+        assert_eq!(
+            listed(dir),
+            (vec!["dir/sub".into()], vec!["dir/b.csv".into()])
+        );
+    }
+}
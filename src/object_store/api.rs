@@ -0,0 +1,724 @@
+//! Authenticated requests to the Cloud Storage JSON API
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use datafusion::datafusion_data_access::Result;
+use futures::future;
+use md5::{Digest, Md5};
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Deserializer};
+
+use crate::error::GCSError;
+use crate::object_store::auth::TokenProvider;
+use crate::object_store::clock::Clock;
+use crate::object_store::encryption::Encryption;
+#[cfg(feature = "grpc")]
+use crate::object_store::grpc::GrpcClient;
+use crate::object_store::limiter::{Limiter, Permit};
+use crate::object_store::metadata::MetadataCache;
+use crate::object_store::metrics::{Metrics, RequestEvent, RequestKind};
+use crate::object_store::path::{self, GCSPath};
+use crate::object_store::progress::{DownloadProgress, ProgressObserver};
+use crate::object_store::retry::RetryPolicy;
+use crate::object_store::sandbox::Sandbox;
+use crate::object_store::throttle::UploadThrottle;
+
+/// Endpoint of the Cloud Storage API unless `STORAGE_EMULATOR_HOST` or the builder say otherwise
+pub(crate) const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+
+/// HTTP client, credentials and request context shared by a `GCSFileSystem` and the
+/// readers and writers it creates
+#[derive(Debug, Clone)]
+pub(crate) struct ApiClient {
+    pub(crate) http: reqwest::Client,
+    /// Scheme and authority the JSON and upload API paths are appended to
+    pub(crate) endpoint: Arc<str>,
+    pub(crate) tokens: TokenProvider,
+    pub(crate) clock: Arc<dyn Clock>,
+    /// Context labels, sent as `x-goog-custom-audit-*` headers and stored as custom
+    /// metadata on written objects
+    pub(crate) labels: Arc<BTreeMap<String, String>>,
+    pub(crate) retry: RetryPolicy,
+    /// Metadata of objects seen in listings and lookups
+    pub(crate) metadata: MetadataCache,
+    /// Check downloads of whole objects against the hashes GCS stores
+    pub(crate) verify_checksums: bool,
+    pub(crate) user_projects: Arc<UserProjects>,
+    /// Project charged for API quota, sent as `x-goog-user-project`
+    pub(crate) quota_project: Option<Arc<str>>,
+    pub(crate) encryption: Arc<Encryption>,
+    pub(crate) metrics: Arc<dyn Metrics>,
+    /// Caps the requests in flight across the store and all its readers and writers, each
+    /// holding a permit until its response headers arrive
+    pub(crate) limiter: Option<Arc<Limiter>>,
+    /// Queue of the `limiter` requests wait in, shared by the requests of one query
+    pub(crate) queue: Arc<str>,
+    pub(crate) progress: Option<Arc<dyn ProgressObserver>>,
+    /// Client object data is read with instead of the JSON API, see `Transport::Grpc`
+    #[cfg(feature = "grpc")]
+    pub(crate) grpc: Option<Arc<GrpcClient>>,
+    /// Paces the bytes of uploads, if their bandwidth is limited
+    pub(crate) upload_throttle: Option<Arc<UploadThrottle>>,
+    /// Buckets and prefixes requests are restricted to, if any
+    pub(crate) sandbox: Option<Arc<Sandbox>>,
+}
+
+/// Projects billed for requests, sent as `userProject` so requester-pays buckets can be
+/// accessed
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UserProjects {
+    /// Project billed for buckets without an override
+    pub(crate) default: Option<String>,
+    /// Project billed for each bucket, by bucket name
+    pub(crate) buckets: BTreeMap<String, String>,
+}
+
+impl UserProjects {
+    pub(crate) fn for_bucket(&self, bucket: &str) -> Option<&str> {
+        self.buckets
+            .get(bucket)
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+}
+
+impl ApiClient {
+    /// Base URL of the JSON API
+    pub(crate) fn json_api(&self) -> String {
+        format!("{}/storage/v1", self.endpoint)
+    }
+
+    /// Base URL of the media upload API
+    pub(crate) fn upload_api(&self) -> String {
+        format!("{}/upload/storage/v1", self.endpoint)
+    }
+
+    /// Fail with `GCSError::OutsideSandbox` unless the object at `path` may be accessed
+    pub(crate) fn check_access(&self, path: &GCSPath) -> Result<()> {
+        match &self.sandbox {
+            Some(sandbox) => sandbox.check(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait until `bytes` of an upload may be sent, if uploads are throttled
+    pub(crate) async fn throttle_upload(&self, bytes: u64) {
+        if let Some(throttle) = &self.upload_throttle {
+            throttle.reserve(self.clock.as_ref(), bytes).await;
+        }
+    }
+
+    /// Start an authenticated request to `url`
+    pub(crate) async fn request(&self, method: Method, url: &str) -> Result<RequestBuilder> {
+        let bucket = path::resource(url).0;
+        // callers check the objects, this catches any request to another bucket
+        if let (Some(sandbox), Some(bucket)) = (&self.sandbox, &bucket) {
+            sandbox.check_listing(bucket, "")?;
+        }
+        let token = self
+            .tokens
+            .token(&self.http)
+            .await
+            .map_err(std::io::Error::from)?;
+        let mut request = self.http.request(method, url);
+        // anonymous credentials have no token to send
+        if !token.is_empty() {
+            request = request.bearer_auth(token);
+        }
+        for (key, value) in self.labels.iter() {
+            request = request.header(format!("x-goog-custom-audit-{}", key), value);
+        }
+        if let Some(project) = &self.quota_project {
+            request = request.header("x-goog-user-project", project.as_ref());
+        }
+        // resumable upload session URLs keep the parameters the session was started with
+        let bucket = bucket.filter(|_| !url.contains("userProject="));
+        if let Some(project) = bucket.and_then(|bucket| self.user_projects.for_bucket(&bucket)) {
+            request = request.query(&[("userProject", project)]);
+        }
+        Ok(request)
+    }
+
+    /// Present the customer-supplied encryption key configured for `path`, if any
+    pub(crate) fn with_customer_key(
+        &self,
+        request: RequestBuilder,
+        path: &GCSPath,
+    ) -> RequestBuilder {
+        match self.encryption.customer_key(path) {
+            Some(key) => key.apply(request),
+            None => request,
+        }
+    }
+
+    /// Present the customer-supplied encryption key configured for `path`, if any, as the
+    /// key of the source object of a rewrite
+    pub(crate) fn with_source_customer_key(
+        &self,
+        request: RequestBuilder,
+        path: &GCSPath,
+    ) -> RequestBuilder {
+        match self.encryption.customer_key(path) {
+            Some(key) => key.apply_source(request),
+            None => request,
+        }
+    }
+
+    /// Have GCS encrypt the object written to `path` with the Cloud KMS key configured for
+    /// it, if any, passing the key name as the query parameter `param`
+    pub(crate) fn with_kms_key(
+        &self,
+        request: RequestBuilder,
+        path: &GCSPath,
+        param: &str,
+    ) -> RequestBuilder {
+        match self.encryption.kms_key(path) {
+            Some(name) => request.query(&[(param, name)]),
+            None => request,
+        }
+    }
+
+    /// Fetch one page of objects in `bucket` matching `query` (`prefix`, `delimiter`, ...)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "gcs.list_page", level = "debug", skip(self))
+    )]
+    pub(crate) async fn list_page(
+        &self,
+        bucket: &str,
+        query: &[(&str, &str)],
+        page_token: Option<&str>,
+    ) -> Result<ObjectList> {
+        if let Some(sandbox) = &self.sandbox {
+            let prefix = query.iter().find(|(key, _)| *key == "prefix");
+            sandbox.check_listing(bucket, prefix.map_or("", |(_, prefix)| prefix))?;
+        }
+        let mut request = self
+            .request(Method::GET, &format!("{}/b/{}/o", self.json_api(), bucket))
+            .await?
+            .query(query);
+        if let Some(page_token) = page_token {
+            request = request.query(&[("pageToken", page_token)]);
+        }
+        let mut list: ObjectList = self
+            .send_retrying(RequestKind::List, request)
+            .await?
+            .json()
+            .await
+            .map_err(http_error)?;
+        if let Some(sandbox) = &self.sandbox {
+            list.items
+                .retain(|object| sandbox.allows(&GCSPath::new(bucket, &object.name)));
+            list.prefixes
+                .retain(|prefix| sandbox.overlaps(bucket, prefix));
+        }
+        // noncurrent generations must not replace the current ones reads are pinned to
+        if !query.contains(&("versions", "true")) {
+            for object in &list.items {
+                self.metadata.insert(bucket, object);
+            }
+        }
+        Ok(list)
+    }
+
+    /// Fetch the metadata of the object at `path`
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "gcs.metadata",
+            level = "debug",
+            skip_all,
+            fields(bucket = %path.bucket, key = %path.key)
+        )
+    )]
+    pub(crate) async fn object(&self, path: &GCSPath) -> Result<ObjectResource> {
+        self.check_access(path)?;
+        let name = path.to_string();
+        if let Some(message) = self.metadata.cached_miss(&name) {
+            return Err(GCSError::NotFound {
+                bucket: Some(path.bucket.clone()),
+                key: Some(path.key.clone()),
+                message,
+            }
+            .into());
+        }
+        let request = self.request(Method::GET, &self.object_url(path)).await?;
+        let request = self.with_customer_key(request, path);
+        let response = match self.send_retrying(RequestKind::Metadata, request).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let message = match err.get_ref().and_then(|e| e.downcast_ref::<GCSError>()) {
+                    Some(GCSError::NotFound { message, .. }) => message.clone(),
+                    _ => err.to_string(),
+                };
+                self.metadata.remember_miss(name.clone(), name, message);
+                return Err(err);
+            }
+            response => response?,
+        };
+        let object: ObjectResource = response.json().await.map_err(http_error)?;
+        self.metadata.insert(&path.bucket, &object);
+        Ok(object)
+    }
+
+    /// Download `length` bytes of the object at `path` from `start`, or everything from
+    /// `start` on if `length` is zero, along with the generation that was read. Reads
+    /// `generation` if given, failing with `NotFound` once it no longer exists.
+    pub(crate) async fn download(
+        &self,
+        path: &GCSPath,
+        start: u64,
+        length: usize,
+        generation: Option<u64>,
+    ) -> Result<(Bytes, Option<u64>)> {
+        let mut body = BytesMut::with_capacity(length);
+        let (_, generation) = self
+            .receive(path, start, length, generation, |chunk| {
+                body.extend_from_slice(&chunk);
+                future::ready(true)
+            })
+            .await?;
+        Ok((body.freeze(), generation))
+    }
+
+    /// Download like [`download`](Self::download), but hand the body to `on_chunk` as it
+    /// arrives instead of collecting it, and stop once `on_chunk` returns `false`. Returns
+    /// the number of bytes received and the generation that was read.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "gcs.download",
+            level = "debug",
+            skip(self, path, on_chunk),
+            fields(bucket = %path.bucket, key = %path.key)
+        )
+    )]
+    pub(crate) async fn receive<F, Fut>(
+        &self,
+        path: &GCSPath,
+        start: u64,
+        length: usize,
+        generation: Option<u64>,
+        mut on_chunk: F,
+    ) -> Result<(u64, Option<u64>)>
+    where
+        F: FnMut(Bytes) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &self.grpc {
+            return grpc
+                .receive(self, path, start, length, generation, on_chunk)
+                .await;
+        }
+        // every attempt only holds a limiter permit until its headers arrive, a body read
+        // as slowly as its consumer goes must not hold up other requests
+        let request = self
+            .download_request(path, start, length, generation)
+            .await?;
+        let mut response = self.send_retrying(RequestKind::Download, request).await?;
+        let headers = response.headers();
+        let generation = headers
+            .get("x-goog-generation")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .or(generation);
+        // GCS only publishes hashes of whole objects, of the stored bytes which are also
+        // what is received
+        let mut hashes = (self.verify_checksums
+            && (response.status() == StatusCode::OK || covers_object(headers)))
+        .then(|| {
+            let expected = headers
+                .get_all("x-goog-hash")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            ObjectHashes::new(expected)
+        });
+
+        let name = path.to_string();
+        let mut received = 0;
+        let mut stopped = false;
+        let mut attempt = 1;
+        'download: loop {
+            let expected = response.content_length().map(|length| received + length);
+            let err = loop {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => {
+                        received += chunk.len() as u64;
+                        if let Some(hashes) = &mut hashes {
+                            hashes.update(&chunk);
+                        }
+                        if let Some(progress) = &self.progress {
+                            progress.progress(&DownloadProgress {
+                                path: &name,
+                                start,
+                                received,
+                                expected,
+                            });
+                        }
+                        if !on_chunk(chunk).await {
+                            stopped = true;
+                            break 'download;
+                        }
+                    }
+                    Ok(None) => break 'download,
+                    Err(err) => break err,
+                }
+            };
+            // a dropped connection only loses the rest of the body, which is requested
+            // again from the generation already read, so the parts cannot mix generations
+            let resumable = (err.is_body() || err.is_timeout())
+                && generation.is_some()
+                && attempt < self.retry.max_attempts;
+            if !resumable {
+                return Err(http_error(err));
+            }
+            let remaining = length.saturating_sub(received as usize);
+            if length > 0 && remaining == 0 {
+                break;
+            }
+            self.clock.sleep(self.retry.delay(attempt)).await;
+            attempt += 1;
+            let request = self
+                .download_request(path, start + received, remaining, generation)
+                .await?;
+            response = self.send_retrying(RequestKind::Download, request).await?;
+        }
+        if let Some(progress) = &self.progress {
+            progress.finished(&name, start, received);
+        }
+        self.metrics
+            .bytes_downloaded(RequestKind::Download, received);
+        // a consumer that stopped early did not see the whole object either
+        if let Some(hashes) = hashes.filter(|_| !stopped) {
+            hashes.verify(path)?;
+        }
+        Ok((received, generation))
+    }
+
+    /// The request for `length` bytes of the object at `path` from `start`, or everything
+    /// from `start` on if `length` is zero, of `generation` if given
+    async fn download_request(
+        &self,
+        path: &GCSPath,
+        start: u64,
+        length: usize,
+        generation: Option<u64>,
+    ) -> Result<RequestBuilder> {
+        self.check_access(path)?;
+        let range = if length > 0 {
+            format!("bytes={}-{}", start, start + length as u64 - 1)
+        } else {
+            format!("bytes={}-", start)
+        };
+        let mut request = self
+            .request(Method::GET, &self.object_url(path))
+            .await?
+            .query(&[("alt", "media")])
+            .header(header::RANGE, range)
+            // objects stored with `Content-Encoding: gzip` are otherwise decompressed in
+            // transit, which ignores the range and serves more bytes than the stored size
+            .header(header::ACCEPT_ENCODING, "gzip");
+        if let Some(generation) = generation {
+            request = request.query(&[("generation", generation)]);
+        }
+        Ok(self.with_customer_key(request, path))
+    }
+
+    /// Delete the object at `path`
+    pub(crate) async fn delete(&self, path: &GCSPath) -> Result<()> {
+        self.check_access(path)?;
+        let request = self.request(Method::DELETE, &self.object_url(path)).await?;
+        self.metadata.remove(path);
+        self.send_retrying(RequestKind::Delete, request).await?;
+        self.metadata.remove(path);
+        Ok(())
+    }
+
+    /// Send `request` once as attempt number `attempt` of a `kind` request, reporting it
+    /// to the metrics hook
+    pub(crate) async fn execute(
+        &self,
+        kind: RequestKind,
+        attempt: u32,
+        request: RequestBuilder,
+    ) -> reqwest::Result<Response> {
+        let request = request.build()?;
+        let _permit = self.permit().await;
+        let (bucket, _key) = path::resource(request.url().as_str());
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "gcs.request",
+            ?kind,
+            method = %request.method(),
+            bucket = ?bucket,
+            key = ?_key,
+            attempt,
+            status = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+        let started = Instant::now();
+        let response = self.http.execute(request);
+        #[cfg(feature = "tracing")]
+        let response = tracing::Instrument::instrument(response, span.clone());
+        let response = response.await;
+        #[cfg(feature = "tracing")]
+        match &response {
+            Ok(response) => span.record("status", response.status().as_u16()),
+            Err(err) => span.record("error", tracing::field::display(err)),
+        };
+        self.metrics.request(&RequestEvent {
+            kind,
+            bucket,
+            attempt,
+            status: response.as_ref().ok().map(|r| r.status().as_u16()),
+            latency: started.elapsed(),
+        });
+        response
+    }
+
+    /// Wait until another request may be sent, if their number is limited
+    pub(crate) async fn permit(&self) -> Option<Permit> {
+        match &self.limiter {
+            Some(limiter) => Some(limiter.acquire(&self.queue).await),
+            None => None,
+        }
+    }
+
+    /// Send `request` once, turning unsuccessful statuses into errors
+    pub(crate) async fn send(
+        &self,
+        kind: RequestKind,
+        request: RequestBuilder,
+    ) -> Result<Response> {
+        let response = self.execute(kind, 1, request).await.map_err(http_error)?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(status_error(response).await)
+        }
+    }
+
+    /// Send `request`, retrying transient failures according to the retry policy
+    pub(crate) async fn send_retrying(
+        &self,
+        kind: RequestKind,
+        request: RequestBuilder,
+    ) -> Result<Response> {
+        let mut attempt = 1;
+        loop {
+            // requests with streaming bodies cannot be replayed
+            let current = match request.try_clone() {
+                Some(current) => current,
+                None => return self.send(kind, request).await,
+            };
+            let retryable = attempt < self.retry.max_attempts;
+            match self.execute(kind, attempt, current).await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response)
+                    if retryable && self.retry.is_retryable_status(response.status().as_u16()) => {}
+                Ok(response) => return Err(status_error(response).await),
+                Err(err) if retryable && (err.is_connect() || err.is_timeout()) => {}
+                Err(err) => return Err(http_error(err)),
+            }
+            self.clock.sleep(self.retry.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Wait until the endpoint answers, retrying refused connections and gateway errors
+    /// according to `policy`.
+    ///
+    /// Emulators and sidecar proxies refuse connections or answer 502/503 for a while
+    /// after they start. Any other response, even an authorization error, means the
+    /// endpoint is up. The probe is sent without credentials.
+    pub(crate) async fn wait_until_ready(&self, policy: &RetryPolicy) -> Result<()> {
+        let url = format!("{}/b", self.json_api());
+        let mut attempt = 1;
+        loop {
+            let retryable = attempt < policy.max_attempts;
+            match self.http.get(&url).send().await {
+                Ok(response) if !policy.is_retryable_status(response.status().as_u16()) => {
+                    return Ok(())
+                }
+                Ok(_) if retryable => {}
+                Ok(response) => return Err(status_error(response).await),
+                Err(err) if retryable && (err.is_connect() || err.is_timeout()) => {}
+                Err(err) => return Err(http_error(err)),
+            }
+            self.clock.sleep(policy.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    fn object_url(&self, path: &GCSPath) -> String {
+        format!(
+            "{}/b/{}/o/{}",
+            self.json_api(),
+            path.bucket,
+            path.encoded_key()
+        )
+    }
+}
+
+/// Returns `true` if the `Content-Range` of a partial response spans the whole object
+fn covers_object(headers: &HeaderMap) -> bool {
+    let range = headers
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "));
+    let (range, total) = match range.and_then(|range| range.split_once('/')) {
+        Some(range) => range,
+        None => return false,
+    };
+    match range.split_once('-') {
+        Some((start, end)) => {
+            start == "0" && end.parse::<u64>().ok().map(|end| end + 1) == total.parse().ok()
+        }
+        None => false,
+    }
+}
+
+/// Hashes of a download of a whole object, computed as its chunks arrive
+struct ObjectHashes {
+    /// The `crc32c=...,md5=...` hashes of the `x-goog-hash` header
+    expected: String,
+    crc32c: u32,
+    md5: Md5,
+}
+
+impl ObjectHashes {
+    fn new(expected: String) -> Self {
+        Self {
+            expected,
+            crc32c: 0,
+            md5: Md5::new(),
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        self.crc32c = crc32c::crc32c_append(self.crc32c, chunk);
+        self.md5.update(chunk);
+    }
+
+    /// Compare the hashes of the bytes received with the expected ones
+    fn verify(self, path: &GCSPath) -> Result<()> {
+        let crc32c = base64::encode(self.crc32c.to_be_bytes());
+        let md5 = base64::encode(self.md5.finalize());
+        for hash in self.expected.split(',').map(str::trim) {
+            let (algorithm, actual, expected) = match hash.split_once('=') {
+                Some(("crc32c", expected)) => ("crc32c", &crc32c, expected),
+                Some(("md5", expected)) => ("md5", &md5, expected),
+                _ => continue,
+            };
+            if actual != expected {
+                return Err(GCSError::ChecksumMismatch {
+                    uri: format!("gs://{}", path),
+                    algorithm: algorithm.to_string(),
+                    expected: expected.to_string(),
+                    actual: actual.clone(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One page of an objects list response
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ObjectList {
+    #[serde(default)]
+    pub(crate) items: Vec<ObjectResource>,
+    #[serde(default)]
+    pub(crate) prefixes: Vec<String>,
+    pub(crate) next_page_token: Option<String>,
+}
+
+/// The fields of an object resource this crate uses
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ObjectResource {
+    pub(crate) name: String,
+    #[serde(deserialize_with = "from_str")]
+    pub(crate) size: u64,
+    /// Base64 of the big-endian CRC32C of the data
+    pub(crate) crc32c: Option<String>,
+    pub(crate) updated: DateTime<Utc>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub(crate) generation: u64,
+    pub(crate) kms_key_name: Option<String>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub(crate) metageneration: u64,
+    pub(crate) etag: Option<String>,
+    pub(crate) content_type: Option<String>,
+    pub(crate) content_encoding: Option<String>,
+    pub(crate) storage_class: Option<String>,
+    #[serde(default)]
+    pub(crate) metadata: BTreeMap<String, String>,
+    pub(crate) time_created: Option<DateTime<Utc>>,
+    /// When the generation became noncurrent, only set in listings of versions
+    pub(crate) time_deleted: Option<DateTime<Utc>>,
+}
+
+impl ObjectResource {
+    /// Whether the object is in a storage class whose reads incur retrieval fees
+    pub(crate) fn archived(&self) -> bool {
+        matches!(self.storage_class.as_deref(), Some("COLDLINE" | "ARCHIVE"))
+    }
+}
+
+/// The JSON API encodes 64 bit integers as strings
+fn from_str<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u64, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+pub(crate) fn http_error(err: reqwest::Error) -> std::io::Error {
+    let kind = if err.is_timeout() {
+        ErrorKind::TimedOut
+    } else {
+        ErrorKind::Other
+    };
+    std::io::Error::new(kind, GCSError::GCS(format!("{:?}", err)))
+}
+
+pub(crate) async fn status_error(response: Response) -> std::io::Error {
+    let status = response.status().as_u16();
+    let url = response.url().to_string();
+    let body = response.text().await.unwrap_or_default();
+    GCSError::from_response(&url, status, &body).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_range(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_RANGE, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn ranges_covering_whole_objects() {
+        assert!(covers_object(&content_range("bytes 0-99/100")));
+        assert!(covers_object(&content_range("bytes 0-0/1")));
+        assert!(!covers_object(&content_range("bytes 0-98/100")));
+        assert!(!covers_object(&content_range("bytes 1-99/100")));
+        assert!(!covers_object(&content_range("bytes 0-99/*")));
+        assert!(!covers_object(&content_range("bytes */100")));
+        assert!(!covers_object(&content_range("items 0-99/100")));
+        assert!(!covers_object(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,724 @@
+//! Credential sources used to authenticate requests to Google Cloud Storage
+
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::error::GCSError;
+use crate::object_store::clock::Clock;
+
+pub(crate) const DEFAULT_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.full_control";
+const IAM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+const METADATA_HOST_ENV: &str = "GCE_METADATA_HOST";
+/// Key file of the application default credentials, honoured by the Google client libraries too
+pub(crate) const CREDENTIALS_ENV: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+const METADATA_MAX_ATTEMPTS: u32 = 5;
+const METADATA_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Tokens are refreshed this long before they expire unless configured otherwise
+pub const DEFAULT_REFRESH_WINDOW: Duration = Duration::from_secs(300);
+
+/// Source of the OAuth2 access tokens attached to every GCS request
+#[derive(Debug, Clone, Default)]
+pub enum Credentials {
+    /// Service account key file found through the `SERVICE_ACCOUNT` or
+    /// `GOOGLE_APPLICATION_CREDENTIALS` environment variables
+    #[default]
+    ServiceAccount,
+    /// Tokens issued by the GCE metadata server, as used by GKE Workload Identity.
+    /// `None` selects the `default` service account of the instance.
+    MetadataServer(Option<String>),
+    /// Tokens for `target` issued through the IAM Credentials `generateAccessToken` flow.
+    /// The `base` principal needs `roles/iam.serviceAccountTokenCreator` on `target`
+    /// (or on the last of `delegates`).
+    Impersonated {
+        /// Credentials of the calling principal
+        base: Box<Credentials>,
+        /// Email of the service account to impersonate
+        target: String,
+        /// Chain of service accounts to delegate through, in order
+        delegates: Vec<String>,
+        /// Lifetime of each minted token, at most one hour unless the org policy allows more
+        lifetime: Duration,
+    },
+    /// No credentials at all, for emulators such as fake-gcs-server and public buckets
+    Anonymous,
+}
+
+impl Credentials {
+    /// Impersonate `target` using `base`, with one hour tokens and no delegation chain
+    pub fn impersonate(base: Credentials, target: impl Into<String>) -> Self {
+        Credentials::Impersonated {
+            base: Box::new(base),
+            target: target.into(),
+            delegates: vec![],
+            lifetime: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Describes where tokens come from without revealing any key material
+impl Display for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::ServiceAccount => {
+                if std::env::var("SERVICE_ACCOUNT_JSON").is_ok() {
+                    return write!(f, "service account key from $SERVICE_ACCOUNT_JSON");
+                }
+                match std::env::var("SERVICE_ACCOUNT").or_else(|_| std::env::var(CREDENTIALS_ENV)) {
+                    Ok(path) => write!(f, "service account key file {}", path),
+                    Err(_) => write!(f, "service account key (no key file configured)"),
+                }
+            }
+            Credentials::MetadataServer(account) => write!(
+                f,
+                "metadata server, account {}",
+                account.as_deref().unwrap_or("default")
+            ),
+            Credentials::Impersonated {
+                base,
+                target,
+                delegates,
+                lifetime,
+            } => {
+                write!(f, "impersonating {}", target)?;
+                if !delegates.is_empty() {
+                    write!(f, " via {}", delegates.join(", "))?;
+                }
+                write!(f, " for {:?} with {}", lifetime, base)
+            }
+            Credentials::Anonymous => write!(f, "anonymous"),
+        }
+    }
+}
+
+/// A source that can mint a fresh access token and its expiry (seconds since the Unix epoch)
+#[async_trait]
+pub(crate) trait TokenSource: Debug + Send + Sync {
+    async fn fetch(&self, client: &reqwest::Client, scope: &str)
+        -> Result<(String, u64), GCSError>;
+
+    /// Email of the service account behind this source
+    async fn account(&self, client: &reqwest::Client) -> Result<String, GCSError>;
+
+    /// Sign `payload` with RSA-SHA256 as the service account behind this source
+    async fn sign(&self, client: &reqwest::Client, payload: &[u8]) -> Result<Vec<u8>, GCSError>;
+}
+
+/// Caching token provider shared by every request made for a `GCSFileSystem`.
+///
+/// Clones share the cached token, so readers running on their own runtimes do not
+/// each have to go back to the token source.
+#[derive(Clone)]
+pub(crate) struct TokenProvider {
+    source: Arc<dyn TokenSource>,
+    state: Arc<RwLock<Option<(String, u64)>>>,
+    scope: String,
+    refresh_window: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenProvider {
+    pub(crate) fn new(
+        credentials: &Credentials,
+        refresh_window: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let source: Arc<dyn TokenSource> = match credentials {
+            Credentials::ServiceAccount => Arc::new(ServiceAccount::new(clock.clone())),
+            Credentials::MetadataServer(account) => {
+                Arc::new(MetadataServer::new(account.clone(), clock.clone()))
+            }
+            Credentials::Impersonated {
+                base,
+                target,
+                delegates,
+                lifetime,
+            } => Arc::new(Impersonated::new(
+                base,
+                target,
+                delegates,
+                *lifetime,
+                clock.clone(),
+            )),
+            Credentials::Anonymous => Arc::new(Anonymous),
+        };
+        Self {
+            source,
+            state: Arc::new(RwLock::new(None)),
+            scope: DEFAULT_SCOPE.to_string(),
+            refresh_window,
+            clock,
+        }
+    }
+
+    /// A provider of the tokens of `source`, e.g. a stub in tests
+    #[cfg(test)]
+    pub(crate) fn with_source(source: Arc<dyn TokenSource>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            source,
+            state: Arc::new(RwLock::new(None)),
+            scope: DEFAULT_SCOPE.to_string(),
+            refresh_window: DEFAULT_REFRESH_WINDOW,
+            clock,
+        }
+    }
+
+    /// Returns a token that is valid for at least `refresh_window`, refreshing it if required
+    pub(crate) async fn token(&self, client: &reqwest::Client) -> Result<String, GCSError> {
+        if let Some(token) = self.valid_token(&*self.state.read().await) {
+            return Ok(token);
+        }
+
+        // hold the write lock across the refresh so concurrent readers wait for a single fetch
+        let mut state = self.state.write().await;
+        if let Some(token) = self.valid_token(&state) {
+            return Ok(token);
+        }
+        let (token, exp) = self.source.fetch(client, &self.scope).await?;
+        *state = Some((token.clone(), exp));
+        Ok(token)
+    }
+
+    /// Email of the service account behind these credentials
+    pub(crate) async fn account(&self, client: &reqwest::Client) -> Result<String, GCSError> {
+        self.source.account(client).await
+    }
+
+    /// Sign `payload` as the service account behind these credentials
+    pub(crate) async fn sign(
+        &self,
+        client: &reqwest::Client,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, GCSError> {
+        self.source.sign(client, payload).await
+    }
+
+    fn valid_token(&self, state: &Option<(String, u64)>) -> Option<String> {
+        match state {
+            Some((token, exp)) if self.clock.unix_secs() + self.refresh_window.as_secs() < *exp => {
+                Some(token.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Debug for TokenProvider {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenProvider")
+            .field("source", &self.source)
+            .field("scope", &self.scope)
+            .field("refresh_window", &self.refresh_window)
+            .finish()
+    }
+}
+
+/// Service account key file, read when the first token is requested
+#[derive(Debug)]
+struct ServiceAccount {
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// Check that the file at `path` holds a service account key, describing what is wrong
+/// with it otherwise
+pub(crate) fn check_key_file(path: &str) -> Result<(), String> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|err| format!("unable to read key file {}: {}", path, err))?;
+    let key: serde_json::Value = serde_json::from_str(&json)
+        .map_err(|err| format!("key file {} is not JSON: {}", path, err))?;
+    match key.get("type").and_then(|kind| kind.as_str()) {
+        Some("service_account") => {}
+        Some(kind) => {
+            return Err(format!(
+                "key file {} holds {} credentials, only service account keys are supported",
+                path, kind
+            ))
+        }
+        None => return Err(format!("key file {} has no credential type", path)),
+    }
+    serde_json::from_value::<ServiceAccountKey>(key)
+        .map(|_| ())
+        .map_err(|err| format!("invalid service account key in {}: {}", path, err))
+}
+
+#[derive(Serialize)]
+struct JwtClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct OAuthToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl ServiceAccount {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock }
+    }
+
+    fn key(&self) -> Result<ServiceAccountKey, GCSError> {
+        let json = match std::env::var("SERVICE_ACCOUNT_JSON") {
+            Ok(json) => json,
+            Err(_) => {
+                let path = std::env::var("SERVICE_ACCOUNT")
+                    .or_else(|_| std::env::var(CREDENTIALS_ENV))
+                    .map_err(|_| {
+                        GCSError::Auth(
+                            "set SERVICE_ACCOUNT or GOOGLE_APPLICATION_CREDENTIALS to a key file"
+                                .into(),
+                        )
+                    })?;
+                std::fs::read_to_string(&path).map_err(|err| {
+                    GCSError::Auth(format!("unable to read key file {}: {}", path, err))
+                })?
+            }
+        };
+        serde_json::from_str(&json)
+            .map_err(|err| GCSError::Auth(format!("invalid service account key: {}", err)))
+    }
+}
+
+#[async_trait]
+impl TokenSource for ServiceAccount {
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        scope: &str,
+    ) -> Result<(String, u64), GCSError> {
+        let key = self.key()?;
+        let iat = self.clock.unix_secs();
+        let claims = JwtClaims {
+            iss: &key.client_email,
+            scope,
+            aud: &key.token_uri,
+            iat,
+            exp: iat + 3600,
+        };
+        let signing_key = jsonwebtoken::EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+            .map_err(|err| GCSError::Auth(format!("invalid service account key: {}", err)))?;
+        let assertion = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256),
+            &claims,
+            &signing_key,
+        )
+        .map_err(|err| GCSError::Auth(format!("unable to sign token request: {}", err)))?;
+
+        let response = client
+            .post(&key.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|err| GCSError::Auth(format!("{:?}", err)))?;
+        if !response.status().is_success() {
+            return Err(GCSError::Auth(format!(
+                "token request for {} failed: {}",
+                key.client_email,
+                response.text().await.unwrap_or_default()
+            )));
+        }
+        let token = response
+            .json::<OAuthToken>()
+            .await
+            .map_err(|err| GCSError::Auth(format!("{:?}", err)))?;
+        Ok((token.access_token, iat + token.expires_in))
+    }
+
+    async fn account(&self, _client: &reqwest::Client) -> Result<String, GCSError> {
+        Ok(self.key()?.client_email)
+    }
+
+    async fn sign(&self, _client: &reqwest::Client, payload: &[u8]) -> Result<Vec<u8>, GCSError> {
+        let key = self.key()?;
+        let signing_key = jsonwebtoken::EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+            .map_err(|err| GCSError::Auth(format!("invalid service account key: {}", err)))?;
+        let signature =
+            jsonwebtoken::crypto::sign(payload, &signing_key, jsonwebtoken::Algorithm::RS256)
+                .map_err(|err| GCSError::Auth(format!("unable to sign: {}", err)))?;
+        base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|err| GCSError::Auth(format!("unable to sign: {}", err)))
+    }
+}
+
+/// Short-lived tokens for `target` minted by IAM Credentials `generateAccessToken`,
+/// authenticated with an access token from `base`
+#[derive(Debug)]
+struct Impersonated {
+    base: TokenProvider,
+    target: String,
+    url: String,
+    delegates: Vec<String>,
+    lifetime: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Serialize)]
+struct SignBlobRequest<'a> {
+    payload: String,
+    delegates: &'a [String],
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignBlobResponse {
+    signed_blob: String,
+}
+
+/// Sign `payload` as `account` with IAM Credentials `signBlob`
+async fn sign_blob(
+    client: &reqwest::Client,
+    token: &str,
+    account: &str,
+    delegates: &[String],
+    payload: &[u8],
+) -> Result<Vec<u8>, GCSError> {
+    let url = format!(
+        "https://iamcredentials.googleapis.com/v1/{}:signBlob",
+        service_account_resource(account)
+    );
+    let response = client
+        .post(&url)
+        .bearer_auth(token)
+        .json(&SignBlobRequest {
+            payload: base64::encode(payload),
+            delegates,
+        })
+        .send()
+        .await
+        .map_err(|err| GCSError::Auth(format!("{:?}", err)))?;
+    if !response.status().is_success() {
+        return Err(GCSError::Auth(format!(
+            "signBlob as {} failed: {}",
+            account,
+            response.text().await.unwrap_or_default()
+        )));
+    }
+    let signed = response
+        .json::<SignBlobResponse>()
+        .await
+        .map_err(|err| GCSError::Auth(format!("{:?}", err)))?;
+    base64::decode(signed.signed_blob)
+        .map_err(|err| GCSError::Auth(format!("invalid signBlob response: {}", err)))
+}
+
+#[derive(Serialize)]
+struct GenerateAccessTokenRequest<'a> {
+    scope: [&'a str; 1],
+    delegates: Vec<String>,
+    lifetime: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: String,
+}
+
+impl Impersonated {
+    fn new(
+        base: &Credentials,
+        target: &str,
+        delegates: &[String],
+        lifetime: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let mut base = TokenProvider::new(base, DEFAULT_REFRESH_WINDOW, clock.clone());
+        base.scope = IAM_SCOPE.to_string();
+        Self {
+            base,
+            target: target.to_string(),
+            url: format!(
+                "https://iamcredentials.googleapis.com/v1/{}:generateAccessToken",
+                service_account_resource(target)
+            ),
+            delegates: delegates
+                .iter()
+                .map(|d| service_account_resource(d))
+                .collect(),
+            lifetime,
+            clock,
+        }
+    }
+}
+
+#[async_trait]
+impl TokenSource for Impersonated {
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        scope: &str,
+    ) -> Result<(String, u64), GCSError> {
+        let base_token = self.base.token(client).await?;
+        let response = client
+            .post(&self.url)
+            .bearer_auth(base_token)
+            .json(&GenerateAccessTokenRequest {
+                scope: [scope],
+                delegates: self.delegates.clone(),
+                lifetime: format!("{}s", self.lifetime.as_secs()),
+            })
+            .send()
+            .await
+            .map_err(|err| GCSError::Auth(format!("{:?}", err)))?;
+        if !response.status().is_success() {
+            return Err(GCSError::Auth(format!(
+                "impersonation via {} failed: {}",
+                self.url,
+                response.text().await.unwrap_or_default()
+            )));
+        }
+        let token = response
+            .json::<GenerateAccessTokenResponse>()
+            .await
+            .map_err(|err| GCSError::Auth(format!("{:?}", err)))?;
+        let exp = chrono::DateTime::parse_from_rfc3339(&token.expire_time)
+            .map(|t| t.timestamp() as u64)
+            .unwrap_or_else(|_| self.clock.unix_secs() + self.lifetime.as_secs());
+        Ok((token.access_token, exp))
+    }
+
+    async fn account(&self, _client: &reqwest::Client) -> Result<String, GCSError> {
+        Ok(self.target.clone())
+    }
+
+    async fn sign(&self, client: &reqwest::Client, payload: &[u8]) -> Result<Vec<u8>, GCSError> {
+        let base_token = self.base.token(client).await?;
+        sign_blob(client, &base_token, &self.target, &self.delegates, payload).await
+    }
+}
+
+fn service_account_resource(email: &str) -> String {
+    if email.starts_with("projects/") {
+        email.to_string()
+    } else {
+        format!("projects/-/serviceAccounts/{}", email)
+    }
+}
+
+#[derive(Deserialize)]
+struct MetadataToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// GCE metadata server, honoring `GCE_METADATA_HOST` like the Google client libraries
+#[derive(Debug)]
+struct MetadataServer {
+    account_url: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl MetadataServer {
+    fn new(account: Option<String>, clock: Arc<dyn Clock>) -> Self {
+        let host =
+            std::env::var(METADATA_HOST_ENV).unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_string());
+        let account = account.unwrap_or_else(|| "default".to_string());
+        Self {
+            account_url: format!(
+                "http://{}/computeMetadata/v1/instance/service-accounts/{}",
+                host, account
+            ),
+            clock,
+        }
+    }
+
+    async fn fetch_once(
+        &self,
+        client: &reqwest::Client,
+        scope: &str,
+    ) -> Result<MetadataToken, (bool, GCSError)> {
+        let response = client
+            .get(format!("{}/token", self.account_url))
+            .query(&[("scopes", scope)])
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .map_err(|err| {
+                (
+                    true,
+                    GCSError::Auth(format!("metadata server unreachable: {}", err)),
+                )
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retryable =
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+            return Err((
+                retryable,
+                GCSError::Auth(format!("metadata server returned {}", status)),
+            ));
+        }
+
+        response.json::<MetadataToken>().await.map_err(|err| {
+            (
+                false,
+                GCSError::Auth(format!("invalid metadata server token: {}", err)),
+            )
+        })
+    }
+}
+
+#[async_trait]
+impl TokenSource for MetadataServer {
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        scope: &str,
+    ) -> Result<(String, u64), GCSError> {
+        let mut delay = METADATA_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match self.fetch_once(client, scope).await {
+                Ok(token) => {
+                    return Ok((
+                        token.access_token,
+                        self.clock.unix_secs() + token.expires_in,
+                    ))
+                }
+                Err((true, _)) if attempt < METADATA_MAX_ATTEMPTS => {
+                    self.clock.sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err((_, err)) => return Err(err),
+            }
+        }
+    }
+
+    async fn account(&self, client: &reqwest::Client) -> Result<String, GCSError> {
+        client
+            .get(format!("{}/email", self.account_url))
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| GCSError::Auth(format!("metadata server unreachable: {}", err)))?
+            .text()
+            .await
+            .map_err(|err| GCSError::Auth(format!("{:?}", err)))
+    }
+
+    async fn sign(&self, client: &reqwest::Client, payload: &[u8]) -> Result<Vec<u8>, GCSError> {
+        let email = self.account(client).await?;
+        let (token, _) = self.fetch(client, IAM_SCOPE).await?;
+        sign_blob(client, &token, &email, &[], payload).await
+    }
+}
+
+/// Hands out an empty token that never expires, which requests are sent without
+#[derive(Debug)]
+struct Anonymous;
+
+#[async_trait]
+impl TokenSource for Anonymous {
+    async fn fetch(&self, _: &reqwest::Client, _: &str) -> Result<(String, u64), GCSError> {
+        Ok((String::new(), u64::MAX))
+    }
+
+    async fn account(&self, _: &reqwest::Client) -> Result<String, GCSError> {
+        Err(GCSError::NotImplemented(
+            "anonymous credentials have no service account".to_string(),
+        ))
+    }
+
+    async fn sign(&self, _: &reqwest::Client, _: &[u8]) -> Result<Vec<u8>, GCSError> {
+        Err(GCSError::NotImplemented(
+            "anonymous credentials cannot sign".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::object_store::clock::MockClock;
+
+    /// Mints `token-<n>` tokens that are valid for an hour
+    #[derive(Debug)]
+    struct Counting {
+        fetches: AtomicU64,
+        clock: Arc<MockClock>,
+    }
+
+    #[async_trait]
+    impl TokenSource for Counting {
+        async fn fetch(&self, _: &reqwest::Client, _: &str) -> Result<(String, u64), GCSError> {
+            let n = self.fetches.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok((format!("token-{}", n), self.clock.unix_secs() + 3600))
+        }
+
+        async fn account(&self, _: &reqwest::Client) -> Result<String, GCSError> {
+            Ok("test@example.iam.gserviceaccount.com".to_string())
+        }
+
+        async fn sign(&self, _: &reqwest::Client, payload: &[u8]) -> Result<Vec<u8>, GCSError> {
+            Ok(payload.to_vec())
+        }
+    }
+
+    fn provider(clock: Arc<MockClock>) -> TokenProvider {
+        let source = Counting {
+            fetches: AtomicU64::new(0),
+            clock: clock.clone(),
+        };
+        TokenProvider::with_source(Arc::new(source), clock)
+    }
+
+    #[tokio::test]
+    async fn tokens_are_refreshed_within_the_window() {
+        let clock = Arc::new(MockClock::default());
+        let provider = provider(clock.clone());
+        let client = reqwest::Client::new();
+
+        assert_eq!(provider.token(&client).await.unwrap(), "token-1");
+        // still valid for longer than the refresh window
+        clock.advance(Duration::from_secs(3600) - DEFAULT_REFRESH_WINDOW - Duration::from_secs(1));
+        assert_eq!(provider.token(&client).await.unwrap(), "token-1");
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(provider.token(&client).await.unwrap(), "token-2");
+        // clones share the refreshed token
+        assert_eq!(provider.clone().token(&client).await.unwrap(), "token-2");
+    }
+
+    #[tokio::test]
+    async fn metadata_server_retries_back_off_on_the_clock() {
+        let clock = Arc::new(MockClock::default());
+        // nothing listens on the discard port, so every attempt fails to connect
+        let server = MetadataServer {
+            account_url: "http://127.0.0.1:9/computeMetadata/v1/instance/service-accounts/default"
+                .to_string(),
+            clock: clock.clone(),
+        };
+        let err = server
+            .fetch(&reqwest::Client::new(), DEFAULT_SCOPE)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, GCSError::Auth(_)), "{:?}", err);
+        let millis = [100, 200, 400, 800].map(Duration::from_millis);
+        assert_eq!(clock.sleeps(), millis);
+    }
+}
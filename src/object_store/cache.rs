@@ -0,0 +1,240 @@
+//! In-memory cache of downloaded byte ranges, snapshots of the caches of a store, and how
+//! scans use them
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// How a `GCSFileSystem` uses its metadata, listing, negative, block and disk caches, set
+/// per scan with `GCSFileSystem::with_cache_mode`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Answer from the caches, and remember what is listed, looked up and read
+    #[default]
+    ReadThrough,
+    /// Send every listing, lookup and read to GCS and leave the caches as they are, for
+    /// jobs that must see the latest data whatever the TTLs
+    Bypass,
+    /// Send every listing, lookup and read to GCS, and replace the cached entries with
+    /// what it returned, so later scans see the same data
+    Refresh,
+}
+
+impl CacheMode {
+    /// Whether lookups may be answered from the caches
+    pub(crate) fn reads(self) -> bool {
+        self == Self::ReadThrough
+    }
+
+    /// Whether what is listed, looked up and read is cached
+    pub(crate) fn writes(self) -> bool {
+        self != Self::Bypass
+    }
+}
+
+/// Contents of the metadata and block caches of a `GCSFileSystem`, taken with
+/// `GCSFileSystem::cache_snapshot` and loaded with `GCSFileSystem::restore_cache`.
+///
+/// Snapshots implement `Serialize` and `Deserialize`, so a warmed up worker can hand its
+/// caches to new replicas in whatever format the orchestration layer prefers. Block data
+/// is serialized as base64.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    /// Objects seen in listings and lookups
+    pub objects: Vec<ObjectSnapshot>,
+    /// Cached ranges, least recently used first
+    pub blocks: Vec<BlockSnapshot>,
+}
+
+/// Cached metadata of one object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ObjectSnapshot {
+    /// `bucket/key` of the object
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Generation the metadata belongs to
+    pub generation: u64,
+    /// Last modification time
+    pub updated: DateTime<Utc>,
+    /// In the `COLDLINE` or `ARCHIVE` storage class
+    #[serde(default)]
+    pub archived: bool,
+}
+
+/// One cached range of an object generation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockSnapshot {
+    /// `bucket/key` of the object
+    pub path: String,
+    /// Generation the data was read from
+    pub generation: u64,
+    /// Offset of the range
+    pub start: u64,
+    /// Requested length of the range, `0` for the rest of the object
+    pub length: usize,
+    /// Contents of the range
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub data: Vec<u8>,
+}
+
+fn to_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(data))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    base64::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// An object generation and byte range within it. Including the generation means an
+/// overwritten object never serves stale bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct BlockKey {
+    pub(crate) path: String,
+    pub(crate) generation: u64,
+    pub(crate) start: u64,
+    pub(crate) length: usize,
+}
+
+#[derive(Debug, Default)]
+struct Blocks {
+    entries: HashMap<BlockKey, (Bytes, u64)>,
+    /// Keys by the tick they were last used at, oldest first
+    recency: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    size: usize,
+}
+
+/// Least recently used cache of downloaded ranges, bounded by the bytes it holds
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    capacity: usize,
+    blocks: Mutex<Blocks>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: Mutex::new(Blocks::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &BlockKey) -> Option<Bytes> {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.tick += 1;
+        let tick = blocks.tick;
+        let (bytes, last_used) = blocks.entries.get_mut(key)?;
+        let (bytes, previous) = (bytes.clone(), std::mem::replace(last_used, tick));
+        blocks.recency.remove(&previous);
+        blocks.recency.insert(tick, key.clone());
+        Some(bytes)
+    }
+
+    /// Every cached block, least recently used first
+    pub(crate) fn blocks(&self) -> Vec<(BlockKey, Bytes)> {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .recency
+            .values()
+            .filter_map(|key| Some((key.clone(), blocks.entries.get(key)?.0.clone())))
+            .collect()
+    }
+
+    /// Cache `bytes` under `key`, evicting the least recently used blocks to make room.
+    /// Blocks larger than the whole cache are not kept.
+    pub(crate) fn insert(&self, key: BlockKey, bytes: Bytes) {
+        if bytes.len() > self.capacity {
+            return;
+        }
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.tick += 1;
+        let tick = blocks.tick;
+        blocks.size += bytes.len();
+        if let Some((old, last_used)) = blocks.entries.insert(key.clone(), (bytes, tick)) {
+            blocks.size -= old.len();
+            blocks.recency.remove(&last_used);
+        }
+        blocks.recency.insert(tick, key);
+
+        while blocks.size > self.capacity {
+            let oldest = match blocks.recency.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            let oldest = blocks.recency.remove(&oldest).unwrap();
+            if let Some((evicted, _)) = blocks.entries.remove(&oldest) {
+                blocks.size -= evicted.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(start: u64) -> BlockKey {
+        BlockKey {
+            path: "bucket/a.parquet".to_string(),
+            generation: 1,
+            start,
+            length: 10,
+        }
+    }
+
+    fn cached(cache: &BlockCache) -> Vec<u64> {
+        cache
+            .blocks()
+            .into_iter()
+            .map(|(key, _)| key.start)
+            .collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = BlockCache::new(30);
+        for start in [0, 10, 20] {
+            cache.insert(key(start), Bytes::from(vec![0; 10]));
+        }
+        assert_eq!(cached(&cache), [0, 10, 20]);
+
+        // reads make a block the most recently used
+        assert!(cache.get(&key(0)).is_some());
+        assert_eq!(cached(&cache), [10, 20, 0]);
+        cache.insert(key(30), Bytes::from(vec![0; 10]));
+        assert_eq!(cached(&cache), [20, 0, 30]);
+        assert!(cache.get(&key(10)).is_none());
+
+        // the same range of another generation is another block
+        let other = BlockKey {
+            generation: 2,
+            ..key(20)
+        };
+        assert!(cache.get(&other).is_none());
+    }
+
+    #[test]
+    fn stays_within_its_byte_budget() {
+        let cache = BlockCache::new(30);
+        cache.insert(key(0), Bytes::from(vec![0; 10]));
+        cache.insert(key(10), Bytes::from(vec![0; 10]));
+        // as many blocks as needed make room
+        cache.insert(key(20), Bytes::from(vec![0; 25]));
+        assert_eq!(cached(&cache), [20]);
+
+        // a replaced block only counts once
+        cache.insert(key(20), Bytes::from(vec![1; 5]));
+        cache.insert(key(30), Bytes::from(vec![0; 25]));
+        assert_eq!(cached(&cache), [20, 30]);
+        assert_eq!(cache.get(&key(20)).unwrap(), vec![1; 5]);
+
+        // blocks larger than the cache are not kept and evict nothing
+        cache.insert(key(40), Bytes::from(vec![0; 31]));
+        assert_eq!(cached(&cache), [30, 20]);
+        assert_eq!(cache.blocks.lock().unwrap().size, 30);
+    }
+}
@@ -0,0 +1,88 @@
+//! Time source used for token expiry, retry backoff and cache TTLs
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+
+/// Abstraction over the current time and sleeping, so schedules can be simulated in tests
+#[async_trait]
+pub trait Clock: Debug + Send + Sync {
+    /// The current wall clock time
+    fn now(&self) -> SystemTime;
+
+    /// Wait for `duration` to pass
+    async fn sleep(&self, duration: Duration);
+
+    /// The current time in whole seconds since the Unix epoch
+    fn unix_secs(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// The real clock, sleeping on the Tokio timer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// Deterministic clock that only moves when advanced or slept on.
+///
+/// `sleep` returns immediately after advancing the time by the requested duration and
+/// recording it, so backoff schedules and TTL expiry can be asserted without waiting.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+    sleeps: Mutex<Vec<Duration>>,
+}
+
+impl MockClock {
+    /// Create a clock starting at `start`
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(start),
+            sleeps: Mutex::new(vec![]),
+        }
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Durations passed to `sleep`, in call order
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(UNIX_EPOCH)
+    }
+}
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.sleeps.lock().unwrap().push(duration);
+        self.advance(duration);
+    }
+}
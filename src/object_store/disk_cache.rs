@@ -0,0 +1,366 @@
+//! On-disk cache of downloaded byte ranges for interactive workloads
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+
+use crate::object_store::cache::BlockKey;
+
+#[derive(Debug, Default)]
+struct Files {
+    /// Size and last use of each cached file, by file name
+    entries: HashMap<String, (u64, u64)>,
+    /// File names by the tick they were last used at, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+impl Files {
+    fn touch(&mut self, name: &str, size: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        self.size += size;
+        if let Some((old_size, last_used)) = self.entries.insert(name.to_string(), (size, tick)) {
+            self.size -= old_size;
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(tick, name.to_string());
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some((size, last_used)) = self.entries.remove(name) {
+            self.size -= size;
+            self.recency.remove(&last_used);
+        }
+    }
+}
+
+/// How the files of a disk cache are encoded, set with
+/// `GCSFileSystemBuilder::with_disk_cache_compression`.
+///
+/// Compressing multiplies how many ranges of text formats like CSV and NDJSON fit the
+/// budget, at the cost of CPU time on every insert and hit. Parquet pages are mostly
+/// compressed already, so gain little.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiskCacheCompression {
+    /// Store ranges as downloaded
+    #[default]
+    None,
+    /// gzip at a level from 0 (fastest) to 9 (smallest)
+    Gzip(u32),
+    /// zstd at a level from 1 (fastest) to 22 (smallest), where 0 is zstd's default
+    Zstd(i32),
+}
+
+impl DiskCacheCompression {
+    /// Added to the hashed file names, so files are never decoded with another codec than
+    /// they were written with. Levels need no tag, any level decodes the same way.
+    fn tag(self) -> &'static str {
+        match self {
+            DiskCacheCompression::None => "",
+            DiskCacheCompression::Gzip(_) => "#gzip",
+            DiskCacheCompression::Zstd(_) => "#zstd",
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            DiskCacheCompression::None => Ok(Cow::Borrowed(bytes)),
+            DiskCacheCompression::Gzip(level) => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::new(level.min(9)));
+                encoder.write_all(bytes)?;
+                Ok(Cow::Owned(encoder.finish()?))
+            }
+            DiskCacheCompression::Zstd(level) => {
+                let mut encoder = zstd::Encoder::new(vec![], level)?;
+                // like gzip's CRC, so corrupted files are misses rather than wrong data
+                encoder.include_checksum(true)?;
+                encoder.write_all(bytes)?;
+                Ok(Cow::Owned(encoder.finish()?))
+            }
+        }
+    }
+
+    /// Read `file` to the end, decompressing as it is read
+    fn decode(self, file: File, capacity: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(capacity);
+        match self {
+            DiskCacheCompression::None => io::BufReader::new(file).read_to_end(&mut bytes)?,
+            DiskCacheCompression::Gzip(_) => GzDecoder::new(file).read_to_end(&mut bytes)?,
+            DiskCacheCompression::Zstd(_) => zstd::Decoder::new(file)?.read_to_end(&mut bytes)?,
+        };
+        Ok(bytes)
+    }
+}
+
+/// Subdirectory of the configured directory the cache owns, versioned so a change to the
+/// naming or contents of the files never reads old ones
+const SUBDIRECTORY: &str = "datafusion-gcs-v1";
+
+/// Downloaded ranges persisted as one file each in a directory, evicting the least
+/// recently used files once their total size exceeds the budget.
+///
+/// Files are named after a hash of the object, generation, range and codec, so a range is
+/// only ever reused for the generation it was downloaded from. The budget counts the
+/// size of the files, compressed or not. They are kept in
+/// [`SUBDIRECTORY`] of the configured directory, which is a cache: any I/O error just
+/// means a miss, and files in it named like the cache's own are adopted on startup.
+/// Other files are never read or deleted.
+#[derive(Debug)]
+pub(crate) struct DiskCache {
+    dir: PathBuf,
+    budget: u64,
+    compression: DiskCacheCompression,
+    files: Mutex<Files>,
+}
+
+impl DiskCache {
+    /// Use a subdirectory of `dir`, created on the first insert if needed, for at most
+    /// `budget` bytes, encoding the files with `compression`
+    pub(crate) fn new(dir: PathBuf, budget: u64, compression: DiskCacheCompression) -> Self {
+        let dir = dir.join(SUBDIRECTORY);
+        let mut existing = std::fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let name = entry.file_name().into_string().ok()?;
+                (metadata.is_file() && is_cache_file(&name))
+                    .then(|| (metadata.modified().ok(), name, metadata.len()))
+            })
+            .collect::<Vec<_>>();
+        existing.sort();
+
+        let mut files = Files::default();
+        for (_, name, size) in existing {
+            files.touch(&name, size);
+        }
+        let cache = Self {
+            dir,
+            budget,
+            compression,
+            files: Mutex::new(files),
+        };
+        cache.evict();
+        cache
+    }
+
+    pub(crate) fn get(&self, key: &BlockKey) -> Option<Bytes> {
+        let name = self.file_name(key);
+        // not locked while reading, so lookups do not wait on each other's I/O
+        let (size, _) = *self.files.lock().unwrap().entries.get(&name)?;
+        let read = File::open(self.dir.join(&name)).and_then(|file| {
+            if file.metadata()?.len() != size {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.compression.decode(file, key.length)
+        });
+        let mut files = self.files.lock().unwrap();
+        // only update the entry if it was not evicted or replaced meanwhile
+        let current = files.entries.get(&name).map(|(size, _)| *size) == Some(size);
+        match read {
+            Ok(bytes) => {
+                if current {
+                    files.touch(&name, size);
+                }
+                Some(Bytes::from(bytes))
+            }
+            _ => {
+                // removed, truncated or corrupted behind our back
+                if current {
+                    files.remove(&name);
+                }
+                None
+            }
+        }
+    }
+
+    /// Persist `bytes` under `key`; ranges larger than the whole budget are not kept
+    pub(crate) fn insert(&self, key: &BlockKey, bytes: &[u8]) {
+        let bytes = match self.compression.encode(bytes) {
+            Ok(bytes) if bytes.len() as u64 <= self.budget => bytes,
+            _ => return,
+        };
+        let name = self.file_name(key);
+        let (path, temporary) = (self.dir.join(&name), self.dir.join(format!("{}.tmp", name)));
+        // write and rename, so readers never see a partially written file
+        let written = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&temporary, &bytes))
+            .and_then(|_| std::fs::rename(&temporary, &path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temporary);
+            return;
+        }
+        self.files.lock().unwrap().touch(&name, bytes.len() as u64);
+        self.evict();
+    }
+
+    fn file_name(&self, key: &BlockKey) -> String {
+        let digest = Sha256::digest(
+            format!(
+                "{}#{}#{}#{}{}",
+                key.path,
+                key.generation,
+                key.start,
+                key.length,
+                self.compression.tag()
+            )
+            .as_bytes(),
+        );
+        hex::encode(digest)
+    }
+
+    fn evict(&self) {
+        let mut evicted = vec![];
+        {
+            let mut files = self.files.lock().unwrap();
+            while files.size > self.budget {
+                let oldest = match files.recency.values().next() {
+                    Some(name) => name.clone(),
+                    None => break,
+                };
+                files.remove(&oldest);
+                evicted.push(oldest);
+            }
+        }
+        // deleted outside the lock, like reads
+        for name in evicted {
+            let _ = std::fs::remove_file(self.dir.join(name));
+        }
+    }
+}
+
+/// Whether `name` is one of [`DiskCache::file_name`]'s, a hex SHA-256
+fn is_cache_file(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory below the system's temporary directory
+    fn directory(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "datafusion-gcs-{}-{}-{:016x}",
+            name,
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key(start: u64) -> BlockKey {
+        BlockKey {
+            path: "bucket/data/file.parquet".to_string(),
+            generation: 1,
+            start,
+            length: 4,
+        }
+    }
+
+    #[test]
+    fn unrelated_files_survive() {
+        let dir = directory("unrelated");
+        std::fs::write(dir.join("notes.txt"), b"keep me").unwrap();
+        std::fs::create_dir_all(dir.join(SUBDIRECTORY)).unwrap();
+        std::fs::write(dir.join(SUBDIRECTORY).join("notes.txt"), b"keep me").unwrap();
+        let name = DiskCache::new(dir.clone(), 4, DiskCacheCompression::None).file_name(&key(0));
+        std::fs::write(dir.join(SUBDIRECTORY).join(&name), b"abcd").unwrap();
+
+        // the budget only fits one range, so the adopted one is evicted by the insert
+        let cache = DiskCache::new(dir.clone(), 4, DiskCacheCompression::None);
+        assert_eq!(cache.get(&key(0)), Some(Bytes::from_static(b"abcd")));
+        cache.insert(&key(4), b"efgh");
+        assert_eq!(cache.get(&key(0)), None);
+        assert_eq!(cache.get(&key(4)), Some(Bytes::from_static(b"efgh")));
+
+        assert!(dir.join("notes.txt").exists());
+        assert!(dir.join(SUBDIRECTORY).join("notes.txt").exists());
+        assert!(!dir.join(SUBDIRECTORY).join(name).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = directory("evict");
+        let cache = DiskCache::new(dir.clone(), 8, DiskCacheCompression::None);
+        cache.insert(&key(0), b"abcd");
+        cache.insert(&key(4), b"efgh");
+        assert!(cache.get(&key(0)).is_some());
+        cache.insert(&key(8), b"ijkl");
+        assert!(cache.get(&key(0)).is_some());
+        assert_eq!(cache.get(&key(4)), None);
+        assert!(cache.get(&key(8)).is_some());
+
+        // ranges over the whole budget are not kept
+        cache.insert(&key(12), b"way too large");
+        assert_eq!(cache.get(&key(12)), None);
+
+        // adopted again on startup
+        let cache = DiskCache::new(dir.clone(), 8, DiskCacheCompression::None);
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(8)).is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compresses_entries() {
+        let csv = "id,name\n".to_string() + &"1,datafusion\n".repeat(400);
+        let key = BlockKey {
+            length: csv.len(),
+            ..key(0)
+        };
+        for compression in [DiskCacheCompression::Gzip(6), DiskCacheCompression::Zstd(3)] {
+            let dir = directory("compress");
+            // only fits compressed
+            let cache = DiskCache::new(dir.clone(), 1024, compression);
+            cache.insert(&key, csv.as_bytes());
+            assert_eq!(cache.get(&key), Some(Bytes::from(csv.clone())));
+            let file = dir.join(SUBDIRECTORY).join(cache.file_name(&key));
+            assert!(std::fs::metadata(&file).unwrap().len() < 1024);
+
+            // never decoded with another codec
+            for other in [
+                DiskCacheCompression::None,
+                DiskCacheCompression::Gzip(1),
+                DiskCacheCompression::Zstd(1),
+            ] {
+                let reopened = DiskCache::new(dir.clone(), 1024, other);
+                let found = reopened.get(&key);
+                if other.tag() == compression.tag() {
+                    assert_eq!(found, Some(Bytes::from(csv.clone())));
+                } else {
+                    assert_eq!(found, None);
+                }
+            }
+
+            // corrupted files are misses
+            let mut bytes = std::fs::read(&file).unwrap();
+            let middle = bytes.len() / 2;
+            bytes[middle] ^= 0xff;
+            std::fs::write(&file, bytes).unwrap();
+            let reopened = DiskCache::new(dir.clone(), 1024, compression);
+            assert_eq!(reopened.get(&key), None);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        // and does not fit uncompressed
+        let dir = directory("uncompressed");
+        let cache = DiskCache::new(dir.clone(), 1024, DiskCacheCompression::None);
+        cache.insert(&key, csv.as_bytes());
+        assert_eq!(cache.get(&key), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+//! Customer-supplied and customer-managed encryption keys for objects
+
+use std::fmt::{Debug, Formatter};
+use std::io::ErrorKind;
+
+use datafusion::datafusion_data_access::Result;
+use reqwest::RequestBuilder;
+use sha2::{Digest, Sha256};
+
+use crate::error::GCSError;
+use crate::object_store::path::GCSPath;
+
+/// An AES-256 customer-supplied encryption key (CSEK).
+///
+/// GCS does not store the key, so every read of an object encrypted with one has to
+/// present it again. `Debug` only shows the SHA-256 hash of the key, which GCS also
+/// reports in object metadata.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    /// Base64 of the key
+    key: String,
+    /// Base64 of the SHA-256 hash of the key
+    sha256: String,
+}
+
+impl EncryptionKey {
+    /// Use the 256 bit `key`
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key: base64::encode(key),
+            sha256: base64::encode(Sha256::digest(key)),
+        }
+    }
+
+    /// Decode a base64 key as passed to `gsutil -o "GSUtil:encryption_key=..."`
+    pub fn from_base64(key: &str) -> Result<Self> {
+        let invalid =
+            |desc: String| std::io::Error::new(ErrorKind::InvalidInput, GCSError::GCS(desc));
+        let bytes = base64::decode(key.trim())
+            .map_err(|err| invalid(format!("Invalid encryption key: {}", err)))?;
+        let key: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            invalid(format!(
+                "Encryption keys are 32 bytes, got {} bytes",
+                bytes.len()
+            ))
+        })?;
+        Ok(Self::new(key))
+    }
+
+    /// Base64 of the SHA-256 hash of the key
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// The key itself, as the gRPC API takes it
+    #[cfg(feature = "grpc")]
+    pub(crate) fn bytes(&self) -> Vec<u8> {
+        base64::decode(&self.key).expect("keys are stored as valid base64")
+    }
+
+    /// Add the headers that present the key to GCS
+    pub(crate) fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .header("x-goog-encryption-algorithm", "AES256")
+            .header("x-goog-encryption-key", &self.key)
+            .header("x-goog-encryption-key-sha256", &self.sha256)
+    }
+
+    /// Add the headers that present the key of the source object of a rewrite to GCS
+    pub(crate) fn apply_source(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .header("x-goog-copy-source-encryption-algorithm", "AES256")
+            .header("x-goog-copy-source-encryption-key", &self.key)
+            .header("x-goog-copy-source-encryption-key-sha256", &self.sha256)
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("sha256", &self.sha256)
+            .finish()
+    }
+}
+
+/// Encryption keys of a store, by the `bucket/prefix` of the objects they apply to
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Encryption {
+    /// Customer-supplied keys, an empty prefix applying to every object
+    customer_keys: Vec<(String, EncryptionKey)>,
+    /// Cloud KMS key names new objects are encrypted with, an empty prefix applying to
+    /// every object
+    kms_keys: Vec<(String, String)>,
+}
+
+impl Encryption {
+    /// Use `key` for objects under `prefix`, or for all objects without one
+    pub(crate) fn add_customer_key(&mut self, prefix: Option<&GCSPath>, key: EncryptionKey) {
+        add(&mut self.customer_keys, prefix, key);
+    }
+
+    /// Encrypt objects written under `prefix`, or all written objects without one, with
+    /// the Cloud KMS key `name`
+    pub(crate) fn add_kms_key(&mut self, prefix: Option<&GCSPath>, name: String) {
+        add(&mut self.kms_keys, prefix, name);
+    }
+
+    /// Customer-supplied keys by prefix, an empty prefix applying to every object
+    pub(crate) fn customer_keys(&self) -> &[(String, EncryptionKey)] {
+        &self.customer_keys
+    }
+
+    /// Cloud KMS key names by prefix, an empty prefix applying to every object
+    pub(crate) fn kms_keys(&self) -> &[(String, String)] {
+        &self.kms_keys
+    }
+
+    /// The customer-supplied key of the longest configured prefix of `path`
+    pub(crate) fn customer_key(&self, path: &GCSPath) -> Option<&EncryptionKey> {
+        longest_prefix(&self.customer_keys, path)
+    }
+
+    /// The Cloud KMS key name of the longest configured prefix of `path`
+    pub(crate) fn kms_key(&self, path: &GCSPath) -> Option<&str> {
+        longest_prefix(&self.kms_keys, path).map(String::as_str)
+    }
+}
+
+/// Set `value` for `prefix`, replacing any value it had
+fn add<T>(entries: &mut Vec<(String, T)>, prefix: Option<&GCSPath>, value: T) {
+    let prefix = prefix.map(GCSPath::to_string).unwrap_or_default();
+    entries.retain(|(existing, _)| *existing != prefix);
+    entries.push((prefix, value));
+}
+
+fn longest_prefix<'a, T>(entries: &'a [(String, T)], path: &GCSPath) -> Option<&'a T> {
+    let path = path.to_string();
+    entries
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(request: RequestBuilder) -> Vec<(String, String)> {
+        let request = request.build().unwrap();
+        let mut headers = request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+            .collect::<Vec<_>>();
+        headers.sort();
+        headers
+    }
+
+    #[test]
+    fn keys_by_longest_prefix() {
+        let (all, data) = (EncryptionKey::new([1; 32]), EncryptionKey::new([2; 32]));
+        let mut encryption = Encryption::default();
+        encryption.add_customer_key(None, all.clone());
+        encryption.add_customer_key(Some(&GCSPath::new("bucket", "data/")), data.clone());
+        let key = |key: &str| encryption.customer_key(&GCSPath::new("bucket", key));
+        assert_eq!(key("data/file.csv"), Some(&data));
+        assert_eq!(key("database/file.csv"), Some(&all));
+        assert_eq!(key("file.csv"), Some(&all));
+    }
+
+    #[test]
+    fn source_and_destination_headers() {
+        let key = EncryptionKey::new([7; 32]);
+        let request = reqwest::Client::new().post("http://localhost/rewrite");
+        let request = key.apply(key.apply_source(request));
+        let encoded = base64::encode([7; 32]);
+        let sha256 = key.sha256().to_string();
+        assert_eq!(
+            headers(request),
+            vec![
+                (
+                    "x-goog-copy-source-encryption-algorithm".into(),
+                    "AES256".into()
+                ),
+                ("x-goog-copy-source-encryption-key".into(), encoded.clone()),
+                (
+                    "x-goog-copy-source-encryption-key-sha256".into(),
+                    sha256.clone()
+                ),
+                ("x-goog-encryption-algorithm".into(), "AES256".into()),
+                ("x-goog-encryption-key".into(), encoded),
+                ("x-goog-encryption-key-sha256".into(), sha256),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(EncryptionKey::from_base64(&base64::encode([0; 16])).is_err());
+        assert!(EncryptionKey::from_base64("not base64!").is_err());
+        assert_eq!(
+            EncryptionKey::from_base64(&base64::encode([3; 32])).unwrap(),
+            EncryptionKey::new([3; 32])
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Listings restricted to objects of a time range or size
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use datafusion::datafusion_data_access::object_store::{
+    FileMetaStream, ListEntryStream, ObjectReader, ObjectStore,
+};
+use datafusion::datafusion_data_access::{FileMeta, ListEntry, Result, SizedFile};
+use futures::{future, StreamExt};
+
+use crate::object_store::gcs::GCSFileSystem;
+
+/// Conditions listed objects must meet, all of them if several are set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListingFilter {
+    /// Only objects last modified after this time
+    pub modified_after: Option<DateTime<Utc>>,
+    /// Only objects last modified before this time
+    pub modified_before: Option<DateTime<Utc>>,
+    /// Only objects of at least this many bytes
+    pub min_size: Option<u64>,
+    /// Only objects of at most this many bytes
+    pub max_size: Option<u64>,
+}
+
+impl ListingFilter {
+    /// A filter that keeps every object
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep objects last modified after `time`, e.g. the start of the last run
+    pub fn modified_after(mut self, time: DateTime<Utc>) -> Self {
+        self.modified_after = Some(time);
+        self
+    }
+
+    /// Only keep objects last modified before `time`
+    pub fn modified_before(mut self, time: DateTime<Utc>) -> Self {
+        self.modified_before = Some(time);
+        self
+    }
+
+    /// Only keep objects of at least `bytes`, e.g. to skip empty marker objects
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = Some(bytes);
+        self
+    }
+
+    /// Only keep objects of at most `bytes`
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Whether `file` meets every condition. Files without a modification time fail
+    /// the time conditions.
+    pub fn matches(&self, file: &FileMeta) -> bool {
+        let (size, modified) = (file.sized_file.size, file.last_modified);
+        let after = self
+            .modified_after
+            .is_none_or(|time| modified.is_some_and(|modified| modified > time));
+        let before = self
+            .modified_before
+            .is_none_or(|time| modified.is_some_and(|modified| modified < time));
+        after
+            && before
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+    }
+}
+
+/// `ObjectStore` listing only the objects of a `GCSFileSystem` that pass a
+/// [`ListingFilter`], e.g. to build a `ListingTable` over the files added since the last
+/// run of an incremental pipeline. Objects are filtered as listing pages arrive rather
+/// than after the whole listing. Create one with [`GCSFileSystem::filtered`].
+#[derive(Debug, Clone)]
+pub struct FilteredGCSFileSystem {
+    store: Arc<GCSFileSystem>,
+    filter: ListingFilter,
+}
+
+impl FilteredGCSFileSystem {
+    pub(crate) fn new(store: Arc<GCSFileSystem>, filter: ListingFilter) -> Self {
+        Self { store, filter }
+    }
+
+    /// The filter objects must pass
+    pub fn filter(&self) -> &ListingFilter {
+        &self.filter
+    }
+
+    /// The unfiltered store
+    pub fn inner(&self) -> &Arc<GCSFileSystem> {
+        &self.store
+    }
+}
+
+#[async_trait]
+impl ObjectStore for FilteredGCSFileSystem {
+    async fn list_file(&self, prefix: &str) -> Result<FileMetaStream> {
+        let filter = self.filter.clone();
+        let files = self.store.list_file(prefix).await?;
+        Ok(Box::pin(files.filter(move |file| {
+            future::ready(file.as_ref().map_or(true, |file| filter.matches(file)))
+        })))
+    }
+
+    async fn list_dir(&self, prefix: &str, delimiter: Option<String>) -> Result<ListEntryStream> {
+        let filter = self.filter.clone();
+        let entries = self.store.list_dir(prefix, delimiter).await?;
+        Ok(Box::pin(entries.filter(move |entry| {
+            future::ready(match entry {
+                Ok(ListEntry::FileMeta(file)) => filter.matches(file),
+                _ => true,
+            })
+        })))
+    }
+
+    fn file_reader(&self, file: SizedFile) -> Result<Arc<dyn ObjectReader>> {
+        self.store.file_reader(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn file(size: u64, last_modified: Option<DateTime<Utc>>) -> FileMeta {
+        FileMeta {
+            sized_file: SizedFile {
+                path: "bucket/a.csv".to_string(),
+                size,
+            },
+            last_modified,
+        }
+    }
+
+    fn time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2022-06-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn times_are_exclusive() {
+        let second = Duration::seconds(1);
+        let after = ListingFilter::new().modified_after(time());
+        assert!(!after.matches(&file(1, Some(time()))));
+        assert!(after.matches(&file(1, Some(time() + second))));
+        assert!(!after.matches(&file(1, Some(time() - second))));
+
+        let before = ListingFilter::new().modified_before(time());
+        assert!(!before.matches(&file(1, Some(time()))));
+        assert!(before.matches(&file(1, Some(time() - second))));
+
+        let between = after.modified_before(time() + second * 2);
+        assert!(between.matches(&file(1, Some(time() + second))));
+        assert!(!between.matches(&file(1, Some(time() + second * 2))));
+    }
+
+    #[test]
+    fn sizes_are_inclusive() {
+        let filter = ListingFilter::new().min_size(10).max_size(20);
+        assert!(!filter.matches(&file(9, None)));
+        assert!(filter.matches(&file(10, None)));
+        assert!(filter.matches(&file(20, None)));
+        assert!(!filter.matches(&file(21, None)));
+        // an empty range keeps nothing
+        let empty = ListingFilter::new().min_size(20).max_size(10);
+        assert!(!empty.matches(&file(15, None)));
+    }
+
+    #[test]
+    fn files_without_a_modification_time() {
+        assert!(ListingFilter::new().matches(&file(0, None)));
+        assert!(ListingFilter::new().min_size(0).matches(&file(0, None)));
+        // fail any time condition, whichever way it points
+        assert!(!ListingFilter::new()
+            .modified_after(time())
+            .matches(&file(1, None)));
+        assert!(!ListingFilter::new()
+            .modified_before(time())
+            .matches(&file(1, None)));
+    }
+}
@@ -1,37 +1,2211 @@
 //! ObjectStore implementation for the Google Cloud Storage API
 
+use std::collections::{BTreeMap, HashMap};
 use std::io::{ErrorKind, Read};
-use std::sync::{mpsc, Arc};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 
 use async_trait::async_trait;
-use bytes::Buf;
-use futures::{stream, AsyncRead, StreamExt};
+use bytes::{Buf, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::{stream, AsyncRead, Stream, StreamExt};
+use reqwest::{header, Method};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
 
 use datafusion::datafusion_data_access::object_store::{
     FileMetaStream, ListEntryStream, ObjectReader, ObjectStore,
 };
-use datafusion::datafusion_data_access::{FileMeta, Result, SizedFile};
+use datafusion::datafusion_data_access::{FileMeta, ListEntry, Result, SizedFile};
 
-use cloud_storage::client::Client;
+use crate::error::GCSError;
+use crate::object_store::api::{self, ApiClient, UserProjects, DEFAULT_ENDPOINT};
+use crate::object_store::auth::{
+    self, Credentials, TokenProvider, DEFAULT_REFRESH_WINDOW, DEFAULT_SCOPE,
+};
+use crate::object_store::cache::{
+    BlockCache, BlockKey, BlockSnapshot, CacheMode, CacheSnapshot, ObjectSnapshot,
+};
+use crate::object_store::clock::{Clock, SystemClock};
+use crate::object_store::disk_cache::{DiskCache, DiskCacheCompression};
+use crate::object_store::encryption::{Encryption, EncryptionKey};
+use crate::object_store::filter::{FilteredGCSFileSystem, ListingFilter};
+#[cfg(feature = "grpc")]
+use crate::object_store::grpc::{self, GrpcClient};
+use crate::object_store::limiter::Limiter;
+use crate::object_store::metadata::{CachedObject, MetadataCache, ObjectMetadata};
+use crate::object_store::metrics::{Metrics, NoMetrics, RequestKind};
+use crate::object_store::path::{self, GCSPath, GLOB_OPTIONS};
+use crate::object_store::progress::ProgressObserver;
+use crate::object_store::refresh::{ListingRefresh, RefreshSchedule};
+use crate::object_store::retry::RetryPolicy;
+use crate::object_store::sandbox::Sandbox;
+use crate::object_store::signing;
+use crate::object_store::snapshot::SnapshotGCSFileSystem;
+use crate::object_store::throttle::{MaxUploadRate, UploadSchedule, UploadThrottle};
+use crate::object_store::writer::{
+    self, GCSObjectWriter, Precondition, ResumableUpload, DEFAULT_UPLOAD_CHUNK_SIZE,
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RewriteResponse {
+    done: bool,
+    rewrite_token: Option<String>,
+}
+
+/// Long-running operation, as returned by folder renames
+#[derive(Deserialize)]
+struct Operation {
+    name: String,
+    #[serde(default)]
+    done: bool,
+    error: Option<serde_json::Value>,
+}
+
+const OPERATION_POLL_DELAY: Duration = Duration::from_millis(200);
+
+/// GCS limit on the number of sources of a single compose request
+const MAX_COMPOSE_SOURCES: usize = 32;
+
+/// Number of deletes `delete_prefix` keeps in flight
+const DELETE_CONCURRENCY: usize = 32;
+
+/// Bytes Parquet reads from the end of a file to find its footer, which footer prefetch
+/// reads ahead in the same range so the footer read hits the block cache
+const FOOTER_READ_SIZE: u64 = 64 * 1024;
+
+/// Number of parts of a large read downloaded concurrently
+pub const DEFAULT_DOWNLOAD_PARALLELISM: usize = 4;
+
+/// Size of the parts large reads are split into, 8 MiB
+pub const DEFAULT_DOWNLOAD_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Size of the blocks sequential readers prefetch, 4 MiB
+pub const DEFAULT_PREFETCH_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Reads larger than this are streamed instead of downloaded in one go, 64 MiB
+pub const DEFAULT_STREAM_THRESHOLD: usize = 64 * 1024 * 1024;
+
+/// Chunks of a streamed response body received ahead of the reader
+pub(crate) const STREAM_BUFFER_CHUNKS: usize = 16;
+
+/// Reads this close to the previous one are served from the same request, 1 MiB
+pub const DEFAULT_COALESCE_GAP: usize = 1024 * 1024;
+
+/// Number of objects whose metadata a store keeps from listings and lookups
+pub const DEFAULT_METADATA_CACHE_CAPACITY: usize = 100_000;
+
+/// Time the metadata of an object is kept for, 10 minutes
+pub const DEFAULT_METADATA_CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// Time any read is given, regardless of its length
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Throughput below which reads time out, 1 MiB/s
+pub const DEFAULT_MIN_READ_THROUGHPUT: u64 = 1024 * 1024;
+
+/// Size at which `INSERT INTO` starts a new part file, 256 MiB
+pub const DEFAULT_TARGET_PART_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Host (and port) of a Cloud Storage emulator, honoured by the Google client libraries too
+const EMULATOR_HOST_ENV: &str = "STORAGE_EMULATOR_HOST";
+
+/// Project charged for API quota, honoured by the Google client libraries too
+const QUOTA_PROJECT_ENV: &str = "GOOGLE_CLOUD_QUOTA_PROJECT";
+
+/// Project the Google client libraries work in unless told otherwise
+const PROJECT_ENV: &str = "GOOGLE_CLOUD_PROJECT";
+
+/// Proxy variables reqwest routes requests through, checked by `from_env`
+const PROXY_ENVS: [&str; 4] = ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"];
+
+/// TLS backend selected by the crate features, for `describe`
+#[cfg(feature = "rustls-tls")]
+const TLS_BACKEND: &str = "rustls";
+#[cfg(not(feature = "rustls-tls"))]
+const TLS_BACKEND: &str = "native-tls";
+
+/// `ObjectStore` implementation for the Google Cloud Storage API
+#[derive(Debug)]
+pub struct GCSFileSystem {
+    api: ApiClient,
+    upload_chunk_size: usize,
+    parts: PartLimits,
+    hierarchical_namespace: bool,
+    skip_hidden_files: bool,
+    archived_objects: ArchivedObjects,
+    reads: ReadOptions,
+    /// Client for reads, whose connections live on `io`
+    read_http: reqwest::Client,
+    io: Arc<IoRuntime>,
+    block_cache: Option<Arc<BlockCache>>,
+    disk_cache: Option<Arc<DiskCache>>,
+    cache_mode: CacheMode,
+    /// Footers of listed Parquet files read ahead at once, `0` for none
+    footer_prefetch: usize,
+    project: Option<String>,
+    description: String,
+}
+
+/// Runtime that performs the reads of a `GCSFileSystem`, so the many small reads of a
+/// scan share pooled connections instead of each paying for a TLS handshake
+#[derive(Debug)]
+pub(crate) struct IoRuntime(Option<tokio::runtime::Runtime>);
+
+impl IoRuntime {
+    fn new(threads: usize) -> Self {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads.max(1))
+            .thread_name("gcs-io")
+            .enable_all()
+            .build()
+            .expect("failed to start the GCS I/O runtime");
+        Self(Some(runtime))
+    }
+
+    fn handle(&self) -> &tokio::runtime::Handle {
+        self.0
+            .as_ref()
+            .expect("runtime is only taken on drop")
+            .handle()
+    }
+}
+
+impl Drop for IoRuntime {
+    fn drop(&mut self) {
+        // the file system may be dropped inside another runtime, where blocking is not allowed
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Run `future` in the current tracing span, so work spawned for a read or listing is
+/// traced as part of it
+#[cfg(feature = "tracing")]
+fn in_current_span<F: std::future::Future>(future: F) -> tracing::instrument::Instrumented<F> {
+    tracing::Instrument::in_current_span(future)
+}
+
+#[cfg(not(feature = "tracing"))]
+fn in_current_span<F>(future: F) -> F {
+    future
+}
+
+/// How ranged reads are timed out and split up
+#[derive(Debug, Clone, Copy)]
+struct ReadOptions {
+    /// Time any read gets before it fails with `ErrorKind::TimedOut`, `None` for no limit
+    timeout: Option<Duration>,
+    min_throughput: u64,
+    /// Number of parts of a large read downloaded at once
+    parallelism: usize,
+    part_size: usize,
+    /// Number of blocks a sequential reader keeps in flight ahead of the consumer, `0`
+    /// to download reads in one go
+    prefetch_depth: usize,
+    prefetch_block_size: usize,
+    /// Reads larger than this are streamed, `0` to download every read in one go
+    stream_threshold: usize,
+    /// Bytes past the end of a small read that are fetched with it, so nearby reads that
+    /// follow are served without another request
+    coalesce_gap: usize,
+}
+
+/// When `INSERT INTO` finishes a part file and starts the next one
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PartLimits {
+    /// Encoded bytes a part file is finished at, or for Parquet the bytes encoded plus
+    /// those of the rows buffered for the next row group
+    pub(crate) target_size: u64,
+    /// Rows a part file has at most
+    pub(crate) max_rows: Option<u64>,
+}
+
+impl ReadOptions {
+    /// `timeout` plus the time `bytes` take at the minimum throughput, or `None` if reads
+    /// never time out
+    fn timeout_for(&self, bytes: u64) -> Option<Duration> {
+        let transfer = match self.min_throughput {
+            0 => Duration::ZERO,
+            throughput => Duration::from_secs_f64(bytes as f64 / throughput as f64),
+        };
+        self.timeout.map(|timeout| timeout + transfer)
+    }
+}
+
+impl GCSFileSystem {
+    /// Create new `ObjectStore`
+    pub async fn new() -> Self {
+        GCSFileSystemBuilder::new().build()
+    }
+
+    /// Create a builder to configure a new `GCSFileSystem`
+    pub fn builder() -> GCSFileSystemBuilder {
+        GCSFileSystemBuilder::new()
+    }
+
+    /// Create a `GCSFileSystem` configured from the standard Google environment
+    /// variables, see [`GCSFileSystemBuilder::from_env`]
+    pub fn from_env() -> Result<Self> {
+        Ok(GCSFileSystemBuilder::from_env()?.build())
+    }
+
+    /// The project this store works in, see
+    /// [`GCSFileSystemBuilder::with_project`]
+    pub fn project(&self) -> Option<&str> {
+        self.project.as_deref()
+    }
+
+    /// The effective configuration of this store, one setting per line, with secrets
+    /// redacted. Meant to be pasted into bug reports.
+    pub fn describe(&self) -> &str {
+        &self.description
+    }
+
+    /// The runtime reads run on
+    pub(crate) fn io_runtime(&self) -> &Arc<IoRuntime> {
+        &self.io
+    }
+
+    /// Client the store sends its requests with
+    pub(crate) fn api(&self) -> &ApiClient {
+        &self.api
+    }
+
+    /// When inserts into tables of this store roll over to a new part file
+    pub(crate) fn part_limits(&self) -> PartLimits {
+        self.parts
+    }
+
+    /// Size and modification time of the object at `uri`, from an earlier listing or
+    /// lookup by this store if there was one
+    pub async fn head(&self, uri: &str) -> Result<FileMeta> {
+        let path = object_path(uri)?;
+        let (size, updated) = match self.api.metadata.get(&path) {
+            Some(cached) => (cached.size, cached.updated),
+            None => {
+                let object = self.api.object(&path).await?;
+                (object.size, object.updated)
+            }
+        };
+        Ok(FileMeta {
+            sized_file: SizedFile {
+                path: path.to_string(),
+                size,
+            },
+            last_modified: Some(updated),
+        })
+    }
+
+    /// [`list_metadata`](Self::list_metadata) collected by the `bucket/key` path listings
+    /// report in `FileMeta`, to look objects of a table up by their file
+    pub async fn metadata_by_path(&self, uri: &str) -> Result<HashMap<String, ObjectMetadata>> {
+        let mut objects = self.list_metadata(uri).await?;
+        let mut by_path = HashMap::new();
+        while let Some(object) = objects.next().await {
+            let object = object?;
+            by_path.insert(object.path.clone(), object);
+        }
+        Ok(by_path)
+    }
+
+    /// Look up the metadata of the object at `uri` with a single request, without
+    /// listing its prefix. The size and generation are remembered for later reads.
+    pub async fn metadata(&self, uri: &str) -> Result<ObjectMetadata> {
+        let path = object_path(uri)?;
+        let object = self.api.object(&path).await?;
+        Ok(ObjectMetadata::new(&path.bucket, object))
+    }
+
+    /// A view of this store whose listings only include the objects passing `filter`,
+    /// to register tables over e.g. the files modified since a given time
+    pub fn filtered(self: &Arc<Self>, filter: ListingFilter) -> FilteredGCSFileSystem {
+        FilteredGCSFileSystem::new(self.clone(), filter)
+    }
+
+    /// A view of this store serving versioned buckets as they were at `time`, to query
+    /// point-in-time snapshots of tables
+    pub fn snapshot(self: &Arc<Self>, time: DateTime<Utc>) -> SnapshotGCSFileSystem {
+        SnapshotGCSFileSystem::new(self.clone(), time)
+    }
+
+    /// A view of this store that uses its caches according to `mode`, sharing them with
+    /// this store, e.g. to register a table for one scan that must see the latest data
+    pub fn with_cache_mode(&self, mode: CacheMode) -> Self {
+        let api = ApiClient {
+            metadata: self.api.metadata.with_mode(mode),
+            ..self.api.clone()
+        };
+        Self {
+            block_cache: self.block_cache.clone().filter(|_| mode.writes()),
+            disk_cache: self.disk_cache.clone().filter(|_| mode.writes()),
+            cache_mode: mode,
+            ..self.view(api, format!("cache mode: {:?}", mode))
+        }
+    }
+
+    /// A view of this store whose requests wait for a slot of
+    /// [`with_max_concurrent_requests`](GCSFileSystemBuilder::with_max_concurrent_requests)
+    /// in `queue`. Freed slots go to the queues with waiting requests in turn, so giving
+    /// each query its own queue keeps a large scan from starving a small query's reads.
+    /// Requests of this store itself wait in the unnamed queue `""`.
+    pub fn with_request_queue(&self, queue: impl Into<String>) -> Self {
+        let queue = queue.into();
+        let api = ApiClient {
+            queue: queue.as_str().into(),
+            ..self.api.clone()
+        };
+        self.view(api, format!("request queue: {}", queue))
+    }
+
+    /// This store sending its requests through `api`, described with the added `line`
+    fn view(&self, api: ApiClient, line: String) -> Self {
+        Self {
+            api,
+            upload_chunk_size: self.upload_chunk_size,
+            parts: self.parts,
+            hierarchical_namespace: self.hierarchical_namespace,
+            skip_hidden_files: self.skip_hidden_files,
+            archived_objects: self.archived_objects,
+            reads: self.reads,
+            read_http: self.read_http.clone(),
+            io: self.io.clone(),
+            block_cache: self.block_cache.clone(),
+            disk_cache: self.disk_cache.clone(),
+            cache_mode: self.cache_mode,
+            footer_prefetch: self.footer_prefetch,
+            project: self.project.clone(),
+            description: format!("{}\n{}", self.description, line),
+        }
+    }
+
+    /// A reader of `generation` of `file`, which may be a noncurrent generation listed by
+    /// [`list_versions`](Self::list_versions). Reads fail with `GCSError::GenerationChanged`
+    /// once the generation is deleted for good.
+    pub fn generation_reader(
+        &self,
+        file: SizedFile,
+        generation: u64,
+    ) -> Result<Arc<dyn ObjectReader>> {
+        self.reader(file, Some(generation))
+    }
+
+    /// A reader of `file`, of `generation` if given and otherwise of the generation it
+    /// was last seen with
+    fn reader(&self, file: SizedFile, generation: Option<u64>) -> Result<Arc<dyn ObjectReader>> {
+        let api = ApiClient {
+            http: self.read_http.clone(),
+            ..self.api.clone()
+        };
+        Ok(Arc::new(GCSFileReader::new(
+            file,
+            api,
+            self.reads,
+            self.io.clone(),
+            generation,
+            self.block_cache.clone(),
+            self.disk_cache.clone(),
+            self.cache_mode,
+        )?))
+    }
+
+    /// Pass `files` on, and read the footers of the Parquet files among them into the block
+    /// cache in the background, so the footer reads of planning and execution that follow
+    /// find them there
+    fn prefetch_footers(&self, files: FileMetaStream) -> FileMetaStream {
+        if self.footer_prefetch == 0 || self.block_cache.is_none() || !self.cache_mode.writes() {
+            return files;
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<SizedFile>();
+        let (api, options, io) = (self.api.clone(), self.reads, self.io.clone());
+        let (cache, disk_cache) = (self.block_cache.clone(), self.disk_cache.clone());
+        let (mode, concurrency) = (self.cache_mode, self.footer_prefetch);
+        let api = ApiClient {
+            http: self.read_http.clone(),
+            ..api
+        };
+        // stops once the listing is dropped and the footers sent so far are read
+        self.io.handle().spawn(in_current_span(async move {
+            futures::stream::poll_fn(|cx| rx.poll_recv(cx))
+                .for_each_concurrent(concurrency, |file| {
+                    let length = file.size.min(FOOTER_READ_SIZE);
+                    let start = file.size - length;
+                    let reader = GCSFileReader::new(
+                        file,
+                        api.clone(),
+                        options,
+                        io.clone(),
+                        None,
+                        cache.clone(),
+                        disk_cache.clone(),
+                        mode,
+                    );
+                    async move {
+                        // best effort, a failed read is just not cached
+                        if let Ok(reader) = reader {
+                            let _ = reader.chunk_reader(start, length as usize).await;
+                        }
+                    }
+                })
+                .await;
+        }));
+        Box::pin(files.inspect(move |file| {
+            if let Ok(file) = file {
+                if file.sized_file.size > 0 && file.sized_file.path.ends_with(".parquet") {
+                    let _ = tx.send(file.sized_file.clone());
+                }
+            }
+        }))
+    }
+
+    /// The `SizedFile` to read the object at `uri` through `file_reader`, without a
+    /// request if its size is already known
+    pub async fn sized_file(&self, uri: &str) -> Result<SizedFile> {
+        Ok(self.head(uri).await?.sized_file)
+    }
+
+    /// Drop cached listings that may include objects under `uri`, and cached misses
+    /// objects under it would answer, after changes made outside this store
+    pub fn invalidate_listings(&self, uri: &str) -> Result<()> {
+        let path = GCSPath::parse(uri)?.to_string();
+        if let Some(listings) = self.api.metadata.listings() {
+            listings.invalidate(&path);
+        }
+        if let Some(missing) = self.api.metadata.missing() {
+            missing.invalidate(&path);
+        }
+        Ok(())
+    }
+
+    /// Drop all cached listings and misses
+    pub fn clear_listing_cache(&self) {
+        if let Some(listings) = self.api.metadata.listings() {
+            listings.clear();
+        }
+        if let Some(missing) = self.api.metadata.missing() {
+            missing.clear();
+        }
+    }
+
+    /// Relist each of `uris`, as passed to `list_file`, on `schedule` and replace their
+    /// cached listings, so the first query after a quiet period is planned from the cache
+    /// instead of listing large partitioned tables. Refreshed listings still expire after
+    /// [`with_listing_cache_ttl`](GCSFileSystemBuilder::with_listing_cache_ttl), which
+    /// should outlast the gaps between refreshes, and failed listings are left to the next
+    /// refresh. Fails with `InvalidInput` if this store caches no listings.
+    pub fn refresh_listings(
+        &self,
+        uris: impl IntoIterator<Item = impl Into<String>>,
+        schedule: RefreshSchedule,
+    ) -> Result<ListingRefresh> {
+        if self.api.metadata.listings().is_none() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                GCSError::GCS("Listings are only refreshed with a listing cache".to_string()),
+            ));
+        }
+        let uris = uris.into_iter().map(Into::into).collect::<Vec<String>>();
+        for uri in &uris {
+            GCSPath::parse(uri)?;
+        }
+        let store = self.with_cache_mode(CacheMode::Refresh);
+        let task = self.io.handle().spawn(async move {
+            let clock = store.api.clock.clone();
+            loop {
+                let now = DateTime::<Utc>::from(clock.now());
+                let next = match schedule.next_after(now) {
+                    Some(next) => next,
+                    None => return,
+                };
+                clock.sleep((next - now).to_std().unwrap_or_default()).await;
+                for uri in &uris {
+                    // listed to completion, which caches the listing
+                    if let Ok(files) = store.list_objects(uri, false, NameFilter::default()).await {
+                        files.for_each(|_| async {}).await;
+                    }
+                }
+            }
+        });
+        Ok(ListingRefresh::new(task))
+    }
+
+    /// Copy the object metadata and, if enabled, the block cache of this store, e.g. to
+    /// warm up new replicas with [`restore_cache`](Self::restore_cache)
+    pub fn cache_snapshot(&self) -> CacheSnapshot {
+        let objects = self
+            .api
+            .metadata
+            .entries()
+            .into_iter()
+            .map(|(path, object)| ObjectSnapshot {
+                path,
+                size: object.size,
+                generation: object.generation,
+                updated: object.updated,
+                archived: object.archived,
+            })
+            .collect();
+        let blocks = match &self.block_cache {
+            Some(cache) => cache
+                .blocks()
+                .into_iter()
+                .map(|(key, data)| BlockSnapshot {
+                    path: key.path,
+                    generation: key.generation,
+                    start: key.start,
+                    length: key.length,
+                    data: data.to_vec(),
+                })
+                .collect(),
+            None => vec![],
+        };
+        CacheSnapshot { objects, blocks }
+    }
+
+    /// Load a snapshot taken with [`cache_snapshot`](Self::cache_snapshot). Blocks are
+    /// dropped if this store has no block cache, or evicted if they do not all fit.
+    pub fn restore_cache(&self, snapshot: CacheSnapshot) {
+        for object in snapshot.objects {
+            self.api.metadata.restore(
+                object.path,
+                CachedObject {
+                    size: object.size,
+                    generation: object.generation,
+                    updated: object.updated,
+                    archived: object.archived,
+                },
+            );
+        }
+        if let Some(cache) = &self.block_cache {
+            for block in snapshot.blocks {
+                let key = BlockKey {
+                    path: block.path,
+                    generation: block.generation,
+                    start: block.start,
+                    length: block.length,
+                };
+                cache.insert(key, Bytes::from(block.data));
+            }
+        }
+    }
+
+    /// Upload `data` as the object at `uri`, replacing any existing object
+    pub async fn put(&self, uri: &str, data: impl Into<Vec<u8>>) -> Result<()> {
+        writer::upload(&self.api, &object_path(uri)?, data.into(), None).await?;
+        Ok(())
+    }
+
+    /// Upload `data` as the object at `uri` only if `precondition` holds, and return the
+    /// generation of the written object for later compare-and-swap writes
+    pub async fn put_if(
+        &self,
+        uri: &str,
+        data: impl Into<Vec<u8>>,
+        precondition: Precondition,
+    ) -> Result<u64> {
+        let path = object_path(uri)?;
+        let object = writer::upload(&self.api, &path, data.into(), Some(precondition)).await?;
+        Ok(object.generation)
+    }
+
+    /// Current generation of the object at `uri`, to use in a `Precondition`
+    pub async fn generation(&self, uri: &str) -> Result<u64> {
+        Ok(self.api.object(&object_path(uri)?).await?.generation)
+    }
+
+    /// Create a writer that uploads everything written to it to `uri` once finished
+    pub fn writer(&self, uri: &str) -> Result<GCSObjectWriter> {
+        Ok(GCSObjectWriter::new(self.api.clone(), object_path(uri)?))
+    }
+
+    /// Delete the object at `uri`
+    pub async fn delete(&self, uri: &str) -> Result<()> {
+        self.api.delete(&object_path(uri)?).await
+    }
+
+    /// Delete every object listed under `uri`, which may be a prefix or a glob pattern,
+    /// and return how many objects were deleted
+    pub async fn delete_prefix(&self, uri: &str) -> Result<usize> {
+        let api = &self.api;
+        let mut deletes = self
+            .list_objects(uri, false, NameFilter::default())
+            .await?
+            .map(|meta| async move { api.delete(&GCSPath::parse(&meta?.sized_file.path)?).await })
+            .buffer_unordered(DELETE_CONCURRENCY);
+
+        let mut deleted = 0;
+        while let Some(result) = deletes.next().await {
+            result?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    /// Copy the object at `from` to `to` with the rewrite API, which works across buckets,
+    /// locations and storage classes. Large objects take several rewrite calls.
+    pub async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.copy_object(from, to, None).await
+    }
+
+    /// Like [`copy`](Self::copy), but the destination is only written if `precondition`
+    /// holds, e.g. `Precondition::DoesNotExist` to never overwrite an object
+    pub async fn copy_if(&self, from: &str, to: &str, precondition: Precondition) -> Result<()> {
+        self.copy_object(from, to, Some(precondition)).await
+    }
+
+    async fn copy_object(
+        &self,
+        from: &str,
+        to: &str,
+        precondition: Option<Precondition>,
+    ) -> Result<()> {
+        let (from, to) = (object_path(from)?, object_path(to)?);
+        self.api.check_access(&from)?;
+        self.api.check_access(&to)?;
+        let url = format!(
+            "{}/b/{}/o/{}/rewriteTo/b/{}/o/{}",
+            self.api.json_api(),
+            from.bucket,
+            from.encoded_key(),
+            to.bucket,
+            to.encoded_key()
+        );
+        self.api.metadata.remove(&to);
+
+        let mut rewrite_token: Option<String> = None;
+        loop {
+            let mut request = self
+                .api
+                .request(Method::POST, &url)
+                .await?
+                .header(header::CONTENT_LENGTH, 0);
+            if let Some(rewrite_token) = &rewrite_token {
+                request = request.query(&[("rewriteToken", rewrite_token)]);
+            }
+            let request = writer::with_precondition(request, precondition);
+            let request = self.api.with_source_customer_key(request, &from);
+            let request = self.api.with_customer_key(request, &to);
+            let request = self.api.with_kms_key(request, &to, "destinationKmsKeyName");
+
+            let rewrite = self
+                .api
+                .send(RequestKind::Copy, request)
+                .await?
+                .json::<RewriteResponse>()
+                .await
+                .map_err(api::http_error)?;
+            if rewrite.done {
+                // reads racing the rewrite may have cached the replaced generation
+                self.api.metadata.remove(&to);
+                return Ok(());
+            }
+            rewrite_token = Some(rewrite.rewrite_token.ok_or_else(|| {
+                std::io::Error::other(GCSError::GCS(
+                    "Incomplete rewrite without a rewrite token".into(),
+                ))
+            })?);
+        }
+    }
+
+    /// Concatenate `sources`, which must all be in the destination's bucket, into the
+    /// object at `destination`. More than 32 sources are composed through temporary
+    /// intermediate objects, which are deleted afterwards.
+    pub async fn compose(&self, sources: &[&str], destination: &str) -> Result<()> {
+        let destination = object_path(destination)?;
+        self.api.check_access(&destination)?;
+        let mut names = sources
+            .iter()
+            .map(|source| {
+                let source = object_path(source)?;
+                self.api.check_access(&source)?;
+                if source.bucket != destination.bucket {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        GCSError::GCS(format!(
+                            "Cannot compose {} into bucket {}",
+                            source, destination.bucket
+                        )),
+                    ));
+                }
+                // GCS decrypts the sources with the key the destination is written with
+                let key = self.api.encryption.customer_key(&destination);
+                if self.api.encryption.customer_key(&source) != key {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        GCSError::GCS(format!(
+                            "Cannot compose {}, which has another customer-supplied \
+                             encryption key than {}",
+                            source, destination
+                        )),
+                    ));
+                }
+                Ok(source.key)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.api.metadata.remove(&destination);
+
+        let mut temporaries = vec![];
+        let mut result = Ok(());
+        let mut level = 0;
+        while names.len() > MAX_COMPOSE_SOURCES && result.is_ok() {
+            let targets = (0..names.len().div_ceil(MAX_COMPOSE_SOURCES))
+                .map(|i| format!("{}.compose-{}-{}", destination.key, level, i))
+                .collect::<Vec<_>>();
+            temporaries.extend(targets.iter().cloned());
+            result = futures::future::try_join_all(
+                names
+                    .chunks(MAX_COMPOSE_SOURCES)
+                    .zip(&targets)
+                    .map(|(group, target)| self.compose_once(&destination.bucket, group, target)),
+            )
+            .await
+            .map(|_| ());
+            names = targets;
+            level += 1;
+        }
+        if result.is_ok() {
+            result = self
+                .compose_once(&destination.bucket, &names, &destination.key)
+                .await;
+        }
+
+        for temporary in temporaries {
+            let _ = self
+                .api
+                .delete(&GCSPath::new(&destination.bucket, temporary))
+                .await;
+        }
+        result
+    }
+
+    async fn compose_once(
+        &self,
+        bucket: &str,
+        sources: &[String],
+        destination: &str,
+    ) -> Result<()> {
+        let url = format!(
+            "{}/b/{}/o/{}/compose",
+            self.api.json_api(),
+            bucket,
+            GCSPath::new(bucket, destination).encoded_key()
+        );
+        let body = serde_json::json!({
+            "sourceObjects": sources.iter().map(|name| serde_json::json!({ "name": name })).collect::<Vec<_>>(),
+            "destination": {
+                "contentType": writer::content_type(destination),
+                "metadata": &*self.api.labels,
+            },
+        });
+        let request = self.api.request(Method::POST, &url).await?.json(&body);
+        let destination = GCSPath::new(bucket, destination);
+        let request = self.api.with_customer_key(request, &destination);
+        let request = self.api.with_kms_key(request, &destination, "kmsKeyName");
+        self.api.send(RequestKind::Compose, request).await?;
+        self.api.metadata.remove(&destination);
+        Ok(())
+    }
+
+    /// Create a V4 signed URL that lets its holder perform `method` (e.g. `GET` to
+    /// download, `PUT` to upload) on the object at `uri` without IAM access to the
+    /// bucket, for at most seven days
+    pub async fn signed_url(
+        &self,
+        uri: &str,
+        method: Method,
+        expires_in: Duration,
+    ) -> Result<String> {
+        signing::signed_url(&self.api, &object_path(uri)?, method, expires_in).await
+    }
+
+    /// Context labels attached to this store's requests and written objects
+    pub fn context_labels(&self) -> &BTreeMap<String, String> {
+        &self.api.labels
+    }
+
+    /// Move the object at `from` to `to` by copying it and deleting the source
+    pub async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.copy(from, to).await?;
+        self.delete(from).await
+    }
+
+    /// Names of the Hive-style partition columns of the table at `uri`, outermost first.
+    ///
+    /// Starting at `uri`, each level of `column=value` directories adds its column, as
+    /// named by the first such directory listed, e.g. `["dt", "region"]` for a table with
+    /// files under `dt=2023-01-01/region=us/`. Pass them as `table_partition_cols` of the
+    /// `ListingOptions` so DataFusion exposes them as columns and prunes partitions
+    /// that filters on them exclude.
+    pub async fn partition_columns(&self, uri: &str) -> Result<Vec<String>> {
+        list_partition_columns(self, uri).await
+    }
+
+    /// Rename the folder `from` to `to` within one bucket. With hierarchical namespace
+    /// enabled this is a single folder operation; on flat buckets every object under the
+    /// prefix is renamed individually.
+    pub async fn rename_folder(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (folder_path(from)?, folder_path(to)?);
+        self.api.check_access(&from)?;
+        self.api.check_access(&to)?;
+        if from.bucket != to.bucket {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                GCSError::GCS(format!("Cannot rename {} to another bucket", from)),
+            ));
+        }
+
+        if !self.hierarchical_namespace {
+            let (from_key, to, this) = (&from.key, &to, self);
+            let mut renames = self
+                .list_objects(
+                    &format!("gs://{}/{}", from.bucket, path::escape_glob(&from.key)),
+                    false,
+                    NameFilter::default(),
+                )
+                .await?
+                .map(|meta| async move {
+                    let source = GCSPath::parse(&meta?.sized_file.path)?;
+                    let target = GCSPath::new(
+                        &to.bucket,
+                        format!("{}{}", to.key, &source.key[from_key.len()..]),
+                    );
+                    this.rename(&source.to_string(), &target.to_string()).await
+                })
+                .buffer_unordered(DELETE_CONCURRENCY);
+            while let Some(result) = renames.next().await {
+                result?;
+            }
+            return Ok(());
+        }
+
+        let url = format!(
+            "{}/b/{}/folders/{}/renameTo/folders/{}",
+            self.api.json_api(),
+            from.bucket,
+            from.encoded_key(),
+            to.encoded_key()
+        );
+        self.api.metadata.remove_prefix(&from);
+        self.api.metadata.remove_prefix(&to);
+        let request = self
+            .api
+            .request(Method::POST, &url)
+            .await?
+            .header(header::CONTENT_LENGTH, 0);
+        let mut operation = self
+            .api
+            .send(RequestKind::Folder, request)
+            .await?
+            .json::<Operation>()
+            .await
+            .map_err(api::http_error)?;
+
+        let mut delay = OPERATION_POLL_DELAY;
+        while !operation.done {
+            self.api.clock.sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(5));
+            let id = operation.name.rsplit('/').next().unwrap_or_default();
+            let url = format!(
+                "{}/b/{}/operations/{}",
+                self.api.json_api(),
+                from.bucket,
+                id
+            );
+            let request = self.api.request(Method::GET, &url).await?;
+            operation = self
+                .api
+                .send(RequestKind::Folder, request)
+                .await?
+                .json::<Operation>()
+                .await
+                .map_err(api::http_error)?;
+        }
+        match operation.error {
+            None => Ok(()),
+            Some(error) => Err(std::io::Error::other(GCSError::GCS(format!(
+                "Renaming {} failed: {}",
+                from, error
+            )))),
+        }
+    }
+
+    /// List the objects matching `uri` that pass `names`. With `require_match` a listing
+    /// without any such objects ends in a `NotFound` error naming `uri`, so a missing
+    /// table is not mistaken for an empty one.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "gcs.list", level = "debug", skip(self))
+    )]
+    pub(crate) async fn list_objects(
+        &self,
+        uri: &str,
+        require_match: bool,
+        names: NameFilter,
+    ) -> Result<FileMetaStream> {
+        let path = GCSPath::parse(uri)?;
+        let (prefix, pattern) = path.split_glob()?;
+        let listed = path.to_string();
+        // cached listings hold every object matching the URI, whatever the name filter
+        if let Some(files) = self.api.metadata.cached_listing(&listed) {
+            let metadata = &self.api.metadata;
+            let files = files
+                .into_iter()
+                .filter_map(|file| {
+                    let archived = GCSPath::parse(&file.sized_file.path)
+                        .ok()
+                        .and_then(|path| metadata.get(&path))
+                        .is_some_and(|object| object.archived);
+                    names.check(&prefix, file, archived).transpose()
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if files.is_empty() && require_match {
+                return Err(no_match(&path));
+            }
+            return Ok(Box::pin(stream::iter(files.into_iter().map(Ok))));
+        }
+        if self.api.metadata.cached_miss(&listed).is_some() {
+            if require_match {
+                return Err(no_match(&path));
+            }
+            return Ok(Box::pin(stream::empty::<Result<FileMeta>>()));
+        }
+        let missing = no_match(&path);
+        let bucket = path.bucket;
+        let api = self.api.clone();
+
+        // pages are fetched by a background task that stays at most one page ahead of the
+        // consumer, so dropping the stream early (e.g. for a LIMIT) stops the listing
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<FileMeta>>>(1);
+        tokio::spawn(in_current_span(async move {
+            let query = [("prefix", prefix.as_str())];
+            let mut page_token: Option<String> = None;
+            // only complete listings are cached
+            let mut files = api.metadata.remembers_listings().then(Vec::new);
+            // objects matching the URI, whether or not the name filter keeps them
+            let mut matched = false;
+            let mut found = false;
+            loop {
+                let (page, next) = match api.list_page(&bucket, &query, page_token.as_deref()).await
+                {
+                    Ok(list) => {
+                        let metas = list
+                            .items
+                            .into_iter()
+                            .filter(|o| match &pattern {
+                                Some(pattern) => pattern.matches_with(&o.name, GLOB_OPTIONS),
+                                None => true,
+                            })
+                            .map(|o| {
+                                let archived = o.archived();
+                                let meta = FileMeta {
+                                    sized_file: SizedFile {
+                                        path: format!("{}/{}", &bucket, o.name),
+                                        size: o.size,
+                                    },
+                                    last_modified: Some(o.updated),
+                                };
+                                (meta, archived)
+                            })
+                            .collect::<Vec<_>>();
+                        matched |= !metas.is_empty();
+                        (Ok(metas), list.next_page_token)
+                    }
+                    Err(err) => (Err(err), None),
+                };
+                files = match (files.take(), &page) {
+                    (Some(mut files), Ok(metas)) => {
+                        files.extend(metas.iter().map(|(meta, _)| meta.clone()));
+                        Some(files)
+                    }
+                    _ => None,
+                };
+                let page = page.and_then(|metas| {
+                    metas
+                        .into_iter()
+                        .filter_map(|(meta, archived)| {
+                            names.check(&prefix, meta, archived).transpose()
+                        })
+                        .collect::<Result<Vec<_>>>()
+                });
+                let failed = page.is_err();
+                found |= page.as_ref().is_ok_and(|metas| !metas.is_empty());
+                if tx.send(page).await.is_err() || failed {
+                    return;
+                }
+                if next.is_none() {
+                    break;
+                }
+                page_token = next;
+            }
+            let prefix = format!("{}/{}", bucket, prefix);
+            if !matched {
+                let message = missing.to_string();
+                api.metadata
+                    .remember_miss(listed.clone(), prefix.clone(), message);
+            }
+            if let Some(files) = files {
+                api.metadata.remember_listing(listed, prefix, files);
+            }
+            if !found && require_match {
+                let _ = tx.send(Err(missing)).await;
+            }
+        }));
+
+        Ok(Box::pin(flatten_pages(rx)))
+    }
+
+    /// List the objects matching `uri` with their full metadata, including the etag,
+    /// storage class and custom metadata that `list_file` drops, in one listing instead
+    /// of a lookup per object. Not cached, and not subject to the hidden file and archived
+    /// object settings of table listings.
+    pub async fn list_metadata(&self, uri: &str) -> Result<ObjectMetadataStream> {
+        self.list_resources(uri, false).await
+    }
+
+    /// Like [`list_metadata`](Self::list_metadata), but also list the noncurrent
+    /// generations a versioned bucket keeps of overwritten and deleted objects. Versions
+    /// are listed by name, oldest generation first; noncurrent ones have `deleted` set.
+    /// Read one with [`generation_reader`](Self::generation_reader).
+    pub async fn list_versions(&self, uri: &str) -> Result<ObjectMetadataStream> {
+        self.list_resources(uri, true).await
+    }
+
+    async fn list_resources(&self, uri: &str, versions: bool) -> Result<ObjectMetadataStream> {
+        let path = GCSPath::parse(uri)?;
+        let (prefix, pattern) = path.split_glob()?;
+        let bucket = path.bucket;
+        let api = self.api.clone();
+
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<ObjectMetadata>>>(1);
+        tokio::spawn(in_current_span(async move {
+            let mut query = vec![("prefix", prefix.as_str())];
+            if versions {
+                query.push(("versions", "true"));
+            }
+            let mut page_token: Option<String> = None;
+            loop {
+                let (page, next) = match api.list_page(&bucket, &query, page_token.as_deref()).await
+                {
+                    Ok(list) => {
+                        let objects = list
+                            .items
+                            .into_iter()
+                            .filter(|o| match &pattern {
+                                Some(pattern) => pattern.matches_with(&o.name, GLOB_OPTIONS),
+                                None => true,
+                            })
+                            .map(|o| ObjectMetadata::new(&bucket, o))
+                            .collect();
+                        (Ok(objects), list.next_page_token)
+                    }
+                    Err(err) => (Err(err), None),
+                };
+                if tx.send(page).await.is_err() || next.is_none() {
+                    break;
+                }
+                page_token = next;
+            }
+        }));
+
+        Ok(Box::pin(flatten_pages(rx)))
+    }
+
+    /// The filter of objects listed as files of a table, ending in `suffix`
+    pub(crate) fn table_files(&self, suffix: &str) -> NameFilter {
+        NameFilter {
+            suffix: suffix.to_string(),
+            skip_hidden: self.skip_hidden_files,
+            archived: self.archived_objects,
+        }
+    }
+
+    /// Start a resumable upload to `uri` that sends data in chunks of the configured
+    /// upload chunk size instead of buffering the whole object
+    pub async fn resumable_upload(&self, uri: &str) -> Result<ResumableUpload> {
+        let path = object_path(uri)?;
+        ResumableUpload::start(self.api.clone(), &path, self.upload_chunk_size, None).await
+    }
+
+    /// Like [`resumable_upload`](Self::resumable_upload), but the object is only written
+    /// if `precondition` holds
+    pub async fn resumable_upload_if(
+        &self,
+        uri: &str,
+        precondition: Precondition,
+    ) -> Result<ResumableUpload> {
+        let path = object_path(uri)?;
+        ResumableUpload::start(
+            self.api.clone(),
+            &path,
+            self.upload_chunk_size,
+            Some(precondition),
+        )
+        .await
+    }
+}
+
+/// Stream of [`ObjectMetadata`] returned by [`GCSFileSystem::list_metadata`] and
+/// [`GCSFileSystem::list_versions`]
+pub type ObjectMetadataStream =
+    std::pin::Pin<Box<dyn Stream<Item = Result<ObjectMetadata>> + Send + Sync + 'static>>;
+
+/// Which of the objects matching a listed URI are listed
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NameFilter {
+    /// Only objects whose name ends in this, e.g. the `.parquet` of a table
+    suffix: String,
+    /// Skip objects with a file or directory name below the listed prefix that starts
+    /// with `_` or `.`, such as `_SUCCESS`, `.part-0.crc` or `_temporary/`
+    skip_hidden: bool,
+    archived: ArchivedObjects,
+}
+
+impl NameFilter {
+    /// `file` if it passes, where `archived` says whether it is in the `COLDLINE` or
+    /// `ARCHIVE` storage class, or an error for archived files if configured to fail
+    pub(crate) fn check(
+        &self,
+        prefix: &str,
+        file: FileMeta,
+        archived: bool,
+    ) -> Result<Option<FileMeta>> {
+        if !self.matches_file(prefix, &file.sized_file.path) {
+            return Ok(None);
+        }
+        match (archived, self.archived) {
+            (false, _) | (true, ArchivedObjects::Read) => Ok(Some(file)),
+            (true, ArchivedObjects::Skip) => Ok(None),
+            (true, ArchivedObjects::Fail) => Err(GCSError::ArchivedObject {
+                uri: format!("gs://{}", file.sized_file.path),
+            }
+            .into()),
+        }
+    }
+
+    /// Whether the listed `bucket/name` of a listing of `prefix` passes
+    fn matches_file(&self, prefix: &str, path: &str) -> bool {
+        let name = path.split_once('/').map_or(path, |(_, name)| name);
+        if !name.ends_with(self.suffix.as_str()) {
+            return false;
+        }
+        // the name of the directory the prefix ends in is part of what was listed
+        let start = prefix.rfind('/').map_or(0, |i| i + 1);
+        let below = name.get(start..).unwrap_or_default();
+        !(self.skip_hidden
+            && below
+                .split('/')
+                .any(|part| part.starts_with('_') || part.starts_with('.')))
+    }
+}
+
+/// The error of a listing of `path` that matched no objects
+pub(crate) fn no_match(path: &GCSPath) -> std::io::Error {
+    GCSError::NotFound {
+        bucket: Some(path.bucket.clone()),
+        key: Some(path.key.clone()),
+        message: "no objects match".to_string(),
+    }
+    .into()
+}
+
+/// Flatten the pages sent by a listing task into a stream of entries
+fn flatten_pages<T: Send + Sync + 'static>(
+    rx: tokio::sync::mpsc::Receiver<Result<Vec<T>>>,
+) -> impl Stream<Item = Result<T>> + Send + Sync {
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|page| (page, rx))
+    })
+    .flat_map(|page| {
+        stream::iter(match page {
+            Ok(entries) => entries.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(err) => vec![Err(err)],
+        })
+    })
+}
+
+/// Parse `uri` as a folder, adding the trailing `/` if it is missing
+fn folder_path(uri: &str) -> Result<GCSPath> {
+    let mut path = GCSPath::parse(uri)?;
+    if path.key.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            GCSError::GCS(format!("{} does not name a folder", uri)),
+        ));
+    }
+    if !path.key.ends_with('/') {
+        path.key.push('/');
+    }
+    Ok(path)
+}
+
+/// Partition columns of the table at `uri` in `store`, see
+/// [`GCSFileSystem::partition_columns`]
+pub(crate) async fn list_partition_columns(
+    store: &dyn ObjectStore,
+    uri: &str,
+) -> Result<Vec<String>> {
+    let table = GCSPath::parse(uri)?;
+    let mut directory = match table.key.trim_end_matches('/') {
+        "" => format!("{}/", table.bucket),
+        key => format!("{}/{}/", table.bucket, key),
+    };
+    let mut columns = vec![];
+    loop {
+        let mut entries = store.list_dir(&directory, Some("/".to_string())).await?;
+        let mut partition = None;
+        while let Some(entry) = entries.next().await {
+            if let ListEntry::Prefix(prefix) = entry? {
+                let name = prefix.trim_end_matches('/').rsplit('/').next();
+                if let Some((column, _)) = name.and_then(path::partition) {
+                    partition = Some((column.to_string(), prefix));
+                    break;
+                }
+            }
+        }
+        match partition {
+            Some((column, prefix)) => {
+                columns.push(column);
+                directory = prefix;
+            }
+            None => return Ok(columns),
+        }
+    }
+}
+
+/// Parse `uri` and make sure it names an object rather than a bucket
+fn object_path(uri: &str) -> Result<GCSPath> {
+    let path = GCSPath::parse(uri)?;
+    if path.key.is_empty() || path.key.ends_with('/') {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            GCSError::GCS(format!("{} does not name an object", uri)),
+        ));
+    }
+    Ok(path)
+}
+
+/// Replace the `user:password@` part of `url`, if any
+fn redact_userinfo(url: &str) -> String {
+    let authority = url.find("://").map_or(0, |i| i + 3);
+    let end = url[authority..]
+        .find('/')
+        .map_or(url.len(), |i| authority + i);
+    match url[authority..end].rfind('@') {
+        Some(at) => format!("{}***{}", &url[..authority], &url[authority + at..]),
+        None => url.to_string(),
+    }
+}
+
+fn describe_retry(policy: &RetryPolicy) -> String {
+    format!(
+        "{} attempts, {:?} doubling up to {:?}{}, statuses {:?}",
+        policy.max_attempts,
+        policy.base_delay,
+        policy.max_delay,
+        if policy.jitter { " with jitter" } else { "" },
+        policy.retryable_statuses
+    )
+}
+
+/// IP versions used to connect to GCS endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpVersion {
+    /// Whatever the resolver returns, trying addresses in order
+    #[default]
+    Any,
+    /// Only connect over IPv4, e.g. where IPv6 egress is blocked by network policies
+    V4,
+    /// Only connect over IPv6
+    V6,
+}
+
+/// Protocol object data is read with, see [`GCSFileSystemBuilder::with_transport`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// The JSON API, the default
+    #[default]
+    Json,
+    /// The Cloud Storage gRPC API at `endpoint`, e.g. `https://storage.googleapis.com`,
+    /// which streams large reads with less overhead from inside Google Cloud. The channel
+    /// connects to `endpoint` directly, DirectPath routing needs the xDS resolver of
+    /// Google's own gRPC clients, which tonic does not have. Needs the `grpc` feature.
+    #[cfg(feature = "grpc")]
+    Grpc {
+        /// Scheme and authority of the gRPC API, with TLS for `https`
+        endpoint: String,
+    },
+}
+
+/// What table listings do with objects in the `COLDLINE` and `ARCHIVE` storage classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchivedObjects {
+    /// List and read them like any other object, the default
+    #[default]
+    Read,
+    /// Leave them out of listings
+    Skip,
+    /// Fail the listing with `GCSError::ArchivedObject`
+    Fail,
+}
+
+/// Builder for [`GCSFileSystem`]
+#[derive(Debug, Clone)]
+pub struct GCSFileSystemBuilder {
+    credentials: Credentials,
+    token_refresh_window: Duration,
+    clock: Arc<dyn Clock>,
+    upload_chunk_size: usize,
+    parts: PartLimits,
+    context_labels: BTreeMap<String, String>,
+    hierarchical_namespace: bool,
+    skip_hidden_files: bool,
+    archived_objects: ArchivedObjects,
+    retry: RetryPolicy,
+    endpoint: String,
+    startup_retry: RetryPolicy,
+    reads: ReadOptions,
+    io_threads: usize,
+    /// Runtime shared with other stores, used instead of starting one with `io_threads`
+    io: Option<Arc<IoRuntime>>,
+    dns_overrides: Vec<(String, SocketAddr)>,
+    ip_version: IpVersion,
+    transport: Transport,
+    block_cache_capacity: usize,
+    footer_prefetch: usize,
+    disk_cache: Option<(PathBuf, u64)>,
+    disk_cache_compression: DiskCacheCompression,
+    metadata_cache_capacity: usize,
+    metadata_cache_ttl: Option<Duration>,
+    listing_cache_ttl: Option<Duration>,
+    negative_cache_ttl: Option<Duration>,
+    verify_checksums: bool,
+    user_projects: UserProjects,
+    quota_project: Option<String>,
+    project: Option<String>,
+    encryption: Encryption,
+    metrics: Arc<dyn Metrics>,
+    progress: Option<Arc<dyn ProgressObserver>>,
+    upload_schedule: Option<Arc<dyn UploadSchedule>>,
+    root_certificates: Vec<reqwest::Certificate>,
+    built_in_root_certificates: bool,
+    max_concurrent_requests: Option<usize>,
+    sandbox: Option<Sandbox>,
+}
+
+impl Default for GCSFileSystemBuilder {
+    fn default() -> Self {
+        let endpoint = match std::env::var(EMULATOR_HOST_ENV) {
+            Ok(host) if !host.is_empty() => emulator_endpoint(&host),
+            _ => DEFAULT_ENDPOINT.to_string(),
+        };
+        Self {
+            credentials: Credentials::default(),
+            token_refresh_window: DEFAULT_REFRESH_WINDOW,
+            clock: Arc::new(SystemClock),
+            upload_chunk_size: DEFAULT_UPLOAD_CHUNK_SIZE,
+            parts: PartLimits {
+                target_size: DEFAULT_TARGET_PART_SIZE,
+                max_rows: None,
+            },
+            context_labels: BTreeMap::new(),
+            hierarchical_namespace: false,
+            skip_hidden_files: false,
+            archived_objects: ArchivedObjects::default(),
+            retry: RetryPolicy::default(),
+            endpoint,
+            startup_retry: RetryPolicy::startup(),
+            reads: ReadOptions {
+                timeout: Some(DEFAULT_READ_TIMEOUT),
+                min_throughput: DEFAULT_MIN_READ_THROUGHPUT,
+                parallelism: DEFAULT_DOWNLOAD_PARALLELISM,
+                part_size: DEFAULT_DOWNLOAD_PART_SIZE,
+                prefetch_depth: 0,
+                prefetch_block_size: DEFAULT_PREFETCH_BLOCK_SIZE,
+                stream_threshold: DEFAULT_STREAM_THRESHOLD,
+                coalesce_gap: DEFAULT_COALESCE_GAP,
+            },
+            io_threads: num_cpus::get(),
+            io: None,
+            dns_overrides: vec![],
+            ip_version: IpVersion::default(),
+            transport: Transport::Json,
+            block_cache_capacity: 0,
+            footer_prefetch: 0,
+            disk_cache: None,
+            disk_cache_compression: DiskCacheCompression::None,
+            metadata_cache_capacity: DEFAULT_METADATA_CACHE_CAPACITY,
+            metadata_cache_ttl: Some(DEFAULT_METADATA_CACHE_TTL),
+            listing_cache_ttl: None,
+            negative_cache_ttl: None,
+            verify_checksums: false,
+            user_projects: UserProjects::default(),
+            quota_project: std::env::var(QUOTA_PROJECT_ENV)
+                .ok()
+                .filter(|project| !project.is_empty()),
+            project: None,
+            encryption: Encryption::default(),
+            metrics: Arc::new(NoMetrics),
+            progress: None,
+            upload_schedule: None,
+            root_certificates: vec![],
+            built_in_root_certificates: true,
+            max_concurrent_requests: None,
+            sandbox: None,
+        }
+    }
+}
+
+impl GCSFileSystemBuilder {
+    /// Create a builder with the default configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a builder configured from the environment variables the Google client
+    /// libraries read, failing with `GCSError::Environment` naming the first variable that
+    /// is malformed:
+    ///
+    /// - `GOOGLE_APPLICATION_CREDENTIALS` must name a readable service account key file,
+    ///   which is then used for credentials. Without it, and without this crate's own
+    ///   `SERVICE_ACCOUNT` and `SERVICE_ACCOUNT_JSON`, tokens come from the metadata
+    ///   server, or none are sent to an emulator.
+    /// - `GOOGLE_CLOUD_PROJECT` must be a project ID or number, and sets the project
+    /// - `STORAGE_EMULATOR_HOST` must be a `host:port` or URL, and sets the endpoint
+    /// - `HTTPS_PROXY` and `HTTP_PROXY`, in either case, must be proxy URLs. reqwest
+    ///   routes requests through them and honours `NO_PROXY`.
+    ///
+    /// `GOOGLE_CLOUD_QUOTA_PROJECT` is read as by [`new`](Self::new). Empty variables
+    /// count as unset.
+    pub fn from_env() -> Result<Self> {
+        let mut builder = Self::default();
+        let emulator = env_var(EMULATOR_HOST_ENV)?;
+        if let Some(host) = &emulator {
+            let endpoint = emulator_endpoint(host);
+            match reqwest::Url::parse(&endpoint) {
+                Ok(url) if url.has_host() => {}
+                Ok(_) => {
+                    return Err(env_error(
+                        EMULATOR_HOST_ENV,
+                        format!("{} has no host", redact_userinfo(host)),
+                    ))
+                }
+                Err(err) => {
+                    let host = redact_userinfo(host);
+                    return Err(env_error(EMULATOR_HOST_ENV, format!("{}: {}", host, err)));
+                }
+            }
+            builder.endpoint = endpoint;
+        }
+
+        let own_key = ["SERVICE_ACCOUNT_JSON", "SERVICE_ACCOUNT"]
+            .iter()
+            .any(|name| std::env::var(name).is_ok());
+        builder.credentials = match env_var(auth::CREDENTIALS_ENV)? {
+            // this crate's variables take precedence when reading the key
+            _ if own_key => Credentials::ServiceAccount,
+            Some(path) => {
+                auth::check_key_file(&path)
+                    .map_err(|message| env_error(auth::CREDENTIALS_ENV, message))?;
+                Credentials::ServiceAccount
+            }
+            None if emulator.is_some() => Credentials::Anonymous,
+            None => Credentials::MetadataServer(None),
+        };
+
+        if let Some(project) = env_var(PROJECT_ENV)? {
+            if !is_project(&project) {
+                return Err(env_error(
+                    PROJECT_ENV,
+                    format!("{} is not a project ID or number", project),
+                ));
+            }
+            builder.project = Some(project);
+        }
+
+        for name in PROXY_ENVS {
+            if let Some(proxy) = env_var(name)? {
+                reqwest::Proxy::all(proxy.as_str()).map_err(|err| {
+                    env_error(name, format!("{}: {}", redact_userinfo(&proxy), err))
+                })?;
+            }
+        }
+        Ok(builder)
+    }
+
+    /// Set the source of access tokens, e.g. `Credentials::MetadataServer(None)` on GKE
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Refresh access tokens this long before they expire
+    pub fn with_token_refresh_window(mut self, window: Duration) -> Self {
+        self.token_refresh_window = window;
+        self
+    }
+
+    /// Use `clock` for token expiry, backoff and TTLs instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Send resumable uploads in chunks of `bytes`, rounded down to a multiple of 256 KiB
+    pub fn with_upload_chunk_size(mut self, bytes: usize) -> Self {
+        self.upload_chunk_size = bytes;
+        self
+    }
+
+    /// Send at most `bytes_per_second` of uploads at a time, shared by every upload of the
+    /// store, e.g. so backfills leave egress for other traffic. Chunks of resumable uploads
+    /// and whole simple uploads wait until the bytes before them would have been sent at
+    /// this rate.
+    pub fn with_max_upload_rate(self, bytes_per_second: u64) -> Self {
+        self.with_upload_schedule(Arc::new(MaxUploadRate(bytes_per_second.max(1))))
+    }
+
+    /// Limit the bandwidth of uploads to what `schedule` allows at the time, e.g. only
+    /// upload outside of business hours with a
+    /// [`PeakHours`](crate::object_store::throttle::PeakHours) schedule. Replaces
+    /// [`with_max_upload_rate`](Self::with_max_upload_rate).
+    pub fn with_upload_schedule(mut self, schedule: Arc<dyn UploadSchedule>) -> Self {
+        self.upload_schedule = Some(schedule);
+        self
+    }
+
+    /// Finish each part file of `INSERT INTO` once about `bytes` are written to it and
+    /// continue in the next, `part-{job}-{partition}-{n}`, so downstream scans get files of
+    /// a useful size. Parts are checked after every batch. Parquet rows count with their
+    /// in-memory size until their row group is encoded, so compressible data gives
+    /// smaller files. Defaults to [`DEFAULT_TARGET_PART_SIZE`].
+    pub fn with_target_part_size(mut self, bytes: u64) -> Self {
+        self.parts.target_size = bytes.max(1);
+        self
+    }
+
+    /// Also finish each part file of `INSERT INTO` once it has `rows` rows, splitting
+    /// batches where needed
+    pub fn with_max_part_rows(mut self, rows: u64) -> Self {
+        self.parts.max_rows = Some(rows.max(1));
+        self
+    }
+
+    /// Attach a context label such as a team, job id or purpose to everything this store
+    /// does. Labels are stored as custom metadata on written objects and sent as
+    /// `x-goog-custom-audit-{key}` headers, which Cloud Audit Logs records (GCS accepts
+    /// at most four of them per request).
+    pub fn with_context_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.context_labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Treat buckets as having hierarchical namespace enabled, so folders are listed as
+    /// first-class entries and renamed with a single folder operation
+    pub fn with_hierarchical_namespace(mut self, enabled: bool) -> Self {
+        self.hierarchical_namespace = enabled;
+        self
+    }
+
+    /// Leave objects out of table listings (`list_file`) if their file name, or a directory
+    /// name below the listed prefix, starts with `_` or `.`, like Spark and Hive do. This
+    /// skips `_SUCCESS` markers, `.crc` checksums and `_temporary/` output of unfinished
+    /// jobs, which DataFusion would otherwise try to read as data.
+    pub fn with_skip_hidden_files(mut self, enabled: bool) -> Self {
+        self.skip_hidden_files = enabled;
+        self
+    }
+
+    /// Choose what table listings (`list_file`) do with objects in the `COLDLINE` and
+    /// `ARCHIVE` storage classes. Reads of those incur retrieval fees and high latency,
+    /// which queries otherwise only run into midway.
+    pub fn with_archived_objects(mut self, archived: ArchivedObjects) -> Self {
+        self.archived_objects = archived;
+        self
+    }
+
+    /// Retry transient listing, metadata and download failures, and resume resumable
+    /// uploads after them, according to `policy`; use `RetryPolicy::none()` to fail on the
+    /// first error
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Fail reads that take longer than `timeout` plus the time their length takes at the
+    /// minimum read throughput, so small footer reads fail fast and large row groups get
+    /// the time they need. `None` disables the timeout.
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.reads.timeout = timeout;
+        self
+    }
+
+    /// Allow reads `1s` for every `bytes_per_second` requested on top of the read timeout,
+    /// `0` makes the timeout independent of the read length
+    pub fn with_min_read_throughput(mut self, bytes_per_second: u64) -> Self {
+        self.reads.min_throughput = bytes_per_second;
+        self
+    }
+
+    /// Download reads larger than `part_size` as parts of that size, `parallelism` at a
+    /// time, instead of as a single stream. A `parallelism` of `1` disables splitting.
+    pub fn with_parallel_downloads(mut self, parallelism: usize, part_size: usize) -> Self {
+        self.reads.parallelism = parallelism.max(1);
+        self.reads.part_size = part_size.max(1);
+        self
+    }
+
+    /// Return readers for reads larger than `block_size` that download blocks of that size
+    /// in order, keeping `depth` blocks in flight while the current one is consumed. Suits
+    /// sequentially scanned formats such as CSV and NDJSON; `depth` `0` turns it off.
+    pub fn with_prefetch(mut self, block_size: usize, depth: usize) -> Self {
+        self.reads.prefetch_block_size = block_size.max(1);
+        self.reads.prefetch_depth = depth;
+        self
+    }
+
+    /// Return readers for reads larger than `bytes` that hand out the response body as it
+    /// arrives, with only a few chunks of it buffered, instead of downloading the whole
+    /// range before the first byte is read. Bounds the memory a large Parquet row group
+    /// takes per partition. Streamed reads are sent as a single request and bypass the
+    /// block and disk caches; `0` turns streaming off.
+    pub fn with_stream_threshold(mut self, bytes: usize) -> Self {
+        self.reads.stream_threshold = bytes;
+        self
+    }
+
+    /// Fetch up to `bytes` past the end of each small read and serve following reads
+    /// that fall into them from memory. Parquet reads column chunk headers and pages in
+    /// many small, nearly adjacent ranges; `0` sends every read as its own request.
+    pub fn with_coalesce_gap(mut self, bytes: usize) -> Self {
+        self.reads.coalesce_gap = bytes;
+        self
+    }
+
+    /// Keep up to `bytes` of downloaded ranges in memory, evicting the least recently used,
+    /// so repeated queries over the same files (footers and dictionaries especially) skip
+    /// the download. Ranges are only cached for objects whose generation this store
+    /// learned from a listing or lookup. `0`, the default, disables the cache.
+    pub fn with_block_cache(mut self, bytes: usize) -> Self {
+        self.block_cache_capacity = bytes;
+        self
+    }
+
+    /// When `list_file` lists Parquet files, e.g. to plan or `EXPLAIN` a query, read their
+    /// footers into the block cache in the background, up to `concurrency` at a time, so
+    /// the footer reads of planning and execution that follow are answered from memory.
+    /// Needs [`with_block_cache`](Self::with_block_cache) to keep the footers; `0`, the
+    /// default, disables it.
+    pub fn with_footer_prefetch(mut self, concurrency: usize) -> Self {
+        self.footer_prefetch = concurrency;
+        self
+    }
+
+    /// Persist downloaded ranges as files below `dir`, using at most `budget` bytes and
+    /// evicting the least recently used files beyond that. Like the block cache, ranges
+    /// are only reused for the object generation they were downloaded from. The files are
+    /// kept in a `datafusion-gcs-v1` subdirectory, and nothing else in `dir` is touched.
+    pub fn with_disk_cache(mut self, dir: impl Into<PathBuf>, budget: u64) -> Self {
+        self.disk_cache = Some((dir.into(), budget));
+        self
+    }
+
+    /// Compress the files of [`with_disk_cache`](Self::with_disk_cache), so more ranges of
+    /// text formats fit its budget. Hits are decompressed while the file is read. Files
+    /// written with another codec are not reused. Defaults to
+    /// [`DiskCacheCompression::None`].
+    pub fn with_disk_cache_compression(mut self, compression: DiskCacheCompression) -> Self {
+        self.disk_cache_compression = compression;
+        self
+    }
+
+    /// Keep the size and generation of up to `capacity` listed or looked up objects, each
+    /// for `ttl` (`None` until evicted), to pin reads to the listed generation and skip
+    /// lookups. The least recently used objects are evicted first; `0` keeps none.
+    /// Defaults to [`DEFAULT_METADATA_CACHE_CAPACITY`] and [`DEFAULT_METADATA_CACHE_TTL`].
+    pub fn with_metadata_cache(mut self, capacity: usize, ttl: Option<Duration>) -> Self {
+        self.metadata_cache_capacity = capacity;
+        self.metadata_cache_ttl = ttl;
+        self
+    }
+
+    /// Answer repeated `list_file` calls for the same URI from the previous result for
+    /// `ttl`, so planning hot tables does not list the bucket on every query. Writes and
+    /// deletes through this store drop the listings they affect;
+    /// [`GCSFileSystem::invalidate_listings`] drops listings after outside changes.
+    pub fn with_listing_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.listing_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Remember for `ttl` which object lookups and table listings GCS found nothing for,
+    /// and answer them again with `NotFound` (or an empty listing) without a request.
+    /// Planning queries over partitions that do not exist yet, e.g. today's, otherwise
+    /// probes them on every query. Keep `ttl` short: objects written by anyone but this
+    /// store only show up once it expires, or after
+    /// [`GCSFileSystem::invalidate_listings`].
+    pub fn with_negative_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Check downloads against the CRC32C and MD5 hashes GCS stores, failing reads with
+    /// `GCSError::ChecksumMismatch` (`ErrorKind::InvalidData`) on a mismatch. GCS only
+    /// publishes hashes of whole objects, so only reads that cover a whole object in one
+    /// request are checked; ranged and parallel part reads are not.
+    pub fn with_checksum_verification(mut self, enabled: bool) -> Self {
+        self.verify_checksums = enabled;
+        self
+    }
+
+    /// Bill requests to `project`, which requester-pays buckets require; GCS rejects
+    /// requests to those without one. Requests to other buckets are billed as usual but
+    /// still need `serviceusage.services.use` on the project, so prefer
+    /// [`with_bucket_user_project`](Self::with_bucket_user_project) when only some buckets
+    /// are requester-pays.
+    pub fn with_user_project(mut self, project: impl Into<String>) -> Self {
+        self.user_projects.default = Some(project.into());
+        self
+    }
+
+    /// Bill requests to `bucket` to `project` instead of the project set with
+    /// [`with_user_project`](Self::with_user_project)
+    pub fn with_bucket_user_project(
+        mut self,
+        bucket: impl Into<String>,
+        project: impl Into<String>,
+    ) -> Self {
+        self.user_projects
+            .buckets
+            .insert(bucket.into(), project.into());
+        self
+    }
+
+    /// Set the project this store works in, as reported by [`GCSFileSystem::project`].
+    /// Requests are not billed to it, see [`with_user_project`](Self::with_user_project)
+    /// and [`with_quota_project`](Self::with_quota_project) for that.
+    pub fn with_project(mut self, project: impl Into<String>) -> Self {
+        self.project = Some(project.into());
+        self
+    }
+
+    /// Charge API quota to `project` by sending it as `x-goog-user-project`, instead of
+    /// `$GOOGLE_CLOUD_QUOTA_PROJECT` or the project of the credentials. User credentials
+    /// belong to a Google-owned project without the Cloud Storage API, which GCS rejects
+    /// with "API not enabled on consumer project" unless a quota project is set. Unlike
+    /// [`with_user_project`](Self::with_user_project) this does not bill object access.
+    pub fn with_quota_project(mut self, project: impl Into<String>) -> Self {
+        self.quota_project = Some(project.into());
+        self
+    }
+
+    /// Read and write every object with the customer-supplied encryption `key`, unless a
+    /// prefix has its own key. Copies and composes present it for their sources and
+    /// destinations, and reads of objects with a key bypass the block and disk caches.
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption.add_customer_key(None, key);
+        self
+    }
+
+    /// Read and write objects under `prefix` with the customer-supplied encryption `key`.
+    /// The longest matching prefix wins; objects under no configured prefix are read and
+    /// written without a key.
+    pub fn with_prefix_encryption_key(mut self, prefix: GCSPath, key: EncryptionKey) -> Self {
+        self.encryption.add_customer_key(Some(&prefix), key);
+        self
+    }
+
+    /// Encrypt objects this store writes with the Cloud KMS key `name`
+    /// (`projects/../locations/../keyRings/../cryptoKeys/..`) instead of the bucket's
+    /// default key, unless a prefix has its own key. Objects are still read without any
+    /// configuration, as GCS decrypts them with the key recorded on the object.
+    pub fn with_kms_key(mut self, name: impl Into<String>) -> Self {
+        self.encryption.add_kms_key(None, name.into());
+        self
+    }
+
+    /// Encrypt objects this store writes under `prefix` with the Cloud KMS key `name`,
+    /// e.g. one key per dataset. The longest matching prefix wins. A prefix must not also
+    /// have a customer-supplied key, which GCS rejects.
+    pub fn with_prefix_kms_key(mut self, prefix: GCSPath, name: impl Into<String>) -> Self {
+        self.encryption.add_kms_key(Some(&prefix), name.into());
+        self
+    }
+
+    /// Report every request and transferred body to `metrics`, e.g. a
+    /// [`MetricsRecorder`](crate::object_store::metrics::MetricsRecorder) kept by the
+    /// caller to attribute egress and find slow scans
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Also trust the PEM-encoded CA certificate `pem`, e.g. the root of a
+    /// TLS-intercepting proxy or the self-signed certificate of an emulator
+    pub fn with_root_certificate(mut self, pem: impl AsRef<[u8]>) -> Result<Self> {
+        let certificate = reqwest::Certificate::from_pem(pem.as_ref()).map_err(|err| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                GCSError::GCS(format!("Invalid root certificate: {}", err)),
+            )
+        })?;
+        self.root_certificates.push(certificate);
+        Ok(self)
+    }
+
+    /// Trust the root certificates built into the TLS backend (the system store with
+    /// `native-tls`, the Mozilla roots with `rustls-tls`), on by default. Turn it off to
+    /// only trust certificates added with
+    /// [`with_root_certificate`](Self::with_root_certificate).
+    pub fn with_built_in_root_certificates(mut self, enabled: bool) -> Self {
+        self.built_in_root_certificates = enabled;
+        self
+    }
+
+    /// Keep at most `requests` requests in flight at a time, across this store and every
+    /// reader and writer it creates; further requests wait for a slot. A request holds its
+    /// slot until the response headers arrive, so bodies streamed to slow readers do not
+    /// hold up other requests. Wide scans otherwise send a request per
+    /// partition and parallel part, which can trip rate limits or run out of sockets.
+    /// Queries sharing the store get slots in turn if each uses its own
+    /// [`GCSFileSystem::with_request_queue`].
+    pub fn with_max_concurrent_requests(mut self, requests: usize) -> Self {
+        self.max_concurrent_requests = Some(requests.max(1));
+        self
+    }
+
+    /// Restrict every request to the buckets and prefixes of `sandbox`, failing the
+    /// others locally with `ErrorKind::PermissionDenied`; see [`Sandbox`]
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Report the progress of every download body to `observer`, per object and chunk
+    pub fn with_progress_observer(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.progress = Some(observer);
+        self
+    }
 
-use crate::error::GCSError;
+    /// Run reads on `threads` dedicated I/O threads, one per CPU by default
+    pub fn with_io_threads(mut self, threads: usize) -> Self {
+        self.io_threads = threads;
+        self
+    }
 
-async fn new_client() -> Client {
-    Client::new()
-}
+    /// Run reads on the I/O runtime of another store instead of starting one
+    pub(crate) fn with_io_runtime(mut self, io: Arc<IoRuntime>) -> Self {
+        self.io = Some(io);
+        self
+    }
 
-/// `ObjectStore` implementation for the Google Cloud Storage API
-#[derive(Debug)]
-pub struct GCSFileSystem {
-    client: Client,
-}
+    /// Connect to `addr` whenever a request goes to `host`, bypassing DNS (e.g. to pin
+    /// `storage.googleapis.com` to a Private Google Access VIP). The port of the URL is
+    /// used, not the one of `addr`.
+    pub fn with_dns_override(mut self, host: impl Into<String>, addr: SocketAddr) -> Self {
+        self.dns_overrides.push((host.into(), addr));
+        self
+    }
 
-impl GCSFileSystem {
-    /// Create new `ObjectStore`
-    pub async fn new() -> Self {
-        Self {
-            client: new_client().await,
+    /// Only connect over `version`, skipping the happy-eyeballs fallback between address
+    /// families that adds latency or fails on dual-stack clusters
+    pub fn with_ip_version(mut self, version: IpVersion) -> Self {
+        self.ip_version = version;
+        self
+    }
+
+    /// Send requests to `endpoint` (e.g. `http://localhost:4443` for fake-gcs-server)
+    /// instead of `https://storage.googleapis.com` or `$STORAGE_EMULATOR_HOST`
+    pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Read object data over `transport` instead of the JSON API. Lookups, listings and
+    /// writes always use the JSON API at [`with_endpoint`](Self::with_endpoint). Fails
+    /// with `InvalidInput` if the endpoint of `Transport::Grpc` is not a valid URI.
+    pub fn with_transport(mut self, transport: Transport) -> Result<Self> {
+        #[cfg(feature = "grpc")]
+        if let Transport::Grpc { endpoint } = &transport {
+            grpc::endpoint(endpoint)?;
+        }
+        self.transport = transport;
+        Ok(self)
+    }
+
+    /// Retry according to `policy` while [`connect`](Self::connect) waits for the endpoint
+    /// to come up, instead of [`RetryPolicy::startup`]
+    pub fn with_startup_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.startup_retry = policy;
+        self
+    }
+
+    /// Create the configured `GCSFileSystem` once its endpoint answers.
+    ///
+    /// Emulators and sidecar proxies that are still starting refuse connections or
+    /// answer 503; those are retried with the short, aggressive startup policy so
+    /// integration environments need no sleep loop before registering tables.
+    pub async fn connect(self) -> Result<GCSFileSystem> {
+        let startup_retry = self.startup_retry.clone();
+        let store = self.build();
+        store.api.wait_until_ready(&startup_retry).await?;
+        Ok(store)
+    }
+
+    fn describe(&self) -> String {
+        let mut lines = vec![
+            format!("endpoint: {}", redact_userinfo(&self.endpoint)),
+            format!("credentials: {}", self.credentials),
+            format!("scope: {}", DEFAULT_SCOPE),
+            format!("token refresh window: {:?}", self.token_refresh_window),
+            format!("retry: {}", describe_retry(&self.retry)),
+            format!("startup retry: {}", describe_retry(&self.startup_retry)),
+        ];
+        lines.push(match self.reads.timeout {
+            Some(timeout) => format!(
+                "read timeout: {:?} + 1s per {} bytes",
+                timeout, self.reads.min_throughput
+            ),
+            None => "read timeout: none".to_string(),
+        });
+        lines.push(format!(
+            "parallel downloads: {} parts of {} bytes",
+            self.reads.parallelism, self.reads.part_size
+        ));
+        lines.push(match self.reads.prefetch_depth {
+            0 => "prefetch: off".to_string(),
+            depth => format!(
+                "prefetch: {} blocks of {} bytes",
+                depth, self.reads.prefetch_block_size
+            ),
+        });
+        lines.push(match self.reads.stream_threshold {
+            0 => "streaming: off".to_string(),
+            bytes => format!("streaming: reads over {} bytes", bytes),
+        });
+        lines.push(format!("coalesce gap: {} bytes", self.reads.coalesce_gap));
+        lines.push(match self.block_cache_capacity {
+            0 => "block cache: off".to_string(),
+            bytes => format!("block cache: {} bytes", bytes),
+        });
+        lines.push(match self.footer_prefetch {
+            0 => "footer prefetch: off".to_string(),
+            concurrency => format!("footer prefetch: {} at a time", concurrency),
+        });
+        lines.push(
+            match (self.metadata_cache_capacity, self.metadata_cache_ttl) {
+                (0, _) => "metadata cache: off".to_string(),
+                (capacity, Some(ttl)) => {
+                    format!("metadata cache: {} objects for {:?}", capacity, ttl)
+                }
+                (capacity, None) => format!("metadata cache: {} objects", capacity),
+            },
+        );
+        lines.push(match self.listing_cache_ttl {
+            Some(ttl) => format!("listing cache: {:?}", ttl),
+            None => "listing cache: off".to_string(),
+        });
+        lines.push(match self.negative_cache_ttl {
+            Some(ttl) => format!("negative cache: {:?}", ttl),
+            None => "negative cache: off".to_string(),
+        });
+        lines.push(match &self.disk_cache {
+            Some((dir, budget)) => format!(
+                "disk cache: {} bytes in {}, compression: {:?}",
+                budget,
+                dir.display(),
+                self.disk_cache_compression
+            ),
+            None => "disk cache: off".to_string(),
+        });
+        lines.push(format!("checksum verification: {}", self.verify_checksums));
+        lines.push(match self.max_concurrent_requests {
+            Some(requests) => format!("max concurrent requests: {}", requests),
+            None => "max concurrent requests: unlimited".to_string(),
+        });
+        lines.push(match &self.sandbox {
+            Some(sandbox) => format!("sandbox: {}", sandbox),
+            None => "sandbox: off".to_string(),
+        });
+        lines.push(match self.io {
+            Some(_) => "io threads: shared".to_string(),
+            None => format!("io threads: {}", self.io_threads),
+        });
+        lines.push(format!("upload chunk size: {}", self.upload_chunk_size));
+        lines.push(match &self.upload_schedule {
+            Some(schedule) => format!("upload schedule: {:?}", schedule),
+            None => "upload schedule: unlimited".to_string(),
+        });
+        lines.push(match self.parts.max_rows {
+            Some(rows) => format!(
+                "part files: {} bytes, {} rows",
+                self.parts.target_size, rows
+            ),
+            None => format!("part files: {} bytes", self.parts.target_size),
+        });
+        lines.push(format!(
+            "hierarchical namespace: {}",
+            self.hierarchical_namespace
+        ));
+        lines.push(format!("skip hidden files: {}", self.skip_hidden_files));
+        lines.push(format!("archived objects: {:?}", self.archived_objects));
+        lines.push(format!("ip version: {:?}", self.ip_version));
+        lines.push(match &self.transport {
+            Transport::Json => "transport: json".to_string(),
+            #[cfg(feature = "grpc")]
+            Transport::Grpc { endpoint } => {
+                format!("transport: grpc at {}", redact_userinfo(endpoint))
+            }
+        });
+        lines.push(format!(
+            "tls: {}, {} custom root certificates{}",
+            TLS_BACKEND,
+            self.root_certificates.len(),
+            if self.built_in_root_certificates {
+                ""
+            } else {
+                ", built-in roots off"
+            }
+        ));
+        for (host, addr) in &self.dns_overrides {
+            lines.push(format!("dns override: {} -> {}", host, addr));
+        }
+        for (key, value) in &self.context_labels {
+            lines.push(format!("context label: {}={}", key, value));
+        }
+        if let Some(project) = &self.project {
+            lines.push(format!("project: {}", project));
+        }
+        if let Some(project) = &self.user_projects.default {
+            lines.push(format!("user project: {}", project));
+        }
+        for (bucket, project) in &self.user_projects.buckets {
+            lines.push(format!("user project for {}: {}", bucket, project));
+        }
+        if let Some(project) = &self.quota_project {
+            lines.push(format!("quota project: {}", project));
+        }
+        for (prefix, key) in self.encryption.customer_keys() {
+            lines.push(match prefix.as_str() {
+                "" => format!("encryption key: sha256 {}", key.sha256()),
+                prefix => format!(
+                    "encryption key for gs://{}: sha256 {}",
+                    prefix,
+                    key.sha256()
+                ),
+            });
+        }
+        for (prefix, name) in self.encryption.kms_keys() {
+            lines.push(match prefix.as_str() {
+                "" => format!("kms key: {}", name),
+                prefix => format!("kms key for gs://{}: {}", prefix, name),
+            });
+        }
+        lines.join("\n")
+    }
+
+    fn http_client(&self) -> reqwest::Client {
+        let mut builder =
+            reqwest::Client::builder().tls_built_in_root_certs(self.built_in_root_certificates);
+        // never decompress responses, so reads of gzip objects get the stored bytes, whose
+        // size listings report
+        builder = builder.no_gzip();
+        #[cfg(feature = "rustls-tls")]
+        {
+            builder = builder.use_rustls_tls();
+        }
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        for (host, addr) in &self.dns_overrides {
+            builder = builder.resolve(host, *addr);
+        }
+        // binding to the unspecified address of a family only allows connections over it
+        builder = match self.ip_version {
+            IpVersion::Any => builder,
+            IpVersion::V4 => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            IpVersion::V6 => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        builder.build().expect("failed to create the HTTP client")
+    }
+
+    /// Create the configured `GCSFileSystem`
+    pub fn build(self) -> GCSFileSystem {
+        let tokens = TokenProvider::new(
+            &self.credentials,
+            self.token_refresh_window,
+            self.clock.clone(),
+        );
+        let (http, read_http) = (self.http_client(), self.http_client());
+        let description = self.describe();
+        let mut metadata = MetadataCache::new(
+            self.metadata_cache_capacity,
+            self.metadata_cache_ttl,
+            self.clock.clone(),
+        );
+        if let Some(ttl) = self.listing_cache_ttl {
+            metadata = metadata.with_listings(ttl);
+        }
+        if let Some(ttl) = self.negative_cache_ttl {
+            metadata = metadata.with_missing(ttl);
+        }
+        let io = self
+            .io
+            .unwrap_or_else(|| Arc::new(IoRuntime::new(self.io_threads)));
+        GCSFileSystem {
+            api: ApiClient {
+                http,
+                endpoint: self.endpoint.into(),
+                tokens,
+                clock: self.clock,
+                labels: Arc::new(self.context_labels),
+                retry: self.retry,
+                metadata,
+                verify_checksums: self.verify_checksums,
+                user_projects: Arc::new(self.user_projects),
+                quota_project: self.quota_project.map(Into::into),
+                encryption: Arc::new(self.encryption),
+                metrics: self.metrics,
+                progress: self.progress,
+                upload_throttle: self
+                    .upload_schedule
+                    .map(|schedule| Arc::new(UploadThrottle::new(schedule))),
+                limiter: self
+                    .max_concurrent_requests
+                    .map(|requests| Arc::new(Limiter::new(requests))),
+                queue: "".into(),
+                sandbox: self.sandbox.map(Arc::new),
+                #[cfg(feature = "grpc")]
+                grpc: match self.transport {
+                    Transport::Grpc { endpoint } => {
+                        let endpoint =
+                            grpc::endpoint(&endpoint).expect("checked by with_transport");
+                        Some(Arc::new(GrpcClient::new(endpoint, io.handle())))
+                    }
+                    Transport::Json => None,
+                },
+            },
+            upload_chunk_size: self.upload_chunk_size,
+            parts: self.parts,
+            hierarchical_namespace: self.hierarchical_namespace,
+            skip_hidden_files: self.skip_hidden_files,
+            archived_objects: self.archived_objects,
+            reads: self.reads,
+            read_http,
+            io,
+            block_cache: (self.block_cache_capacity > 0)
+                .then(|| Arc::new(BlockCache::new(self.block_cache_capacity))),
+            disk_cache: self.disk_cache.map(|(dir, budget)| {
+                Arc::new(DiskCache::new(dir, budget, self.disk_cache_compression))
+            }),
+            cache_mode: CacheMode::ReadThrough,
+            footer_prefetch: self.footer_prefetch,
+            project: self.project,
+            description,
         }
     }
 }
@@ -39,49 +2213,70 @@ impl GCSFileSystem {
 #[async_trait]
 impl ObjectStore for GCSFileSystem {
     async fn list_file(&self, uri: &str) -> Result<FileMetaStream> {
-        let (_, prefix) = uri.split_once("gcs://").ok_or_else(|| {
-            std::io::Error::new(ErrorKind::Other, GCSError::GCS("No s3 scheme found".into()))
-        })?;
-        let (bucket, prefix) = match prefix.split_once('/') {
-            Some((bucket, prefix)) => (bucket.to_owned(), prefix),
-            None => (prefix.to_owned(), ""),
-        };
-
-        let mut list_request = cloud_storage::object::ListRequest::default();
-        list_request.prefix = Some(prefix.to_string());
-        let objects = self
-            .client
-            .object()
-            .list(&bucket, list_request)
-            .await
-            .map_err(|err| {
-                std::io::Error::new(ErrorKind::Other, GCSError::GCS(format!("{:?}", err)))
-            })?
-            .flat_map(|r| {
-                let object = r.unwrap_or_default();
-                stream::iter(object.items.into_iter().map(|o| {
-                    Ok::<FileMeta, std::io::Error>(FileMeta {
-                        sized_file: SizedFile {
-                            path: format!("{}/{}", &bucket, o.name),
-                            size: o.size,
-                        },
-                        last_modified: Some(o.updated),
-                    })
-                }))
-            })
-            .collect::<Vec<Result<FileMeta>>>()
-            .await;
+        let files = self.list_objects(uri, true, self.table_files("")).await?;
+        Ok(self.prefetch_footers(files))
+    }
 
-        //Ok(Box::<impl Stream<Item = Result<FileMeta, std::io::Error>>>::pin(objects))
-        Ok(Box::pin(stream::iter(objects)))
+    async fn list_file_with_suffix(&self, uri: &str, suffix: &str) -> Result<FileMetaStream> {
+        let files = self
+            .list_objects(uri, true, self.table_files(suffix))
+            .await?;
+        Ok(self.prefetch_footers(files))
     }
 
-    async fn list_dir(&self, _prefix: &str, _delimiter: Option<String>) -> Result<ListEntryStream> {
-        todo!()
+    async fn list_dir(&self, prefix: &str, delimiter: Option<String>) -> Result<ListEntryStream> {
+        let path = GCSPath::parse(prefix)?;
+        let delimiter = delimiter.unwrap_or_else(|| "/".to_string());
+        let folders = self.hierarchical_namespace;
+        let api = self.api.clone();
+
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<ListEntry>>>(1);
+        tokio::spawn(in_current_span(async move {
+            let mut query = vec![
+                ("prefix", path.key.as_str()),
+                ("delimiter", delimiter.as_str()),
+            ];
+            if folders {
+                // also report empty folders, which have no objects to imply them
+                query.push(("includeFoldersAsPrefixes", "true"));
+            }
+
+            let mut page_token: Option<String> = None;
+            loop {
+                let (page, next) = match api
+                    .list_page(&path.bucket, &query, page_token.as_deref())
+                    .await
+                {
+                    Ok(list) => {
+                        let prefixes = list
+                            .prefixes
+                            .into_iter()
+                            .map(|p| ListEntry::Prefix(format!("{}/{}", path.bucket, p)));
+                        let files = list.items.into_iter().map(|o| {
+                            ListEntry::FileMeta(FileMeta {
+                                sized_file: SizedFile {
+                                    path: format!("{}/{}", path.bucket, o.name),
+                                    size: o.size,
+                                },
+                                last_modified: Some(o.updated),
+                            })
+                        });
+                        (Ok(prefixes.chain(files).collect()), list.next_page_token)
+                    }
+                    Err(err) => (Err(err), None),
+                };
+                if tx.send(page).await.is_err() || next.is_none() {
+                    break;
+                }
+                page_token = next;
+            }
+        }));
+
+        Ok(Box::pin(flatten_pages(rx)))
     }
 
     fn file_reader(&self, file: SizedFile) -> Result<Arc<dyn ObjectReader>> {
-        Ok(Arc::new(GCSFileReader::new(file)?))
+        self.reader(file, None)
     }
 }
 
@@ -93,72 +2288,1209 @@ impl GCSFileSystem {
     }
 }
 
+/// Endpoint of the emulator at `host`, a URL or a `host:port` served over plain HTTP
+fn emulator_endpoint(host: &str) -> String {
+    if host.contains("://") {
+        host.to_string()
+    } else {
+        format!("http://{}", host)
+    }
+}
+
+/// Value of the environment variable `name`, `None` if it is unset or empty
+fn env_var(name: &str) -> Result<Option<String>> {
+    match std::env::var(name) {
+        Ok(value) if value.trim().is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => Err(env_error(name, "not valid Unicode")),
+    }
+}
+
+fn env_error(variable: &str, message: impl Into<String>) -> std::io::Error {
+    GCSError::Environment {
+        variable: variable.to_string(),
+        message: message.into(),
+    }
+    .into()
+}
+
+/// Whether `project` is a project number or a project ID, possibly domain-scoped as in
+/// `example.com:my-project`
+fn is_project(project: &str) -> bool {
+    if !project.is_empty() && project.bytes().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+    let id = project.rsplit(':').next().unwrap_or(project);
+    (6..=30).contains(&id.len())
+        && id.starts_with(|c: char| c.is_ascii_lowercase())
+        && !id.ends_with('-')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Wrap `err` with the object, range and generation being read, keeping its kind so
+/// callers can still tell e.g. missing objects from denied ones
+fn read_error(
+    path: &GCSPath,
+    start: u64,
+    length: usize,
+    generation: Option<u64>,
+    err: std::io::Error,
+) -> std::io::Error {
+    let kind = err.kind();
+    let source = match err.into_inner().map(|inner| inner.downcast::<GCSError>()) {
+        Some(Ok(inner)) => inner,
+        Some(Err(inner)) => Box::new(GCSError::GCS(inner.to_string())),
+        None => Box::new(GCSError::GCS(kind.to_string())),
+    };
+    std::io::Error::new(
+        kind,
+        GCSError::Read {
+            uri: format!("gs://{}", path),
+            start,
+            length,
+            generation,
+            source,
+        },
+    )
+}
+
+/// Download a range of the object at `path` for a reader, along with the generation read.
+/// With a pinned `generation`, fails with `GCSError::GenerationChanged` once the object no
+/// longer has that generation.
+async fn read_range(
+    api: &ApiClient,
+    path: &GCSPath,
+    start: u64,
+    length: usize,
+    generation: Option<u64>,
+) -> Result<(Bytes, Option<u64>)> {
+    match api.download(path, start, length, generation).await {
+        Ok((bytes, generation)) if length > 0 && bytes.len() < length => {
+            // a short body means the object changed or the transfer was cut off
+            Err(read_error(
+                path,
+                start,
+                length,
+                generation,
+                std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    GCSError::GCS(format!("received {} of {} bytes", bytes.len(), length)),
+                ),
+            ))
+        }
+        Ok(download) => Ok(download),
+        Err(err) => Err(download_error(api, path, start, length, generation, err).await),
+    }
+}
+
+/// Wrap `err`, which failed a download of a range of the object at `path`, like
+/// `read_error`, looking up the generation if it is not known yet
+async fn download_error(
+    api: &ApiClient,
+    path: &GCSPath,
+    start: u64,
+    length: usize,
+    generation: Option<u64>,
+    err: std::io::Error,
+) -> std::io::Error {
+    if err.kind() == ErrorKind::NotFound && generation.is_some() {
+        // the cached metadata and listings describe the replaced generation
+        api.metadata.remove(path);
+        let changed = GCSError::GenerationChanged {
+            uri: format!("gs://{}", path),
+            generation: generation.unwrap_or_default(),
+        };
+        return read_error(path, start, length, generation, changed.into());
+    }
+    // errors carry no generation, the object metadata does
+    let mut generation = generation.or_else(|| api.metadata.get(path).map(|o| o.generation));
+    let err = match err.get_ref().and_then(|e| e.downcast_ref::<GCSError>()) {
+        Some(GCSError::KmsKeyUnavailable { key: None, message }) => {
+            // the error payload does not always name the key, the object does
+            let object = api.object(path).await.ok();
+            generation = generation.or_else(|| object.as_ref().map(|o| o.generation));
+            std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                GCSError::KmsKeyUnavailable {
+                    key: object.and_then(|o| o.kms_key_name),
+                    message: message.clone(),
+                },
+            )
+        }
+        _ => err,
+    };
+    if generation.is_none() {
+        generation = api.object(path).await.ok().map(|o| o.generation);
+    }
+    read_error(path, start, length, generation, err)
+}
+
+/// Download `length` bytes from `start` as concurrent ranged requests of at most
+/// `options.part_size` bytes and reassemble them in order, all from `generation` or, if
+/// that is not known yet, from the generation the first part is read from
+async fn read_parts(
+    api: &ApiClient,
+    path: &GCSPath,
+    start: u64,
+    length: usize,
+    options: ReadOptions,
+    mut generation: Option<u64>,
+) -> Result<(Bytes, Option<u64>)> {
+    let mut offsets = (0..length).step_by(options.part_size);
+    let mut bytes = BytesMut::with_capacity(length);
+    if generation.is_none() && length > 0 {
+        offsets.next();
+        let (first, read) =
+            read_range(api, path, start, options.part_size.min(length), None).await?;
+        bytes.extend_from_slice(&first);
+        generation = read;
+    }
+    let parts = offsets.map(move |offset| {
+        read_range(
+            api,
+            path,
+            start + offset as u64,
+            options.part_size.min(length - offset),
+            generation,
+        )
+    });
+    let mut parts = stream::iter(parts).buffered(options.parallelism);
+    while let Some(part) = parts.next().await {
+        bytes.extend_from_slice(&part?.0);
+    }
+    Ok((bytes.freeze(), generation))
+}
+
+/// A channel holding at most `capacity` chunks, from a task on the I/O runtime to a
+/// reader. The task waits for room without blocking its runtime, and the reader blocks
+/// on a std channel, which unlike tokio's works on threads that are inside a runtime.
+fn chunk_channel(capacity: usize) -> (ChunkSender, ChunkReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let room = Arc::new(Semaphore::new(capacity.max(1)));
+    let sender = ChunkSender {
+        tx: Mutex::new(tx),
+        room: room.clone(),
+        closed: AtomicBool::new(false),
+    };
+    let receiver = ChunkReceiver {
+        rx: Mutex::new(rx),
+        room,
+    };
+    (sender, receiver)
+}
+
+/// Sending half of a [`chunk_channel`]
+struct ChunkSender {
+    // the halves of std channels are not (always) `Sync`, readers and the tasks
+    // borrowing the sender across awaits have to be
+    tx: Mutex<mpsc::Sender<Result<Bytes>>>,
+    room: Arc<Semaphore>,
+    closed: AtomicBool,
+}
+
+impl ChunkSender {
+    /// Send `chunk` once there is room for it, returning `false` if the reader is gone
+    async fn send(&self, chunk: Result<Bytes>) -> bool {
+        let sent = match self.room.acquire().await {
+            Ok(permit) => {
+                // given back by the reader when it takes the chunk
+                permit.forget();
+                self.tx.lock().unwrap().send(chunk).is_ok()
+            }
+            Err(_) => false,
+        };
+        if !sent {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Whether a send found the reader gone
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Receiving half of a [`chunk_channel`]
+struct ChunkReceiver {
+    rx: Mutex<mpsc::Receiver<Result<Bytes>>>,
+    room: Arc<Semaphore>,
+}
+
+impl ChunkReceiver {
+    /// The next chunk, waiting at most `timeout` for it, or `None` once the sender is done
+    fn recv(
+        &self,
+        timeout: Option<Duration>,
+    ) -> std::result::Result<Option<Result<Bytes>>, mpsc::RecvTimeoutError> {
+        let rx = self.rx.lock().unwrap();
+        let chunk = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match chunk {
+            Ok(chunk) => {
+                self.room.add_permits(1);
+                Ok(Some(chunk))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Drop for ChunkReceiver {
+    fn drop(&mut self) {
+        // wakes a sender waiting for room, so it stops downloading
+        self.room.close();
+    }
+}
+
+/// Sequential reader over blocks downloaded ahead of it on the I/O runtime
+struct PrefetchReader {
+    blocks: ChunkReceiver,
+    current: Bytes,
+}
+
+impl PrefetchReader {
+    fn start(
+        io: &IoRuntime,
+        api: ApiClient,
+        path: GCSPath,
+        start: u64,
+        length: u64,
+        options: ReadOptions,
+        pinned: Arc<Mutex<Option<u64>>>,
+    ) -> Self {
+        // each block waits in the channel or in the buffered stream, so at most `depth`
+        // blocks are downloaded or held ahead of the consumer
+        let (tx, rx) = chunk_channel(1);
+        io.handle().spawn(in_current_span(async move {
+            let block_size = options.prefetch_block_size as u64;
+            let read_block =
+                |offset: u64, generation: Option<u64>| {
+                    let (api, path) = (&api, &path);
+                    let block_start = start + offset;
+                    let block_length = block_size.min(length - offset) as usize;
+                    async move {
+                        let read = read_range(api, path, block_start, block_length, generation);
+                        match options.timeout_for(block_length as u64) {
+                            Some(timeout) => tokio::time::timeout(timeout, read)
+                                .await
+                                .unwrap_or_else(|_| {
+                                    Err(read_error(
+                                        path,
+                                        block_start,
+                                        block_length,
+                                        generation,
+                                        std::io::Error::new(
+                                            ErrorKind::TimedOut,
+                                            GCSError::GCS(format!(
+                                                "no response after {:?}",
+                                                timeout
+                                            )),
+                                        ),
+                                    ))
+                                }),
+                            None => read.await,
+                        }
+                    }
+                };
+
+            let mut offsets = (0..length).step_by(block_size as usize);
+            let mut generation = *pinned.lock().unwrap();
+            if generation.is_none() {
+                // download the first block alone to learn the generation to pin the rest to
+                if let Some(offset) = offsets.next() {
+                    let block = read_block(offset, None).await;
+                    if let Ok((_, Some(read))) = &block {
+                        generation = Some(*pinned.lock().unwrap().get_or_insert(*read));
+                    }
+                    let failed = block.is_err();
+                    if !tx.send(block.map(|(bytes, _)| bytes)).await || failed {
+                        return;
+                    }
+                }
+            }
+            let blocks = offsets.map(|offset| read_block(offset, generation));
+            let mut blocks =
+                stream::iter(blocks).buffered(options.prefetch_depth.saturating_sub(1).max(1));
+            while let Some(block) = blocks.next().await {
+                let failed = block.is_err();
+                // the reader was dropped, stop downloading
+                if !tx.send(block.map(|(bytes, _)| bytes)).await || failed {
+                    break;
+                }
+            }
+        }));
+        Self {
+            blocks: rx,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Read for PrefetchReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() && !buf.is_empty() {
+            // blocks time out on the I/O runtime, the wait for one does not
+            match self.blocks.recv(None) {
+                Ok(Some(block)) => self.current = block?,
+                Ok(None) | Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        Ok(n)
+    }
+}
+
+/// Sequential reader over the body of a single download, which the I/O runtime receives
+/// at most `STREAM_BUFFER_CHUNKS` chunks ahead of it
+struct StreamReader {
+    chunks: ChunkReceiver,
+    current: Bytes,
+    /// Time the download may take to deliver the next chunk
+    stall_timeout: Option<Duration>,
+    path: GCSPath,
+    start: u64,
+    length: usize,
+    pinned: Arc<Mutex<Option<u64>>>,
+}
+
+impl StreamReader {
+    fn start(
+        io: &IoRuntime,
+        api: ApiClient,
+        path: GCSPath,
+        start: u64,
+        length: u64,
+        options: ReadOptions,
+        pinned: Arc<Mutex<Option<u64>>>,
+    ) -> Self {
+        let (tx, rx) = chunk_channel(STREAM_BUFFER_CHUNKS);
+        let length = length as usize;
+        io.handle().spawn(in_current_span({
+            let (path, pinned) = (path.clone(), pinned.clone());
+            async move {
+                let generation = *pinned.lock().unwrap();
+                let tx = &tx;
+                let received = api
+                    .receive(&path, start, length, generation, move |chunk| {
+                        tx.send(Ok(chunk))
+                    })
+                    .await;
+                let err = match received {
+                    Ok((received, read)) => {
+                        if let Some(read) = read {
+                            pinned.lock().unwrap().get_or_insert(read);
+                        }
+                        // the reader was dropped, or got the whole range
+                        if tx.is_closed() || received as usize >= length {
+                            return;
+                        }
+                        // a short body means the object changed or the transfer was cut off
+                        let short = std::io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            GCSError::GCS(format!("received {} of {} bytes", received, length)),
+                        );
+                        read_error(&path, start, length, read.or(generation), short)
+                    }
+                    Err(err) => download_error(&api, &path, start, length, generation, err).await,
+                };
+                let _ = tx.send(Err(err)).await;
+            }
+        }));
+        Self {
+            chunks: rx,
+            current: Bytes::new(),
+            stall_timeout: options.timeout_for(0),
+            path,
+            start,
+            length,
+            pinned,
+        }
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() && !buf.is_empty() {
+            let chunk = self.chunks.recv(self.stall_timeout).map_err(|_| {
+                let generation = *self.pinned.lock().unwrap();
+                read_error(
+                    &self.path,
+                    self.start,
+                    self.length,
+                    generation,
+                    std::io::Error::new(
+                        ErrorKind::TimedOut,
+                        GCSError::GCS(format!(
+                            "no data for {:?}",
+                            self.stall_timeout.unwrap_or_default()
+                        )),
+                    ),
+                )
+            })?;
+            match chunk {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        Ok(n)
+    }
+}
+
+/// Reader of one file. Every read is pinned to the generation the file had when it was
+/// listed or looked up, or else to the generation of the first read, so a file overwritten
+/// between reads fails with `GCSError::GenerationChanged` instead of mixing versions.
 struct GCSFileReader {
     file: SizedFile,
+    api: ApiClient,
+    options: ReadOptions,
+    io: Arc<IoRuntime>,
+    /// Generation every read of the file comes from, once known
+    generation: Arc<Mutex<Option<u64>>>,
+    /// Start and contents of the last coalesced read
+    buffered: Mutex<Option<(u64, Bytes)>>,
+    cache: Option<Arc<BlockCache>>,
+    disk_cache: Option<Arc<DiskCache>>,
+    /// Whether reads may be served from `cache` and `disk_cache`, rather than only fill them
+    cached_reads: bool,
 }
 
 impl GCSFileReader {
     #[allow(clippy::too_many_arguments)]
-    fn new(file: SizedFile) -> Result<Self> {
-        Ok(Self { file })
+    fn new(
+        file: SizedFile,
+        api: ApiClient,
+        options: ReadOptions,
+        io: Arc<IoRuntime>,
+        generation: Option<u64>,
+        cache: Option<Arc<BlockCache>>,
+        disk_cache: Option<Arc<DiskCache>>,
+        cache_mode: CacheMode,
+    ) -> Result<Self> {
+        let path = GCSPath::parse(&file.path)?;
+        let generation = generation.or_else(|| api.metadata.get(&path).map(|o| o.generation));
+        // ranges of objects with customer-supplied keys must not outlive the key, so they
+        // are never kept in memory or on disk in plain text
+        let (cache, disk_cache) = match api.encryption.customer_key(&path) {
+            Some(_) => (None, None),
+            None => (cache, disk_cache),
+        };
+        Ok(Self {
+            file,
+            api,
+            options,
+            io,
+            generation: Arc::new(Mutex::new(generation)),
+            buffered: Mutex::new(None),
+            cache,
+            disk_cache,
+            cached_reads: cache_mode.reads(),
+        })
+    }
+
+    fn block_key(&self, generation: u64, start: u64, length: usize) -> BlockKey {
+        BlockKey {
+            path: self.file.path.clone(),
+            generation,
+            start,
+            length,
+        }
+    }
+
+    /// `length` bytes from `start`, if the last coalesced read covered them
+    fn buffered(&self, start: u64, length: usize) -> Option<Bytes> {
+        let buffered = self.buffered.lock().unwrap();
+        let (buffer_start, bytes) = buffered.as_ref()?;
+        let offset = start.checked_sub(*buffer_start)? as usize;
+        (offset + length <= bytes.len()).then(|| bytes.slice(offset..offset + length))
     }
 }
 
 #[async_trait]
 impl ObjectReader for GCSFileReader {
-    async fn chunk_reader(&self, _start: u64, _length: usize) -> Result<Box<dyn AsyncRead>> {
-        todo!("implement once async file readers are available (arrow-rs#78, arrow-rs#111)")
+    /// Download the range at once, like an unbuffered `sync_chunk_reader`. Only the block
+    /// cache is used, as reading and filling the disk cache would block the caller.
+    async fn chunk_reader(&self, start: u64, length: usize) -> Result<Box<dyn AsyncRead>> {
+        let path = GCSPath::parse(&self.file.path)?;
+        let pinned = *self.generation.lock().unwrap();
+        if let Some(generation) = pinned.filter(|_| self.cached_reads) {
+            let key = self.block_key(generation, start, length);
+            if let Some(bytes) = self.cache.as_ref().and_then(|cache| cache.get(&key)) {
+                return Ok(Box::new(futures::io::Cursor::new(bytes)));
+            }
+        }
+
+        let total = match length {
+            0 => self.file.size.saturating_sub(start),
+            length => length as u64,
+        };
+        let timeout = self.options.timeout_for(total);
+        let api = self.api.clone();
+        // the download runs on the I/O runtime, whichever executor awaits it
+        let download = self.io.handle().spawn(in_current_span({
+            let path = path.clone();
+            async move {
+                let read = read_range(&api, &path, start, length, pinned);
+                match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, read).await.map_err(|_| {
+                        std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            GCSError::GCS(format!("no response after {:?}", timeout)),
+                        )
+                    })?,
+                    None => read.await,
+                }
+            }
+        }));
+        let (bytes, generation) = download
+            .await
+            .map_err(|err| std::io::Error::other(GCSError::GCS(format!("{:?}", err))))?
+            .map_err(|err| read_error(&path, start, length, None, err))?;
+
+        if let Some(generation) = generation.or(pinned) {
+            self.generation.lock().unwrap().get_or_insert(generation);
+            if let Some(cache) = &self.cache {
+                cache.insert(self.block_key(generation, start, length), bytes.clone());
+            }
+        }
+        Ok(Box::new(futures::io::Cursor::new(bytes)))
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "gcs.read",
+            level = "debug",
+            skip(self),
+            fields(path = %self.file.path)
+        )
+    )]
     fn sync_chunk_reader(&self, start: u64, length: usize) -> Result<Box<dyn Read + Send + Sync>> {
-        let file_path = self.file.path.clone();
+        let path = GCSPath::parse(&self.file.path)?;
+        let api = self.api.clone();
+        let options = self.options;
+        let total = match length {
+            0 => self.file.size.saturating_sub(start),
+            length => length as u64,
+        };
 
-        // once the async chunk file readers have been implemented this complexity can be removed
-        let (tx, rx) = mpsc::channel();
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
-
-            rt.block_on(async move {
-                // aws_sdk_s3::Client appears bound to the runtime and will deadlock if cloned from the main runtime
-                let client = new_client().await;
-
-                let (bucket, key) = match file_path.split_once('/') {
-                    Some((bucket, prefix)) => (bucket, prefix),
-                    None => (file_path.as_str(), ""),
-                };
+        if options.prefetch_depth > 0 && total as usize > options.prefetch_block_size {
+            return Ok(Box::new(PrefetchReader::start(
+                &self.io,
+                api,
+                path,
+                start,
+                total,
+                options,
+                self.generation.clone(),
+            )));
+        }
+
+        if options.stream_threshold > 0 && total as usize > options.stream_threshold {
+            return Ok(Box::new(StreamReader::start(
+                &self.io,
+                api,
+                path,
+                start,
+                total,
+                options,
+                self.generation.clone(),
+            )));
+        }
+
+        // only blocks of the pinned generation can be served from the caches, anything
+        // else might have been overwritten since it was cached
+        let pinned = *self.generation.lock().unwrap();
+        if let Some(generation) = pinned.filter(|_| self.cached_reads) {
+            let key = self.block_key(generation, start, length);
+            if let Some(bytes) = self.cache.as_ref().and_then(|cache| cache.get(&key)) {
+                return Ok(Box::new(bytes.reader()));
+            }
+            if let Some(bytes) = self.disk_cache.as_ref().and_then(|disk| disk.get(&key)) {
+                if let Some(cache) = &self.cache {
+                    cache.insert(key, bytes.clone());
+                }
+                return Ok(Box::new(bytes.reader()));
+            }
+        }
+
+        let parallel = total as usize > options.part_size && options.parallelism > 1;
+        let fetch_length = match length {
+            0 => 0,
+            length if parallel || options.coalesce_gap == 0 => length,
+            length => {
+                if let Some(bytes) = self.buffered(start, length) {
+                    return Ok(Box::new(bytes.reader()));
+                }
+                let remaining = self.file.size.saturating_sub(start) as usize;
+                (length + options.coalesce_gap).min(remaining).max(length)
+            }
+        };
+        let timeout = options.timeout_for(total.max(fetch_length as u64));
 
-                let resp = if length > 0 {
-                    client
-                        .object()
-                        .download_range(bucket, key, start, length)
-                        .await
+        // DataFusion reads synchronously from blocking threads, so the download runs on the
+        // shared I/O runtime and this thread waits for its result
+        let (tx, rx) = mpsc::channel();
+        self.io.handle().spawn(in_current_span({
+            let path = path.clone();
+            async move {
+                let bytes = if parallel {
+                    read_parts(&api, &path, start, total as usize, options, pinned).await
                 } else {
-                    client.object().download(bucket, key).await
+                    read_range(&api, &path, start, fetch_length, pinned).await
                 };
+                // the receiver is gone if the read timed out
+                let _ = tx.send(bytes);
+            }
+        }));
 
-                let bytes = match resp {
-                    Ok(res) => Ok(bytes::Bytes::from(res)),
-                    Err(err) => Err(std::io::Error::new(
-                        ErrorKind::Other,
-                        GCSError::GCS(format!("{:?}", err)),
-                    )),
-                };
+        let received = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).map_err(|err| {
+                std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    GCSError::GCS(format!("{:?} after {:?}", err, timeout)),
+                )
+            }),
+            None => rx
+                .recv()
+                .map_err(|err| std::io::Error::other(GCSError::GCS(format!("{:?}", err)))),
+        };
+        let (bytes, generation) =
+            received.map_err(|err| read_error(&path, start, length, None, err))??;
 
-                tx.send(bytes).unwrap();
-            })
+        let requested = if fetch_length > length && length > 0 {
+            let requested = bytes.slice(..length);
+            *self.buffered.lock().unwrap() = Some((start, bytes));
+            requested
+        } else {
+            bytes
+        };
+        if let Some(generation) = generation.or(pinned) {
+            self.generation.lock().unwrap().get_or_insert(generation);
+            let key = self.block_key(generation, start, length);
+            if let Some(disk) = &self.disk_cache {
+                disk.insert(&key, &requested);
+            }
+            if let Some(cache) = &self.cache {
+                cache.insert(key, requested.clone());
+            }
+        }
+        Ok(Box::new(requested.reader()))
+    }
+
+    fn length(&self) -> u64 {
+        self.file.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::test_server::{serve, FakeGcs};
+    use futures::TryStreamExt;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn file(path: &str) -> FileMeta {
+        FileMeta {
+            sized_file: SizedFile {
+                path: path.to_string(),
+                size: 1,
+            },
+            last_modified: None,
+        }
+    }
+
+    fn passes(filter: &NameFilter, prefix: &str, path: &str) -> bool {
+        filter.check(prefix, file(path), false).unwrap().is_some()
+    }
+
+    #[test]
+    fn suffixes() {
+        let filter = NameFilter {
+            suffix: ".csv".to_string(),
+            ..Default::default()
+        };
+        assert!(passes(&filter, "data/", "bucket/data/a.csv"));
+        assert!(!passes(&filter, "data/", "bucket/data/a.csv.gz"));
+        assert!(!passes(&filter, "data/", "bucket/data/csv"));
+        // the suffix applies to the object name, not the bucket
+        assert!(!passes(&filter, "", "bucket.csv/a"));
+    }
+
+    #[test]
+    fn hidden_files_below_the_listed_prefix() {
+        let filter = NameFilter {
+            skip_hidden: true,
+            ..Default::default()
+        };
+        assert!(passes(&filter, "data/", "bucket/data/a.csv"));
+        assert!(!passes(&filter, "data/", "bucket/data/_SUCCESS"));
+        assert!(!passes(&filter, "data/", "bucket/data/.a.csv.crc"));
+        assert!(!passes(&filter, "data/", "bucket/data/_temporary/0/a.csv"));
+        assert!(!passes(&filter, "data/", "bucket/data/dt=1/.hidden/a.csv"));
+        // a listed prefix may name a hidden directory itself
+        assert!(passes(&filter, "_staging/", "bucket/_staging/a.csv"));
+        assert!(passes(&filter, "data/_tmp/", "bucket/data/_tmp/a.csv"));
+        // but not part of a hidden name
+        assert!(!passes(&filter, "data/_t", "bucket/data/_tmp/a.csv"));
+
+        let filter = NameFilter::default();
+        assert!(passes(&filter, "data/", "bucket/data/_SUCCESS"));
+    }
+
+    #[test]
+    fn archived_objects() {
+        let filter = |archived| NameFilter {
+            archived,
+            ..Default::default()
+        };
+        let path = "bucket/data/a.csv";
+        let read = filter(ArchivedObjects::Read).check("data/", file(path), true);
+        assert_eq!(read.unwrap(), Some(file(path)));
+        let skip = filter(ArchivedObjects::Skip).check("data/", file(path), true);
+        assert_eq!(skip.unwrap(), None);
+        let fail = filter(ArchivedObjects::Fail)
+            .check("data/", file(path), true)
+            .unwrap_err();
+        assert_eq!(fail.kind(), ErrorKind::Unsupported);
+        // objects in other storage classes pass either way
+        let live = filter(ArchivedObjects::Fail).check("data/", file(path), false);
+        assert_eq!(live.unwrap(), Some(file(path)));
+    }
+
+    /// A CSV file of `rows` rows and the store serving it at `bucket/table.csv`
+    fn csv_store(rows: usize, builder: GCSFileSystemBuilder) -> (GCSFileSystem, SizedFile, Bytes) {
+        let mut csv = "id,name\n".to_string();
+        for row in 0..rows {
+            csv.push_str(&format!("{},name-{}\n", row, row));
+        }
+        let data = Bytes::from(csv);
+        let endpoint = serve(HashMap::from([(
+            "bucket/table.csv".to_string(),
+            data.clone(),
+        )]));
+        let store = builder
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(endpoint)
+            .build();
+        let file = SizedFile {
+            path: "bucket/table.csv".to_string(),
+            size: data.len() as u64,
+        };
+        (store, file, data)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn prefetched_reads_inside_a_runtime() {
+        let builder = GCSFileSystem::builder().with_prefetch(4096, 3);
+        let (store, file, data) = csv_store(10_000, builder);
+        let reader = store.file_reader(file).unwrap();
+
+        let mut csv = String::new();
+        reader
+            .sync_chunk_reader(0, 0)
+            .unwrap()
+            .read_to_string(&mut csv)
+            .unwrap();
+        assert_eq!(csv.as_bytes(), &data[..]);
+        assert_eq!(csv.lines().nth(5000), Some("4999,name-4999"));
+
+        // a range starting and ending mid block
+        let mut range = vec![0; 10_000];
+        reader
+            .sync_chunk_reader(1000, range.len())
+            .unwrap()
+            .read_exact(&mut range)
+            .unwrap();
+        assert_eq!(range, &data[1000..11_000]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_reads_inside_a_runtime() {
+        let builder = GCSFileSystem::builder()
+            .with_prefetch(4096, 0)
+            .with_stream_threshold(4096)
+            .with_read_timeout(Some(Duration::from_secs(30)));
+        let (store, file, data) = csv_store(10_000, builder);
+        let reader = store.file_reader(file).unwrap();
+
+        let mut csv = String::new();
+        reader
+            .sync_chunk_reader(0, 0)
+            .unwrap()
+            .read_to_string(&mut csv)
+            .unwrap();
+        assert_eq!(csv.as_bytes(), &data[..]);
+
+        // dropping a reader midway neither blocks nor panics
+        let mut head = vec![0; 100];
+        let mut streamed = reader.sync_chunk_reader(100, 50_000).unwrap();
+        streamed.read_exact(&mut head).unwrap();
+        assert_eq!(head, &data[100..200]);
+        drop(streamed);
+    }
+
+    async fn read_async(
+        reader: Arc<dyn ObjectReader>,
+        start: u64,
+        length: usize,
+    ) -> Result<Vec<u8>> {
+        let mut chunk = Box::into_pin(reader.chunk_reader(start, length).await?);
+        let mut bytes = vec![];
+        futures::AsyncReadExt::read_to_end(&mut chunk, &mut bytes).await?;
+        Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn async_reads() {
+        let builder = GCSFileSystem::builder().with_block_cache(1024 * 1024);
+        let (store, file, data) = csv_store(1000, builder);
+        let reader = store.file_reader(file).unwrap();
+
+        let read = |start, length| read_async(reader.clone(), start, length);
+        assert_eq!(read(0, 0).await.unwrap(), &data[..]);
+        assert_eq!(read(100, 50).await.unwrap(), &data[100..150]);
+        assert_eq!(
+            read(data.len() as u64 - 10, 0).await.unwrap(),
+            &data[data.len() - 10..]
+        );
+        let blocks = store.cache_snapshot().blocks;
+        let ranges = blocks
+            .iter()
+            .map(|block| (block.generation, block.start, block.length));
+        assert_eq!(
+            ranges.collect::<Vec<_>>(),
+            [(1, 0, 0), (1, 100, 50), (1, data.len() as u64 - 10, 0)]
+        );
+
+        // reads the cached blocks without a server
+        let cached = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint("http://127.0.0.1:9")
+            .with_block_cache(1024 * 1024)
+            .build();
+        let object = ObjectSnapshot {
+            path: "bucket/table.csv".to_string(),
+            size: data.len() as u64,
+            generation: 1,
+            updated: Utc::now(),
+            archived: false,
+        };
+        cached.restore_cache(CacheSnapshot {
+            objects: vec![object],
+            blocks,
         });
+        let file = SizedFile {
+            path: "bucket/table.csv".to_string(),
+            size: data.len() as u64,
+        };
+        let range = read_async(cached.file_reader(file).unwrap(), 100, 50).await;
+        assert_eq!(range.unwrap(), &data[100..150]);
 
-        let bytes = rx.recv_timeout(Duration::from_secs(10)).map_err(|err| {
-            std::io::Error::new(ErrorKind::TimedOut, GCSError::GCS(format!("{:?}", err)))
-        })??;
+        let missing = SizedFile {
+            path: "bucket/missing.csv".to_string(),
+            size: 10,
+        };
+        let missing = store.file_reader(missing).unwrap();
+        let err = read_async(missing, 0, 0).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
 
-        Ok(Box::new(bytes.reader()))
+    async fn list_paths(store: &GCSFileSystem, uri: &str) -> Vec<String> {
+        let files = store.list_objects(uri, false, NameFilter::default());
+        let files: Vec<_> = files.await.unwrap().try_collect().await.unwrap();
+        files.into_iter().map(|file| file.sized_file.path).collect()
     }
 
-    fn length(&self) -> u64 {
-        self.file.size
+    #[tokio::test]
+    async fn cache_modes() {
+        let (fake, server) = FakeGcs::start();
+        fake.insert("bucket/t/a.csv", "a\n1\n");
+        let store = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(&server.endpoint)
+            .with_metadata_cache(100, None)
+            .with_listing_cache_ttl(Duration::from_secs(3600))
+            .with_negative_cache_ttl(Duration::from_secs(3600))
+            .with_block_cache(1024 * 1024)
+            .build();
+        let read = |store: &GCSFileSystem| {
+            let file = SizedFile {
+                path: "bucket/t/a.csv".to_string(),
+                size: 4,
+            };
+            read_async(store.file_reader(file).unwrap(), 0, 0)
+        };
+
+        // warm the caches, then change everything behind the store's back
+        assert_eq!(
+            list_paths(&store, "gs://bucket/t/").await,
+            ["bucket/t/a.csv"]
+        );
+        assert!(list_paths(&store, "gs://bucket/u/").await.is_empty());
+        assert_eq!(read(&store).await.unwrap(), b"a\n1\n");
+        fake.insert("bucket/t/a.csv", "a\n2\n");
+        fake.insert("bucket/t/b.csv", "b\n3\n");
+        fake.insert("bucket/u/c.csv", "c\n4\n");
+        assert_eq!(read(&store).await.unwrap(), b"a\n1\n");
+
+        let bypass = store.with_cache_mode(CacheMode::Bypass);
+        assert!(bypass.describe().ends_with("\ncache mode: Bypass"));
+        let listed = ["bucket/t/a.csv", "bucket/t/b.csv"];
+        assert_eq!(list_paths(&bypass, "gs://bucket/t/").await, listed);
+        assert_eq!(
+            list_paths(&bypass, "gs://bucket/u/").await,
+            ["bucket/u/c.csv"]
+        );
+        assert_eq!(read(&bypass).await.unwrap(), b"a\n2\n");
+        // which left the caches as they were
+        assert_eq!(
+            list_paths(&store, "gs://bucket/t/").await,
+            ["bucket/t/a.csv"]
+        );
+        assert!(list_paths(&store, "gs://bucket/u/").await.is_empty());
+        assert_eq!(read(&store).await.unwrap(), b"a\n1\n");
+
+        let refresh = store.with_cache_mode(CacheMode::Refresh);
+        assert_eq!(list_paths(&refresh, "gs://bucket/t/").await, listed);
+        assert_eq!(
+            list_paths(&refresh, "gs://bucket/u/").await,
+            ["bucket/u/c.csv"]
+        );
+        assert_eq!(read(&refresh).await.unwrap(), b"a\n2\n");
+        // and later scans see what was refreshed, from the caches
+        let requests = server.requests().len();
+        assert_eq!(list_paths(&store, "gs://bucket/t/").await, listed);
+        assert_eq!(
+            list_paths(&store, "gs://bucket/u/").await,
+            ["bucket/u/c.csv"]
+        );
+        assert_eq!(read(&store).await.unwrap(), b"a\n2\n");
+        assert_eq!(server.requests().len(), requests);
+    }
+
+    #[tokio::test]
+    async fn prefetches_parquet_footers() {
+        let (fake, server) = FakeGcs::start();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        fake.insert("bucket/t/a.parquet", data.clone());
+        fake.insert("bucket/t/b.csv", "b\n1\n");
+        let store = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(&server.endpoint)
+            .with_block_cache(1024 * 1024)
+            .with_footer_prefetch(2)
+            .build();
+        assert!(store.describe().contains("footer prefetch: 2 at a time"));
+
+        let files = store.list_file("gs://bucket/t/").await.unwrap();
+        let files: Vec<_> = files.try_collect().await.unwrap();
+        assert_eq!(files.len(), 2);
+        let cache = store.block_cache.clone().unwrap();
+        for _ in 0..500 {
+            if !cache.blocks().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // only the footer of the Parquet file, in the range Parquet reads
+        let start = data.len() as u64 - FOOTER_READ_SIZE;
+        let keys: Vec<_> = cache.blocks().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(
+            keys,
+            [BlockKey {
+                path: "bucket/t/a.parquet".to_string(),
+                generation: keys[0].generation,
+                start,
+                length: FOOTER_READ_SIZE as usize,
+            }]
+        );
+
+        let requests = server.requests().len();
+        let file = SizedFile {
+            path: "bucket/t/a.parquet".to_string(),
+            size: data.len() as u64,
+        };
+        let footer = read_async(store.file_reader(file).unwrap(), start, 64 * 1024).await;
+        assert_eq!(footer.unwrap(), &data[start as usize..]);
+        assert_eq!(server.requests().len(), requests);
+    }
+
+    /// A clock whose sleeps report their duration, then wait for the test to end them
+    #[derive(Debug)]
+    struct SteppedClock {
+        now: std::sync::Mutex<SystemTime>,
+        sleeps: tokio::sync::mpsc::UnboundedSender<Duration>,
+        wake: Semaphore,
+    }
+
+    #[async_trait]
+    impl Clock for SteppedClock {
+        fn now(&self) -> SystemTime {
+            *self.now.lock().unwrap()
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            let _ = self.sleeps.send(duration);
+            self.wake.acquire().await.unwrap().forget();
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_listings_on_schedule() {
+        let (fake, server) = FakeGcs::start();
+        fake.insert("bucket/t/a.csv", "a\n1\n");
+        let (tx, mut sleeps) = tokio::sync::mpsc::unbounded_channel();
+        let clock = Arc::new(SteppedClock {
+            now: std::sync::Mutex::new(UNIX_EPOCH),
+            sleeps: tx,
+            wake: Semaphore::new(0),
+        });
+        let builder = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(&server.endpoint)
+            .with_clock(clock.clone());
+        let uncached = builder.clone().build();
+        let store = builder
+            .with_listing_cache_ttl(Duration::from_secs(2 * 24 * 3600))
+            .build();
+        let schedule = || RefreshSchedule::DailyAt(vec!["02:00:00".parse().unwrap()]);
+
+        let err = uncached.refresh_listings(["gs://bucket/t/"], schedule());
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let refresh = store.refresh_listings(["gs://bucket/t/"], schedule());
+        let _refresh = refresh.unwrap();
+        assert_eq!(sleeps.recv().await, Some(Duration::from_secs(2 * 3600)));
+        assert_eq!(
+            list_paths(&store, "gs://bucket/t/").await,
+            ["bucket/t/a.csv"]
+        );
+        fake.insert("bucket/t/b.csv", "b\n2\n");
+        assert_eq!(
+            list_paths(&store, "gs://bucket/t/").await,
+            ["bucket/t/a.csv"]
+        );
+
+        // relisted at 2am, then waits for the next day
+        clock.wake.add_permits(1);
+        assert_eq!(sleeps.recv().await, Some(Duration::from_secs(24 * 3600)));
+        let requests = server.requests().len();
+        let listed = ["bucket/t/a.csv", "bucket/t/b.csv"];
+        assert_eq!(list_paths(&store, "gs://bucket/t/").await, listed);
+        assert_eq!(server.requests().len(), requests);
+    }
+
+    #[tokio::test]
+    async fn limited_downloads_release_their_slot_with_the_headers() {
+        let builder = GCSFileSystem::builder().with_max_concurrent_requests(1);
+        let (store, file, data) = csv_store(1000, builder);
+        let path = GCSPath::parse(&file.path).unwrap();
+
+        // the consumer of the first body waits until the second download is done
+        let (done, waiting) = tokio::sync::oneshot::channel::<()>();
+        let mut waiting = Some(waiting);
+        let first = store.api.receive(&path, 0, 0, None, |_| {
+            let waiting = waiting.take();
+            async move {
+                if let Some(waiting) = waiting {
+                    let _ = waiting.await;
+                }
+                true
+            }
+        });
+        let second = async {
+            let read = store.api.download(&path, 100, 100, None).await;
+            let _ = done.send(());
+            read
+        };
+        let (first, second) =
+            tokio::time::timeout(Duration::from_secs(5), futures::future::join(first, second))
+                .await
+                .expect("the streamed body held the only slot");
+        assert_eq!(first.unwrap().0, data.len() as u64);
+        assert_eq!(second.unwrap().0, &data[100..200]);
+    }
+
+    #[test]
+    fn cache_snapshots_round_trip() {
+        let updated = DateTime::parse_from_rfc3339("2022-06-01T00:00:00Z").unwrap();
+        let object = |path: &str, generation| ObjectSnapshot {
+            path: path.to_string(),
+            size: 100,
+            generation,
+            updated: updated.with_timezone(&Utc),
+            archived: false,
+        };
+        let block = |start, data: &[u8]| BlockSnapshot {
+            path: "bucket/a.csv".to_string(),
+            generation: 7,
+            start,
+            length: data.len(),
+            data: data.to_vec(),
+        };
+        let snapshot = CacheSnapshot {
+            objects: vec![object("bucket/a.csv", 7), object("bucket/b.csv", 3)],
+            blocks: vec![block(0, b"id,name\n"), block(50, &[0, 159, 255])],
+        };
+        // nothing is served, reads from the restored caches must not need a request
+        let builder = || {
+            GCSFileSystem::builder()
+                .with_credentials(Credentials::Anonymous)
+                .with_endpoint("http://127.0.0.1:9")
+                .with_io_threads(1)
+        };
+
+        let store = builder().with_block_cache(1024).build();
+        store.restore_cache(snapshot.clone());
+        assert_eq!(store.cache_snapshot(), snapshot);
+        let file = SizedFile {
+            path: "bucket/a.csv".to_string(),
+            size: 100,
+        };
+        let mut head = String::new();
+        let reader = store.file_reader(file).unwrap();
+        reader
+            .sync_chunk_reader(0, 8)
+            .unwrap()
+            .read_to_string(&mut head)
+            .unwrap();
+        assert_eq!(head, "id,name\n");
+
+        // the read made the first object and block the most recently used
+        let warm = store.cache_snapshot();
+        let mut used = snapshot.clone();
+        used.objects.reverse();
+        used.blocks.reverse();
+        assert_eq!(warm, used);
+
+        let json = serde_json::to_string(&warm).unwrap();
+        assert!(json.contains(r#""data":"AJ//""#), "{}", json);
+        let restored: CacheSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, warm);
+
+        // a smaller block cache keeps the most recently used blocks that fit
+        let small = builder().with_block_cache(10).build();
+        small.restore_cache(restored.clone());
+        assert_eq!(small.cache_snapshot().blocks, &warm.blocks[1..]);
+
+        // without a block cache only the metadata is restored, as much as fits
+        let metadata_only = builder().with_metadata_cache(1, None).build();
+        metadata_only.restore_cache(restored);
+        let restored = metadata_only.cache_snapshot();
+        assert_eq!(restored.objects, &warm.objects[1..]);
+        assert!(restored.blocks.is_empty());
     }
 }
@@ -0,0 +1,587 @@
+//! Object reads over the Cloud Storage gRPC API, see `Transport::Grpc`
+//!
+//! Only `ReadObject` is used. Reads go through the same limiter, retry policy, checksum
+//! verification, progress and metrics hooks as downloads over the JSON API, gRPC codes
+//! standing in for the HTTP statuses they correspond to.
+
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Instant;
+
+use bytes::Bytes;
+use datafusion::datafusion_data_access::Result;
+use http::uri::PathAndQuery;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Code, Status};
+
+use crate::error::GCSError;
+use crate::object_store::api::ApiClient;
+use crate::object_store::metrics::{RequestEvent, RequestKind};
+use crate::object_store::path::GCSPath;
+use crate::object_store::progress::DownloadProgress;
+
+/// Method path of `ReadObject`
+const READ_OBJECT: &str = "/google.storage.v2.Storage/ReadObject";
+
+/// The messages of `google/storage/v2/storage.proto` reads use, with only the fields this
+/// crate sets or reads. Fields left out are skipped when decoding.
+pub(crate) mod proto {
+    use bytes::Bytes;
+
+    /// Request of `ReadObject`
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct ReadObjectRequest {
+        /// `projects/_/buckets/{bucket}`
+        #[prost(string, tag = "1")]
+        pub(crate) bucket: String,
+        #[prost(string, tag = "2")]
+        pub(crate) object: String,
+        /// `0` for the live generation
+        #[prost(int64, tag = "3")]
+        pub(crate) generation: i64,
+        #[prost(int64, tag = "4")]
+        pub(crate) read_offset: i64,
+        /// `0` to read to the end of the object
+        #[prost(int64, tag = "5")]
+        pub(crate) read_limit: i64,
+        #[prost(message, optional, tag = "10")]
+        pub(crate) common_object_request_params: Option<CommonObjectRequestParams>,
+    }
+
+    /// Customer-supplied encryption key of a request
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct CommonObjectRequestParams {
+        #[prost(string, tag = "1")]
+        pub(crate) encryption_algorithm: String,
+        #[prost(bytes = "bytes", tag = "4")]
+        pub(crate) encryption_key_bytes: Bytes,
+        #[prost(bytes = "bytes", tag = "5")]
+        pub(crate) encryption_key_sha256_bytes: Bytes,
+    }
+
+    /// One message of the `ReadObject` response stream
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct ReadObjectResponse {
+        #[prost(message, optional, tag = "1")]
+        pub(crate) checksummed_data: Option<ChecksummedData>,
+        /// Metadata of the object read, only in the first message
+        #[prost(message, optional, tag = "4")]
+        pub(crate) metadata: Option<Object>,
+    }
+
+    /// A chunk of object data and its CRC32C
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct ChecksummedData {
+        #[prost(bytes = "bytes", tag = "1")]
+        pub(crate) content: Bytes,
+        #[prost(fixed32, optional, tag = "2")]
+        pub(crate) crc32c: Option<u32>,
+    }
+
+    /// An object resource
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Object {
+        #[prost(int64, tag = "3")]
+        pub(crate) generation: i64,
+        #[prost(int64, tag = "6")]
+        pub(crate) size: i64,
+    }
+}
+
+use proto::{CommonObjectRequestParams, ReadObjectRequest, ReadObjectResponse};
+
+/// Channel to the gRPC API of a store, whose connections live on its I/O runtime
+#[derive(Debug, Clone)]
+pub(crate) struct GrpcClient {
+    channel: Channel,
+}
+
+/// The endpoint of `uri`, using TLS with the webpki roots for `https` URIs
+pub(crate) fn endpoint(uri: &str) -> Result<Endpoint> {
+    let invalid = |err: tonic::transport::Error| {
+        std::io::Error::new(
+            ErrorKind::InvalidInput,
+            GCSError::GCS(format!("Invalid gRPC endpoint {}: {}", uri, err)),
+        )
+    };
+    let endpoint = Endpoint::from_shared(uri.trim_end_matches('/').to_string())
+        .map_err(invalid)?
+        .http2_adaptive_window(true);
+    match endpoint.uri().scheme_str() {
+        Some("https") => endpoint.tls_config(ClientTlsConfig::new()).map_err(invalid),
+        _ => Ok(endpoint),
+    }
+}
+
+impl GrpcClient {
+    /// A client of `endpoint`, connecting on first use from tasks of `runtime`
+    pub(crate) fn new(endpoint: Endpoint, runtime: &tokio::runtime::Handle) -> Self {
+        // the channel spawns its worker on the runtime it is created in
+        let _runtime = runtime.enter();
+        Self {
+            channel: endpoint.connect_lazy(),
+        }
+    }
+
+    /// [`ApiClient::receive`] over gRPC: read like `api` would, handing the chunks to
+    /// `on_chunk` until it returns `false`
+    pub(crate) async fn receive<F, Fut>(
+        &self,
+        api: &ApiClient,
+        path: &GCSPath,
+        start: u64,
+        length: usize,
+        mut generation: Option<u64>,
+        mut on_chunk: F,
+    ) -> Result<(u64, Option<u64>)>
+    where
+        F: FnMut(Bytes) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        api.check_access(path)?;
+        let name = path.to_string();
+        let mut received = 0;
+        let mut expected = (length > 0).then_some(length as u64);
+        let mut attempt = 1;
+        'download: loop {
+            let remaining = length.saturating_sub(received as usize);
+            if length > 0 && remaining == 0 {
+                break;
+            }
+            let request = read_request(api, path, start + received, remaining, generation).await?;
+            let status = match self.read_object(api, &path.bucket, attempt, request).await {
+                Ok(mut messages) => loop {
+                    let data = match messages.message().await {
+                        Ok(Some(message)) => {
+                            if let Some(object) = message.metadata {
+                                generation = generation.or(Some(object.generation as u64));
+                                let rest = (object.size as u64).saturating_sub(start + received);
+                                expected = expected.or(Some(received + rest));
+                            }
+                            match message.checksummed_data {
+                                Some(data) => data,
+                                None => continue,
+                            }
+                        }
+                        Ok(None) => break 'download,
+                        Err(status) => break status,
+                    };
+                    // every chunk carries its own hash, so ranges are verified too
+                    if let Some(expected) = data.crc32c.filter(|_| api.verify_checksums) {
+                        let actual = crc32c::crc32c(&data.content);
+                        if actual != expected {
+                            return Err(GCSError::ChecksumMismatch {
+                                uri: format!("gs://{}", path),
+                                algorithm: "crc32c".to_string(),
+                                expected: base64::encode(expected.to_be_bytes()),
+                                actual: base64::encode(actual.to_be_bytes()),
+                            }
+                            .into());
+                        }
+                    }
+                    received += data.content.len() as u64;
+                    if let Some(progress) = &api.progress {
+                        progress.progress(&DownloadProgress {
+                            path: &name,
+                            start,
+                            received,
+                            expected,
+                        });
+                    }
+                    if !on_chunk(data.content).await {
+                        break 'download;
+                    }
+                },
+                Err(status) => status,
+            };
+            // like dropped JSON downloads, the rest is read from the generation already
+            // read, so the parts cannot mix generations
+            let retryable = api.retry.is_retryable_status(http_status(status.code()))
+                && (received == 0 || generation.is_some())
+                && attempt < api.retry.max_attempts;
+            if !retryable {
+                return Err(status_error(path, &status));
+            }
+            api.clock.sleep(api.retry.delay(attempt)).await;
+            attempt += 1;
+        }
+        if let Some(progress) = &api.progress {
+            progress.finished(&name, start, received);
+        }
+        api.metrics
+            .bytes_downloaded(RequestKind::Download, received);
+        Ok((received, generation))
+    }
+
+    /// Start `ReadObject` as attempt number `attempt`, holding a permit of the limiter of
+    /// `api` until the response headers arrive and reporting the attempt to its metrics
+    async fn read_object(
+        &self,
+        api: &ApiClient,
+        bucket: &str,
+        attempt: u32,
+        request: tonic::Request<ReadObjectRequest>,
+    ) -> std::result::Result<Streaming<ReadObjectResponse>, Status> {
+        let _permit = api.permit().await;
+        let started = Instant::now();
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        let response = match grpc.ready().await {
+            Ok(()) => {
+                let method = PathAndQuery::from_static(READ_OBJECT);
+                grpc.server_streaming(request, method, ProstCodec::default())
+                    .await
+            }
+            Err(err) => Err(Status::unavailable(err.to_string())),
+        };
+        api.metrics.request(&RequestEvent {
+            kind: RequestKind::Download,
+            bucket: Some(bucket.to_string()),
+            attempt,
+            status: Some(match &response {
+                Ok(_) => 200,
+                Err(status) => http_status(status.code()),
+            }),
+            latency: started.elapsed(),
+        });
+        response.map(tonic::Response::into_inner)
+    }
+}
+
+/// The `ReadObject` request for `length` bytes of the object at `path` from `start`, or
+/// everything from `start` on if `length` is zero, with the metadata the JSON API gets as
+/// headers
+async fn read_request(
+    api: &ApiClient,
+    path: &GCSPath,
+    start: u64,
+    length: usize,
+    generation: Option<u64>,
+) -> Result<tonic::Request<ReadObjectRequest>> {
+    let bucket = format!("projects/_/buckets/{}", path.bucket);
+    let params = api
+        .encryption
+        .customer_key(path)
+        .map(|key| CommonObjectRequestParams {
+            encryption_algorithm: "AES256".to_string(),
+            encryption_key_bytes: key.bytes().into(),
+            encryption_key_sha256_bytes: base64::decode(key.sha256()).unwrap_or_default().into(),
+        });
+    let mut request = tonic::Request::new(ReadObjectRequest {
+        bucket: bucket.clone(),
+        object: path.key.clone(),
+        generation: generation.unwrap_or_default() as i64,
+        read_offset: start as i64,
+        read_limit: length as i64,
+        common_object_request_params: params,
+    });
+
+    let token = api
+        .tokens
+        .token(&api.http)
+        .await
+        .map_err(std::io::Error::from)?;
+    let metadata = request.metadata_mut();
+    // routes the request to the bucket's location
+    let routing = format!("bucket={}", utf8_percent_encode(&bucket, NON_ALPHANUMERIC));
+    insert(metadata, "x-goog-request-params", &routing)?;
+    // anonymous credentials have no token to send
+    if !token.is_empty() {
+        insert(metadata, "authorization", &format!("Bearer {}", token))?;
+    }
+    for (key, value) in api.labels.iter() {
+        insert(metadata, &format!("x-goog-custom-audit-{}", key), value)?;
+    }
+    // the gRPC API bills requester-pays buckets to the quota project
+    let project = api
+        .user_projects
+        .for_bucket(&path.bucket)
+        .or(api.quota_project.as_deref());
+    if let Some(project) = project {
+        insert(metadata, "x-goog-user-project", project)?;
+    }
+    Ok(request)
+}
+
+/// Add `key: value` to `metadata`, failing with `InvalidInput` if either is not valid
+fn insert(metadata: &mut MetadataMap, key: &str, value: &str) -> Result<()> {
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidInput,
+            GCSError::GCS(format!("Invalid gRPC metadata {}", key)),
+        )
+    };
+    let name =
+        MetadataKey::from_bytes(key.to_ascii_lowercase().as_bytes()).map_err(|_| invalid())?;
+    let value: MetadataValue<_> = value.parse().map_err(|_| invalid())?;
+    metadata.insert(name, value);
+    Ok(())
+}
+
+/// The HTTP status the JSON API answers with where the gRPC API returns `code`
+fn http_status(code: Code) -> u16 {
+    match code {
+        Code::Ok => 200,
+        Code::InvalidArgument => 400,
+        Code::Unauthenticated => 401,
+        Code::PermissionDenied => 403,
+        Code::NotFound => 404,
+        Code::AlreadyExists | Code::Aborted => 409,
+        Code::FailedPrecondition => 412,
+        Code::OutOfRange => 416,
+        Code::ResourceExhausted => 429,
+        Code::Cancelled => 499,
+        Code::Unimplemented => 501,
+        Code::Unavailable => 503,
+        Code::DeadlineExceeded => 504,
+        Code::Unknown | Code::Internal | Code::DataLoss => 500,
+    }
+}
+
+/// The error of a failed read of `path`, classified like the HTTP status of the code
+fn status_error(path: &GCSPath, status: &Status) -> std::io::Error {
+    GCSError::from_status(
+        Some(path.bucket.clone()),
+        Some(path.key.clone()),
+        http_status(status.code()),
+        status.message().to_string(),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use futures::stream;
+    use tonic::body::BoxBody;
+    use tonic::codegen::{BoxFuture, BoxStream, Service};
+    use tonic::server::ServerStreamingService;
+    use tonic::transport::{Body, NamedService, Server};
+
+    use super::proto::{ChecksummedData, Object};
+    use super::*;
+    use crate::object_store::auth::Credentials;
+    use crate::object_store::clock::MockClock;
+    use crate::object_store::gcs::{GCSFileSystem, Transport};
+
+    const GENERATION: i64 = 7;
+    const CHUNK_SIZE: usize = 30_000;
+
+    /// In-memory `google.storage.v2.Storage` service that only implements `ReadObject`
+    #[derive(Debug, Clone, Default)]
+    struct FakeStorage {
+        /// Object data by `bucket/key`
+        objects: Arc<Mutex<HashMap<String, Bytes>>>,
+        requests: Arc<Mutex<Vec<(ReadObjectRequest, MetadataMap)>>>,
+        /// Number of reads still to fail with `UNAVAILABLE` after their first chunk
+        drops: Arc<AtomicUsize>,
+    }
+
+    impl FakeStorage {
+        /// Serve on a local port, returning the endpoint to connect to
+        async fn start(&self) -> String {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let incoming = stream::unfold(listener, |listener| async move {
+                let connection = listener.accept().await.map(|(stream, _)| stream);
+                Some((connection, listener))
+            });
+            let server = Server::builder().add_service(self.clone());
+            tokio::spawn(server.serve_with_incoming(Box::pin(incoming)));
+            endpoint
+        }
+
+        /// The messages answering `request`, `None` if the object does not exist
+        fn read(&self, request: &ReadObjectRequest) -> Option<Vec<ReadObjectResponse>> {
+            let bucket = request.bucket.trim_start_matches("projects/_/buckets/");
+            let name = format!("{}/{}", bucket, request.object);
+            let data = self.objects.lock().unwrap().get(&name).cloned()?;
+            let start = (request.read_offset as usize).min(data.len());
+            let end = match request.read_limit {
+                0 => data.len(),
+                limit => (start + limit as usize).min(data.len()),
+            };
+            let mut messages: Vec<_> = data[start..end]
+                .chunks(CHUNK_SIZE)
+                .map(|chunk| ReadObjectResponse {
+                    checksummed_data: Some(ChecksummedData {
+                        content: Bytes::copy_from_slice(chunk),
+                        crc32c: Some(crc32c::crc32c(chunk)),
+                    }),
+                    metadata: None,
+                })
+                .collect();
+            if messages.is_empty() {
+                messages.push(ReadObjectResponse::default());
+            }
+            messages[0].metadata = Some(Object {
+                generation: GENERATION,
+                size: data.len() as i64,
+            });
+            Some(messages)
+        }
+    }
+
+    impl NamedService for FakeStorage {
+        const NAME: &'static str = "google.storage.v2.Storage";
+    }
+
+    impl Service<http::Request<Body>> for FakeStorage {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Infallible>;
+
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+            assert_eq!(request.uri().path(), READ_OBJECT);
+            let read = ReadObject(self.clone());
+            Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                Ok(grpc.server_streaming(read, request).await)
+            })
+        }
+    }
+
+    struct ReadObject(FakeStorage);
+
+    impl ServerStreamingService<ReadObjectRequest> for ReadObject {
+        type Response = ReadObjectResponse;
+        type ResponseStream = BoxStream<ReadObjectResponse>;
+        type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+        fn call(&mut self, request: tonic::Request<ReadObjectRequest>) -> Self::Future {
+            let fake = self.0.clone();
+            let metadata = request.metadata().clone();
+            let request = request.into_inner();
+            fake.requests
+                .lock()
+                .unwrap()
+                .push((request.clone(), metadata));
+            let messages = fake
+                .read(&request)
+                .ok_or_else(|| Status::not_found(format!("No such object: {}", request.object)));
+            let dropped = fake
+                .drops
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |drops| {
+                    drops.checked_sub(1)
+                })
+                .is_ok();
+            Box::pin(async move {
+                let mut messages: Vec<_> = messages?.into_iter().map(Ok).collect();
+                if dropped {
+                    messages.truncate(1);
+                    messages.push(Err(Status::unavailable("connection reset")));
+                }
+                let messages: Self::ResponseStream = Box::pin(stream::iter(messages));
+                Ok(tonic::Response::new(messages))
+            })
+        }
+    }
+
+    fn data() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn grpc_store(fake: &FakeStorage, clock: Arc<MockClock>) -> GCSFileSystem {
+        let endpoint = fake.start().await;
+        GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            // nothing may be sent over the JSON API
+            .with_endpoint("http://127.0.0.1:9")
+            .with_transport(Transport::Grpc { endpoint })
+            .unwrap()
+            .with_checksum_verification(true)
+            .with_quota_project("quota")
+            .with_clock(clock)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn reads_objects_over_grpc() {
+        let fake = FakeStorage::default();
+        fake.objects
+            .lock()
+            .unwrap()
+            .insert("bucket/t/data.bin".to_string(), data().into());
+        let store = grpc_store(&fake, Arc::default()).await;
+        assert!(store
+            .describe()
+            .contains("transport: grpc at http://127.0.0.1:"));
+
+        let path = GCSPath::new("bucket", "t/data.bin");
+        let (bytes, generation) = store.api().download(&path, 10, 50_000, None).await.unwrap();
+        assert_eq!(bytes, &data()[10..50_010]);
+        assert_eq!(generation, Some(GENERATION as u64));
+
+        let (request, metadata) = fake.requests.lock().unwrap()[0].clone();
+        assert_eq!(
+            request,
+            ReadObjectRequest {
+                bucket: "projects/_/buckets/bucket".to_string(),
+                object: "t/data.bin".to_string(),
+                generation: 0,
+                read_offset: 10,
+                read_limit: 50_000,
+                common_object_request_params: None,
+            }
+        );
+        let header = |key: &str| {
+            metadata
+                .get(key)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+        assert_eq!(
+            header("x-goog-request-params").unwrap(),
+            "bucket=projects%2F%5F%2Fbuckets%2Fbucket"
+        );
+        assert_eq!(header("x-goog-user-project").unwrap(), "quota");
+        // anonymous credentials send no token
+        assert_eq!(header("authorization"), None);
+
+        let missing = GCSPath::new("bucket", "t/missing.bin");
+        let err = store
+            .api()
+            .download(&missing, 0, 0, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn resumes_dropped_reads_from_the_generation_read() {
+        let fake = FakeStorage::default();
+        fake.objects
+            .lock()
+            .unwrap()
+            .insert("bucket/data.bin".to_string(), data().into());
+        fake.drops.store(1, Ordering::SeqCst);
+        let clock = Arc::new(MockClock::default());
+        let store = grpc_store(&fake, clock.clone()).await;
+
+        let path = GCSPath::new("bucket", "data.bin");
+        let (bytes, generation) = store.api().download(&path, 0, 0, None).await.unwrap();
+        assert_eq!(bytes, data());
+        assert_eq!(generation, Some(GENERATION as u64));
+        assert_eq!(clock.sleeps().len(), 1);
+
+        let requests = fake.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        let (resumed, _) = &requests[1];
+        assert_eq!(resumed.read_offset, CHUNK_SIZE as i64);
+        assert_eq!(resumed.read_limit, 0);
+        assert_eq!(resumed.generation, GENERATION);
+    }
+}
@@ -0,0 +1,173 @@
+//! Fair sharing of a cap on the requests in flight between the queries using a store
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+/// Caps the requests in flight. Requests wait in the queue of the query that sends them,
+/// and freed slots go to the queues with waiting requests in turn, so a scan with
+/// hundreds of queued reads delays another query's requests by at most one slot each.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    available: usize,
+    /// Waiting requests by queue, oldest first
+    waiting: HashMap<Arc<str>, VecDeque<oneshot::Sender<()>>>,
+    /// Queues with waiting requests, the next to be given a slot first
+    turns: VecDeque<Arc<str>>,
+}
+
+/// A slot of a [`Limiter`], freed when dropped
+#[derive(Debug)]
+pub(crate) struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+/// A request waiting in its queue, which hands its slot on if it gives up after being
+/// given one
+struct Waiting {
+    slot: Option<oneshot::Receiver<()>>,
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(mut slot) = self.slot.take() {
+            slot.close();
+            if slot.try_recv().is_ok() {
+                self.limiter.release();
+            }
+        }
+    }
+}
+
+impl Limiter {
+    /// A limiter of `capacity` requests at a time
+    pub(crate) fn new(capacity: usize) -> Self {
+        let state = State {
+            available: capacity,
+            ..Default::default()
+        };
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Wait for a slot in `queue`
+    pub(crate) async fn acquire(self: &Arc<Self>, queue: &Arc<str>) -> Permit {
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            // queued requests go first
+            if state.available > 0 && state.turns.is_empty() {
+                state.available -= 1;
+                return Permit {
+                    limiter: self.clone(),
+                };
+            }
+            let (tx, rx) = oneshot::channel();
+            let waiting = state.waiting.entry(queue.clone()).or_default();
+            waiting.push_back(tx);
+            if waiting.len() == 1 {
+                state.turns.push_back(queue.clone());
+            }
+            rx
+        };
+        let mut waiting = Waiting {
+            slot: Some(slot),
+            limiter: self.clone(),
+        };
+        // senders are only dropped after sending
+        let _ = waiting.slot.as_mut().unwrap().await;
+        waiting.slot = None;
+        Permit {
+            limiter: self.clone(),
+        }
+    }
+
+    /// Hand a freed slot to the queue whose turn it is, or keep it if nothing waits
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(queue) = state.turns.pop_front() {
+            let waiting = state.waiting.get_mut(&queue).unwrap();
+            let next = waiting.pop_front().unwrap();
+            if waiting.is_empty() {
+                state.waiting.remove(&queue);
+            } else {
+                state.turns.push_back(queue);
+            }
+            // fails if the request gave up waiting
+            if next.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn queues_take_turns() {
+        let limiter = Arc::new(Limiter::new(1));
+        let (scan, query): (Arc<str>, Arc<str>) = ("scan".into(), "query".into());
+        let held = limiter.acquire(&scan).await;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = vec![];
+        for (queue, request) in [(&scan, 1), (&scan, 2), (&scan, 3), (&query, 1), (&query, 2)] {
+            let (limiter, queue, tx) = (limiter.clone(), queue.clone(), tx.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = limiter.acquire(&queue).await;
+                tx.send(format!("{} {}", queue, request)).unwrap();
+            }));
+            // queue in this order
+            tokio::task::yield_now().await;
+        }
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        drop(tx);
+        let mut order = vec![];
+        while let Some(request) = rx.recv().await {
+            order.push(request);
+        }
+        assert_eq!(order, ["scan 1", "query 1", "scan 2", "query 2", "scan 3"]);
+    }
+
+    #[tokio::test]
+    async fn requests_giving_up_pass_their_slot_on() {
+        let limiter = Arc::new(Limiter::new(1));
+        let queue: Arc<str> = "".into();
+        let held = limiter.acquire(&queue).await;
+
+        // gives up before being given a slot
+        assert!(limiter.acquire(&queue).now_or_never().is_none());
+        // given the slot, but dropped before taking it
+        let mut given = Box::pin(limiter.acquire(&queue));
+        assert!((&mut given).now_or_never().is_none());
+        drop(held);
+        drop(given);
+
+        let permit = limiter.acquire(&queue).now_or_never();
+        assert!(permit.is_some());
+        // and only one slot exists
+        assert!(limiter.acquire(&queue).now_or_never().is_none());
+        drop(permit);
+        assert!(limiter.acquire(&queue).now_or_never().is_some());
+    }
+}
@@ -0,0 +1,201 @@
+//! Checksum manifests pinning the objects under a prefix
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+
+use chrono::{DateTime, Utc};
+use datafusion::datafusion_data_access::Result;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::error::GCSError;
+use crate::object_store::gcs::GCSFileSystem;
+use crate::object_store::path::{self, GCSPath};
+
+/// Name, size, CRC32C and generation of every object under a prefix, e.g. to record the
+/// exact version of a dataset a query ran against and later check it is unchanged.
+///
+/// Manifests are stored as JSON objects with [`save`](Self::save) and read back with
+/// [`load`](Self::load). Taking a manifest only lists the prefix, it does not read any
+/// object data: the checksums are the ones GCS computed when the objects were written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// `gs://` URI of the listed prefix
+    pub uri: String,
+    /// When the prefix was listed
+    pub created: DateTime<Utc>,
+    /// The listed objects, by name
+    pub objects: Vec<ManifestEntry>,
+}
+
+/// One object of a [`Manifest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// `bucket/key` of the object
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Base64 of the big-endian CRC32C of the data
+    pub crc32c: Option<String>,
+    /// Generation of the data
+    pub generation: u64,
+}
+
+/// How the objects under a prefix differ from a [`Manifest`] of it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestDiff {
+    /// Objects of the manifest that no longer exist
+    pub missing: Vec<String>,
+    /// Objects of the manifest whose size, checksum or generation changed
+    pub changed: Vec<String>,
+    /// Objects that are not in the manifest
+    pub added: Vec<String>,
+}
+
+impl ManifestDiff {
+    /// Whether the objects still match the manifest
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty() && self.added.is_empty()
+    }
+}
+
+impl Manifest {
+    /// List every object under the prefix `uri` of `store`
+    pub async fn build(store: &GCSFileSystem, uri: &str) -> Result<Self> {
+        let path = GCSPath::parse(uri)?;
+        let created = DateTime::<Utc>::from(store.api().clock.now());
+        Ok(Self {
+            uri: format!("gs://{}", path),
+            created,
+            objects: list_entries(store, &path).await?.into_values().collect(),
+        })
+    }
+
+    /// Read the manifest stored at `uri` by [`save`](Self::save)
+    pub async fn load(store: &GCSFileSystem, uri: &str) -> Result<Self> {
+        let path = GCSPath::parse(uri)?;
+        let (data, _) = store.api().download(&path, 0, 0, None).await?;
+        serde_json::from_slice(&data).map_err(|err| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                GCSError::GCS(format!("Invalid manifest {}: {}", uri, err)),
+            )
+        })
+    }
+
+    /// Store the manifest as a JSON object at `uri`
+    pub async fn save(&self, store: &GCSFileSystem, uri: &str) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        store.put(uri, data).await
+    }
+
+    /// List the prefix again and compare its objects to the manifest
+    pub async fn verify(&self, store: &GCSFileSystem) -> Result<ManifestDiff> {
+        let mut current = list_entries(store, &GCSPath::parse(&self.uri)?).await?;
+        let mut diff = ManifestDiff::default();
+        for entry in &self.objects {
+            match current.remove(&entry.path) {
+                None => diff.missing.push(entry.path.clone()),
+                Some(object) if object != *entry => diff.changed.push(entry.path.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.added = current.into_keys().collect();
+        Ok(diff)
+    }
+}
+
+/// The objects under `path`, by `bucket/key`
+async fn list_entries(
+    store: &GCSFileSystem,
+    path: &GCSPath,
+) -> Result<BTreeMap<String, ManifestEntry>> {
+    // the prefix is literal, the listed URI could be a glob pattern
+    let uri = format!("{}/{}", path.bucket, path::escape_glob(&path.key));
+    store
+        .list_metadata(&uri)
+        .await?
+        .map_ok(|object| {
+            let entry = ManifestEntry {
+                path: object.path.clone(),
+                size: object.size,
+                crc32c: object.crc32c,
+                generation: object.generation,
+            };
+            (object.path, entry)
+        })
+        .try_collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::auth::Credentials;
+    use crate::object_store::test_server::FakeGcs;
+
+    #[tokio::test]
+    async fn diffs_against_the_listed_objects() {
+        let (fake, server) = FakeGcs::start();
+        fake.insert("bucket/data/a.csv", "a\n1\n");
+        fake.insert("bucket/data/b.csv", "b\n2\n");
+        fake.insert("bucket/data/c.csv", "c\n3\n");
+        fake.insert("bucket/database/d.csv", "d\n4\n");
+        let store = GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(&server.endpoint)
+            .build();
+
+        let manifest = Manifest::build(&store, "gs://bucket/data/").await.unwrap();
+        assert_eq!(manifest.uri, "gs://bucket/data/");
+        let paths = manifest.objects.iter().map(|entry| entry.path.as_str());
+        assert_eq!(
+            paths.collect::<Vec<_>>(),
+            [
+                "bucket/data/a.csv",
+                "bucket/data/b.csv",
+                "bucket/data/c.csv"
+            ]
+        );
+        assert_eq!(
+            manifest.objects[0],
+            ManifestEntry {
+                path: "bucket/data/a.csv".to_string(),
+                size: 4,
+                crc32c: Some(base64::encode(crc32c::crc32c(b"a\n1\n").to_be_bytes())),
+                generation: 1,
+            }
+        );
+        assert!(manifest.verify(&store).await.unwrap().is_empty());
+
+        let saved = "gs://bucket/manifests/data.json";
+        manifest.save(&store, saved).await.unwrap();
+        assert_eq!(Manifest::load(&store, saved).await.unwrap(), manifest);
+
+        // same size, other checksum
+        fake.insert("bucket/data/b.csv", "b\n5\n");
+        // same data, other generation
+        fake.insert("bucket/data/c.csv", "c\n3\n");
+        fake.delete_at("bucket/data/a.csv", Utc::now());
+        fake.insert("bucket/data/e.csv", "e\n6\n");
+        let diff = manifest.verify(&store).await.unwrap();
+        assert_eq!(
+            diff,
+            ManifestDiff {
+                missing: vec!["bucket/data/a.csv".to_string()],
+                changed: vec![
+                    "bucket/data/b.csv".to_string(),
+                    "bucket/data/c.csv".to_string()
+                ],
+                added: vec!["bucket/data/e.csv".to_string()],
+            }
+        );
+        assert!(!diff.is_empty());
+
+        let err = Manifest::load(&store, "gs://bucket/data/e.csv")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
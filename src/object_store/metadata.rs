@@ -0,0 +1,631 @@
+//! Object metadata learned from listings and lookups, reused between planning and reads
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use datafusion::datafusion_data_access::{FileMeta, SizedFile};
+
+use crate::object_store::api::ObjectResource;
+use crate::object_store::cache::CacheMode;
+use crate::object_store::clock::Clock;
+use crate::object_store::path::GCSPath;
+
+/// Metadata of a single object, as returned by [`GCSFileSystem::metadata`]
+///
+/// [`GCSFileSystem::metadata`]: crate::object_store::gcs::GCSFileSystem::metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    /// `bucket/key` of the object, as used in `SizedFile`
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Base64 of the big-endian CRC32C of the data, if the listing or lookup reported one
+    pub crc32c: Option<String>,
+    /// Generation of the object's data, to pin reads or use in a `Precondition`
+    pub generation: u64,
+    /// Version of the metadata of this generation, incremented on every metadata change
+    pub metageneration: u64,
+    /// HTTP entity tag, which changes with the data and the metadata
+    pub etag: Option<String>,
+    /// Time of the last change of the data or metadata
+    pub updated: DateTime<Utc>,
+    /// `Content-Type` of the object, if set
+    pub content_type: Option<String>,
+    /// `Content-Encoding` of the object, if set. Objects with `gzip` are read as stored,
+    /// i.e. compressed, so readers get the number of bytes listings report.
+    pub content_encoding: Option<String>,
+    /// Storage class, e.g. `STANDARD` or `ARCHIVE`
+    pub storage_class: Option<String>,
+    /// Custom metadata set by the writer (sent as `x-goog-meta-*` by the XML API),
+    /// including this crate's context labels
+    pub custom: BTreeMap<String, String>,
+    /// Time this generation was written
+    pub created: Option<DateTime<Utc>>,
+    /// Time this generation was overwritten or deleted, for the noncurrent generations
+    /// of versioned buckets listed by [`GCSFileSystem::list_versions`]
+    ///
+    /// [`GCSFileSystem::list_versions`]: crate::object_store::gcs::GCSFileSystem::list_versions
+    pub deleted: Option<DateTime<Utc>>,
+}
+
+impl ObjectMetadata {
+    pub(crate) fn new(bucket: &str, object: ObjectResource) -> Self {
+        Self {
+            path: format!("{}/{}", bucket, object.name),
+            size: object.size,
+            crc32c: object.crc32c,
+            generation: object.generation,
+            metageneration: object.metageneration,
+            etag: object.etag,
+            updated: object.updated,
+            content_type: object.content_type,
+            content_encoding: object.content_encoding,
+            storage_class: object.storage_class,
+            custom: object.metadata,
+            created: object.time_created,
+            deleted: object.time_deleted,
+        }
+    }
+
+    /// Whether this generation was the current one at `time`
+    pub fn live_at(&self, time: DateTime<Utc>) -> bool {
+        self.created.unwrap_or(self.updated) <= time
+            && self.deleted.is_none_or(|deleted| deleted > time)
+    }
+
+    /// Whether the object is in a storage class whose reads incur retrieval fees
+    pub(crate) fn archived(&self) -> bool {
+        matches!(self.storage_class.as_deref(), Some("COLDLINE" | "ARCHIVE"))
+    }
+
+    /// The `FileMeta` a listing reports for the object, e.g. to build the file list of a
+    /// table from objects selected by their custom metadata
+    pub fn file_meta(&self) -> FileMeta {
+        FileMeta {
+            sized_file: SizedFile {
+                path: self.path.clone(),
+                size: self.size,
+            },
+            last_modified: Some(self.updated),
+        }
+    }
+}
+
+/// Size, generation and modification time of an object as last seen by this store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CachedObject {
+    pub(crate) size: u64,
+    pub(crate) generation: u64,
+    pub(crate) updated: DateTime<Utc>,
+    /// In the `COLDLINE` or `ARCHIVE` storage class
+    pub(crate) archived: bool,
+}
+
+/// Metadata of objects this store has listed or looked up, keyed by `bucket/key`.
+///
+/// At most `capacity` objects are kept, evicting the least recently used, and each for
+/// at most `ttl`. Entries, and cached listings that may include the object, are dropped
+/// whenever this store writes, copies onto or deletes the object; changes made by anyone
+/// else are only picked up by listing or looking the object up again, or once the
+/// entries and cached listings expire.
+#[derive(Debug, Clone)]
+pub(crate) struct MetadataCache {
+    objects: Arc<Mutex<Objects>>,
+    capacity: usize,
+    ttl: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    listings: Option<Arc<ListingCache>>,
+    missing: Option<Arc<MissingCache>>,
+    mode: CacheMode,
+}
+
+#[derive(Debug, Default)]
+struct Objects {
+    entries: HashMap<String, Entry>,
+    /// Keys by the tick they were last used at, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    object: CachedObject,
+    seen: SystemTime,
+    last_used: u64,
+}
+
+impl Objects {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&str, &Entry) -> bool) {
+        let recency = &mut self.recency;
+        self.entries.retain(|key, entry| {
+            let kept = keep(key, entry);
+            if !kept {
+                recency.remove(&entry.last_used);
+            }
+            kept
+        });
+    }
+}
+
+/// Results of `list_file` calls, kept for a fixed time
+#[derive(Debug)]
+pub(crate) struct ListingCache {
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    /// Listed files and when they were listed, by listed URI (without scheme)
+    entries: RwLock<HashMap<String, Listing>>,
+}
+
+#[derive(Debug)]
+struct Listing {
+    /// `bucket/prefix` every listed file starts with
+    prefix: String,
+    listed: SystemTime,
+    files: Vec<FileMeta>,
+}
+
+impl ListingCache {
+    pub(crate) fn get(&self, uri: &str) -> Option<Vec<FileMeta>> {
+        let entries = self.entries.read().unwrap();
+        let listing = entries.get(uri)?;
+        (self.clock.now() < listing.listed + self.ttl).then(|| listing.files.clone())
+    }
+
+    /// Cache `files`, the complete result of listing `uri`, whose objects share `prefix`
+    pub(crate) fn insert(&self, uri: String, prefix: String, files: Vec<FileMeta>) {
+        let listing = Listing {
+            prefix,
+            listed: self.clock.now(),
+            files,
+        };
+        self.entries.write().unwrap().insert(uri, listing);
+    }
+
+    /// Drop every listing that could include objects under `prefix` (`bucket/key...`)
+    pub(crate) fn invalidate(&self, prefix: &str) {
+        self.entries
+            .write()
+            .unwrap()
+            .retain(|_, listing| !overlaps(&listing.prefix, prefix));
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
+
+/// Objects and listings GCS found nothing for, kept for a fixed time so probes of
+/// partitions that do not exist yet are not repeated on every query
+#[derive(Debug)]
+pub(crate) struct MissingCache {
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    /// Misses by `bucket/key` of looked up objects and by listed URI (without scheme)
+    entries: RwLock<HashMap<String, Missing>>,
+}
+
+#[derive(Debug)]
+struct Missing {
+    /// `bucket/key` prefix of every object that would have been found
+    prefix: String,
+    seen: SystemTime,
+    /// Message GCS answered with
+    message: String,
+}
+
+impl MissingCache {
+    /// The message `key` was found missing with, unless that was longer than the TTL ago
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        let missing = entries.get(key)?;
+        (self.clock.now() < missing.seen + self.ttl).then(|| missing.message.clone())
+    }
+
+    /// Remember that GCS found nothing for `key`, whose objects would share `prefix`
+    pub(crate) fn insert(&self, key: String, prefix: String, message: String) {
+        let now = self.clock.now();
+        let mut entries = self.entries.write().unwrap();
+        // misses are probed once per query at most, so expired ones are dropped here
+        entries.retain(|_, missing| now < missing.seen + self.ttl);
+        let missing = Missing {
+            prefix,
+            seen: now,
+            message,
+        };
+        entries.insert(key, missing);
+    }
+
+    /// Drop every miss an object under `prefix` (`bucket/key...`) would answer
+    pub(crate) fn invalidate(&self, prefix: &str) {
+        self.entries
+            .write()
+            .unwrap()
+            .retain(|_, missing| !overlaps(&missing.prefix, prefix));
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
+
+/// Whether objects under `prefix` and under `other` can be the same
+fn overlaps(prefix: &str, other: &str) -> bool {
+    other.starts_with(prefix) || prefix.starts_with(other)
+}
+
+impl MetadataCache {
+    /// A cache of the metadata of up to `capacity` objects, each kept for `ttl` unless
+    /// `None`, timed by `clock`
+    pub(crate) fn new(capacity: usize, ttl: Option<Duration>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            objects: Default::default(),
+            capacity,
+            ttl,
+            clock,
+            listings: None,
+            missing: None,
+            mode: CacheMode::ReadThrough,
+        }
+    }
+
+    /// This cache, shared with `self`, used according to `mode`. Entries are dropped on
+    /// writes whatever the mode.
+    pub(crate) fn with_mode(&self, mode: CacheMode) -> Self {
+        Self {
+            mode,
+            ..self.clone()
+        }
+    }
+
+    /// This cache, also keeping listings for `ttl`
+    pub(crate) fn with_listings(mut self, ttl: Duration) -> Self {
+        self.listings = Some(Arc::new(ListingCache {
+            ttl,
+            clock: self.clock.clone(),
+            entries: RwLock::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /// This cache, also keeping the objects and listings found missing for `ttl`
+    pub(crate) fn with_missing(mut self, ttl: Duration) -> Self {
+        self.missing = Some(Arc::new(MissingCache {
+            ttl,
+            clock: self.clock.clone(),
+            entries: RwLock::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /// The listing cache, if listings are cached
+    pub(crate) fn listings(&self) -> Option<&ListingCache> {
+        self.listings.as_deref()
+    }
+
+    /// The cache of misses, if misses are cached
+    pub(crate) fn missing(&self) -> Option<&MissingCache> {
+        self.missing.as_deref()
+    }
+
+    /// The files listed for `uri`, if cached and the mode reads the cache
+    pub(crate) fn cached_listing(&self, uri: &str) -> Option<Vec<FileMeta>> {
+        self.listings().filter(|_| self.mode.reads())?.get(uri)
+    }
+
+    /// The message `key` was found missing with, if cached and the mode reads the cache
+    pub(crate) fn cached_miss(&self, key: &str) -> Option<String> {
+        self.missing().filter(|_| self.mode.reads())?.get(key)
+    }
+
+    /// Whether complete listings are to be passed to [`remember_listing`](Self::remember_listing)
+    pub(crate) fn remembers_listings(&self) -> bool {
+        self.listings.is_some() && self.mode.writes()
+    }
+
+    /// Cache the `files` listed for `uri`, which all start with `prefix`
+    pub(crate) fn remember_listing(&self, uri: String, prefix: String, files: Vec<FileMeta>) {
+        if let Some(listings) = self.listings().filter(|_| self.mode.writes()) {
+            listings.insert(uri, prefix, files);
+        }
+    }
+
+    /// Cache that GCS found nothing for `key`, whose objects would share `prefix`
+    pub(crate) fn remember_miss(&self, key: String, prefix: String, message: String) {
+        if let Some(missing) = self.missing().filter(|_| self.mode.writes()) {
+            missing.insert(key, prefix, message);
+        }
+    }
+
+    /// Whether an entry seen at `seen` is still fresh
+    fn fresh(&self, seen: SystemTime) -> bool {
+        self.ttl.is_none_or(|ttl| self.clock.now() < seen + ttl)
+    }
+
+    pub(crate) fn get(&self, path: &GCSPath) -> Option<CachedObject> {
+        if !self.mode.reads() {
+            return None;
+        }
+        let key = path.to_string();
+        let mut objects = self.objects.lock().unwrap();
+        let seen = objects.entries.get(&key)?.seen;
+        if !self.fresh(seen) {
+            objects.remove(&key);
+            return None;
+        }
+        objects.tick += 1;
+        let tick = objects.tick;
+        let entry = objects.entries.get_mut(&key)?;
+        let (object, previous) = (entry.object, std::mem::replace(&mut entry.last_used, tick));
+        objects.recency.remove(&previous);
+        objects.recency.insert(tick, key);
+        Some(object)
+    }
+
+    pub(crate) fn insert(&self, bucket: &str, object: &ObjectResource) {
+        if !self.mode.writes() {
+            return;
+        }
+        let key = format!("{}/{}", bucket, object.name);
+        if let Some(missing) = &self.missing {
+            missing.invalidate(&key);
+        }
+        let object = CachedObject {
+            size: object.size,
+            generation: object.generation,
+            updated: object.updated,
+            archived: object.archived(),
+        };
+        self.put(key, object, self.clock.now());
+    }
+
+    /// Cache `object` as seen at `seen`, evicting the least recently used entries to
+    /// make room
+    fn put(&self, key: String, object: CachedObject, seen: SystemTime) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut objects = self.objects.lock().unwrap();
+        objects.tick += 1;
+        let last_used = objects.tick;
+        let entry = Entry {
+            object,
+            seen,
+            last_used,
+        };
+        if let Some(old) = objects.entries.insert(key.clone(), entry) {
+            objects.recency.remove(&old.last_used);
+        }
+        objects.recency.insert(last_used, key);
+        while objects.entries.len() > self.capacity {
+            let oldest = match objects.recency.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            let oldest = objects.recency.remove(&oldest).unwrap();
+            objects.entries.remove(&oldest);
+        }
+    }
+
+    /// Every entry that has not expired, keyed by `bucket/key`, least recently used first
+    pub(crate) fn entries(&self) -> Vec<(String, CachedObject)> {
+        let objects = self.objects.lock().unwrap();
+        objects
+            .recency
+            .values()
+            .filter_map(|key| {
+                let entry = objects.entries.get(key)?;
+                self.fresh(entry.seen).then(|| (key.clone(), entry.object))
+            })
+            .collect()
+    }
+
+    /// Restore an entry taken from [`entries`](Self::entries), as if it was seen now
+    pub(crate) fn restore(&self, key: String, object: CachedObject) {
+        self.put(key, object, self.clock.now());
+    }
+
+    pub(crate) fn remove(&self, path: &GCSPath) {
+        let path = path.to_string();
+        self.objects.lock().unwrap().remove(&path);
+        if let Some(listings) = &self.listings {
+            listings.invalidate(&path);
+        }
+        // the object is about to be written, or was just deleted
+        if let Some(missing) = &self.missing {
+            missing.invalidate(&path);
+        }
+    }
+
+    /// Drop every entry under `prefix`, e.g. a renamed folder
+    pub(crate) fn remove_prefix(&self, prefix: &GCSPath) {
+        let prefix = prefix.to_string();
+        self.objects
+            .lock()
+            .unwrap()
+            .retain(|key, _| !key.starts_with(&prefix));
+        if let Some(listings) = &self.listings {
+            listings.invalidate(&prefix);
+        }
+        if let Some(missing) = &self.missing {
+            missing.invalidate(&prefix);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::clock::MockClock;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn file(path: &str) -> FileMeta {
+        FileMeta {
+            sized_file: SizedFile {
+                path: path.to_string(),
+                size: 1,
+            },
+            last_modified: None,
+        }
+    }
+
+    fn object(name: &str) -> ObjectResource {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "size": "1",
+            "updated": "2022-01-01T00:00:00Z",
+            "generation": "7",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn generations_live_from_creation_until_deletion() {
+        let time = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        let mut object = ObjectMetadata::new("bucket", object("a.csv"));
+        object.created = Some(time("2022-01-01T00:00:00Z"));
+        object.updated = time("2022-01-02T00:00:00Z");
+        assert!(!object.live_at(time("2021-12-31T23:59:59Z")));
+        assert!(object.live_at(time("2022-01-01T00:00:00Z")));
+        assert!(object.live_at(time("2030-01-01T00:00:00Z")));
+
+        object.deleted = Some(time("2022-01-03T00:00:00Z"));
+        assert!(object.live_at(time("2022-01-02T23:59:59Z")));
+        assert!(!object.live_at(time("2022-01-03T00:00:00Z")));
+
+        // listings without a creation time fall back to the last update
+        object.created = None;
+        assert!(!object.live_at(time("2022-01-01T00:00:00Z")));
+        assert!(object.live_at(time("2022-01-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn listings_expire_after_the_ttl() {
+        let clock = Arc::new(MockClock::default());
+        let cache = MetadataCache::new(10, None, clock.clone()).with_listings(TTL);
+        let listings = cache.listings().unwrap();
+        listings.insert(
+            "bucket/data/*.csv".to_string(),
+            "bucket/data/".to_string(),
+            vec![file("bucket/data/a.csv")],
+        );
+
+        clock.advance(TTL - Duration::from_secs(1));
+        assert_eq!(
+            listings.get("bucket/data/*.csv"),
+            Some(vec![file("bucket/data/a.csv")])
+        );
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(listings.get("bucket/data/*.csv"), None);
+    }
+
+    #[test]
+    fn writes_invalidate_overlapping_listings() {
+        let clock = Arc::new(MockClock::default());
+        let cache = MetadataCache::new(10, None, clock).with_listings(TTL);
+        let listings = cache.listings().unwrap();
+        listings.insert("bucket/data/".into(), "bucket/data/".into(), vec![]);
+        listings.insert("bucket/other/".into(), "bucket/other/".into(), vec![]);
+
+        cache.remove(&GCSPath::parse("bucket/data/b.csv").unwrap());
+        assert_eq!(listings.get("bucket/data/"), None);
+        assert_eq!(listings.get("bucket/other/"), Some(vec![]));
+    }
+
+    #[test]
+    fn misses_expire_after_the_ttl() {
+        let clock = Arc::new(MockClock::default());
+        let cache = MetadataCache::new(10, None, clock.clone()).with_missing(TTL);
+        let missing = cache.missing().unwrap();
+        missing.insert(
+            "bucket/dt=2022-01-02/".into(),
+            "bucket/dt=2022-01-02/".into(),
+            "no such prefix".into(),
+        );
+
+        assert_eq!(
+            missing.get("bucket/dt=2022-01-02/").as_deref(),
+            Some("no such prefix")
+        );
+        clock.advance(TTL);
+        assert_eq!(missing.get("bucket/dt=2022-01-02/"), None);
+    }
+
+    #[test]
+    fn found_objects_clear_misses() {
+        let clock = Arc::new(MockClock::default());
+        let cache = MetadataCache::new(10, None, clock).with_missing(TTL);
+        let missing = cache.missing().unwrap();
+        missing.insert("bucket/a.csv".into(), "bucket/a.csv".into(), "gone".into());
+        missing.insert("bucket/b.csv".into(), "bucket/b.csv".into(), "gone".into());
+
+        cache.insert("bucket", &object("a.csv"));
+        assert_eq!(missing.get("bucket/a.csv"), None);
+        assert_eq!(missing.get("bucket/b.csv").as_deref(), Some("gone"));
+        let cached = cache.get(&GCSPath::parse("bucket/a.csv").unwrap()).unwrap();
+        assert_eq!((cached.size, cached.generation), (1, 7));
+    }
+
+    #[test]
+    fn objects_expire_after_the_ttl() {
+        let clock = Arc::new(MockClock::default());
+        let cache = MetadataCache::new(10, Some(TTL), clock.clone());
+        cache.insert("bucket", &object("a.csv"));
+        let path = GCSPath::parse("bucket/a.csv").unwrap();
+
+        clock.advance(TTL - Duration::from_secs(1));
+        assert!(cache.get(&path).is_some());
+        // reads do not extend the TTL
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get(&path), None);
+        assert!(cache.entries().is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_objects_are_evicted() {
+        let cache = MetadataCache::new(2, None, Arc::new(MockClock::default()));
+        let path = |name: &str| GCSPath::new("bucket", name);
+        cache.insert("bucket", &object("a"));
+        cache.insert("bucket", &object("b"));
+        assert!(cache.get(&path("a")).is_some());
+        cache.insert("bucket", &object("c"));
+
+        assert!(cache.get(&path("a")).is_some());
+        assert_eq!(cache.get(&path("b")), None);
+        assert!(cache.get(&path("c")).is_some());
+        let keys: Vec<_> = cache.entries().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["bucket/a", "bucket/c"]);
+
+        cache.remove(&path("a"));
+        cache.insert("bucket", &object("d"));
+        assert!(cache.get(&path("c")).is_some());
+        assert_eq!(cache.entries().len(), 2);
+
+        let disabled = MetadataCache::new(0, None, Arc::new(MockClock::default()));
+        disabled.insert("bucket", &object("a"));
+        assert_eq!(disabled.get(&path("a")), None);
+    }
+
+    #[test]
+    fn prefixes_are_removed() {
+        let cache = MetadataCache::new(10, None, Arc::new(MockClock::default()));
+        for name in ["data/a", "data/b", "database/a"] {
+            cache.insert("bucket", &object(name));
+        }
+        cache.remove_prefix(&GCSPath::new("bucket", "data/"));
+        let keys: Vec<_> = cache.entries().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["bucket/database/a"]);
+    }
+}
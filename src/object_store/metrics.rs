@@ -0,0 +1,129 @@
+//! Instrumentation hook for the requests a store sends
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What a request to GCS does, for attributing requests and bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequestKind {
+    /// A page of an object listing
+    List,
+    /// An object metadata lookup
+    Metadata,
+    /// A download of object data
+    Download,
+    /// A multipart upload or a request of a resumable upload session
+    Upload,
+    /// An object deletion
+    Delete,
+    /// A rewrite call of a copy
+    Copy,
+    /// A compose call
+    Compose,
+    /// A folder rename or a poll of its long-running operation
+    Folder,
+}
+
+/// One HTTP attempt, reported once its response headers arrived or it failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestEvent {
+    /// What the request does
+    pub kind: RequestKind,
+    /// Bucket the request went to, if it names one
+    pub bucket: Option<String>,
+    /// `1` for the first attempt, higher for retries
+    pub attempt: u32,
+    /// HTTP status, `None` if no response arrived
+    pub status: Option<u16>,
+    /// Time until the response headers arrived or the request failed
+    pub latency: Duration,
+}
+
+/// Receives request and transfer events of a `GCSFileSystem`.
+///
+/// Methods are called on the I/O path, so implementations should only update counters
+/// or hand events off, e.g. to the `metrics` or `prometheus` crates.
+pub trait Metrics: Debug + Send + Sync {
+    /// Called for every HTTP attempt, including retries
+    fn request(&self, event: &RequestEvent);
+
+    /// Called with the size of every downloaded response body
+    fn bytes_downloaded(&self, _kind: RequestKind, _bytes: u64) {}
+
+    /// Called with the size of every uploaded request body
+    fn bytes_uploaded(&self, _kind: RequestKind, _bytes: u64) {}
+}
+
+/// Metrics implementation that discards every event, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {
+    fn request(&self, _event: &RequestEvent) {}
+}
+
+/// Totals of the requests of one kind
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestTotals {
+    /// HTTP attempts, including retries
+    pub requests: u64,
+    /// Attempts after the first one of a request
+    pub retries: u64,
+    /// Attempts that failed or returned an unsuccessful status
+    pub failures: u64,
+    /// Sum of the latencies of all attempts
+    pub latency: Duration,
+    /// Bytes of response bodies
+    pub bytes_downloaded: u64,
+    /// Bytes of request bodies
+    pub bytes_uploaded: u64,
+}
+
+/// Metrics implementation that keeps totals per request kind in memory, e.g. to log
+/// what a query cost once it finished
+#[derive(Debug, Default)]
+pub struct MetricsRecorder {
+    totals: Mutex<BTreeMap<RequestKind, RequestTotals>>,
+}
+
+impl MetricsRecorder {
+    /// Create a recorder without any events
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Totals recorded so far, by request kind
+    pub fn totals(&self) -> BTreeMap<RequestKind, RequestTotals> {
+        self.totals.lock().unwrap().clone()
+    }
+
+    /// Forget every event recorded so far
+    pub fn reset(&self) {
+        self.totals.lock().unwrap().clear();
+    }
+
+    fn update(&self, kind: RequestKind, update: impl FnOnce(&mut RequestTotals)) {
+        update(self.totals.lock().unwrap().entry(kind).or_default());
+    }
+}
+
+impl Metrics for MetricsRecorder {
+    fn request(&self, event: &RequestEvent) {
+        self.update(event.kind, |totals| {
+            totals.requests += 1;
+            totals.retries += (event.attempt > 1) as u64;
+            totals.failures += !matches!(event.status, Some(200..=299 | 308)) as u64;
+            totals.latency += event.latency;
+        });
+    }
+
+    fn bytes_downloaded(&self, kind: RequestKind, bytes: u64) {
+        self.update(kind, |totals| totals.bytes_downloaded += bytes);
+    }
+
+    fn bytes_uploaded(&self, kind: RequestKind, bytes: u64) {
+        self.update(kind, |totals| totals.bytes_uploaded += bytes);
+    }
+}
@@ -0,0 +1,492 @@
+//! In-memory stand-in for `GCSFileSystem` to test query code without GCS
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, ErrorKind, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use datafusion::datafusion_data_access::object_store::{
+    FileMetaStream, ListEntryStream, ObjectReader, ObjectStore,
+};
+use datafusion::datafusion_data_access::{FileMeta, ListEntry, Result, SizedFile};
+use futures::{stream, AsyncRead};
+
+use crate::error::GCSError;
+use crate::object_store::gcs;
+use crate::object_store::metadata::ObjectMetadata;
+use crate::object_store::path::{GCSPath, GLOB_OPTIONS};
+use crate::object_store::writer::{self, Precondition};
+
+#[derive(Debug, Clone)]
+struct MockObject {
+    data: Bytes,
+    generation: u64,
+    updated: DateTime<Utc>,
+}
+
+/// `ObjectStore` keeping objects in memory, with the URIs, listing semantics and error
+/// kinds of [`GCSFileSystem`](crate::object_store::gcs::GCSFileSystem), to unit-test code
+/// that reads and writes `gs://` URIs without network access or an emulator.
+///
+/// Register it for the `gs` and `gcs` schemes of a `RuntimeEnv` in place of the real
+/// store. Load fixtures with [`with_object`](Self::with_object) or
+/// [`load_dir`](Self::load_dir).
+#[derive(Debug, Default)]
+pub struct MockGCSFileSystem {
+    /// Objects by `bucket/key`
+    objects: RwLock<BTreeMap<String, MockObject>>,
+    generations: AtomicU64,
+}
+
+impl MockGCSFileSystem {
+    /// Create a store without any objects
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the object `uri` with `data`, for setting up fixtures
+    pub fn with_object(self, uri: &str, data: impl Into<Bytes>) -> Result<Self> {
+        self.insert(&object_path(uri)?, data.into());
+        Ok(self)
+    }
+
+    /// Add every file below the local directory `dir` as an object under `uri`, keeping
+    /// its relative path, e.g. to serve checked-in Parquet fixtures from
+    /// `gs://bucket/tables/`. Returns the number of objects added.
+    pub fn load_dir(&self, dir: impl AsRef<Path>, uri: &str) -> Result<usize> {
+        let root = GCSPath::parse(uri)?;
+        let prefix = match root.key.trim_end_matches('/') {
+            "" => String::new(),
+            key => format!("{}/", key),
+        };
+        let mut pending = vec![dir.as_ref().to_path_buf()];
+        let mut added = 0;
+        while let Some(directory) = pending.pop() {
+            for entry in std::fs::read_dir(&directory)? {
+                let file = entry?.path();
+                if file.is_dir() {
+                    pending.push(file);
+                    continue;
+                }
+                let relative = file
+                    .strip_prefix(dir.as_ref())
+                    .map_err(std::io::Error::other)?
+                    .components()
+                    .map(|part| part.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let path = GCSPath {
+                    bucket: root.bucket.clone(),
+                    key: format!("{}{}", prefix, relative),
+                };
+                self.insert(&path, Bytes::from(std::fs::read(&file)?));
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// The data of the object at `uri`, e.g. to check what a query wrote
+    pub fn get(&self, uri: &str) -> Result<Bytes> {
+        Ok(self.object(&object_path(uri)?)?.data)
+    }
+
+    /// `bucket/key` of every object, in order
+    pub fn paths(&self) -> Vec<String> {
+        self.objects.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Size and modification time of the object at `uri`
+    pub async fn head(&self, uri: &str) -> Result<FileMeta> {
+        let path = object_path(uri)?;
+        let object = self.object(&path)?;
+        Ok(file_meta(&path.to_string(), &object))
+    }
+
+    /// The `SizedFile` to read the object at `uri` through `file_reader`
+    pub async fn sized_file(&self, uri: &str) -> Result<SizedFile> {
+        Ok(self.head(uri).await?.sized_file)
+    }
+
+    /// Metadata of the object at `uri`; content type, storage class and custom metadata
+    /// are what this crate's writes would set
+    pub async fn metadata(&self, uri: &str) -> Result<ObjectMetadata> {
+        let path = object_path(uri)?;
+        let object = self.object(&path)?;
+        Ok(ObjectMetadata {
+            path: path.to_string(),
+            size: object.data.len() as u64,
+            crc32c: Some(base64::encode(crc32c::crc32c(&object.data).to_be_bytes())),
+            generation: object.generation,
+            metageneration: 1,
+            etag: Some(object.generation.to_string()),
+            updated: object.updated,
+            content_type: Some(writer::content_type(&path.key).to_string()),
+            content_encoding: None,
+            storage_class: Some("STANDARD".to_string()),
+            custom: BTreeMap::new(),
+            created: Some(object.updated),
+            deleted: None,
+        })
+    }
+
+    /// Store `data` as the object at `uri`, replacing any existing object
+    pub async fn put(&self, uri: &str, data: impl Into<Vec<u8>>) -> Result<()> {
+        self.insert(&object_path(uri)?, Bytes::from(data.into()));
+        Ok(())
+    }
+
+    /// Store `data` as the object at `uri` only if `precondition` holds, and return the
+    /// generation of the written object
+    pub async fn put_if(
+        &self,
+        uri: &str,
+        data: impl Into<Vec<u8>>,
+        precondition: Precondition,
+    ) -> Result<u64> {
+        let path = object_path(uri)?;
+        let mut objects = self.objects.write().unwrap();
+        let existing = objects.get(&path.to_string()).map(|o| o.generation);
+        let holds = match precondition {
+            Precondition::DoesNotExist => existing.is_none(),
+            Precondition::GenerationMatch(generation) => existing == Some(generation),
+        };
+        if !holds {
+            return Err(GCSError::PreconditionFailed {
+                bucket: Some(path.bucket),
+                key: Some(path.key),
+                status: 412,
+                message: format!("{:?} does not hold", precondition),
+            }
+            .into());
+        }
+        let object = self.new_object(Bytes::from(data.into()));
+        let generation = object.generation;
+        objects.insert(path.to_string(), object);
+        Ok(generation)
+    }
+
+    /// Current generation of the object at `uri`
+    pub async fn generation(&self, uri: &str) -> Result<u64> {
+        Ok(self.object(&object_path(uri)?)?.generation)
+    }
+
+    /// Delete the object at `uri`
+    pub async fn delete(&self, uri: &str) -> Result<()> {
+        let path = object_path(uri)?;
+        match self.objects.write().unwrap().remove(&path.to_string()) {
+            Some(_) => Ok(()),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    /// Delete every object under the prefix `uri` and return how many were deleted
+    pub async fn delete_prefix(&self, uri: &str) -> Result<usize> {
+        let path = GCSPath::parse(uri)?;
+        let prefix = path.to_string();
+        let mut objects = self.objects.write().unwrap();
+        let before = objects.len();
+        objects.retain(|name, _| !name.starts_with(&prefix));
+        Ok(before - objects.len())
+    }
+
+    /// Copy the object at `from` to `to`
+    pub async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let data = self.object(&object_path(from)?)?.data;
+        self.insert(&object_path(to)?, data);
+        Ok(())
+    }
+
+    /// Move the object at `from` to `to`
+    pub async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.copy(from, to).await?;
+        self.delete(from).await
+    }
+
+    /// Names of the Hive-style partition columns of the table at `uri`, see
+    /// [`GCSFileSystem::partition_columns`](crate::object_store::gcs::GCSFileSystem::partition_columns)
+    pub async fn partition_columns(&self, uri: &str) -> Result<Vec<String>> {
+        gcs::list_partition_columns(self, uri).await
+    }
+
+    fn insert(&self, path: &GCSPath, data: Bytes) {
+        let object = self.new_object(data);
+        self.objects
+            .write()
+            .unwrap()
+            .insert(path.to_string(), object);
+    }
+
+    fn new_object(&self, data: Bytes) -> MockObject {
+        MockObject {
+            data,
+            generation: self.generations.fetch_add(1, Ordering::SeqCst) + 1,
+            updated: Utc::now(),
+        }
+    }
+
+    fn object(&self, path: &GCSPath) -> Result<MockObject> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(&path.to_string())
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for MockGCSFileSystem {
+    async fn list_file(&self, uri: &str) -> Result<FileMetaStream> {
+        let path = GCSPath::parse(uri)?;
+        let (prefix, pattern) = path.split_glob()?;
+        let start = format!("{}/{}", path.bucket, prefix);
+        let files: Vec<_> = self
+            .objects
+            .read()
+            .unwrap()
+            .range(start.clone()..)
+            .take_while(|(name, _)| name.starts_with(&start))
+            .filter(|(name, _)| match &pattern {
+                Some(pattern) => pattern.matches_with(&name[path.bucket.len() + 1..], GLOB_OPTIONS),
+                None => true,
+            })
+            .map(|(name, object)| Ok(file_meta(name, object)))
+            .collect();
+        if files.is_empty() {
+            return Err(not_found(&path));
+        }
+        Ok(Box::pin(stream::iter(files)))
+    }
+
+    async fn list_dir(&self, prefix: &str, delimiter: Option<String>) -> Result<ListEntryStream> {
+        let path = GCSPath::parse(prefix)?;
+        let delimiter = delimiter.unwrap_or_else(|| "/".to_string());
+        let start = path.to_string();
+        let mut prefixes = BTreeSet::new();
+        let mut files = vec![];
+        for (name, object) in self.objects.read().unwrap().range(start.clone()..) {
+            let rest = match name.strip_prefix(&start) {
+                Some(rest) => rest,
+                None => break,
+            };
+            match rest.find(delimiter.as_str()) {
+                Some(end) => {
+                    prefixes.insert(format!("{}{}", start, &rest[..end + delimiter.len()]));
+                }
+                None => files.push(ListEntry::FileMeta(file_meta(name, object))),
+            }
+        }
+        let entries = prefixes
+            .into_iter()
+            .map(ListEntry::Prefix)
+            .chain(files)
+            .map(Ok)
+            .collect::<Vec<_>>();
+        Ok(Box::pin(stream::iter(entries)))
+    }
+
+    fn file_reader(&self, file: SizedFile) -> Result<Arc<dyn ObjectReader>> {
+        let data = self.object(&GCSPath::parse(&file.path)?)?.data;
+        Ok(Arc::new(MockFileReader { data }))
+    }
+}
+
+/// Reader of the data an object had when the reader was created
+struct MockFileReader {
+    data: Bytes,
+}
+
+impl MockFileReader {
+    /// `length` bytes from `start`, or the rest of the data for `0`
+    fn range(&self, start: u64, length: usize) -> Bytes {
+        let start = (start as usize).min(self.data.len());
+        let end = match length {
+            0 => self.data.len(),
+            length => (start + length).min(self.data.len()),
+        };
+        self.data.slice(start..end)
+    }
+}
+
+#[async_trait]
+impl ObjectReader for MockFileReader {
+    async fn chunk_reader(&self, start: u64, length: usize) -> Result<Box<dyn AsyncRead>> {
+        Ok(Box::new(futures::io::Cursor::new(
+            self.range(start, length),
+        )))
+    }
+
+    fn sync_chunk_reader(&self, start: u64, length: usize) -> Result<Box<dyn Read + Send + Sync>> {
+        Ok(Box::new(Cursor::new(self.range(start, length))))
+    }
+
+    fn length(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
+
+fn file_meta(path: &str, object: &MockObject) -> FileMeta {
+    FileMeta {
+        sized_file: SizedFile {
+            path: path.to_string(),
+            size: object.data.len() as u64,
+        },
+        last_modified: Some(object.updated),
+    }
+}
+
+fn object_path(uri: &str) -> Result<GCSPath> {
+    let path = GCSPath::parse(uri)?;
+    if path.key.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            GCSError::GCS(format!("No object key found in {}", uri)),
+        ));
+    }
+    Ok(path)
+}
+
+fn not_found(path: &GCSPath) -> std::io::Error {
+    GCSError::NotFound {
+        bucket: Some(path.bucket.clone()),
+        key: Some(path.key.clone()),
+        message: "No such object in the mock store".to_string(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{AsyncReadExt, TryStreamExt};
+
+    use super::*;
+
+    fn store() -> MockGCSFileSystem {
+        MockGCSFileSystem::new()
+            .with_object("gs://bucket/data/a.csv", "a,b\n1,2\n")
+            .unwrap()
+            .with_object("gs://bucket/data/b.parquet", "parquet")
+            .unwrap()
+            .with_object("gs://bucket/data/part=1/c.csv", "c")
+            .unwrap()
+            .with_object("gs://bucket/database/d.csv", "d")
+            .unwrap()
+    }
+
+    fn kind(result: Result<impl Sized>) -> ErrorKind {
+        result.err().expect("an error").kind()
+    }
+
+    #[tokio::test]
+    async fn reads_ranges() {
+        let store = store();
+        let file = store.sized_file("gs://bucket/data/a.csv").await.unwrap();
+        let reader = store.file_reader(file).unwrap();
+        assert_eq!(reader.length(), 8);
+        for (start, length, expected) in [(0, 0, "a,b\n1,2\n"), (4, 3, "1,2"), (6, 10, "2\n")] {
+            let mut sync = String::new();
+            reader
+                .sync_chunk_reader(start, length)
+                .unwrap()
+                .read_to_string(&mut sync)
+                .unwrap();
+            assert_eq!(sync, expected);
+            let mut read = String::new();
+            Box::into_pin(reader.chunk_reader(start, length).await.unwrap())
+                .read_to_string(&mut read)
+                .await
+                .unwrap();
+            assert_eq!(read, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_like_gcs() {
+        let store = store();
+        let paths = |files: Vec<FileMeta>| {
+            files
+                .into_iter()
+                .map(|file| file.sized_file.path)
+                .collect::<Vec<_>>()
+        };
+        let files = store.list_file("gs://bucket/data/").await.unwrap();
+        assert_eq!(
+            paths(files.try_collect().await.unwrap()),
+            [
+                "bucket/data/a.csv",
+                "bucket/data/b.parquet",
+                "bucket/data/part=1/c.csv"
+            ]
+        );
+        let files = store.list_file("gs://bucket/data/*.csv").await.unwrap();
+        assert_eq!(
+            paths(files.try_collect().await.unwrap()),
+            ["bucket/data/a.csv"]
+        );
+        assert_eq!(
+            kind(store.list_file("gs://bucket/missing/").await),
+            ErrorKind::NotFound
+        );
+
+        let entries: Vec<_> = store
+            .list_dir("gs://bucket/data/", None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let entries = entries
+            .into_iter()
+            .map(|entry| match entry {
+                ListEntry::Prefix(prefix) => prefix,
+                ListEntry::FileMeta(file) => file.sized_file.path,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                "bucket/data/part=1/",
+                "bucket/data/a.csv",
+                "bucket/data/b.parquet"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_with_preconditions() {
+        let store = store();
+        let uri = "gs://bucket/out/result.csv";
+        let generation = store
+            .put_if(uri, "first", Precondition::DoesNotExist)
+            .await
+            .unwrap();
+        assert_eq!(store.generation(uri).await.unwrap(), generation);
+        assert_eq!(
+            kind(store.put_if(uri, "again", Precondition::DoesNotExist).await),
+            ErrorKind::Other
+        );
+        store
+            .put_if(uri, "second", Precondition::GenerationMatch(generation))
+            .await
+            .unwrap();
+        assert_eq!(store.get(uri).unwrap(), "second");
+        assert!(store.generation(uri).await.unwrap() > generation);
+
+        store
+            .rename(uri, "gs://bucket/out/moved.csv")
+            .await
+            .unwrap();
+        assert_eq!(kind(store.get(uri)), ErrorKind::NotFound);
+        assert_eq!(store.delete_prefix("gs://bucket/out/").await.unwrap(), 1);
+        assert_eq!(kind(store.delete(uri).await), ErrorKind::NotFound);
+        assert_eq!(
+            kind(store.put("gs://bucket", "no key").await),
+            ErrorKind::InvalidInput
+        );
+    }
+}
@@ -1,3 +1,32 @@
 //! `ObjectStore` implementation for the Goolge Cloud Storage API
 
-pub mod gcs;
\ No newline at end of file
+#[cfg(feature = "object_store")]
+pub mod adapter;
+pub mod api;
+pub mod auth;
+pub mod cache;
+pub mod clock;
+pub mod disk_cache;
+pub mod encryption;
+pub mod filter;
+pub mod gcs;
+#[cfg(feature = "grpc")]
+pub(crate) mod grpc;
+pub(crate) mod limiter;
+pub mod manifest;
+pub mod metadata;
+pub mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod path;
+pub mod progress;
+pub mod provider;
+pub mod refresh;
+pub mod retry;
+pub mod sandbox;
+pub mod signing;
+pub mod snapshot;
+#[cfg(test)]
+pub(crate) mod test_server;
+pub mod throttle;
+pub mod writer;
@@ -0,0 +1,309 @@
+//! Parsing of `gs://` and `gcs://` object locations, and their encoding in request URLs
+
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+
+use datafusion::datafusion_data_access::Result;
+use glob::{MatchOptions, Pattern};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::error::GCSError;
+
+/// URI schemes understood by this crate. `gs` is what gsutil, BigQuery and Spark use.
+pub const SCHEMES: [&str; 2] = ["gs", "gcs"];
+
+const GLOB_CHARS: &[char] = &['*', '?', '['];
+
+/// Everything but RFC 3986 unreserved characters is escaped in path segments and query
+/// values, including the `/`, `#`, `?`, spaces and non-ASCII characters of object names
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Object names in the path of XML API and signed URLs keep `/` unescaped
+const PATH: &AsciiSet = &UNRESERVED.remove(b'/');
+
+/// `*` and `?` stay within one path segment, as in a shell
+pub const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Bucket and object key (or key prefix) of a location in GCS.
+///
+/// Keys are kept exactly as GCS names the objects, with spaces, `#`, `?` and non-ASCII
+/// characters unescaped, so the `bucket/key` paths of listings parse back to the same
+/// objects. They are only percent-encoded when placed in request URLs. In listed URIs
+/// `*`, `?` and `[` are glob characters; see [`escape_glob`] to list keys containing them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GCSPath {
+    /// Bucket name
+    pub bucket: String,
+    /// Object name or prefix within the bucket, without a leading `/`
+    pub key: String,
+}
+
+impl GCSPath {
+    /// Create a path from a bucket and key
+    pub fn new(bucket: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            bucket: bucket.into(),
+            key: key.into(),
+        }
+    }
+
+    /// Parse `gs://bucket/key`, `gcs://bucket/key` or a bare `bucket/key` as produced by
+    /// listings. Any other scheme is rejected.
+    pub fn parse(uri: &str) -> Result<Self> {
+        let path = match uri.split_once("://") {
+            Some((scheme, path)) if SCHEMES.contains(&scheme) => path,
+            Some((scheme, _)) => {
+                return Err(invalid_input(format!(
+                    "Unsupported scheme {} in {}, expected gs:// or gcs://",
+                    scheme, uri
+                )))
+            }
+            None => uri,
+        };
+        let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(invalid_input(format!("No bucket found in {}", uri)));
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    /// The key escaped for use as a single JSON API path segment, e.g. `/o/{key}`
+    pub fn encoded_key(&self) -> String {
+        encode(&self.key)
+    }
+
+    /// Split the key into the longest literal prefix to list and, if the key contains glob
+    /// characters, the pattern listed object names have to match. Glob characters escaped
+    /// as `[*]`, `[?]`, `[[]` or `[]]` are literal and do not make the key a pattern.
+    pub fn split_glob(&self) -> Result<(String, Option<Pattern>)> {
+        let mut prefix = String::new();
+        let mut rest = self.key.as_str();
+        while let Some(index) = rest.find(GLOB_CHARS) {
+            prefix.push_str(&rest[..index]);
+            rest = &rest[index..];
+            match escaped_glob_char(rest) {
+                Some(c) => {
+                    prefix.push(c);
+                    rest = &rest[3..];
+                }
+                None => {
+                    let pattern = Pattern::new(&self.key).map_err(|err| {
+                        invalid_input(format!("Invalid glob pattern {}: {}", self.key, err))
+                    })?;
+                    return Ok((prefix, Some(pattern)));
+                }
+            }
+        }
+        prefix.push_str(rest);
+        Ok((prefix, None))
+    }
+}
+
+/// Escape the glob characters of `key`, so listing it matches objects whose names start
+/// with it literally, e.g. `gs://bucket/{escape_glob("reports [final]/")}`
+pub fn escape_glob(key: &str) -> String {
+    Pattern::escape(key)
+}
+
+/// The glob character at the start of `glob` if it is escaped as `[c]`
+fn escaped_glob_char(glob: &str) -> Option<char> {
+    match glob.as_bytes() {
+        [b'[', c @ (b'*' | b'?' | b'[' | b']'), b']', ..] => Some(*c as char),
+        _ => None,
+    }
+}
+
+/// `value` escaped for use as a single URL path segment or query value
+pub(crate) fn encode(value: &str) -> String {
+    utf8_percent_encode(value, UNRESERVED).to_string()
+}
+
+/// `value` escaped for use in a URL path, keeping its `/`s
+pub(crate) fn encode_path(value: &str) -> String {
+    utf8_percent_encode(value, PATH).to_string()
+}
+
+/// Bucket and object named by a JSON or upload API `url`
+pub(crate) fn resource(url: &str) -> (Option<String>, Option<String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let rest = match path.split_once("/b/") {
+        Some((_, rest)) => rest,
+        None => return (None, None),
+    };
+    let (bucket, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    let key = match rest.strip_prefix("o/") {
+        Some(rest) => rest.split('/').next().map(decode),
+        // uploads name the object in the query, form-encoded with `+` for spaces
+        None => query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("name="))
+            .map(|key| decode(&key.replace('+', " "))),
+    };
+    (Some(bucket.to_string()), key.filter(|key| !key.is_empty()))
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+impl GCSPath {
+    /// Hive-style `column=value` partitions named by the directories of the key, outermost
+    /// first, e.g. `[("dt", "2023-01-01"), ("region", "us")]` for
+    /// `table/dt=2023-01-01/region=us/part-0.parquet`
+    pub fn partitions(&self) -> Vec<(&str, &str)> {
+        let directories = self
+            .key
+            .rsplit_once('/')
+            .map_or("", |(directories, _)| directories);
+        directories.split('/').filter_map(partition).collect()
+    }
+}
+
+/// Split a `column=value` directory name
+pub(crate) fn partition(directory: &str) -> Option<(&str, &str)> {
+    let (column, value) = directory.split_once('=')?;
+    (!column.is_empty()).then_some((column, value))
+}
+
+impl Display for GCSPath {
+    /// Formats as `bucket/key`, the form used for `SizedFile` paths
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.bucket, self.key)
+    }
+}
+
+fn invalid_input(desc: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidInput, GCSError::GCS(desc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(key: &str) -> (String, Option<String>) {
+        let (prefix, pattern) = GCSPath::new("bucket", key).split_glob().unwrap();
+        (prefix, pattern.map(|p| p.as_str().to_string()))
+    }
+
+    #[test]
+    fn literal_keys_are_not_patterns() {
+        assert_eq!(split("data/a.csv"), ("data/a.csv".to_string(), None));
+        assert_eq!(split(""), (String::new(), None));
+        assert_eq!(
+            split("data/[*]/a[?].csv"),
+            ("data/*/a?.csv".to_string(), None)
+        );
+        assert_eq!(
+            split(&escape_glob("reports [final]/")),
+            ("reports [final]/".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn patterns_are_listed_from_their_literal_prefix() {
+        assert_eq!(
+            split("data/dt=*/part-?.csv"),
+            (
+                "data/dt=".to_string(),
+                Some("data/dt=*/part-?.csv".to_string())
+            )
+        );
+        assert_eq!(
+            split("data/[*]x/*.csv"),
+            ("data/*x/".to_string(), Some("data/[*]x/*.csv".to_string()))
+        );
+        assert_eq!(split("*.csv").0, "");
+    }
+
+    #[test]
+    fn wildcards_stay_within_a_directory() {
+        let (_, pattern) = GCSPath::new("bucket", "data/*.csv").split_glob().unwrap();
+        let pattern = pattern.unwrap();
+        assert!(pattern.matches_with("data/a.csv", GLOB_OPTIONS));
+        assert!(pattern.matches_with("data/.a.csv", GLOB_OPTIONS));
+        assert!(!pattern.matches_with("data/x/a.csv", GLOB_OPTIONS));
+        assert!(!pattern.matches_with("data/A.CSV", GLOB_OPTIONS));
+    }
+
+    const NAMES: [&str; 6] = [
+        "plain.csv",
+        "dir/sub dir/a b.csv",
+        "reports #1?/q=1&r=2.csv",
+        "100% + more.csv",
+        "caf\u{e9}/\u{65e5}\u{672c}.csv",
+        "a/b//c~_-.csv",
+    ];
+
+    #[test]
+    fn encoding_escapes_everything_but_unreserved_characters() {
+        assert_eq!(encode("a b/c#d?e"), "a%20b%2Fc%23d%3Fe");
+        assert_eq!(encode("50%+1&x=y"), "50%25%2B1%26x%3Dy");
+        assert_eq!(encode("caf\u{e9}"), "caf%C3%A9");
+        assert_eq!(encode("a-b.c_d~e"), "a-b.c_d~e");
+        assert_eq!(encode_path("dir/a b#.csv"), "dir/a%20b%23.csv");
+        assert_eq!(
+            GCSPath::new("bucket", "dir/a b.csv").encoded_key(),
+            "dir%2Fa%20b.csv"
+        );
+    }
+
+    #[test]
+    fn names_round_trip_through_request_urls() {
+        for name in NAMES {
+            let url = format!(
+                "https://storage.googleapis.com/storage/v1/b/bucket/o/{}?alt=media",
+                encode(name)
+            );
+            assert_eq!(
+                resource(&url),
+                (Some("bucket".to_string()), Some(name.to_string()))
+            );
+            // uploads name the object in the query instead
+            let url = format!(
+                "https://storage.googleapis.com/upload/storage/v1/b/bucket/o?uploadType=media&name={}",
+                encode(name)
+            );
+            assert_eq!(resource(&url).1.as_deref(), Some(name));
+        }
+        let url = "https://storage.googleapis.com/upload/storage/v1/b/bucket/o?name=a+b.csv";
+        assert_eq!(resource(url).1.as_deref(), Some("a b.csv"));
+    }
+
+    #[test]
+    fn names_round_trip_through_listed_paths() {
+        for name in NAMES {
+            let path = GCSPath::new("bucket", name);
+            assert_eq!(GCSPath::parse(&path.to_string()).unwrap(), path);
+            assert_eq!(GCSPath::parse(&format!("gs://{}", path)).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn resources_without_objects() {
+        let url = "https://storage.googleapis.com/storage/v1/b/bucket/o?prefix=data%2F";
+        assert_eq!(resource(url), (Some("bucket".to_string()), None));
+        let url = "https://storage.googleapis.com/storage/v1/b/bucket";
+        assert_eq!(resource(url), (Some("bucket".to_string()), None));
+        assert_eq!(
+            resource("https://oauth2.googleapis.com/token"),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        let err = GCSPath::new("bucket", "data/[").split_glob().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
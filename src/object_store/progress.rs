@@ -0,0 +1,29 @@
+//! Observer of download progress, per object
+
+use std::fmt::Debug;
+
+/// A chunk of a download body that arrived
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadProgress<'a> {
+    /// `bucket/key` of the object, as used in `SizedFile`
+    pub path: &'a str,
+    /// Object offset of the first byte of the request
+    pub start: u64,
+    /// Bytes of the request received so far, including this chunk
+    pub received: u64,
+    /// Bytes the request is expected to return, if the response said
+    pub expected: Option<u64>,
+}
+
+/// Receives the progress of every download a `GCSFileSystem` and its readers make, e.g.
+/// to show per-file progress of queries over large objects or to detect stalled reads.
+///
+/// Called on the I/O path for every chunk of a body, so implementations should only
+/// record the event or hand it off.
+pub trait ProgressObserver: Debug + Send + Sync {
+    /// Called whenever a chunk of a download body arrived
+    fn progress(&self, event: &DownloadProgress<'_>);
+
+    /// Called once a download body arrived completely
+    fn finished(&self, _path: &str, _start: u64, _bytes: u64) {}
+}
@@ -0,0 +1,165 @@
+//! Lazily constructed, per-bucket `GCSFileSystem`s
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+
+use datafusion::datafusion_data_access::object_store::{
+    FileMetaStream, ListEntryStream, ObjectReader, ObjectStore,
+};
+use datafusion::datafusion_data_access::{Result, SizedFile};
+
+use crate::object_store::gcs::{GCSFileSystem, GCSFileSystemBuilder, IoRuntime};
+use crate::object_store::path::GCSPath;
+
+/// `ObjectStore` that creates a `GCSFileSystem` for each bucket the first time it is used.
+///
+/// Buckets registered with [`GCSObjectStoreProvider::with_bucket`] use their own builder,
+/// e.g. with the credentials, user project and endpoint of another project, or the store
+/// given to [`GCSObjectStoreProvider::with_bucket_store`]. All others are built from the
+/// default builder. Stores built by the provider share one I/O runtime. Register the
+/// provider once for the `gs` and `gcs` schemes instead of constructing a store for every
+/// bucket up front, and use [`store_for`](Self::store_for) for writes and the other
+/// operations only `GCSFileSystem` has.
+#[derive(Debug, Default)]
+pub struct GCSObjectStoreProvider {
+    default: GCSFileSystemBuilder,
+    buckets: HashMap<String, GCSFileSystemBuilder>,
+    stores: RwLock<Stores>,
+}
+
+#[derive(Debug, Default)]
+struct Stores {
+    by_bucket: HashMap<String, Arc<GCSFileSystem>>,
+    /// Runtime of the first store the provider built, which later ones share
+    io: Option<Arc<IoRuntime>>,
+}
+
+impl GCSObjectStoreProvider {
+    /// Create a provider building every bucket's store from `default`
+    pub fn new(default: GCSFileSystemBuilder) -> Self {
+        Self {
+            default,
+            ..Default::default()
+        }
+    }
+
+    /// Use `builder` instead of the default builder for `bucket`
+    pub fn with_bucket(mut self, bucket: impl Into<String>, builder: GCSFileSystemBuilder) -> Self {
+        self.buckets.insert(bucket.into(), builder);
+        self
+    }
+
+    /// Send the requests for `bucket` through `store`, which keeps its own I/O runtime
+    pub fn with_bucket_store(
+        self,
+        bucket: impl Into<String>,
+        store: impl Into<Arc<GCSFileSystem>>,
+    ) -> Self {
+        self.stores
+            .write()
+            .unwrap()
+            .by_bucket
+            .insert(bucket.into(), store.into());
+        self
+    }
+
+    /// Return the store for `bucket`, constructing it on first use
+    pub fn get_store(&self, bucket: &str) -> Arc<GCSFileSystem> {
+        if let Some(store) = self.stores.read().unwrap().by_bucket.get(bucket) {
+            return store.clone();
+        }
+
+        let mut stores = self.stores.write().unwrap();
+        if let Some(store) = stores.by_bucket.get(bucket) {
+            return store.clone();
+        }
+        let mut builder = self.buckets.get(bucket).unwrap_or(&self.default).clone();
+        if let Some(io) = &stores.io {
+            builder = builder.with_io_runtime(io.clone());
+        }
+        let store = Arc::new(builder.build());
+        stores.io.get_or_insert_with(|| store.io_runtime().clone());
+        stores.by_bucket.insert(bucket.to_string(), store.clone());
+        store
+    }
+
+    /// Return the store for the bucket of `uri`, constructing it on first use
+    pub fn store_for(&self, uri: &str) -> Result<Arc<GCSFileSystem>> {
+        Ok(self.get_store(&GCSPath::parse(uri)?.bucket))
+    }
+
+    /// The configuration of every store constructed so far by bucket, see
+    /// [`GCSFileSystem::describe`]
+    pub fn describe(&self) -> String {
+        let stores = self.stores.read().unwrap();
+        let stores = &stores.by_bucket;
+        let mut buckets = stores.keys().collect::<Vec<_>>();
+        buckets.sort();
+        buckets
+            .into_iter()
+            .map(|bucket| format!("bucket {}:\n{}", bucket, stores[bucket].describe()))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+#[async_trait]
+impl ObjectStore for GCSObjectStoreProvider {
+    async fn list_file(&self, uri: &str) -> Result<FileMetaStream> {
+        self.store_for(uri)?.list_file(uri).await
+    }
+
+    async fn list_file_with_suffix(&self, uri: &str, suffix: &str) -> Result<FileMetaStream> {
+        self.store_for(uri)?
+            .list_file_with_suffix(uri, suffix)
+            .await
+    }
+
+    async fn list_dir(&self, prefix: &str, delimiter: Option<String>) -> Result<ListEntryStream> {
+        self.store_for(prefix)?.list_dir(prefix, delimiter).await
+    }
+
+    fn file_reader(&self, file: SizedFile) -> Result<Arc<dyn ObjectReader>> {
+        self.store_for(&file.path)?.file_reader(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::auth::Credentials;
+
+    fn builder() -> GCSFileSystemBuilder {
+        GCSFileSystem::builder()
+            .with_credentials(Credentials::Anonymous)
+            .with_io_threads(1)
+    }
+
+    #[test]
+    fn stores_by_bucket() {
+        let prebuilt = Arc::new(builder().build());
+        let provider = GCSObjectStoreProvider::new(builder())
+            .with_bucket("large", builder().with_upload_chunk_size(32 * 1024 * 1024))
+            .with_bucket_store("prebuilt", prebuilt.clone());
+
+        let (first, large) = (provider.get_store("first"), provider.get_store("large"));
+        assert!(Arc::ptr_eq(
+            &first,
+            &provider.store_for("gs://first/a.csv").unwrap()
+        ));
+        assert!(Arc::ptr_eq(&prebuilt, &provider.get_store("prebuilt")));
+        assert!(large.describe().contains("upload chunk size: 33554432"));
+
+        // stores built later run on the runtime of the first
+        assert!(Arc::ptr_eq(first.io_runtime(), large.io_runtime()));
+        assert!(large.describe().contains("io threads: shared"));
+        assert!(!Arc::ptr_eq(first.io_runtime(), prebuilt.io_runtime()));
+
+        let description = provider.describe();
+        assert!(description.starts_with("bucket first:\n"));
+        assert!(description.contains("\n\nbucket large:\n"));
+        assert!(description.contains("\n\nbucket prebuilt:\n"));
+    }
+}
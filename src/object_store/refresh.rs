@@ -0,0 +1,83 @@
+//! Scheduled relisting of cached table listings, see `GCSFileSystem::refresh_listings`
+
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveTime, Utc};
+use tokio::task::JoinHandle;
+
+/// When `GCSFileSystem::refresh_listings` relists its URIs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefreshSchedule {
+    /// Every `Duration`, the first time one period after the refresh starts
+    Every(Duration),
+    /// Every day at each of these times of day in UTC, e.g. an off-peak window before the
+    /// working day starts
+    DailyAt(Vec<NaiveTime>),
+}
+
+impl RefreshSchedule {
+    /// When the first refresh after `now` is due, `None` if never
+    pub(crate) fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            RefreshSchedule::Every(period) if period.is_zero() => None,
+            RefreshSchedule::Every(period) => Some(now + chrono::Duration::from_std(*period).ok()?),
+            RefreshSchedule::DailyAt(times) => times
+                .iter()
+                .map(|time| {
+                    let today = now.date_naive().and_time(*time).and_utc();
+                    if today > now {
+                        today
+                    } else {
+                        today + chrono::Duration::days(1)
+                    }
+                })
+                .min(),
+        }
+    }
+}
+
+/// A running listing refresh, stopped when dropped
+#[derive(Debug)]
+pub struct ListingRefresh {
+    task: JoinHandle<()>,
+}
+
+impl ListingRefresh {
+    pub(crate) fn new(task: JoinHandle<()>) -> Self {
+        Self { task }
+    }
+
+    /// Stop refreshing, like dropping the handle
+    pub fn stop(self) {}
+}
+
+impl Drop for ListingRefresh {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    #[test]
+    fn next_refreshes() {
+        let now = at("2022-05-01T03:00:00Z");
+        let every = RefreshSchedule::Every(Duration::from_secs(90));
+        assert_eq!(every.next_after(now), Some(at("2022-05-01T03:01:30Z")));
+        assert_eq!(RefreshSchedule::Every(Duration::ZERO).next_after(now), None);
+
+        let times = ["02:00:00", "05:30:00"].map(|time| time.parse().unwrap());
+        let daily = RefreshSchedule::DailyAt(times.to_vec());
+        assert_eq!(daily.next_after(now), Some(at("2022-05-01T05:30:00Z")));
+        // a refresh due right now is done, the next one is tomorrow
+        let done = at("2022-05-01T05:30:00Z");
+        assert_eq!(daily.next_after(done), Some(at("2022-05-02T02:00:00Z")));
+        assert_eq!(RefreshSchedule::DailyAt(vec![]).next_after(now), None);
+    }
+}
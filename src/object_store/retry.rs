@@ -0,0 +1,131 @@
+//! Retrying transient GCS failures with exponential backoff
+
+use std::time::Duration;
+
+/// When and how often failed requests are retried.
+///
+/// The `n`th retry waits `base_delay * 2^(n - 1)`, capped at `max_delay`. With `jitter`
+/// the wait is drawn uniformly from zero to that value instead, which spreads out the
+/// retries of many readers that failed at the same time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one; `1` disables retries
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Upper bound for any single delay
+    pub max_delay: Duration,
+    /// Randomize delays ("full jitter")
+    pub jitter: bool,
+    /// HTTP statuses treated as transient. Connection failures and timeouts are always
+    /// retried.
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+            retryable_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The short, aggressive policy used while waiting for an endpoint to come up: up to
+    /// 30 attempts over roughly half a minute, retrying gateway errors only
+    pub fn startup() -> Self {
+        Self {
+            max_attempts: 30,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+            jitter: false,
+            retryable_statuses: vec![502, 503, 504],
+        }
+    }
+
+    /// A policy that never retries
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns `true` if a response with `status` should be retried
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+
+    /// The delay before retry number `retry`, starting at 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .checked_mul(1 << retry.saturating_sub(1).min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        if self.jitter {
+            backoff.mul_f64(rand::random::<f64>())
+        } else {
+            backoff
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let delays: Vec<_> = (1..=6).map(|retry| policy().delay(retry)).collect();
+        let millis = [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis);
+        assert_eq!(delays, millis);
+        assert_eq!(policy().delay(0), Duration::from_millis(100));
+        // retries past the shift width still end at the cap
+        assert_eq!(policy().delay(64), Duration::from_secs(1));
+        assert_eq!(policy().delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jittered_delays_stay_below_the_backoff() {
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy()
+        };
+        for retry in 1..=6 {
+            let delay = policy.delay(retry);
+            let backoff = RetryPolicy {
+                jitter: false,
+                ..policy.clone()
+            }
+            .delay(retry);
+            assert!(delay <= backoff, "{:?} > {:?}", delay, backoff);
+        }
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        let policy = RetryPolicy::default();
+        for status in [408, 429, 500, 502, 503, 504] {
+            assert!(policy.is_retryable_status(status), "{}", status);
+        }
+        for status in [400, 401, 403, 404, 412] {
+            assert!(!policy.is_retryable_status(status), "{}", status);
+        }
+        assert!(!RetryPolicy::startup().is_retryable_status(500));
+        assert_eq!(RetryPolicy::none().max_attempts, 1);
+    }
+}
@@ -0,0 +1,163 @@
+//! Restricting a store to some buckets and prefixes
+
+use std::fmt::{Display, Formatter};
+
+use datafusion::datafusion_data_access::Result;
+
+use crate::error::GCSError;
+use crate::object_store::path::GCSPath;
+
+/// Buckets and prefixes a `GCSFileSystem` may access, e.g. when it runs SQL from users
+/// who must not point tables at other data its credentials can read.
+///
+/// Requests are checked before they are sent: reads, lookups, writes, copies and signed
+/// URLs of objects outside the sandbox fail locally with `ErrorKind::PermissionDenied`
+/// and `GCSError::OutsideSandbox`. A listing of a prefix containing allowed prefixes,
+/// such as a whole bucket, only returns the objects inside the sandbox.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Allowed prefixes, with an empty key for whole buckets
+    allowed: Vec<GCSPath>,
+}
+
+impl Sandbox {
+    /// A sandbox allowing nothing yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow every object in `bucket`
+    pub fn allow_bucket(mut self, bucket: impl Into<String>) -> Self {
+        self.allowed.push(GCSPath::new(bucket, ""));
+        self
+    }
+
+    /// Allow the objects whose names start with the key of `uri`, e.g.
+    /// `gs://lake/tables/`. Like GCS prefixes these match by string, so end the prefix
+    /// with `/` to allow a single folder: `gs://lake/tables` also allows `tables_old/`.
+    pub fn allow_prefix(mut self, uri: &str) -> Result<Self> {
+        self.allowed.push(GCSPath::parse(uri)?);
+        Ok(self)
+    }
+
+    /// Whether the object at `path` is inside the sandbox
+    pub fn allows(&self, path: &GCSPath) -> bool {
+        self.allowed
+            .iter()
+            .any(|allowed| allowed.bucket == path.bucket && path.key.starts_with(&allowed.key))
+    }
+
+    /// Whether listing `prefix` in `bucket` can return objects inside the sandbox
+    pub(crate) fn overlaps(&self, bucket: &str, prefix: &str) -> bool {
+        self.allowed.iter().any(|allowed| {
+            allowed.bucket == bucket
+                && (prefix.starts_with(&allowed.key) || allowed.key.starts_with(prefix))
+        })
+    }
+
+    /// Fail unless the object at `path` is inside the sandbox
+    pub(crate) fn check(&self, path: &GCSPath) -> Result<()> {
+        if !self.allows(path) {
+            return Err(outside(path));
+        }
+        Ok(())
+    }
+
+    /// Fail unless listing `prefix` in `bucket` can return objects inside the sandbox
+    pub(crate) fn check_listing(&self, bucket: &str, prefix: &str) -> Result<()> {
+        if !self.overlaps(bucket, prefix) {
+            return Err(outside(&GCSPath::new(bucket, prefix)));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Sandbox {
+    /// Formats as the comma-separated `gs://` URIs of the allowed prefixes
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let allowed = self
+            .allowed
+            .iter()
+            .map(|path| format!("gs://{}", path))
+            .collect::<Vec<_>>();
+        if allowed.is_empty() {
+            return write!(f, "nothing allowed");
+        }
+        write!(f, "{}", allowed.join(", "))
+    }
+}
+
+fn outside(path: &GCSPath) -> std::io::Error {
+    GCSError::OutsideSandbox {
+        uri: format!("gs://{}", path),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(sandbox: &Sandbox, uri: &str) -> bool {
+        sandbox.allows(&GCSPath::parse(uri).unwrap())
+    }
+
+    #[test]
+    fn folder_prefixes_stop_at_the_separator() {
+        let sandbox = Sandbox::new().allow_prefix("gs://lake/data/").unwrap();
+        assert!(allows(&sandbox, "gs://lake/data/a.csv"));
+        assert!(allows(&sandbox, "gs://lake/data/dt=1/a.csv"));
+        assert!(!allows(&sandbox, "gs://lake/database/a.csv"));
+        assert!(!allows(&sandbox, "gs://lake/data"));
+        assert!(!allows(&sandbox, "gs://lake/other/data/a.csv"));
+        assert!(!allows(&sandbox, "gs://lake2/data/a.csv"));
+        // keys are names, not file system paths
+        assert!(allows(&sandbox, "gs://lake/data/../secret.csv"));
+    }
+
+    #[test]
+    fn prefixes_without_separator_match_by_string() {
+        let sandbox = Sandbox::new().allow_prefix("gs://lake/data").unwrap();
+        assert!(allows(&sandbox, "gs://lake/data/a.csv"));
+        assert!(allows(&sandbox, "gs://lake/database/a.csv"));
+        assert!(!allows(&sandbox, "gs://lake/dat"));
+    }
+
+    #[test]
+    fn buckets() {
+        let sandbox = Sandbox::new()
+            .allow_bucket("lake")
+            .allow_prefix("gcs://other/tables/")
+            .unwrap();
+        assert!(allows(&sandbox, "gs://lake/anything"));
+        assert!(allows(&sandbox, "lake/"));
+        assert!(allows(&sandbox, "gs://other/tables/t/a.parquet"));
+        assert!(!allows(&sandbox, "gs://other/secrets/key.json"));
+        assert!(!allows(&sandbox, "gs://lake-backup/anything"));
+        assert!(!allows(&Sandbox::new(), "gs://lake/anything"));
+        assert_eq!(
+            sandbox.to_string(),
+            "gs://lake/, gs://other/tables/".to_string()
+        );
+        assert_eq!(Sandbox::new().to_string(), "nothing allowed");
+    }
+
+    #[test]
+    fn listings() {
+        let sandbox = Sandbox::new().allow_prefix("gs://lake/data/").unwrap();
+        // a bucket or parent listing can reach the allowed prefix
+        assert!(sandbox.overlaps("lake", ""));
+        assert!(sandbox.overlaps("lake", "da"));
+        assert!(sandbox.overlaps("lake", "data/dt=1/"));
+        assert!(!sandbox.overlaps("lake", "database/"));
+        assert!(!sandbox.overlaps("lake", "other/"));
+        assert!(!sandbox.overlaps("lake2", ""));
+
+        let err = sandbox.check_listing("lake", "database/").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        let err = sandbox
+            .check(&GCSPath::new("lake", "database/a.csv"))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+}
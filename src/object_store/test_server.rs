@@ -0,0 +1,559 @@
+//! Local HTTP server standing in for the Cloud Storage API in unit tests
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+
+use crate::object_store::path;
+
+/// A request received by a [`TestServer`]
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// Path and query of the request
+    pub(crate) url: String,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the header `name`, compared case-insensitively
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of the query parameter `name`, still percent-encoded
+    pub(crate) fn query(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.url.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The `bucket` and `key` of an object the request names
+    pub(crate) fn object(&self) -> (Option<String>, Option<String>) {
+        path::resource(&self.url)
+    }
+}
+
+/// Response sent by a [`TestServer`]
+#[derive(Debug, Clone)]
+pub(crate) struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+impl Reply {
+    /// An empty response with `status`
+    pub(crate) fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: Bytes::new(),
+        }
+    }
+
+    /// A `200 OK` response with `value` as its body
+    pub(crate) fn json(value: serde_json::Value) -> Self {
+        Self::status(200)
+            .header("Content-Type", "application/json")
+            .body(value.to_string())
+    }
+
+    pub(crate) fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub(crate) fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    fn write(&self, stream: &mut TcpStream) {
+        let reason = http::StatusCode::from_u16(self.status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or("Unknown");
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        let _ = stream.write_all(head.as_bytes());
+        let _ = stream.write_all(&self.body);
+    }
+}
+
+/// Server on a local port that answers every request with a handler and keeps the
+/// requests it received. Every response closes its connection.
+pub(crate) struct TestServer {
+    /// Scheme and authority to use as a store's endpoint
+    pub(crate) endpoint: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub(crate) fn start(handler: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler = Arc::new(handler);
+        std::thread::spawn({
+            let requests = requests.clone();
+            move || {
+                for mut stream in listener.incoming().flatten() {
+                    let (handler, requests) = (handler.clone(), requests.clone());
+                    std::thread::spawn(move || {
+                        if let Some(request) = read_request(&stream) {
+                            let reply = handler(&request);
+                            requests.lock().unwrap().push(request);
+                            reply.write(&mut stream);
+                        }
+                    });
+                }
+            }
+        });
+        Self { endpoint, requests }
+    }
+
+    /// Requests received so far, in the order they were answered
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split(' ');
+    let method = parts.next()?.to_string();
+    let url = parts.next()?.to_string();
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+    }
+    let mut request = Request {
+        method,
+        url,
+        headers,
+        body: vec![],
+    };
+    let length = request
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body).ok()?;
+    Some(request)
+}
+
+/// Serve `objects`, by `bucket/key`, as ranged JSON API downloads on a local port and
+/// return the endpoint
+pub(crate) fn serve(objects: HashMap<String, Bytes>) -> String {
+    TestServer::start(move |request| download(request, &objects)).endpoint
+}
+
+fn download(request: &Request, objects: &HashMap<String, Bytes>) -> Reply {
+    let object = match request.object() {
+        (Some(bucket), Some(key)) => objects.get(&format!("{}/{}", bucket, key)),
+        _ => None,
+    };
+    match object {
+        Some(data) if !data.is_empty() => ranged(request, data, 1),
+        _ => Reply::status(404),
+    }
+}
+
+/// The range of generation `generation` of an object with `data` that `request` asks for
+fn ranged(request: &Request, data: &Bytes, generation: u64) -> Reply {
+    if data.is_empty() {
+        return Reply::status(200).header("x-goog-generation", generation.to_string());
+    }
+    let last = data.len() as u64 - 1;
+    let (start, end) = request
+        .header("range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .map_or((0, last), |(start, end)| {
+            (
+                start.parse().unwrap(),
+                end.parse().unwrap_or(last).min(last),
+            )
+        });
+    Reply::status(206)
+        .header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, end, data.len()),
+        )
+        .header("x-goog-generation", generation.to_string())
+        .body(data.slice(start as usize..=end as usize))
+}
+
+/// In-memory stand-in for the JSON and upload APIs, keeping every generation of every
+/// object like a versioned bucket. It lists, reads, writes (with `ifGenerationMatch`),
+/// rewrites and deletes objects, and runs resumable upload sessions.
+#[derive(Debug, Default)]
+pub(crate) struct FakeGcs {
+    state: Mutex<FakeState>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    /// Generations by `bucket/key`, oldest first
+    objects: BTreeMap<String, Vec<Version>>,
+    generation: u64,
+    sessions: HashMap<String, Session>,
+    uploads: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Version {
+    data: Bytes,
+    generation: u64,
+    created: DateTime<Utc>,
+    deleted: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct Session {
+    path: String,
+    if_generation_match: Option<u64>,
+    data: Vec<u8>,
+}
+
+impl FakeState {
+    fn live(&self, path: &str) -> Option<&Version> {
+        self.objects
+            .get(path)?
+            .last()
+            .filter(|version| version.deleted.is_none())
+    }
+
+    fn delete_at(&mut self, path: &str, time: DateTime<Utc>) -> bool {
+        match self
+            .objects
+            .get_mut(path)
+            .and_then(|versions| versions.last_mut())
+        {
+            Some(live) if live.deleted.is_none() => {
+                live.deleted = Some(time);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn write_at(&mut self, path: &str, data: Bytes, time: DateTime<Utc>) -> u64 {
+        self.delete_at(path, time);
+        self.generation += 1;
+        let version = Version {
+            data,
+            generation: self.generation,
+            created: time,
+            deleted: None,
+        };
+        self.objects
+            .entry(path.to_string())
+            .or_default()
+            .push(version);
+        self.generation
+    }
+
+    /// Write `data` unless `if_generation_match` names another live generation
+    fn write(&mut self, path: &str, data: Bytes, if_generation_match: Option<u64>) -> Reply {
+        let live = self.live(path).map_or(0, |version| version.generation);
+        if if_generation_match.is_some_and(|expected| expected != live) {
+            return error(412, "conditionNotMet");
+        }
+        self.write_at(path, data, Utc::now());
+        Reply::json(self.resource(path, self.live(path).unwrap()))
+    }
+
+    fn resource(&self, path: &str, version: &Version) -> serde_json::Value {
+        let (_, name) = path.split_once('/').unwrap();
+        let crc32c = crc32c::crc32c(&version.data);
+        let mut resource = serde_json::json!({
+            "name": name,
+            "size": version.data.len().to_string(),
+            "generation": version.generation.to_string(),
+            "crc32c": base64::encode(crc32c.to_be_bytes()),
+            "updated": version.created.to_rfc3339(),
+            "timeCreated": version.created.to_rfc3339(),
+        });
+        if let Some(deleted) = version.deleted {
+            resource["timeDeleted"] = deleted.to_rfc3339().into();
+        }
+        resource
+    }
+}
+
+impl FakeGcs {
+    /// Serve a new, empty fake on a local port
+    pub(crate) fn start() -> (Arc<Self>, TestServer) {
+        let fake = Arc::new(Self::default());
+        let server = TestServer::start({
+            let fake = fake.clone();
+            move |request| fake.respond(request)
+        });
+        (fake, server)
+    }
+
+    /// Write `data` as a new generation of `bucket/key` at `time`, returning the generation
+    pub(crate) fn insert_at(&self, path: &str, data: impl Into<Bytes>, time: DateTime<Utc>) -> u64 {
+        self.state.lock().unwrap().write_at(path, data.into(), time)
+    }
+
+    /// Write `data` as a new generation of `bucket/key`, returning the generation
+    pub(crate) fn insert(&self, path: &str, data: impl Into<Bytes>) -> u64 {
+        self.insert_at(path, data, Utc::now())
+    }
+
+    /// Remove every generation of `bucket/key`, as lifecycle rules do
+    pub(crate) fn purge(&self, path: &str) {
+        self.state.lock().unwrap().objects.remove(path);
+    }
+
+    /// Delete the live generation of `bucket/key` at `time`
+    pub(crate) fn delete_at(&self, path: &str, time: DateTime<Utc>) {
+        self.state.lock().unwrap().delete_at(path, time);
+    }
+
+    /// Contents of the live generation of `bucket/key`
+    pub(crate) fn get(&self, path: &str) -> Option<Bytes> {
+        let state = self.state.lock().unwrap();
+        state.live(path).map(|version| version.data.clone())
+    }
+
+    /// `bucket/key` of every live object
+    pub(crate) fn paths(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let paths = state
+            .objects
+            .keys()
+            .filter(|path| state.live(path).is_some());
+        paths.cloned().collect()
+    }
+
+    /// Upload sessions neither completed nor cancelled
+    pub(crate) fn open_sessions(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    fn respond(&self, request: &Request) -> Reply {
+        let mut state = self.state.lock().unwrap();
+        let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+        let parameter = |name: &str| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| decode(value))
+        };
+        let if_generation_match = parameter("ifGenerationMatch").map(|g| g.parse().unwrap());
+        let host = request.header("host").unwrap_or_default();
+
+        if let Some(id) = path.strip_prefix("/upload/session/") {
+            return match request.method.as_str() {
+                "DELETE" => match state.sessions.remove(id) {
+                    Some(_) => Reply::status(499),
+                    None => error(404, "noSuchUpload"),
+                },
+                _ => upload_chunk(&mut state, id, request),
+            };
+        }
+        let (bucket, key) = request.object();
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return error(404, "notFound"),
+        };
+        match (request.method.as_str(), key) {
+            ("GET", None) => list(&state, &bucket, &parameter),
+            ("POST", _) if path.starts_with("/upload/") => {
+                if parameter("uploadType").as_deref() == Some("resumable") {
+                    let resource: serde_json::Value =
+                        serde_json::from_slice(&request.body).unwrap();
+                    state.uploads += 1;
+                    let id = state.uploads.to_string();
+                    let session = Session {
+                        path: format!("{}/{}", bucket, resource["name"].as_str().unwrap()),
+                        if_generation_match,
+                        data: vec![],
+                    };
+                    state.sessions.insert(id.clone(), session);
+                    Reply::status(200)
+                        .header("Location", format!("http://{}/upload/session/{}", host, id))
+                } else {
+                    let (name, data) = multipart(request);
+                    let path = format!("{}/{}", bucket, name);
+                    state.write(&path, data, if_generation_match)
+                }
+            }
+            (method, Some(key)) => {
+                let object = format!("{}/{}", bucket, key);
+                if method == "POST" && path.contains("/rewriteTo/") {
+                    let destination = path.split_once("/rewriteTo").unwrap().1;
+                    let to = match path::resource(destination) {
+                        (Some(bucket), Some(key)) => format!("{}/{}", bucket, key),
+                        _ => return error(400, "invalid"),
+                    };
+                    let data = match state.live(&object) {
+                        Some(version) => version.data.clone(),
+                        None => return error(404, "notFound"),
+                    };
+                    let written = state.write(&to, data, if_generation_match);
+                    if written.status != 200 {
+                        return written;
+                    }
+                    let resource = state.resource(&to, state.live(&to).unwrap());
+                    return Reply::json(serde_json::json!({"done": true, "resource": resource}));
+                }
+                let generation = parameter("generation").map(|g| g.parse::<u64>().unwrap());
+                let version = match generation {
+                    Some(generation) => state
+                        .objects
+                        .get(&object)
+                        .and_then(|versions| versions.iter().find(|v| v.generation == generation)),
+                    None => state.live(&object),
+                };
+                let version = match version {
+                    Some(version) => version.clone(),
+                    None => return error(404, "notFound"),
+                };
+                match method {
+                    "DELETE" => {
+                        state.delete_at(&object, Utc::now());
+                        Reply::status(204)
+                    }
+                    _ if parameter("alt").as_deref() == Some("media") => {
+                        ranged(request, &version.data, version.generation)
+                    }
+                    _ => Reply::json(state.resource(&object, &version)),
+                }
+            }
+            _ => error(400, "invalid"),
+        }
+    }
+}
+
+/// A JSON API error response
+fn error(status: u16, reason: &str) -> Reply {
+    let body = serde_json::json!({"error": {"code": status, "message": reason}});
+    Reply::json(body).with_status(status)
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn list(state: &FakeState, bucket: &str, parameter: &dyn Fn(&str) -> Option<String>) -> Reply {
+    let prefix = format!("{}/{}", bucket, parameter("prefix").unwrap_or_default());
+    let delimiter = parameter("delimiter");
+    let versions = parameter("versions").as_deref() == Some("true");
+    let mut items = vec![];
+    let mut prefixes = BTreeSet::new();
+    for (path, generations) in state.objects.range(prefix.clone()..) {
+        let rest = match path.strip_prefix(&prefix) {
+            Some(rest) => rest,
+            None => break,
+        };
+        if let Some(end) = delimiter
+            .as_ref()
+            .and_then(|d| Some(rest.find(d.as_str())? + d.len()))
+        {
+            if state.live(path).is_some() || versions {
+                let (_, key) = path.split_once('/').unwrap();
+                prefixes.insert(key[..key.len() - rest.len() + end].to_string());
+            }
+            continue;
+        }
+        if versions {
+            items.extend(
+                generations
+                    .iter()
+                    .map(|version| state.resource(path, version)),
+            );
+        } else if let Some(live) = state.live(path) {
+            items.push(state.resource(path, live));
+        }
+    }
+    Reply::json(serde_json::json!({"items": items, "prefixes": prefixes}))
+}
+
+/// Name and data of a multipart upload
+fn multipart(request: &Request) -> (String, Bytes) {
+    let boundary = request
+        .header("content-type")
+        .and_then(|value| value.split_once("boundary="))
+        .map(|(_, boundary)| format!("--{}", boundary))
+        .unwrap();
+    let body = String::from_utf8_lossy(&request.body).into_owned();
+    let mut parts = body.split(boundary.as_str()).skip(1);
+    let part_body = |part: &str| {
+        let (_, body) = part.split_once("\r\n\r\n").unwrap();
+        body.strip_suffix("\r\n").unwrap_or(body).to_string()
+    };
+    let resource: serde_json::Value =
+        serde_json::from_str(&part_body(parts.next().unwrap())).unwrap();
+    let data = part_body(parts.next().unwrap());
+    (
+        resource["name"].as_str().unwrap().to_string(),
+        Bytes::from(data),
+    )
+}
+
+fn upload_chunk(state: &mut FakeState, id: &str, request: &Request) -> Reply {
+    let session = match state.sessions.get_mut(id) {
+        Some(session) => session,
+        None => return error(404, "noSuchUpload"),
+    };
+    let range = request.header("content-range").unwrap_or_default();
+    let (range, total) = range.trim_start_matches("bytes ").split_once('/').unwrap();
+    if let Some((start, _)) = range.split_once('-') {
+        // a resent chunk overlaps what was already persisted
+        session.data.truncate(start.parse().unwrap());
+        session.data.extend_from_slice(&request.body);
+    }
+    if total != "*" && session.data.len() == total.parse::<usize>().unwrap() {
+        let session = state.sessions.remove(id).unwrap();
+        return state.write(
+            &session.path,
+            session.data.into(),
+            session.if_generation_match,
+        );
+    }
+    match session.data.len() {
+        0 => Reply::status(308),
+        length => Reply::status(308).header("Range", format!("bytes=0-{}", length - 1)),
+    }
+}
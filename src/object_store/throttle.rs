@@ -0,0 +1,151 @@
+//! Upload bandwidth limits, shared by every upload of a store
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, NaiveTime, Utc};
+
+use crate::object_store::clock::Clock;
+
+/// Time a paused upload waits before asking its [`UploadSchedule`] again
+pub const UPLOAD_PAUSE_RECHECK: Duration = Duration::from_secs(60);
+
+/// Decides how fast a store may upload, e.g. to keep backfills from saturating shared
+/// egress during business hours. Set with `GCSFileSystemBuilder::with_upload_schedule`.
+pub trait UploadSchedule: Debug + Send + Sync {
+    /// Bytes per second uploads may send at `now`, `None` for no limit. `Some(0)` pauses
+    /// uploads, which ask again every [`UPLOAD_PAUSE_RECHECK`].
+    fn rate(&self, now: DateTime<Utc>) -> Option<u64>;
+}
+
+/// The same limit at all times, see `GCSFileSystemBuilder::with_max_upload_rate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxUploadRate(pub u64);
+
+impl UploadSchedule for MaxUploadRate {
+    fn rate(&self, _now: DateTime<Utc>) -> Option<u64> {
+        Some(self.0)
+    }
+}
+
+/// `peak` bytes per second between `start` and `end` in UTC, e.g. business hours, and
+/// `off_peak` otherwise. Windows ending before they start span midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeakHours {
+    /// Start of the peak hours
+    pub start: NaiveTime,
+    /// End of the peak hours
+    pub end: NaiveTime,
+    /// Rate during the peak hours, `Some(0)` to only upload outside of them
+    pub peak: Option<u64>,
+    /// Rate outside of the peak hours
+    pub off_peak: Option<u64>,
+}
+
+impl UploadSchedule for PeakHours {
+    fn rate(&self, now: DateTime<Utc>) -> Option<u64> {
+        let time = now.time();
+        let peak = match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => self.start <= time || time < self.end,
+        };
+        match peak {
+            true => self.peak,
+            false => self.off_peak,
+        }
+    }
+}
+
+/// Paces the uploads of a store to its [`UploadSchedule`], across all of its writers
+#[derive(Debug)]
+pub(crate) struct UploadThrottle {
+    schedule: Arc<dyn UploadSchedule>,
+    /// When the bytes reserved so far have been sent at the scheduled rate
+    sent: Mutex<SystemTime>,
+}
+
+impl UploadThrottle {
+    pub(crate) fn new(schedule: Arc<dyn UploadSchedule>) -> Self {
+        Self {
+            schedule,
+            sent: Mutex::new(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    /// Wait until `bytes` may be sent, after the bytes reserved before them
+    pub(crate) async fn reserve(&self, clock: &dyn Clock, bytes: u64) {
+        loop {
+            let now = clock.now();
+            let rate = match self.schedule.rate(now.into()) {
+                Some(0) => {
+                    clock.sleep(UPLOAD_PAUSE_RECHECK).await;
+                    continue;
+                }
+                Some(rate) => rate,
+                None => return,
+            };
+            let start = {
+                let mut sent = self.sent.lock().unwrap();
+                let start = (*sent).max(now);
+                *sent = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+                start
+            };
+            if let Ok(wait) = start.duration_since(now) {
+                if !wait.is_zero() {
+                    clock.sleep(wait).await;
+                }
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::clock::MockClock;
+
+    #[tokio::test]
+    async fn paces_uploads_to_the_rate() {
+        let clock = MockClock::default();
+        let throttle = UploadThrottle::new(Arc::new(MaxUploadRate(1000)));
+        // the first bytes go at once, the following wait for those before them
+        throttle.reserve(&clock, 500).await;
+        throttle.reserve(&clock, 2000).await;
+        throttle.reserve(&clock, 0).await;
+        assert_eq!(
+            clock.sleeps(),
+            [Duration::from_millis(500), Duration::from_secs(2)]
+        );
+
+        // time passing without uploads is not saved up
+        clock.advance(Duration::from_secs(60));
+        throttle.reserve(&clock, 1000).await;
+        assert_eq!(clock.sleeps().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn pauses_during_peak_hours() {
+        let hours = PeakHours {
+            start: "22:00:00".parse().unwrap(),
+            end: "00:02:00".parse().unwrap(),
+            peak: Some(0),
+            off_peak: None,
+        };
+        let at = |time: &str| {
+            DateTime::parse_from_rfc3339(time)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        assert_eq!(hours.rate(at("2022-05-01T21:59:59Z")), None);
+        assert_eq!(hours.rate(at("2022-05-01T23:00:00Z")), Some(0));
+        assert_eq!(hours.rate(at("2022-05-02T00:01:00Z")), Some(0));
+
+        // the mock clock starts at midnight, two minutes before the peak hours end
+        let clock = MockClock::default();
+        let throttle = UploadThrottle::new(Arc::new(hours));
+        throttle.reserve(&clock, 1 << 30).await;
+        assert_eq!(clock.sleeps(), [UPLOAD_PAUSE_RECHECK, UPLOAD_PAUSE_RECHECK]);
+    }
+}
@@ -0,0 +1,620 @@
+//! Writing objects to Google Cloud Storage
+
+use std::io::Write;
+
+use datafusion::datafusion_data_access::Result;
+use reqwest::{header, Method};
+
+use crate::error::GCSError;
+use crate::object_store::api::{http_error, status_error, ApiClient, ObjectResource};
+use crate::object_store::encryption::EncryptionKey;
+use crate::object_store::metrics::RequestKind;
+use crate::object_store::path::GCSPath;
+
+/// Resumable uploads transfer data in multiples of this many bytes
+const CHUNK_GRANULARITY: usize = 256 * 1024;
+const MULTIPART_BOUNDARY: &str = "datafusion-objectstore-gcs-8f1c2d7e5b9a4c36";
+
+/// Default size of each chunk sent by a [`ResumableUpload`]
+pub const DEFAULT_UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Condition the existing object must meet for a write to go ahead, checked atomically by
+/// GCS. A write whose precondition fails changes nothing and returns
+/// `GCSError::PreconditionFailed`.
+///
+/// `DoesNotExist` makes a write create-if-absent, so of several concurrent writers only
+/// the first one succeeds; `GenerationMatch` turns an overwrite into a compare-and-swap
+/// against the generation the writer last read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// No live object may exist at the path
+    DoesNotExist,
+    /// The live object must have this generation
+    GenerationMatch(u64),
+}
+
+impl Precondition {
+    /// Value of the `ifGenerationMatch` parameter, where `0` means no live object
+    fn if_generation_match(self) -> u64 {
+        match self {
+            Precondition::DoesNotExist => 0,
+            Precondition::GenerationMatch(generation) => generation,
+        }
+    }
+}
+
+/// Add the `ifGenerationMatch` parameter for `precondition`, if any
+pub(crate) fn with_precondition(
+    request: reqwest::RequestBuilder,
+    precondition: Option<Precondition>,
+) -> reqwest::RequestBuilder {
+    match precondition {
+        Some(precondition) => {
+            request.query(&[("ifGenerationMatch", precondition.if_generation_match())])
+        }
+        None => request,
+    }
+}
+
+/// Guess the `Content-Type` of an object from the extension of its name
+pub(crate) fn content_type(key: &str) -> &'static str {
+    match key.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("csv") => "text/csv",
+        Some("json") | Some("ndjson") => "application/json",
+        Some("avro") => "application/avro",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Object resource sent along with uploaded data
+fn object_resource(api: &ApiClient, path: &GCSPath) -> serde_json::Value {
+    serde_json::json!({
+        "name": path.key,
+        "contentType": content_type(&path.key),
+        "metadata": &*api.labels,
+    })
+}
+
+/// `Write` implementation that uploads everything written to it as a single object.
+///
+/// Data is buffered in memory until [`GCSObjectWriter::finish`] is called; dropping the
+/// writer without finishing discards it and leaves any existing object untouched.
+#[derive(Debug)]
+pub struct GCSObjectWriter {
+    api: ApiClient,
+    path: GCSPath,
+    buffer: Vec<u8>,
+    precondition: Option<Precondition>,
+}
+
+impl GCSObjectWriter {
+    pub(crate) fn new(api: ApiClient, path: GCSPath) -> Self {
+        Self {
+            api,
+            path,
+            buffer: Vec::new(),
+            precondition: None,
+        }
+    }
+
+    /// Only write the object if `precondition` holds when the upload is finished
+    pub fn with_precondition(mut self, precondition: Precondition) -> Self {
+        self.precondition = Some(precondition);
+        self
+    }
+
+    /// Number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns `true` if nothing has been written yet
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Upload the buffered data, replacing any existing object at the same path unless
+    /// a precondition says otherwise
+    pub async fn finish(self) -> Result<()> {
+        upload(&self.api, &self.path, self.buffer, self.precondition).await?;
+        Ok(())
+    }
+}
+
+impl Write for GCSObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Upload `data` and its object resource in a single multipart request, returning the
+/// resource of the written object
+pub(crate) async fn upload(
+    api: &ApiClient,
+    path: &GCSPath,
+    data: Vec<u8>,
+    precondition: Option<Precondition>,
+) -> Result<ObjectResource> {
+    api.check_access(path)?;
+    let mut body = format!(
+        "--{b}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{}\r\n--{b}\r\nContent-Type: {}\r\n\r\n",
+        object_resource(api, path),
+        content_type(&path.key),
+        b = MULTIPART_BOUNDARY
+    )
+    .into_bytes();
+    body.extend_from_slice(&data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY).as_bytes());
+    let length = body.len() as u64;
+
+    api.throttle_upload(length).await;
+    api.metadata.remove(path);
+    let url = format!("{}/b/{}/o", api.upload_api(), path.bucket);
+    let request = api
+        .request(Method::POST, &url)
+        .await?
+        .query(&[("uploadType", "multipart")])
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/related; boundary={}", MULTIPART_BOUNDARY),
+        )
+        .body(body);
+    let request = api.with_customer_key(request, path);
+    let request = api.with_kms_key(request, path, "kmsKeyName");
+    let response = api
+        .send(
+            RequestKind::Upload,
+            with_precondition(request, precondition),
+        )
+        .await?;
+    api.metrics.bytes_uploaded(RequestKind::Upload, length);
+    // reads racing the upload may have cached the replaced generation
+    api.metadata.remove(path);
+    response.json().await.map_err(http_error)
+}
+
+/// Cancel the resumable upload session at `session_url`, whether or not it is still open
+pub(crate) async fn cancel(api: &ApiClient, session_url: &str) -> Result<()> {
+    let request = api
+        .request(Method::DELETE, session_url)
+        .await?
+        .header(header::CONTENT_LENGTH, 0);
+    api.execute(RequestKind::Upload, 1, request)
+        .await
+        .map_err(http_error)?;
+    Ok(())
+}
+
+/// An object upload through a GCS resumable upload session.
+///
+/// At most one chunk is held in memory. A chunk that fails with a network error, a
+/// timeout or a status the store's `RetryPolicy` considers transient is resumed from the
+/// last byte GCS acknowledged, so such failures do not restart the upload. Any other
+/// failure, such as an expired session, is returned right away, as is a failure to learn
+/// how much of the chunk GCS persisted.
+#[derive(Debug)]
+pub struct ResumableUpload {
+    api: ApiClient,
+    path: GCSPath,
+    session_url: String,
+    /// Key the object is encrypted with, which every chunk has to present
+    customer_key: Option<EncryptionKey>,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    offset: u64,
+}
+
+impl ResumableUpload {
+    pub(crate) async fn start(
+        api: ApiClient,
+        path: &GCSPath,
+        chunk_size: usize,
+        precondition: Option<Precondition>,
+    ) -> Result<Self> {
+        api.check_access(path)?;
+        api.metadata.remove(path);
+        let url = format!("{}/b/{}/o", api.upload_api(), path.bucket);
+        let request = api
+            .request(Method::POST, &url)
+            .await?
+            .query(&[("uploadType", "resumable")])
+            .header("X-Upload-Content-Type", content_type(&path.key))
+            .json(&object_resource(&api, path));
+        let request = api.with_customer_key(request, path);
+        let request = api.with_kms_key(request, path, "kmsKeyName");
+        let response = api
+            .send(
+                RequestKind::Upload,
+                with_precondition(request, precondition),
+            )
+            .await?;
+        let session_url = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| {
+                std::io::Error::other(GCSError::GCS("no upload session returned".into()))
+            })?
+            .to_string();
+
+        let chunk_size = (chunk_size / CHUNK_GRANULARITY).max(1) * CHUNK_GRANULARITY;
+        Ok(Self {
+            customer_key: api.encryption.customer_key(path).cloned(),
+            api,
+            path: path.clone(),
+            session_url,
+            chunk_size,
+            buffer: Vec::with_capacity(chunk_size),
+            offset: 0,
+        })
+    }
+
+    /// Append `data` to the object, sending every completed chunk
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let take = (self.chunk_size - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() == self.chunk_size {
+                let chunk = std::mem::take(&mut self.buffer);
+                self.send(chunk, false).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the remaining data and complete the object
+    pub async fn finish(mut self) -> Result<()> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.send(chunk, true).await?;
+        // reads racing the upload may have cached the replaced generation
+        self.api.metadata.remove(&self.path);
+        Ok(())
+    }
+
+    /// Cancel the upload session; nothing is written to the destination
+    pub async fn abort(self) -> Result<()> {
+        cancel(&self.api, &self.session_url).await
+    }
+
+    /// URL of the session, which identifies it to [`cancel`]
+    #[cfg(feature = "object_store")]
+    pub(crate) fn session_url(&self) -> &str {
+        &self.session_url
+    }
+
+    async fn send(&mut self, mut chunk: Vec<u8>, last: bool) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.put_chunk(&chunk, last, attempt).await {
+                Ok(()) => {
+                    self.offset += chunk.len() as u64;
+                    return Ok(());
+                }
+                Err((true, _)) if attempt < self.api.retry.max_attempts => {
+                    self.api.clock.sleep(self.api.retry.delay(attempt)).await;
+                    attempt += 1;
+                    // GCS may have persisted part of the chunk, or all of the last one,
+                    // before the failure; resuming at a guessed offset would corrupt it
+                    let persisted = match self.persisted().await? {
+                        Some(persisted) => persisted,
+                        None => {
+                            self.offset += chunk.len() as u64;
+                            return Ok(());
+                        }
+                    };
+                    let sent = (persisted.saturating_sub(self.offset) as usize).min(chunk.len());
+                    chunk.drain(..sent);
+                    self.offset += sent as u64;
+                }
+                Err((_, err)) => return Err(err),
+            }
+        }
+    }
+
+    /// Send `chunk` once, failing with whether the failure is transient
+    async fn put_chunk(
+        &self,
+        chunk: &[u8],
+        last: bool,
+        attempt: u32,
+    ) -> std::result::Result<(), (bool, std::io::Error)> {
+        let total = if last {
+            (self.offset + chunk.len() as u64).to_string()
+        } else {
+            "*".to_string()
+        };
+        let range = if chunk.is_empty() {
+            format!("bytes */{}", total)
+        } else {
+            format!(
+                "bytes {}-{}/{}",
+                self.offset,
+                self.offset + chunk.len() as u64 - 1,
+                total
+            )
+        };
+
+        // resent chunks count against the bandwidth too
+        self.api.throttle_upload(chunk.len() as u64).await;
+        let mut request = self
+            .api
+            .request(Method::PUT, &self.session_url)
+            .await
+            .map_err(|err| (false, err))?
+            .header(header::CONTENT_RANGE, range)
+            .body(chunk.to_vec());
+        if let Some(key) = &self.customer_key {
+            request = key.apply(request);
+        }
+        let response = self
+            .api
+            .execute(RequestKind::Upload, attempt, request)
+            .await
+            // the session can be resumed after connections dropped mid-chunk too
+            .map_err(|err| {
+                let transient = err.is_connect() || err.is_timeout() || err.is_request();
+                (transient, http_error(err))
+            })?;
+
+        // 308 acknowledges an intermediate chunk, 200/201 the completed object
+        let status = response.status().as_u16();
+        if response.status().is_success() || (!last && status == 308) {
+            self.api
+                .metrics
+                .bytes_uploaded(RequestKind::Upload, chunk.len() as u64);
+            Ok(())
+        } else {
+            let retryable = self.api.retry.is_retryable_status(status);
+            Err((retryable, status_error(response).await))
+        }
+    }
+
+    /// Ask the session how many bytes it has persisted, or `None` if the object is
+    /// already complete
+    async fn persisted(&self) -> Result<Option<u64>> {
+        let request = self
+            .api
+            .request(Method::PUT, &self.session_url)
+            .await?
+            .header(header::CONTENT_RANGE, "bytes */*")
+            .header(header::CONTENT_LENGTH, 0);
+        let response = self
+            .api
+            .execute(RequestKind::Upload, 1, request)
+            .await
+            .map_err(http_error)?;
+        match response.status().as_u16() {
+            // `Range: bytes=0-N` names the last persisted byte, no header means nothing yet
+            308 => Ok(Some(
+                response
+                    .headers()
+                    .get(header::RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .and_then(|range| range.rsplit_once('-'))
+                    .and_then(|(_, end)| end.parse::<u64>().ok())
+                    .map(|end| end + 1)
+                    .unwrap_or(0),
+            )),
+            200 | 201 => Ok(None),
+            // e.g. 404 or 410 once the session expired
+            _ => Err(status_error(response).await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::object_store::auth::Credentials;
+    use crate::object_store::clock::MockClock;
+    use crate::object_store::gcs::{GCSFileSystem, GCSFileSystemBuilder};
+    use crate::object_store::test_server::{Reply, Request, TestServer};
+
+    const URI: &str = "gs://bucket/a.csv";
+
+    /// A store whose resumable uploads go to a session answered by `session`
+    fn upload_store(
+        builder: GCSFileSystemBuilder,
+        session: impl Fn(&Request) -> Reply + Send + Sync + 'static,
+    ) -> (GCSFileSystem, TestServer) {
+        upload_store_with_clock(builder, Arc::new(MockClock::default()), session)
+    }
+
+    /// [`upload_store`] sleeping on `clock`
+    fn upload_store_with_clock(
+        builder: GCSFileSystemBuilder,
+        clock: Arc<MockClock>,
+        session: impl Fn(&Request) -> Reply + Send + Sync + 'static,
+    ) -> (GCSFileSystem, TestServer) {
+        let server = TestServer::start(move |request| {
+            if request.url.starts_with("/session") {
+                session(request)
+            } else if request.query("uploadType") == Some("resumable") {
+                let host = request.header("host").unwrap_or_default();
+                Reply::status(200).header("Location", format!("http://{}/session", host))
+            } else {
+                Reply::json(serde_json::json!({
+                    "name": "a.csv",
+                    "size": "3",
+                    "updated": "2022-06-01T00:00:00Z",
+                    "generation": "1",
+                }))
+            }
+        });
+        let store = builder
+            .with_credentials(Credentials::Anonymous)
+            .with_endpoint(&server.endpoint)
+            .with_clock(clock)
+            .build();
+        (store, server)
+    }
+
+    /// Acknowledge every chunk of a session, as GCS does once it persisted it
+    fn acknowledge(request: &Request) -> Reply {
+        let range = request.header("content-range").unwrap_or_default();
+        match range.rsplit_once('/') {
+            Some((_, "*")) => {
+                let end = range.trim_start_matches("bytes ").split(['-', '/']).nth(1);
+                Reply::status(308).header("Range", format!("bytes=0-{}", end.unwrap()))
+            }
+            _ => Reply::json(serde_json::json!({})),
+        }
+    }
+
+    /// `Content-Range` of every request sent to the session
+    fn ranges(server: &TestServer) -> Vec<String> {
+        server
+            .requests()
+            .iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| request.header("content-range").unwrap().to_string())
+            .collect()
+    }
+
+    async fn upload(store: &GCSFileSystem, length: usize) -> Result<()> {
+        let mut upload = store.resumable_upload(URI).await?;
+        upload.write(&vec![7; length]).await?;
+        upload.finish().await
+    }
+
+    #[tokio::test]
+    async fn chunks_and_their_ranges() {
+        // chunk sizes round down to the granularity, but never to zero
+        let builder = GCSFileSystem::builder().with_upload_chunk_size(300_000);
+        let (store, server) = upload_store(builder, acknowledge);
+        upload(&store, CHUNK_GRANULARITY + 10).await.unwrap();
+        assert_eq!(
+            ranges(&server),
+            ["bytes 0-262143/*", "bytes 262144-262153/262154"]
+        );
+
+        let builder = GCSFileSystem::builder().with_upload_chunk_size(1);
+        let (store, server) = upload_store(builder, acknowledge);
+        upload(&store, CHUNK_GRANULARITY).await.unwrap();
+        // the object is completed by an empty last chunk
+        assert_eq!(ranges(&server), ["bytes 0-262143/*", "bytes */262144"]);
+        let bodies = server.requests()[1..]
+            .iter()
+            .map(|request| request.body.len())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, [CHUNK_GRANULARITY, 0]);
+
+        let (store, server) = upload_store(GCSFileSystem::builder(), acknowledge);
+        upload(&store, 0).await.unwrap();
+        assert_eq!(ranges(&server), ["bytes */0"]);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_paced_to_the_rate() {
+        let clock = Arc::new(MockClock::default());
+        let builder = GCSFileSystem::builder()
+            .with_upload_chunk_size(CHUNK_GRANULARITY)
+            .with_max_upload_rate(CHUNK_GRANULARITY as u64);
+        let (store, server) = upload_store_with_clock(builder, clock.clone(), acknowledge);
+        assert!(store
+            .describe()
+            .contains("upload schedule: MaxUploadRate(262144)"));
+        upload(&store, 2 * CHUNK_GRANULARITY + 10).await.unwrap();
+        assert_eq!(ranges(&server).len(), 3);
+        // each chunk waits for the one before it to have taken a second
+        let second = std::time::Duration::from_secs(1);
+        assert_eq!(clock.sleeps(), [second, second]);
+    }
+
+    /// Fail the first chunk with a 503 and answer the query that follows with `persisted`
+    fn interrupted(persisted: Reply) -> impl Fn(&Request) -> Reply {
+        let chunks = AtomicUsize::new(0);
+        move |request| match request.header("content-range") {
+            Some("bytes */*") => persisted.clone(),
+            _ if chunks.fetch_add(1, Ordering::SeqCst) == 0 => Reply::status(503),
+            _ => acknowledge(request),
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_after_what_was_persisted() {
+        let builder = || GCSFileSystem::builder().with_upload_chunk_size(CHUNK_GRANULARITY);
+        let persisted = Reply::status(308).header("Range", "bytes=0-99");
+        let (store, server) = upload_store(builder(), interrupted(persisted));
+        upload(&store, CHUNK_GRANULARITY + 10).await.unwrap();
+        assert_eq!(
+            ranges(&server),
+            [
+                "bytes 0-262143/*",
+                "bytes */*",
+                "bytes 100-262143/*",
+                "bytes 262144-262153/262154"
+            ]
+        );
+        let resent = &server.requests()[3];
+        assert_eq!(resent.body.len(), CHUNK_GRANULARITY - 100);
+
+        // nothing persisted yet
+        let (store, server) = upload_store(builder(), interrupted(Reply::status(308)));
+        upload(&store, 10).await.unwrap();
+        assert_eq!(
+            ranges(&server),
+            ["bytes 0-9/10", "bytes */*", "bytes 0-9/10"]
+        );
+
+        // the last chunk completed the object before the failure reached the writer
+        let complete = Reply::json(serde_json::json!({}));
+        let (store, server) = upload_store(builder(), interrupted(complete));
+        upload(&store, 10).await.unwrap();
+        assert_eq!(ranges(&server), ["bytes 0-9/10", "bytes */*"]);
+    }
+
+    #[tokio::test]
+    async fn stops_resuming_expired_sessions() {
+        let (store, server) =
+            upload_store(GCSFileSystem::builder(), interrupted(Reply::status(410)));
+        upload(&store, 10).await.unwrap_err();
+        assert_eq!(ranges(&server), ["bytes 0-9/10", "bytes */*"]);
+    }
+
+    #[tokio::test]
+    async fn preconditions() {
+        let (store, server) = upload_store(GCSFileSystem::builder(), acknowledge);
+        store.resumable_upload(URI).await.unwrap();
+        store
+            .resumable_upload_if(URI, Precondition::DoesNotExist)
+            .await
+            .unwrap();
+        store
+            .resumable_upload_if(URI, Precondition::GenerationMatch(7))
+            .await
+            .unwrap();
+        store.put(URI, "a,b").await.unwrap();
+        let generation = store
+            .put_if(URI, "a,b", Precondition::GenerationMatch(3))
+            .await
+            .unwrap();
+        assert_eq!(generation, 1);
+
+        let requests = server.requests();
+        let parameters = requests
+            .iter()
+            .map(|request| request.query("ifGenerationMatch"))
+            .collect::<Vec<_>>();
+        assert_eq!(parameters, [None, Some("0"), Some("7"), None, Some("3")]);
+        let uploads = requests.iter().map(|request| request.query("uploadType"));
+        assert_eq!(
+            uploads.collect::<Vec<_>>(),
+            [
+                Some("resumable"),
+                Some("resumable"),
+                Some("resumable"),
+                Some("multipart"),
+                Some("multipart")
+            ]
+        );
+    }
+}